@@ -1,15 +1,31 @@
-use acpi::AcpiTables;
+use acpi::{
+    AcpiHandler, AcpiTables,
+    address::{AddressSpace, GenericAddress},
+    fadt::Fadt,
+};
 
 use crate::{
     LIMINE_RSDP_REQUEST,
+    arch_x86_64::hlt,
+    io::{read_u16, write_u8, write_u16},
     memory::{
+        paging::PageTableEntryFlags,
         physical::PhyAddr,
         virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocation, PageAllocator, VirtAddr},
     },
 };
-use core::ptr::NonNull;
+use core::{
+    fmt::Debug,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-#[derive(Clone, Copy, Debug)]
+/// Amount of regions mapped by AcpiTableHandler which weren't unmapped yet
+static OUTSTANDING_MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+/// Amount of with_tables calls currently running
+static TABLES_USERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
 pub struct AcpiTableHandler;
 impl AcpiTableHandler {
     pub fn new() -> Self {
@@ -17,6 +33,19 @@ impl AcpiTableHandler {
     }
 }
 
+impl Debug for AcpiTableHandler {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AcpiTableHandler")
+            .field("outstanding_mappings", &outstanding_mappings())
+            .finish()
+    }
+}
+
+/// Amount of regions the ACPI handler mapped and didn't unmap yet
+pub fn outstanding_mappings() -> usize {
+    OUTSTANDING_MAPPINGS.load(Ordering::Relaxed)
+}
+
 impl acpi::AcpiHandler for AcpiTableHandler {
     unsafe fn map_physical_region<T>(
         &self,
@@ -30,13 +59,15 @@ impl acpi::AcpiHandler for AcpiTableHandler {
         let addr = PhyAddr(physical_address as u64);
         let (alloc, virt_addr) = unsafe {
             GLOBAL_PAGE_ALLOCATOR
-                .map_physical(addr, page_amount)
+                // ACPI tables are normal memory, so they can be cached
+                .map_physical(addr, page_amount, PageTableEntryFlags::kernel_data())
                 .expect("ACPI TABLES SHOULDN'T BE IN USABLE MEMORY")
         };
         let ptr = virt_addr
             .0
             .next_multiple_of(core::mem::align_of::<T>() as u64) as *mut T;
         let ptr = NonNull::new(ptr).unwrap();
+        OUTSTANDING_MAPPINGS.fetch_add(1, Ordering::Relaxed);
         unsafe {
             acpi::PhysicalMapping::new(
                 physical_address,
@@ -56,13 +87,305 @@ impl acpi::AcpiHandler for AcpiTableHandler {
         unsafe {
             GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&allocation);
         }
+        OUTSTANDING_MAPPINGS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Why the ACPI tables can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The bootloader didn't give us an RSDP, i.e. the firmware doesn't have ACPI
+    NoRsdp,
+    /// There's an RSDP, but it or the tables it points to are malformed
+    InvalidTables(acpi::AcpiError),
+}
+
+impl core::fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AcpiError::NoRsdp => {
+                write!(f, "the firmware has no ACPI tables, boot with ACPI enabled")
+            }
+            AcpiError::InvalidTables(e) => {
+                write!(f, "the firmware's ACPI tables are malformed ({:?})", e)
+            }
+        }
     }
 }
 
-pub fn tables() -> AcpiTables<AcpiTableHandler> {
+/// Parse the ACPI tables and use them in f.
+/// In debug builds, checks that everything mapped while using them was unmapped once they're dropped,
+/// unless someone else used the tables at the same time (in which case we can't tell whose mappings are whose).
+/// ## Panic
+/// Panics if the tables are unusable. Boot checks this before initializing the devices which need them.
+pub fn with_tables<R>(f: impl FnOnce(&AcpiTables<AcpiTableHandler>) -> R) -> R {
+    try_with_tables(f).unwrap_or_else(|e| panic!("ACPI tables are unusable: {:?}", e))
+}
+
+/// Check that the ACPI tables are usable, without leaking the mappings it takes to parse them.
+pub fn available() -> Result<(), AcpiError> {
+    try_with_tables(|_| ())
+}
+
+fn try_with_tables<R>(f: impl FnOnce(&AcpiTables<AcpiTableHandler>) -> R) -> Result<R, AcpiError> {
+    TABLES_USERS.fetch_add(1, Ordering::Relaxed);
+    let before = outstanding_mappings();
+    // the tables go out of scope before the mappings are counted
+    let result = match tables() {
+        Ok(tables) => f(&tables),
+        Err(e) => {
+            TABLES_USERS.fetch_sub(1, Ordering::Relaxed);
+            return Err(e);
+        }
+    };
+    let alone = TABLES_USERS.fetch_sub(1, Ordering::Relaxed) == 1;
+    if alone {
+        debug_assert_eq!(outstanding_mappings(), before, "ACPI mappings leaked");
+    }
+    Ok(result)
+}
+
+/// Parse the ACPI tables. Prefer with_tables, which checks for leaked mappings.
+pub fn tables() -> Result<AcpiTables<AcpiTableHandler>, AcpiError> {
+    let rsdp = LIMINE_RSDP_REQUEST
+        .get_response()
+        .map(|rsdp| rsdp.address());
+    tables_from_rsdp(rsdp)
+}
+
+fn tables_from_rsdp(rsdp: Option<usize>) -> Result<AcpiTables<AcpiTableHandler>, AcpiError> {
+    let rsdp = rsdp.ok_or(AcpiError::NoRsdp)?;
+    // the tables are mapped through the page allocator
+    crate::memory::assert_memory_ready();
+    let handler = crate::acpi::AcpiTableHandler::new();
+    unsafe { acpi::AcpiTables::from_rsdp(handler, rsdp) }.map_err(AcpiError::InvalidTables)
+}
+
+/// PM1 control: the sleep type goes in bits 10-12
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+/// PM1 control: enter the sleep state in SLP_TYP
+const SLP_EN: u16 = 1 << 13;
+
+// aml opcodes, for finding the \_S5 package
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+
+/// The SLP_TYPa and SLP_TYPb values of the S5 (soft off) sleep state, from the package named _S5_ in aml.
+/// This isn't a full aml interpreter, it only understands the way every firmware we know of declares it:
+/// Name (_S5, Package (N) { a, b, ... }) with a and b constants.
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    // the name can also show up before its definition, i.e. in a method which mentions it
+    let start = aml
+        .windows(4)
+        .enumerate()
+        .filter(|(_, w)| *w == b"_S5_")
+        .map(|(i, _)| i)
+        .find(|&start| {
+            // the name may be \_S5_, in which case the root prefix comes between NameOp and it
+            let name_op = match start.checked_sub(1).map(|i| aml[i]) {
+                Some(b'\\') => start.checked_sub(2).map(|i| aml[i]),
+                before => before,
+            };
+            name_op == Some(NAME_OP)
+        })?;
+    let mut rest = aml.get(start + 4..)?;
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // the top 2 bits of the first byte of PkgLength are the amount of bytes which follow it
+    let pkg_length_bytes = 1 + (*rest.get(1)? >> 6) as usize;
+    // skip PackageOp, PkgLength and NumElements
+    rest = rest.get(1 + pkg_length_bytes + 1..)?;
+    let mut next_value = || -> Option<u8> {
+        let (value, len) = match *rest.first()? {
+            ZERO_OP => (0, 1),
+            ONE_OP => (1, 1),
+            BYTE_PREFIX => (*rest.get(1)?, 2),
+            _ => return None,
+        };
+        rest = &rest[len..];
+        Some(value)
+    };
+    let a = next_value()?;
+    let b = next_value()?;
+    Some((a, b))
+}
+
+/// The sleep types of S5, from the DSDT
+fn s5_sleep_types(tables: &AcpiTables<AcpiTableHandler>) -> Option<(u8, u8)> {
+    let dsdt = tables.dsdt().ok()?;
+    let length = dsdt.length as usize;
+    let mapping =
+        unsafe { AcpiTableHandler::new().map_physical_region::<u8>(dsdt.address, length) };
+    // safety: the mapping covers the whole aml of the DSDT, and lives until the end of the scope
+    let aml = unsafe { core::slice::from_raw_parts(mapping.virtual_start().as_ptr(), length) };
+    parse_s5(aml)
+}
+
+/// Write an 8 bit register described by the FADT. Returns false if it's somewhere we can't write.
+fn write_generic_address(register: &GenericAddress, value: u8) -> bool {
+    match register.address_space {
+        AddressSpace::SystemIo => {
+            unsafe { write_u8(register.address as u16, value) };
+            true
+        }
+        AddressSpace::SystemMemory => {
+            let addr = PhyAddr(register.address);
+            let Some((alloc, virt_addr)) = (unsafe {
+                GLOBAL_PAGE_ALLOCATOR.map_physical(addr, 1, PageTableEntryFlags::mmio())
+            }) else {
+                return false;
+            };
+            unsafe {
+                (virt_addr.0 as *mut u8).write_volatile(value);
+                GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+fn halt_forever() -> ! {
+    loop {
+        unsafe { hlt() };
+    }
+}
+
+/// Power the machine off, by entering the S5 sleep state through the PM1 control registers of the FADT.
+/// If there are no ACPI tables or they don't say how, or the firmware ignores us, halts forever instead.
+pub fn shutdown() -> ! {
+    unsafe { crate::interrupts::irq_disable() };
+    let registers = tables().ok().and_then(|tables| {
+        let fadt = tables.find_table::<Fadt>().ok()?;
+        let pm1a = fadt.pm1a_control_block().ok()?;
+        let pm1b = fadt.pm1b_control_block().ok()?;
+        Some((pm1a, pm1b, s5_sleep_types(&tables)?))
+    });
+    if let Some((pm1a, pm1b, (slp_typ_a, slp_typ_b))) = registers {
+        for (register, slp_typ) in [(Some(pm1a), slp_typ_a), (pm1b, slp_typ_b)] {
+            // PM1 control blocks are always io ports
+            let Some(register) = register.filter(|r| r.address_space == AddressSpace::SystemIo)
+            else {
+                continue;
+            };
+            let port = register.address as u16;
+            unsafe {
+                let control = read_u16(port) & !SLP_TYP_MASK;
+                write_u16(port, control | ((slp_typ as u16) << SLP_TYP_SHIFT) | SLP_EN);
+            }
+        }
+    }
+    halt_forever()
+}
+
+/// Reset the machine through the reset register of the FADT. Without one, falls back to the reset control
+/// register at 0xCF9, and then to pulsing the reset line through the keyboard controller.
+/// If all of them are ignored, halts forever.
+pub fn reboot() -> ! {
+    unsafe { crate::interrupts::irq_disable() };
+    let reset = tables().ok().and_then(|tables| {
+        let fadt = tables.find_table::<Fadt>().ok()?;
+        let register = fadt.reset_register().ok()?;
+        Some((register, fadt.reset_value))
+    });
+    if let Some((register, value)) = reset
+        && register.address != 0
+    {
+        write_generic_address(&register, value);
+    }
     unsafe {
-        let rsdp = LIMINE_RSDP_REQUEST.get_response().unwrap().address();
-        let handler = crate::acpi::AcpiTableHandler::new();
-        acpi::AcpiTables::from_rsdp(handler, rsdp).unwrap()
+        // reset control register: a full reset, once the reset bit goes from 0 to 1
+        write_u8(0xCF9, 0x02);
+        write_u8(0xCF9, 0x06);
+        // keyboard controller: pulse the reset line
+        write_u8(0x64, 0xFE);
+    }
+    halt_forever()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use acpi::madt::Madt;
+
+    #[test_case]
+    fn no_leaked_mappings() {
+        let before = outstanding_mappings();
+        let lapic_addr = with_tables(|tables| {
+            let madt = tables.find_table::<Madt>().unwrap();
+            assert!(outstanding_mappings() > before);
+            madt.get().local_apic_address
+        });
+        assert_ne!(lapic_addr, 0);
+        assert_eq!(outstanding_mappings(), before);
+    }
+
+    #[test_case]
+    fn missing_rsdp() {
+        let before = outstanding_mappings();
+        assert_eq!(tables_from_rsdp(None).err(), Some(AcpiError::NoRsdp));
+        assert_eq!(outstanding_mappings(), before);
+        assert!(tables().is_ok());
+        assert_eq!(available(), Ok(()));
+        assert_eq!(outstanding_mappings(), before);
+    }
+
+    #[test_case]
+    fn s5_package() {
+        // Name (_S5, Package (4) { 0x05, Zero, Zero, Zero })
+        let aml = [
+            0x10,
+            b'_',
+            b'S',
+            b'B',
+            b'_',
+            NAME_OP,
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            PACKAGE_OP,
+            0x07,
+            0x04,
+            BYTE_PREFIX,
+            0x05,
+            ZERO_OP,
+            ZERO_OP,
+            ZERO_OP,
+        ];
+        assert_eq!(parse_s5(&aml), Some((5, 0)));
+        // Name (\_S5, Package (2) { One, 0x07 })
+        let aml = [
+            NAME_OP,
+            b'\\',
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            PACKAGE_OP,
+            0x05,
+            0x02,
+            ONE_OP,
+            BYTE_PREFIX,
+            0x07,
+        ];
+        assert_eq!(parse_s5(&aml), Some((1, 7)));
+        // not a Name, i.e. a method which mentions it
+        assert_eq!(parse_s5(&aml[1..]), None);
+        // a mention before the real definition is skipped
+        let mut aml_with_mention = alloc::vec::Vec::from(&aml[1..6]);
+        aml_with_mention.extend_from_slice(&aml);
+        assert_eq!(parse_s5(&aml_with_mention), Some((1, 7)));
+        assert_eq!(parse_s5(&aml[..aml.len() - 1]), None);
+        assert_eq!(parse_s5(b"no sleep states"), None);
+        // the firmware's
+        let before = outstanding_mappings();
+        assert!(with_tables(s5_sleep_types).is_some());
+        assert_eq!(outstanding_mappings(), before);
     }
 }
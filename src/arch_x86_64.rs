@@ -1,8 +1,9 @@
 /// Bunch of functions relating to the x86_64 arch.
 /// Register get/set functions will always be inlined (since calling a function may change the output of certain registers,
 /// and also there isn't really a need for a whole function procedures for these functions)
-use crate::idt::IdtPtr;
+use crate::{idt::IdtPtr, memory::physical::PhyAddr};
 use core::arch::asm;
+pub use core::arch::x86_64::CpuidResult;
 // get the cs register
 #[inline(always)]
 pub fn cs() -> u16 {
@@ -17,6 +18,36 @@ pub unsafe fn lidt(idt_ptr: &IdtPtr) {
     unsafe { asm!("lidt [{}]", in(reg) idt_ptr) }
 }
 
+/// CR0.MP: wait/fwait respect CR0.TS
+pub const CR0_MP: u64 = 1 << 1;
+/// CR0.EM: x87/SSE instructions raise #UD, must be clear to use SSE
+pub const CR0_EM: u64 = 1 << 2;
+/// CR0.WP: the kernel can't write to read only pages either
+pub const CR0_WP: u64 = 1 << 16;
+/// CR4.PGE: global pages aren't flushed from the TLB when cr3 is written
+pub const CR4_PGE: u64 = 1 << 7;
+/// CR4.OSFXSR: enables SSE and fxsave/fxrstor
+pub const CR4_OSFXSR: u64 = 1 << 9;
+/// CR4.OSXMMEXCPT: SSE exceptions raise #XM instead of #UD
+pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+/// get the cr0 register
+#[inline(always)]
+pub fn cr0() -> u64 {
+    let out: u64;
+    unsafe { asm!("mov {}, cr0", out(reg) out) };
+    out
+}
+
+/// set the cr0 register
+///
+/// # Safety
+/// cr0 controls paging and protection, clearing the wrong bit will crash the system
+#[inline(always)]
+pub unsafe fn set_cr0(val: u64) {
+    unsafe { asm!("mov cr0, {}", in(reg) val) };
+}
+
 /// get the cr2 register
 #[inline(always)]
 pub fn cr2() -> u64 {
@@ -33,6 +64,68 @@ pub fn cr3() -> u64 {
     out
 }
 
+/// get the cr4 register
+#[inline(always)]
+pub fn cr4() -> u64 {
+    let out: u64;
+    unsafe { asm!("mov {}, cr4", out(reg) out) };
+    out
+}
+
+/// set the cr4 register
+///
+/// # Safety
+/// setting reserved or unsupported bits causes a general protection fault,
+/// and clearing bits which are in use (i.e. PAE) will crash the system
+#[inline(always)]
+pub unsafe fn set_cr4(val: u64) {
+    unsafe { asm!("mov cr4, {}", in(reg) val) };
+}
+
+/// Enable write protection and global pages.
+/// Must be called on every cpu, since control registers are per cpu.
+pub fn enable_cpu_features() {
+    unsafe {
+        set_cr0(cr0() | CR0_WP);
+        set_cr4(cr4() | CR4_PGE);
+    }
+}
+
+/// The memory fxsave saves the x87/SSE state to
+#[repr(C, align(16))]
+pub struct FxSaveArea(pub [u8; 512]);
+
+impl FxSaveArea {
+    pub const fn new() -> Self {
+        Self([0; 512])
+    }
+}
+
+impl Default for FxSaveArea {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// save the x87/SSE state
+///
+/// # Safety
+/// SSE must be enabled (CR4.OSFXSR), see cpu::fpu_init
+#[inline(always)]
+pub unsafe fn fxsave(area: &mut FxSaveArea) {
+    unsafe { asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)) };
+}
+
+/// restore the x87/SSE state
+///
+/// # Safety
+/// SSE must be enabled (CR4.OSFXSR), see cpu::fpu_init, and the area must have been filled by fxsave,
+/// since restoring reserved MXCSR bits causes a general protection fault
+#[inline(always)]
+pub unsafe fn fxrstor(area: &FxSaveArea) {
+    unsafe { asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags, readonly)) };
+}
+
 /// get the rbp register
 #[inline(always)]
 pub fn rbp() -> u64 {
@@ -41,6 +134,90 @@ pub fn rbp() -> u64 {
     out
 }
 
+#[inline(always)]
+pub fn rsp() -> u64 {
+    let out: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) out) };
+    out
+}
+
+/// execute the cpuid instruction with the given leaf (eax) and subleaf (ecx)
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    core::arch::x86_64::__cpuid_count(leaf, subleaf)
+}
+
+/// The cpu features the kernel cares about, from cpuid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub has_apic: bool,
+    pub has_x2apic: bool,
+    /// the execute disable bit, see memory::enable_no_execute
+    pub has_nx: bool,
+    /// level 3 page table entries can map 1 GiB pages
+    pub has_1gib_pages: bool,
+    pub has_tsc: bool,
+    /// the tsc ticks at a constant rate in every power state, so it can be used as a clock
+    pub has_invariant_tsc: bool,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        // the extended leaves only go up to the leaf in eax of 0x8000_0000
+        let max_extended = cpuid(0x8000_0000, 0).eax;
+        let extended_leaf = |leaf| {
+            if max_extended >= leaf {
+                cpuid(leaf, 0)
+            } else {
+                CpuidResult {
+                    eax: 0,
+                    ebx: 0,
+                    ecx: 0,
+                    edx: 0,
+                }
+            }
+        };
+        Self::from_leaves(
+            cpuid(1, 0),
+            extended_leaf(0x8000_0001),
+            extended_leaf(0x8000_0007),
+        )
+    }
+
+    /// Parse leaf 1, leaf 0x8000_0001 and the power management leaf 0x8000_0007
+    fn from_leaves(leaf_1: CpuidResult, extended: CpuidResult, power: CpuidResult) -> Self {
+        Self {
+            has_apic: leaf_1.edx & (1 << 9) != 0,
+            has_x2apic: leaf_1.ecx & (1 << 21) != 0,
+            has_nx: extended.edx & (1 << 20) != 0,
+            has_1gib_pages: extended.edx & (1 << 26) != 0,
+            has_tsc: leaf_1.edx & (1 << 4) != 0,
+            has_invariant_tsc: power.edx & (1 << 8) != 0,
+        }
+    }
+}
+
+/// Read the time stamp counter. It may be read before earlier instructions completed,
+/// so put an lfence before it when timing something.
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags))
+    };
+    (high as u64) << 32 | low as u64
+}
+
+/// Read the time stamp counter after all earlier instructions completed, along with IA32_TSC_AUX
+/// (which the OS sets to identify the cpu)
+#[inline(always)]
+pub fn rdtscp() -> (u64, u32) {
+    let (low, high, aux): (u32, u32, u32);
+    unsafe {
+        asm!("rdtscp", out("eax") low, out("edx") high, out("ecx") aux, options(nomem, nostack, preserves_flags))
+    };
+    ((high as u64) << 32 | low as u64, aux)
+}
+
 /// ## Safety:
 /// if no interrupt is called, this will lock up the computer.
 pub unsafe fn hlt() {
@@ -62,15 +239,76 @@ pub unsafe fn invlpg(addr: u64) {
     }
 }
 
+/// Switch to the address space whose level 4 page table is at pml4, and flush the TLB (except for global pages).
+///
+/// # Safety
+/// pml4 must be a valid level 4 page table, which maps the kernel (including the code running this) the same way.
+#[inline(always)]
+pub unsafe fn set_cr3(pml4: PhyAddr) {
+    debug_assert!(pml4.0 & 0xfff == 0, "unaligned page table {:#x}", pml4.0);
+    unsafe { asm!("mov cr3, {}", in(reg) pml4.0, options(nostack, preserves_flags)) };
+}
+
+/// Flush the TLB (except for global pages) by writing cr3 back as it is
 #[inline(always)]
 pub unsafe fn reload_cr3() {
-    let cr3 = cr3();
+    unsafe { asm!("mov cr3, {}", in(reg) cr3(), options(nostack, preserves_flags)) };
+}
+
+/// iretq to the next instruction. The cpu blocks NMIs until the next iretq,
+/// so call it after leaving an NMI handler without returning from it (i.e. by panicking).
+///
+/// # Safety
+/// must run in ring 0, and only once the NMI handler is done with its state,
+/// since another NMI can arrive right after the iretq
+#[inline(always)]
+pub unsafe fn unblock_nmi() {
     unsafe {
-        asm!("mov {0:r}, cr3", in(reg) 3);
-        asm!("mov {}, cr3", in(reg) cr3)
+        asm!(
+            "mov {tmp:x}, ss",
+            "push {tmp}",
+            "lea {tmp}, [rsp + 8]",
+            "push {tmp}",
+            "pushfq",
+            "mov {tmp:x}, cs",
+            "push {tmp}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "iretq",
+            "2:",
+            tmp = out(reg) _,
+        )
     }
 }
 
+/// Full memory barrier: loads and stores before it complete before loads and stores after it.
+/// x86 only reorders a store with a later load (and weakly ordered accesses), so this is rarely needed;
+/// use it where a device must see a write before we read something back.
+#[inline(always)]
+pub fn mfence() {
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Store barrier, for ordering weakly ordered stores (i.e. to write combining memory, or non temporal stores)
+#[inline(always)]
+pub fn sfence() {
+    unsafe { asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// Load barrier, also keeps later instructions from starting before earlier ones completed (i.e. before rdtsc)
+#[inline(always)]
+pub fn lfence() {
+    unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+}
+
+/// Keep the compiler from moving memory accesses across this point. Emits no instruction.
+/// Needed when a write changes what another address means (i.e. a page table entry),
+/// which the compiler can't know about.
+#[inline(always)]
+pub fn compiler_fence() {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
 #[inline(always)]
 pub unsafe fn sti() {
     unsafe { asm!("sti") }
@@ -93,3 +331,81 @@ pub unsafe fn rflags() -> u64 {
     }
     rflags
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn cpu_features_enabled() {
+        assert!(cr0() & CR0_WP != 0);
+        assert!(cr4() & CR4_PGE != 0);
+    }
+
+    #[test_case]
+    fn set_pge() {
+        let old = cr4();
+        unsafe { set_cr4(old | CR4_PGE) };
+        assert!(cr4() & CR4_PGE != 0);
+        unsafe { set_cr4(old) };
+    }
+
+    #[test_case]
+    fn detected_features() {
+        let leaf_1 = CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 1 << 21,
+            edx: (1 << 9) | (1 << 4),
+        };
+        let extended = CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 1 << 26,
+        };
+        let power = CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 1 << 8,
+        };
+        assert_eq!(
+            CpuFeatures::from_leaves(leaf_1, extended, power),
+            CpuFeatures {
+                has_apic: true,
+                has_x2apic: true,
+                has_nx: false,
+                has_1gib_pages: true,
+                has_tsc: true,
+                has_invariant_tsc: true,
+            }
+        );
+        // the kernel doesn't boot without these
+        let features = CpuFeatures::detect();
+        assert!(features.has_apic);
+        assert!(features.has_tsc);
+        assert_eq!(features.has_nx, crate::memory::no_execute_enabled());
+    }
+
+    #[test_case]
+    fn tsc_advances() {
+        let first = rdtsc();
+        let (second, _) = rdtscp();
+        assert!(second > first);
+        assert!(rdtsc() > second);
+    }
+
+    #[test_case]
+    fn write_cr3_back() {
+        let old = cr3();
+        unsafe { reload_cr3() };
+        assert_eq!(cr3(), old);
+        // switching to the address space we're already in
+        let pml4 = PhyAddr(old & !0xfff);
+        unsafe { set_cr3(pml4) };
+        assert_eq!(cr3(), pml4.0);
+        // set_cr3 drops the cache control bits, if there were any
+        unsafe { asm!("mov cr3, {}", in(reg) old) };
+    }
+}
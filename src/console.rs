@@ -3,21 +3,21 @@ use core::{
     ops::{Deref, DerefMut},
 };
 
-use spin::mutex::SpinMutex;
-
 use crate::screen::{Color, Screen};
+use crate::sync::CheckedMutex;
 
-/// The width of one character in pixels
-const CHAR_WIDTH: usize = 8;
-
-/// the height of one character in pixels
-const CHAR_HEIGHT: usize = 16;
+/// The font Console::new uses, 8x16 pixels
+const DEFAULT_FONT: &[u8] = include_bytes!("../AIXOID9.F16");
+/// The width of one character of the default font in pixels
+const DEFAULT_CHAR_WIDTH: usize = 8;
+/// the height of one character of the default font in pixels
+const DEFAULT_CHAR_HEIGHT: usize = 16;
 // space between characters in pixels
 const SPACE_BETWEEN_CHARS: usize = 1;
 
 /// A thread-unsafe console abstracton on a SCREEN which can draw ascii characters.  
 /// It starts drawing characters from upwards to downwards, if it reaches the end of a line it simply continues to the next line
-/// and if it reaches the end of the screen, it simply continues from the first line.
+/// and if it reaches the end of the screen, it scrolls everything up by a line.
 /// This struct implements fmt::Write, use it for writing multiple characters.
 #[derive(Clone)]
 pub struct Console {
@@ -29,18 +29,66 @@ pub struct Console {
     pub bg_color: Color,
     /// the color of the foreground (text)
     pub fg_color: Color,
+    /// whether the cell at cursor_pos is currently inverted to show the cursor
+    cursor_visible: bool,
+    /// the glyphs of all 256 characters, see Console::with_font
+    font: &'static [u8],
+    /// The width of one character in pixels
+    char_width: usize,
+    /// the height of one character in pixels
+    char_height: usize,
 }
 
 impl Console {
     /// Get a new console from a screen.  
     /// Note: immediately colors the whole screen to bg_color.
-    pub fn new(mut screen: Screen, bg_color: Color, fg_color: Color) -> Self {
+    pub fn new(screen: Screen, bg_color: Color, fg_color: Color) -> Self {
+        Self::with_font(
+            screen,
+            bg_color,
+            fg_color,
+            DEFAULT_FONT,
+            DEFAULT_CHAR_WIDTH,
+            DEFAULT_CHAR_HEIGHT,
+        )
+    }
+
+    /// Get a new console which draws characters with a font of char_width x char_height pixels.
+    /// The font is a raw bitmap font (i.e. the .F16 fonts of DOS): the glyph of every character is char_height rows,
+    /// every row is char_width bits rounded up to whole bytes, and the most significant bit is the leftmost pixel.
+    /// Note: immediately colors the whole screen to bg_color.
+    /// ## Panic
+    /// Panics if the characters are empty, or if the font doesn't have all 256 glyphs.
+    pub fn with_font(
+        mut screen: Screen,
+        bg_color: Color,
+        fg_color: Color,
+        font: &'static [u8],
+        char_width: usize,
+        char_height: usize,
+    ) -> Self {
+        assert!(
+            char_width != 0 && char_height != 0,
+            "characters can't be {}x{}",
+            char_width,
+            char_height
+        );
+        assert!(
+            font.len() >= 256 * char_height * char_width.div_ceil(8),
+            "the font is too small for {}x{} characters",
+            char_width,
+            char_height
+        );
         screen.draw_all(bg_color);
         Self {
             screen,
             cursor_pos: (0, 0),
             bg_color,
             fg_color,
+            cursor_visible: false,
+            font,
+            char_width,
+            char_height,
         }
     }
 
@@ -50,59 +98,111 @@ impl Console {
     }
     /// Print a single ascii character to the console with specific colors
     pub fn print_char_colored(&mut self, c: u8, fg_color: Color, bg_color: Color) {
+        // erase the cursor before drawing over it, and draw it again at the new position
+        let cursor_visible = self.cursor_visible;
+        self.hide_cursor();
+        self.advance(c, fg_color, bg_color);
+        if cursor_visible {
+            self.draw_cursor();
+        }
+    }
+
+    /// Draw the character (if it isn't a newline) and move the cursor past it
+    fn advance(&mut self, c: u8, fg_color: Color, bg_color: Color) {
         let (mut x, mut y) = self.cursor_pos;
         if c == b'\n' {
-            x = 0;
-            y += CHAR_HEIGHT;
-            if y + CHAR_HEIGHT > self.screen.height {
-                y = 0;
-            }
-            self.cursor_pos = (x, y);
+            self.cursor_pos = (0, self.next_line(y));
             return;
         }
         self.draw_char(c, x, y, fg_color, bg_color);
 
         // increment cursor, + 1 for space between characters
-        x += CHAR_WIDTH + SPACE_BETWEEN_CHARS;
+        x += self.char_width + SPACE_BETWEEN_CHARS;
         // we need to make sure there is enough place for the next character
-        if x + CHAR_WIDTH > self.screen.width {
+        if x + self.char_width > self.screen.width {
             // we go to the next line
             x = 0;
-            y += CHAR_HEIGHT;
-            if y + CHAR_HEIGHT > self.screen.height {
-                // we go to the first line
-                y = 0;
-            }
+            y = self.next_line(y);
         }
         self.cursor_pos = (x, y);
     }
 
+    /// The y of the line after the one at y. If y is the last line, the screen is scrolled and it stays the last line.
+    fn next_line(&mut self, y: usize) -> usize {
+        if y + 2 * self.char_height <= self.screen.height {
+            return y + self.char_height;
+        }
+        // everything but the first line moves up, and the last line is cleared
+        self.screen
+            .copy_rect((0, self.char_height), (0, 0), self.screen.width, y);
+        self.screen
+            .fill_rect(0, y, self.screen.width, self.char_height, self.bg_color);
+        y
+    }
+
     /// Clear the console, painting it in the pre-assigned background color
     pub fn clear(&mut self) {
+        let cursor_visible = self.cursor_visible;
         self.cursor_pos = (0, 0);
         self.screen.draw_all(self.bg_color);
+        self.cursor_visible = false;
+        if cursor_visible {
+            self.draw_cursor();
+        }
+    }
+
+    /// Show the cursor by inverting the cell the next character goes to.
+    /// There's no hardware cursor on a framebuffer, so it's drawn over the cell, and printing moves it along.
+    pub fn draw_cursor(&mut self) {
+        if !self.cursor_visible {
+            self.invert_cursor_cell();
+            self.cursor_visible = true;
+        }
+    }
+
+    /// Erase the cursor, restoring the cell under it
+    pub fn hide_cursor(&mut self) {
+        if self.cursor_visible {
+            self.invert_cursor_cell();
+            self.cursor_visible = false;
+        }
+    }
+
+    /// Show the cursor if it's hidden and hide it if it's shown, i.e. on every tick of a timer to make it blink
+    pub fn toggle_cursor(&mut self) {
+        if self.cursor_visible {
+            self.hide_cursor();
+        } else {
+            self.draw_cursor();
+        }
+    }
+
+    pub fn is_cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    fn invert_cursor_cell(&mut self) {
+        let (x, y) = self.cursor_pos;
+        // draw_char draws the glyph's columns one pixel to the right of x
+        self.screen
+            .invert_rect(x + 1, y, self.char_width, self.char_height);
     }
 
     /// Draw a single ascii character to the console
-    // TODO: perhaps make the font and the related constants fields of the Console to generalize to more fonts?
-    fn draw_char(&mut self, c: u8, x: usize, mut y: usize, fg_color: Color, bg_color: Color) {
-        let font_bytes = include_bytes!("../AIXOID9.F16");
-        let mut pos = (c as usize) * CHAR_HEIGHT;
-        for _ in 0..CHAR_HEIGHT {
-            let display_byte = font_bytes[pos];
+    fn draw_char(&mut self, c: u8, x: usize, y: usize, fg_color: Color, bg_color: Color) {
+        let row_bytes = self.char_width.div_ceil(8);
+        let glyph = &self.font[c as usize * self.char_height * row_bytes..];
+        for (row_num, row) in glyph.chunks(row_bytes).take(self.char_height).enumerate() {
             // now we inspect each bit and draw accoridngly
             // possible optimization: have a table which maps bytes to array of bitfields
-            for i in 0..CHAR_WIDTH {
-                let is_set = display_byte & (1 << i) != 0;
-                if is_set {
-                    // we have to draw fg color
-                    self.screen.draw_pixel(x + (CHAR_WIDTH - i), y, fg_color);
-                } else {
-                    self.screen.draw_pixel(x + (CHAR_WIDTH - i), y, bg_color);
-                }
+            for column in 0..self.char_width {
+                let is_set = row[column / 8] & (0x80 >> (column % 8)) != 0;
+                let color = if is_set { fg_color } else { bg_color };
+                // a glyph at the edge of the screen gets clipped, rather than taking down the kernel
+                let _ = self
+                    .screen
+                    .try_draw_pixel(x + 1 + column, y + row_num, color);
             }
-            y += 1;
-            pos += 1;
         }
     }
 }
@@ -118,16 +218,16 @@ impl fmt::Write for Console {
     }
 }
 
-/// thread safe console. This type exists to provide a Write implementation for SpinMutex<Console>.
-pub struct ThreadSafeConsole(SpinMutex<Console>);
+/// thread safe console. This type exists to provide a Write implementation for CheckedMutex<Console>.
+pub struct ThreadSafeConsole(CheckedMutex<Console>);
 
 impl ThreadSafeConsole {
     pub fn new(console: Console) -> Self {
-        ThreadSafeConsole(SpinMutex::new(console))
+        ThreadSafeConsole(CheckedMutex::new(console))
     }
 }
 impl Deref for ThreadSafeConsole {
-    type Target = SpinMutex<Console>;
+    type Target = CheckedMutex<Console>;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -146,3 +246,124 @@ impl fmt::Write for ThreadSafeConsole {
         console.write_str(s)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn scrolls_at_the_bottom() {
+        let (width, height) = (
+            4 * (DEFAULT_CHAR_WIDTH + SPACE_BETWEEN_CHARS),
+            3 * DEFAULT_CHAR_HEIGHT + 5,
+        );
+        let new_console = |buf: &mut [u32]| {
+            let screen =
+                unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+            Console::new(screen, Color::black(), Color::white())
+        };
+        let mut buf = vec![0u32; width * height];
+        let mut console = new_console(&mut buf);
+        // the last line wraps, and pushes out the first one as well
+        for c in b"a\nb\nc\nddddefg" {
+            console.print_char(*c);
+        }
+        assert_eq!(
+            console.cursor_pos,
+            (
+                3 * (DEFAULT_CHAR_WIDTH + SPACE_BETWEEN_CHARS),
+                2 * DEFAULT_CHAR_HEIGHT
+            )
+        );
+
+        let mut expected = vec![0u32; width * height];
+        let mut reference = new_console(&mut expected);
+        for c in b"c\nddddefg" {
+            reference.print_char(*c);
+        }
+        assert_eq!(buf, expected);
+    }
+
+    #[test_case]
+    fn custom_font() {
+        // 10x2 glyphs: 2 bytes per row, character 1 is a frame of its first and last columns
+        static FONT: [u8; 256 * 2 * 2] = {
+            let mut font = [0; 256 * 2 * 2];
+            font[4] = 0b1000_0000;
+            font[5] = 0b0100_0000;
+            font[6] = 0b1000_0000;
+            font[7] = 0b0100_0000;
+            font
+        };
+        // room for a second line, so that wrapping after the character doesn't scroll it away
+        let (width, height) = (12, 4);
+        let mut buf = vec![0u32; width * height];
+        let screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        let mut console = Console::with_font(screen, Color::black(), Color::white(), &FONT, 10, 2);
+        console.print_char(1);
+        for y in 0..height {
+            for x in 0..width {
+                let lit = buf[y * width + x] == Color::white().as_u32();
+                assert_eq!(lit, y < 2 && (x == 1 || x == 10), "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test_case]
+    fn software_cursor() {
+        let (width, height) = (
+            4 * (DEFAULT_CHAR_WIDTH + SPACE_BETWEEN_CHARS),
+            2 * DEFAULT_CHAR_HEIGHT,
+        );
+        let mut buf = vec![0u32; width * height];
+        let screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        let mut console = Console::new(screen, Color::black(), Color::white());
+        console.print_char(b'a');
+        let before = buf.clone();
+
+        console.draw_cursor();
+        assert!(console.is_cursor_visible());
+        // only the cell of the next character changed
+        let second_cell = DEFAULT_CHAR_WIDTH + SPACE_BETWEEN_CHARS + 1;
+        for (i, (now, was)) in buf.iter().zip(&before).enumerate() {
+            let (x, y) = (i % width, i / width);
+            let in_cell = y < DEFAULT_CHAR_HEIGHT
+                && (second_cell..second_cell + DEFAULT_CHAR_WIDTH).contains(&x);
+            assert_eq!(now != was, in_cell, "pixel ({}, {})", x, y);
+        }
+        console.hide_cursor();
+        assert_eq!(buf, before);
+
+        // printing under a visible cursor leaves the same pixels as printing without one
+        let mut reference = buf.clone();
+        let screen =
+            unsafe { Screen::from_raw(reference.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        let mut without_cursor = Console {
+            screen,
+            cursor_pos: console.cursor_pos,
+            bg_color: Color::black(),
+            fg_color: Color::white(),
+            cursor_visible: false,
+            font: DEFAULT_FONT,
+            char_width: DEFAULT_CHAR_WIDTH,
+            char_height: DEFAULT_CHAR_HEIGHT,
+        };
+        console.toggle_cursor();
+        console.print_char(b'b');
+        without_cursor.print_char(b'b');
+        console.toggle_cursor();
+        assert!(!console.is_cursor_visible());
+        assert_eq!(buf, reference);
+    }
+
+    #[test_case]
+    fn empty_characters_rejected() {
+        crate::should_panic_with!("characters can't be 0x16");
+        let mut buf = vec![0u32; 16];
+        let screen = unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), 4, 4, 4, 16) };
+        Console::with_font(screen, Color::black(), Color::white(), DEFAULT_FONT, 0, 16);
+    }
+}
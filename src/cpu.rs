@@ -1,71 +1,211 @@
-use core::{mem::MaybeUninit, u32};
+pub mod gdt;
 
+use core::u32;
+
+use alloc::boxed::Box;
+#[cfg(feature = "smp")]
 use limine::mp::Cpu;
+use spin::Once;
 
+#[cfg(feature = "smp")]
 use crate::LIMINE_CPU_REQUEST;
 use crate::{
-    arch_x86_64::hlt,
+    arch_x86_64::{CR0_EM, CR0_MP, CR4_OSFXSR, CR4_OSXMMEXCPT, cr0, cr4, set_cr0, set_cr4},
     console_println,
     dev::{
         hpet::Hpet,
-        ioapic::{DeilveryMode, DestinationMode, InterruptPolarity, IoApic, IoApicRedirectEntry},
+        ioapic::{DeliveryMode, DestinationMode, InterruptPolarity, IoApic, IoApicRedirectEntry},
+        keyboard::Keyboard,
         local_apic::LocalApic,
+        pci, pic,
     },
+    error::KernelError,
     idt::{IdtEntry, IdtEntryType},
     interrupt_handler_fn,
-    interrupts::SHARED_IDT,
+    interrupts::{SHARED_IDT, register_handler},
+    msr::{IA32_GS_BASE, rdmsr, wrmsr},
 };
 
 #[derive(Debug)]
+#[repr(C)]
 pub struct PerCpu {
-    pub lapic_ticks_per_ms: u32,
+    /// Pointer to this struct. Must be the first field: percpu() reads it with a single gs:[0] load.
+    this: *const PerCpu,
+    /// The initial local apic id, which is also the one cpuid reports. Identifies lock holders, see sync::DebugMutex
+    pub lapic_id: u32,
+    /// Set once the cpu calibrated its local apic timer
+    pub lapic_ticks_per_ms: Once<u32>,
+    /// Rate of the TSC, see time::tsc_calibrate. Per ms rather than per ns, since there are only a few
+    /// per ns. None if the TSC can't be used as a clock. Set once the cpu calibrated it.
+    pub tsc_ticks_per_ms: Once<Option<u64>>,
+}
+
+// safety: this only ever points at the PerCpu itself, and the rest is only set through Once
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub const fn new(lapic_id: u32) -> Self {
+        Self {
+            this: core::ptr::null(),
+            lapic_id,
+            lapic_ticks_per_ms: Once::new(),
+            tsc_ticks_per_ms: Once::new(),
+        }
+    }
+}
+
+/// The PerCpu of every cpu, indexed like cpus(). Allocated by the BSP once the cpu count is known
+static PERCPUS: Once<Box<[PerCpu]>> = Once::new();
+
+/// A PerCpu for each of the cpus, with this pointing at itself
+fn new_percpus(cpus: impl Iterator<Item = CpuInfo>) -> Box<[PerCpu]> {
+    let mut percpus: Box<[PerCpu]> = cpus.map(|cpu| PerCpu::new(cpu.lapic_id)).collect();
+    for percpu in percpus.iter_mut() {
+        percpu.this = percpu;
+    }
+    percpus
+}
+
+/// Get the data of the current cpu, None if init_percpu wasn't called on this cpu yet.
+// Note: we currently never go to user mode, so GS base is always the kernel's one.
+// once we do, we'll need to swapgs on kernel entry/exit.
+pub fn percpu() -> Option<&'static PerCpu> {
+    // the GS base starts out as 0, and reading gs:[0] then would page fault
+    if unsafe { rdmsr(IA32_GS_BASE) } == 0 {
+        return None;
+    }
+    let ptr: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        // safety: init_percpu only loads PerCpus from PERCPUS, which is never dropped
+        Some(&*ptr)
+    }
+}
+
+/// Allocate the PerCpu of every cpu. Must be called by the BSP once the heap works, before the other cpus run.
+/// They're sized by the actual cpu count, so there's no limit on the amount of cpus.
+pub fn alloc_percpus() {
+    PERCPUS.call_once(|| new_percpus(cpus()));
+}
+
+/// Make percpu the data of the current cpu, by storing a pointer to it in the GS base of this cpu.
+/// ## Safety
+/// percpu must stay where it is for as long as it's the data of this cpu.
+unsafe fn load_percpu(percpu: &PerCpu) {
+    unsafe {
+        wrmsr(IA32_GS_BASE, percpu as *const PerCpu as u64);
+    }
+}
+
+/// Set the data of the current cpu, whose local apic id is lapic_id, to its PerCpu from alloc_percpus.
+/// ## Panic
+/// Panics if alloc_percpus wasn't called yet, or if there's no cpu with that id.
+pub fn init_percpu(lapic_id: u32) -> &'static PerCpu {
+    let percpu = PERCPUS
+        .get()
+        .expect("init_percpu before alloc_percpus")
+        .iter()
+        .find(|percpu| percpu.lapic_id == lapic_id)
+        .expect("no such cpu");
+    unsafe { load_percpu(percpu) };
+    percpu
+}
+
+/// Enable the FPU and SSE on this cpu, so that floating point and SIMD instructions don't fault.
+/// Must be called on every cpu before it runs any of them.
+pub fn fpu_init() {
+    unsafe {
+        set_cr0((cr0() | CR0_MP) & !CR0_EM);
+        set_cr4(cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+        core::arch::asm!("fninit", options(nomem, nostack));
+    }
 }
 
-/// Safety: call this after PerCpu has been initialized
-pub unsafe fn percpu() -> &'static PerCpu {
-    // safety: this value is only accessible via this function after it has been initialized,
-    // and it's only accessed in an Immutable manner.
-    let maybe_uninit = unsafe { &PERCPUS[LocalApic::id() as usize] };
-    // we shouldn't call this without first initializing the struct
-    // we should probably just check if it has been initializede
-    unsafe { maybe_uninit.assume_init_ref() }
+/// A cpu in the system, regardless of whether we got it from Limine or not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// ACPI processor id
+    pub id: u32,
+    pub lapic_id: u32,
+    pub is_bsp: bool,
 }
 
-// probably enough for now
-pub const MAX_CPU_COUNT: usize = 32;
+/// All the cpus which we can run on, the BSP included.
+#[cfg(feature = "smp")]
+pub fn cpus() -> impl Iterator<Item = CpuInfo> {
+    let response = LIMINE_CPU_REQUEST.get_response().unwrap();
+    let bsp_lapic_id = response.bsp_lapic_id();
+    response.cpus().iter().map(move |cpu| CpuInfo {
+        id: cpu.id,
+        lapic_id: cpu.lapic_id,
+        is_bsp: cpu.lapic_id == bsp_lapic_id,
+    })
+}
 
-static mut PERCPUS: [MaybeUninit<PerCpu>; MAX_CPU_COUNT] =
-    [const { MaybeUninit::uninit() }; MAX_CPU_COUNT];
+/// All the cpus which we can run on. Without smp it's only the BSP.
+#[cfg(not(feature = "smp"))]
+pub fn cpus() -> impl Iterator<Item = CpuInfo> {
+    core::iter::once(CpuInfo {
+        id: 0,
+        // without the Limine request, we only ever run on the BSP
+        lapic_id: LocalApic::id(),
+        is_bsp: true,
+    })
+}
+
+pub fn bsp_lapic_id() -> u32 {
+    cpus().find(|cpu| cpu.is_bsp).unwrap().lapic_id
+}
+
+/// Amount of cpus we can run on, the BSP included
+pub fn cpu_count() -> usize {
+    cpus().count()
+}
+
+/// The IOAPIC pin of the PIT's irq 0, through the interrupt source override every PC firmware has
+const PIT_IOAPIC_IRQ: u8 = 2;
 
 fn hpet_init() {
     // safety: we are the sole owner of the timer
     let timer = unsafe { Hpet::timer(0) };
     timer.enable();
+    // the legacy mapping would take over the PIT's and the RTC's irqs, and with the RTC's the routing of
+    // timer 1 (the watchdog), so we route the timer to an irq of its own and mask the PIT ourselves
+    IoApic::mask_irq(PIT_IOAPIC_IRQ);
+    let irq =
+        match (16..24).find(|irq| timer.can_route_irq_to(*irq) && IoApic::is_masked(*irq as u8)) {
+            Some(irq) => {
+                Hpet::disable_legacy_mapping();
+                timer.route_irq_to(irq);
+                irq as u8
+            }
+            None => {
+                // the legacy mapping routes the timer to the PIT's irq instead
+                Hpet::enable_legacy_mapping();
+                PIT_IOAPIC_IRQ
+            }
+        };
     let irq_redirection = IoApicRedirectEntry {
         dest: LocalApic::id() as u8,
         mask: false,
         trigger_mode: timer.trigger_mode(),
         interrupt_polarity: InterruptPolarity::HighActive,
         destination_mode: DestinationMode::Physical,
-        delivery_mode: DeilveryMode::Fixed,
+        delivery_mode: DeliveryMode::Fixed,
         redirected_irq_num: 32,
     };
-
-    // currently we can't mask PIT ourselves currently, so we use the legacy mapping to stop it from throwing interrupts
-    // in the future we should probably just route the IRQ ourselves and explicitly mask the PIT
-    Hpet::enable_legacy_mapping();
-    IoApic::redirect_irq(2 as u8, irq_redirection);
+    IoApic::redirect_irq(irq, irq_redirection);
     Hpet::enable();
-    SHARED_IDT.guard(|idt| {
-        idt.lock().as_mut().insert(
-            32,
-            IdtEntry::new_with_current_cs(IdtEntryType::Interrupt(interrupt_handler_fn!(|| {
-                LocalApic::eoi();
-            }))),
-        );
-    });
+    register_handler(
+        32,
+        Box::new(|_| {
+            crate::time::record_timer_latency();
+            LocalApic::eoi();
+        }),
+    );
 
-    console_println!("hpet initialized! irq: {}", 2);
+    console_println!("hpet initialized! irq: {}", irq);
 }
 
 fn local_apic_init() -> u32 {
@@ -85,7 +225,8 @@ fn local_apic_init() -> u32 {
         idt.as_mut().insert(
             34,
             IdtEntry::new_with_current_cs(IdtEntryType::Interrupt(interrupt_handler_fn!(|| {
-                panic!("lapic timer");
+                crate::time::tick();
+                LocalApic::eoi();
             }))),
         );
         idt.as_mut().insert(
@@ -108,13 +249,21 @@ fn local_apic_init() -> u32 {
     ticks_per_ms
 }
 
-pub fn init() {
+/// Bring up the interrupt controllers and timers, then run the BSP.
+/// Only returns if a device it needs is unusable.
+pub fn init() -> Result<(), KernelError> {
+    // the interrupt controllers and timers are all found through ACPI, there's nothing to fall back to yet
+    crate::acpi::available()?;
+    crate::dev::check_required()?;
     SHARED_IDT.guard(|idt| unsafe {
         idt.lock().as_ref().load();
     });
     console_println!("loaded shared idt!");
+    // before anything enables interrupts, so that the legacy PICs can't raise exceptions
+    pic::init();
     IoApic::init();
     hpet_init();
+    Keyboard::init();
     console_println!("lapic ver: {}", LocalApic::version());
     console_println!("apic ver: {}", LocalApic::id());
     console_println!("hpet tick rate: {:?}", Hpet::tick_rate_ms());
@@ -124,17 +273,23 @@ pub fn init() {
         IoApic::maximum_redirections()
     );
     console_println!("io apic id: {:?}", IoApic::id());
+    for device in pci::enumerate() {
+        console_println!("pci device: {}", device);
+    }
 
-    let cpu_response = LIMINE_CPU_REQUEST.get_response().unwrap();
+    // the other cpus look for their PerCpu as soon as they start
+    alloc_percpus();
     #[cfg(feature = "smp")]
     {
-        for cpu in cpu_response.cpus() {
+        for cpu in LIMINE_CPU_REQUEST.get_response().unwrap().cpus() {
             cpu.goto_address.write(cpu_main);
         }
     }
-    unsafe { cpu_main_rs(cpu_response.cpus()[cpu_response.bsp_lapic_id() as usize]) }
+    let bsp = cpus().find(|cpu| cpu.is_bsp).unwrap();
+    cpu_start(bsp)
 }
 
+#[cfg(feature = "smp")]
 #[unsafe(naked)]
 #[unsafe(no_mangle)]
 unsafe extern "C" fn cpu_main(cpu: &Cpu) -> ! {
@@ -146,8 +301,29 @@ unsafe extern "C" fn cpu_main(cpu: &Cpu) -> ! {
         sym cpu_main_rs
     )
 }
+#[cfg(feature = "smp")]
 #[unsafe(no_mangle)]
 unsafe extern "C" fn cpu_main_rs(cpu: &Cpu) -> ! {
+    cpu_start(CpuInfo {
+        id: cpu.id,
+        lapic_id: cpu.lapic_id,
+        is_bsp: false,
+    })
+}
+
+fn cpu_start(cpu: CpuInfo) -> ! {
+    // control registers are per cpu as well
+    crate::arch_x86_64::enable_cpu_features();
+    fpu_init();
+    // EFER is per cpu (the BSP already did this in memory::init). This must come first,
+    // since touching a NO_EXECUTE page (i.e. anything on the heap) without it is a reserved bit page fault.
+    crate::memory::enable_no_execute();
+    if !cpu.is_bsp {
+        // the BSP loaded its GDT at boot. ours is on the heap, so NO_EXECUTE has to be enabled first,
+        // and it has to be loaded before the idt, which needs the tss
+        gdt::init();
+    }
+    let percpu = init_percpu(cpu.lapic_id);
     console_println!(
         "cpu {} online! lapic id: {}, lapic version: {:x}",
         cpu.id,
@@ -161,18 +337,132 @@ unsafe extern "C" fn cpu_main_rs(cpu: &Cpu) -> ! {
             });
         }
     }
-    let lapic_ticks_per_ms = local_apic_init();
-    // safety: LocalApic::id() should be different between each CPU,
-    // hence this changes different elements of PERCPUS
-    unsafe {
-        PERCPUS[LocalApic::id() as usize].write(PerCpu { lapic_ticks_per_ms });
+    percpu.lapic_ticks_per_ms.call_once(local_apic_init);
+    percpu
+        .tsc_ticks_per_ms
+        .call_once(crate::time::tsc_calibrate);
+    if cpu.is_bsp {
+        // the BSP drives time::ticks
+        LocalApic::enable();
+        crate::time::start_ticks();
     }
-    console_println!("CPU {} init done; data: {:?}", LocalApic::id(), unsafe {
-        percpu()
-    },);
-    unsafe {
-        loop {
-            hlt();
+    #[cfg(feature = "smp")]
+    {
+        // the other cpus wait for us to acknowledge their tlb shootdowns, which come as ipis
+        LocalApic::enable();
+        crate::memory::tlb::join();
+    }
+    console_println!("CPU {} init done; data: {:?}", LocalApic::id(), percpu);
+    loop {
+        #[cfg(feature = "smp")]
+        unsafe {
+            // softirqs run with interrupts enabled: one which takes a lock another cpu holds while it waits
+            // for our shootdown ack would deadlock otherwise
+            crate::arch_x86_64::sti();
+            crate::softirq::run_pending();
+            crate::arch_x86_64::cli();
+            // work raised after run_pending returned would otherwise wait for the next interrupt
+            if !crate::softirq::is_pending() {
+                // take interrupts while idle. sti only takes effect after the next instruction,
+                // so nothing can come in between it and the hlt and leave us sleeping
+                core::arch::asm!("sti; hlt; cli", options(nomem, nostack));
+            }
+        }
+        #[cfg(not(feature = "smp"))]
+        unsafe {
+            crate::softirq::run_pending();
+            crate::arch_x86_64::hlt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn percpu_via_gs_base() {
+        // the test runner loads the BSP's, like cpu_start
+        let data = percpu().unwrap();
+        assert!(core::ptr::eq(data.this, data));
+        assert_eq!(data.lapic_id, LocalApic::id());
+        let percpus = PERCPUS.get().unwrap();
+        assert_eq!(percpus.len(), cpu_count());
+        assert!(percpus.iter().any(|percpu| core::ptr::eq(percpu, data)));
+        // without a GS base there's no data, rather than a page fault
+        let old_gs_base = unsafe { rdmsr(IA32_GS_BASE) };
+        unsafe { wrmsr(IA32_GS_BASE, 0) };
+        assert!(percpu().is_none());
+        unsafe { wrmsr(IA32_GS_BASE, old_gs_base) };
+    }
+
+    #[test_case]
+    fn many_percpus() {
+        // more than the old fixed limit of 32
+        let cpus = (0..64).map(|i| CpuInfo {
+            id: i,
+            lapic_id: i + 100,
+            is_bsp: i == 0,
+        });
+        let percpus = new_percpus(cpus);
+        assert_eq!(percpus.len(), 64);
+        let old_gs_base = unsafe { rdmsr(IA32_GS_BASE) };
+        for (i, data) in percpus.iter().enumerate() {
+            unsafe { load_percpu(data) };
+            let current = percpu().unwrap();
+            assert!(core::ptr::eq(current, data));
+            assert!(core::ptr::eq(current.this, data));
+            assert_eq!(current.lapic_id, i as u32 + 100);
+            // calibrating is up to the cpu itself
+            assert!(current.lapic_ticks_per_ms.get().is_none());
+        }
+        unsafe { wrmsr(IA32_GS_BASE, old_gs_base) };
+    }
+
+    #[test_case]
+    fn single_bsp() {
+        assert_eq!(cpus().filter(|cpu| cpu.is_bsp).count(), 1);
+        // tests run on the BSP
+        assert_eq!(bsp_lapic_id(), LocalApic::id());
+    }
+
+    #[test_case]
+    fn floating_point() {
+        use crate::alloc::format;
+        use crate::arch_x86_64::{FxSaveArea, fxrstor, fxsave};
+        fpu_init();
+        assert_eq!(cr0() & CR0_EM, 0);
+        assert_ne!(cr4() & CR4_OSFXSR, 0);
+
+        let sum: u64;
+        unsafe {
+            core::arch::asm!(
+                "movq xmm0, {a}",
+                "movq xmm1, {b}",
+                "addsd xmm0, xmm1",
+                "movq {sum}, xmm0",
+                a = in(reg) 1.5f64.to_bits(),
+                b = in(reg) 2.25f64.to_bits(),
+                sum = lateout(reg) sum,
+                options(nomem, nostack),
+            );
+        }
+        assert_eq!(f64::from_bits(sum), 3.75);
+        assert_eq!(format!("{:.2}", f64::from_bits(sum) * 2.0), "7.50");
+
+        // the state survives a round trip, even if xmm0 changes in between
+        let saved = 1.5f64.to_bits();
+        let mut area = FxSaveArea::default();
+        let restored: u64;
+        unsafe {
+            core::arch::asm!("movq xmm0, {}", in(reg) saved, options(nomem, nostack));
+            fxsave(&mut area);
+            core::arch::asm!("movq xmm0, {}", in(reg) 2.25f64.to_bits(), options(nomem, nostack));
+            fxrstor(&area);
+            core::arch::asm!("movq {}, xmm0", out(reg) restored, options(nomem, nostack));
         }
+        // xmm0 is at byte 160 of the area
+        assert_eq!(area.0[160..168], saved.to_le_bytes());
+        assert_eq!(restored, saved);
     }
 }
@@ -0,0 +1,200 @@
+// Our own GDT and TSS, instead of the bootloader's GDT which has no TSS.
+// The TSS is what gives faults like the double fault a known good stack (an IST entry) to run on,
+// i.e. when the fault came from overflowing the stack we were on.
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::boxed::Box;
+
+/// Same selectors as Limine's GDT, so that IDT entries which were created before we loaded ours stay valid
+pub const KERNEL_CODE_SELECTOR: u16 = 0x28;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x30;
+pub const TSS_SELECTOR: u16 = 0x38;
+/// The IST entry the double fault handler runs on, see IdtEntry::with_ist
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const FAULT_STACK_SIZE: usize = 16 * 1024;
+
+/// 64 bit, present, ring 0, executable and readable
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x00af_9a00_0000_ffff;
+/// present, ring 0, writable
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x00cf_9200_0000_ffff;
+/// available 64 bit TSS
+const TSS_TYPE_AVAILABLE: u64 = 0x9;
+const DESCRIPTOR_PRESENT: u64 = 1 << 47;
+/// the TSS descriptor takes 2 entries
+const GDT_ENTRIES: usize = TSS_SELECTOR as usize / 8 + 2;
+
+#[repr(C, packed)]
+struct Tss {
+    reserved0: u32,
+    /// stacks for privilege level changes, unused while we only run in ring 0
+    rsp: [u64; 3],
+    reserved1: u64,
+    /// interrupt stack table, ist[n - 1] is IST entry n
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+#[repr(C, packed)]
+struct GdtPtr {
+    /// size of the GDT minus 1
+    limit: u16,
+    base: u64,
+}
+
+/// The GDT, TSS and fault stack of a single cpu. The TSS is marked busy once it's loaded,
+/// so every cpu needs its own.
+#[repr(C, align(16))]
+pub struct CpuTables {
+    gdt: [u64; GDT_ENTRIES],
+    tss: Tss,
+    fault_stack: [u8; FAULT_STACK_SIZE],
+}
+
+static mut BSP_TABLES: CpuTables = CpuTables {
+    gdt: [0; GDT_ENTRIES],
+    tss: Tss {
+        reserved0: 0,
+        rsp: [0; 3],
+        reserved1: 0,
+        ist: [0; 7],
+        reserved2: 0,
+        reserved3: 0,
+        iomap_base: 0,
+    },
+    fault_stack: [0; FAULT_STACK_SIZE],
+};
+/// Whether BSP_TABLES were taken. The BSP sets up its GDT before there's a heap, every other cpu allocates its own.
+static BSP_TABLES_TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl CpuTables {
+    fn fill(&mut self) {
+        // the stack grows down, and must be aligned to 16 bytes like any stack
+        let stack_top = (&raw const self.fault_stack).addr() + FAULT_STACK_SIZE;
+        self.tss.ist[DOUBLE_FAULT_IST as usize - 1] = (stack_top & !0xf) as u64;
+        // no io permission bitmap
+        self.tss.iomap_base = size_of::<Tss>() as u16;
+
+        let tss_base = (&raw const self.tss).addr() as u64;
+        let tss_limit = size_of::<Tss>() as u64 - 1;
+        self.gdt = [0; GDT_ENTRIES];
+        self.gdt[KERNEL_CODE_SELECTOR as usize / 8] = KERNEL_CODE_DESCRIPTOR;
+        self.gdt[KERNEL_DATA_SELECTOR as usize / 8] = KERNEL_DATA_DESCRIPTOR;
+        self.gdt[TSS_SELECTOR as usize / 8] = (tss_limit & 0xffff)
+            | (tss_base & 0xff_ffff) << 16
+            | TSS_TYPE_AVAILABLE << 40
+            | DESCRIPTOR_PRESENT
+            | (tss_limit >> 16 & 0xf) << 48
+            | (tss_base >> 24 & 0xff) << 56;
+        self.gdt[TSS_SELECTOR as usize / 8 + 1] = tss_base >> 32;
+    }
+
+    /// ## Safety
+    /// The tables must live forever, and may not be loaded on any other cpu.
+    unsafe fn load(&'static self) {
+        let ptr = GdtPtr {
+            limit: (size_of::<[u64; GDT_ENTRIES]>() - 1) as u16,
+            base: (&raw const self.gdt).addr() as u64,
+        };
+        unsafe {
+            core::arch::asm!(
+                "lgdt [{ptr}]",
+                // cs can only be reloaded with a far jump/return
+                "push {code}",
+                "lea {tmp}, [rip + 2f]",
+                "push {tmp}",
+                "retfq",
+                "2:",
+                // fs and gs are left alone: loading them zeroes their base, and gs base points at the PerCpu
+                "mov ds, {data:x}",
+                "mov es, {data:x}",
+                "mov ss, {data:x}",
+                "ltr {tss:x}",
+                ptr = in(reg) &ptr,
+                code = const KERNEL_CODE_SELECTOR as u64,
+                data = in(reg) KERNEL_DATA_SELECTOR as u64,
+                tss = in(reg) TSS_SELECTOR as u64,
+                tmp = lateout(reg) _,
+            );
+        }
+    }
+}
+
+/// Load a GDT and TSS on the current cpu. Must be called on every cpu before the IDT is loaded on it,
+/// since the double fault entry uses an IST entry, which doesn't exist without a TSS.
+/// The first call (the BSP's) uses static tables, so it may come before the heap is initialized.
+pub fn init() {
+    let tables: &'static mut CpuTables = if !BSP_TABLES_TAKEN.swap(true, Ordering::AcqRel) {
+        // safety: only the first caller gets here
+        unsafe { (&raw mut BSP_TABLES).as_mut_unchecked() }
+    } else {
+        // safety: all zeroes is a valid (empty) CpuTables, which fill fills
+        Box::leak(unsafe { Box::<CpuTables>::new_zeroed().assume_init() })
+    };
+    tables.fill();
+    // safety: the tables are leaked (or static), and only ever loaded here
+    unsafe { tables.load() };
+}
+
+/// The selector in the task register
+pub fn task_register() -> u16 {
+    let tr: u16;
+    unsafe { core::arch::asm!("str {:x}", out(reg) tr, options(nomem, nostack, preserves_flags)) };
+    tr
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arch_x86_64::cs;
+
+    #[test_case]
+    fn tables_are_well_formed() {
+        // built on the heap and never loaded, the cpu keeps running on the ones it booted with
+        let mut tables = unsafe { Box::<CpuTables>::new_zeroed().assume_init() };
+        tables.fill();
+        assert_eq!(tables.gdt[0], 0);
+        assert_eq!(
+            tables.gdt[KERNEL_CODE_SELECTOR as usize / 8],
+            KERNEL_CODE_DESCRIPTOR
+        );
+        assert_eq!(
+            tables.gdt[KERNEL_DATA_SELECTOR as usize / 8],
+            KERNEL_DATA_DESCRIPTOR
+        );
+        let low = tables.gdt[TSS_SELECTOR as usize / 8];
+        let high = tables.gdt[TSS_SELECTOR as usize / 8 + 1];
+        let base = (low >> 16 & 0xff_ffff) | (low >> 56 & 0xff) << 24 | high << 32;
+        let limit = (low & 0xffff) | (low >> 48 & 0xf) << 16;
+        assert_eq!(base, (&raw const tables.tss).addr() as u64);
+        assert_eq!(limit, size_of::<Tss>() as u64 - 1);
+        assert_eq!(low >> 40 & 0xf, TSS_TYPE_AVAILABLE);
+        assert_ne!(low & DESCRIPTOR_PRESENT, 0);
+
+        // the double fault stack starts at the (aligned) top of the fault stack
+        let ist = { tables.tss.ist }[DOUBLE_FAULT_IST as usize - 1];
+        let stack_top = (&raw const tables.fault_stack).addr() as u64 + FAULT_STACK_SIZE as u64;
+        assert!(ist.is_multiple_of(16));
+        assert!((stack_top - 16..=stack_top).contains(&ist));
+        assert_eq!({ tables.tss.iomap_base }, size_of::<Tss>() as u16);
+
+        // the same selectors as the tables the cpu booted with
+        assert_eq!(cs(), KERNEL_CODE_SELECTOR);
+        assert_eq!(task_register(), TSS_SELECTOR);
+        let ss: u16;
+        unsafe { core::arch::asm!("mov {:x}, ss", out(reg) ss) };
+        assert_eq!(ss, KERNEL_DATA_SELECTOR);
+    }
+
+    #[test_case]
+    fn double_fault_on_ist() {
+        // the page fault can't push its frame onto the broken stack either, which is a double fault.
+        // it only gets to the handler (instead of a triple fault) on the IST stack
+        crate::should_panic_with!("double fault");
+        unsafe { core::arch::asm!("mov rsp, 0x1000", "push rax", options(noreturn)) };
+    }
+}
@@ -1,9 +1,15 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use acpi::HpetInfo;
 use spin::Lazy;
 
 use crate::{
-    dev::ioapic::TriggerMode,
+    dev::{
+        ioapic::TriggerMode,
+        msi::{self, MsiMessage},
+    },
     memory::{
+        paging::PageTableEntryFlags,
         physical::PhyAddr,
         virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator, VirtAddr},
     },
@@ -16,15 +22,42 @@ const MAIN_COUNTER_VAL_REGISTER: u64 = 0xf0;
 const LEG_RT_CNF: u64 = 0b10;
 /// enable the counter and start receiving interrupts
 const ENABLE_CNF: u64 = 0b1;
+/// the timer fires every period instead of once
+const TN_TYPE_CNF: u64 = 1 << 3;
+/// the timer is able to fire periodically
+const TN_PER_INT_CAP: u64 = 1 << 4;
+/// the next comparator write sets the comparator itself, and the one after it the period
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+/// the timer delivers its interrupts through the FSB instead of the IOAPIC
+const TN_FSB_EN_CNF: u64 = 1 << 14;
+/// the timer is able to deliver its interrupts through the FSB
+const TN_FSB_INT_DEL_CAP: u64 = 1 << 15;
+
+/// Timers which have a fixed owner: timer 0 is the system timer (see cpu::init) and timer 1 is the watchdog
+const RESERVED_TIMERS: u32 = 0b11;
+/// bit n is set if timer n is owned by someone, see Hpet::claim_timer
+static CLAIMED_TIMERS: AtomicU32 = AtomicU32::new(RESERVED_TIMERS);
+
+/// Whether HPET_BASE_ADDR was initialized, so that it can be checked without mapping it
+static HPET_MAPPED: AtomicBool = AtomicBool::new(false);
 
 static HPET_BASE_ADDR: Lazy<VirtAddr> = Lazy::new(|| {
-    let hpet_info = HpetInfo::new(&crate::acpi::tables()).unwrap();
+    crate::memory::assert_memory_ready();
+    let hpet_info = crate::acpi::with_tables(|tables| HpetInfo::new(tables).unwrap());
     if !hpet_info.main_counter_is_64bits() {
         panic!("HPET IS NOT CAPABLE OF 64 BITS!");
     }
-    unsafe { GLOBAL_PAGE_ALLOCATOR.map_physical(PhyAddr(hpet_info.base_address as u64), 1) }
-        .unwrap()
-        .1
+    let addr = unsafe {
+        GLOBAL_PAGE_ALLOCATOR.map_physical(
+            PhyAddr(hpet_info.base_address as u64),
+            1,
+            PageTableEntryFlags::mmio(),
+        )
+    }
+    .unwrap()
+    .1;
+    HPET_MAPPED.store(true, Ordering::Relaxed);
+    addr
 });
 pub struct Hpet;
 
@@ -39,6 +72,16 @@ impl Hpet {
         }
     }
 
+    /// Whether the registers were already mapped, i.e. whether using the Hpet won't touch ACPI or the page allocator.
+    /// Useful for code which can't afford to fault, like the panic handler.
+    pub fn is_mapped() -> bool {
+        HPET_MAPPED.load(Ordering::Relaxed)
+    }
+
+    pub fn addr() -> VirtAddr {
+        *HPET_BASE_ADDR
+    }
+
     /// get the amount of femto seconds (10e-15) which pass per single tick
     pub fn fs_per_tick() -> u64 {
         let fs_per_tick = unsafe { Self::read(GENERAL_CAPABILITIES_REGISTER) >> 32 };
@@ -130,13 +173,57 @@ impl Hpet {
         assert!(num < Self::num_timers());
         Timer { num }
     }
+
+    /// Take ownership of a timer nobody else owns, or None if they're all taken.
+    /// Give it back with Hpet::release_timer once done with it.
+    pub fn claim_timer() -> Option<Timer> {
+        let mut claimed = CLAIMED_TIMERS.load(Ordering::Relaxed);
+        loop {
+            let free = (!claimed).trailing_zeros() as u64;
+            if free >= Self::num_timers() {
+                return None;
+            }
+            match CLAIMED_TIMERS.compare_exchange_weak(
+                claimed,
+                claimed | (1 << free),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Timer { num: free }),
+                Err(current) => claimed = current,
+            }
+        }
+    }
+
+    /// Give back a timer taken with Hpet::claim_timer, so that it can be claimed again.
+    /// ## Safety
+    /// the timer must have been claimed by claim_timer, and mustn't be used after it's released.
+    pub unsafe fn release_timer(timer: &Timer) {
+        CLAIMED_TIMERS.fetch_and(!(1 << timer.num), Ordering::Release);
+    }
+
+    pub fn is_claimed(num: u64) -> bool {
+        CLAIMED_TIMERS.load(Ordering::Relaxed) & (1 << num) != 0
+    }
 }
 
 pub struct Timer {
     num: u64,
 }
 
+/// The timer can't deliver interrupts through the FSB, see Timer::route_fsb
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FsbNotSupported;
+
+/// The timer can only fire once, see Timer::set_periodic
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PeriodicNotSupported;
+
 impl Timer {
+    pub fn num(&self) -> u64 {
+        self.num
+    }
+
     fn general_capabilties_reg_num(&self) -> u64 {
         0x100 + 0x20 * self.num
     }
@@ -145,6 +232,41 @@ impl Timer {
         0x108 + 0x20 * self.num
     }
 
+    fn fsb_route_reg_num(&self) -> u64 {
+        0x110 + 0x20 * self.num
+    }
+
+    pub fn can_route_fsb(&self) -> bool {
+        unsafe { Hpet::read(self.general_capabilties_reg_num()) & TN_FSB_INT_DEL_CAP != 0 }
+    }
+
+    /// Deliver the timer's interrupts directly to a local apic as an MSI, bypassing the IOAPIC.
+    pub fn route_fsb(&self, dest_lapic_id: u8, vector: u8) -> Result<(), FsbNotSupported> {
+        if !self.can_route_fsb() {
+            return Err(FsbNotSupported);
+        }
+        let msg = msi::compose(dest_lapic_id, vector);
+        unsafe {
+            // the address goes in the upper half, the data in the lower one
+            Hpet::write(
+                self.fsb_route_reg_num(),
+                ((msg.address as u64) << 32) | msg.data as u64,
+            );
+            let old = Hpet::read(self.general_capabilties_reg_num());
+            Hpet::write(self.general_capabilties_reg_num(), old | TN_FSB_EN_CNF);
+        }
+        Ok(())
+    }
+
+    /// The MSI the timer delivers when routed through the FSB
+    pub fn fsb_route(&self) -> MsiMessage {
+        let route = unsafe { Hpet::read(self.fsb_route_reg_num()) };
+        MsiMessage {
+            address: (route >> 32) as u32,
+            data: route as u32,
+        }
+    }
+
     pub fn can_route_irq_to(&self, irq: u64) -> bool {
         unsafe { (Hpet::read(self.general_capabilties_reg_num()) >> 32) & (1 << irq) != 0 }
     }
@@ -154,7 +276,8 @@ impl Timer {
             assert!(irq <= 23);
             unsafe {
                 let old = Hpet::read(self.general_capabilties_reg_num());
-                let new = old | (irq << 9);
+                // replace whatever it was routed to before
+                let new = (old & !(0x1f << 9)) | (irq << 9);
                 Hpet::write(self.general_capabilties_reg_num(), new);
             }
             Some(irq)
@@ -196,4 +319,88 @@ impl Timer {
     pub fn read_counter(&self) -> u64 {
         unsafe { Hpet::read(self.comparator_value_reg_num()) }
     }
+
+    pub fn can_be_periodic(&self) -> bool {
+        unsafe { Hpet::read(self.general_capabilties_reg_num()) & TN_PER_INT_CAP != 0 }
+    }
+
+    /// Fire every period_ticks main counter ticks, starting period_ticks from now.
+    /// The comparator is advanced by the period every time it fires, so set_counter_raw shouldn't be used meanwhile.
+    pub fn set_periodic(&self, period_ticks: u64) -> Result<(), PeriodicNotSupported> {
+        if !self.can_be_periodic() {
+            return Err(PeriodicNotSupported);
+        }
+        unsafe {
+            let old = Hpet::read(self.general_capabilties_reg_num());
+            Hpet::write(
+                self.general_capabilties_reg_num(),
+                old | TN_TYPE_CNF | TN_VAL_SET_CNF,
+            );
+            // the first write is the first deadline, the second one the period
+            Hpet::write(
+                self.comparator_value_reg_num(),
+                Hpet::read_main_counter().wrapping_add(period_ticks),
+            );
+            Hpet::write(self.comparator_value_reg_num(), period_ticks);
+        }
+        Ok(())
+    }
+
+    /// Fire once, when the main counter reaches the comparator. This is how timers start out.
+    pub fn set_one_shot(&self) {
+        unsafe {
+            let old = Hpet::read(self.general_capabilties_reg_num());
+            Hpet::write(self.general_capabilties_reg_num(), old & !TN_TYPE_CNF);
+        }
+    }
+
+    pub fn is_periodic(&self) -> bool {
+        unsafe { Hpet::read(self.general_capabilties_reg_num()) & TN_TYPE_CNF != 0 }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn fsb_routing() {
+        // the last timer, the first ones are the ones in use
+        let timer = unsafe { Hpet::timer(Hpet::num_timers() - 1) };
+        let config_reg = timer.general_capabilties_reg_num();
+        let old_config = unsafe { Hpet::read(config_reg) };
+        if !timer.can_route_fsb() {
+            assert_eq!(timer.route_fsb(0, 0x40), Err(FsbNotSupported));
+            return;
+        }
+        timer.route_fsb(0, 0x40).unwrap();
+        assert_eq!(timer.fsb_route(), msi::compose(0, 0x40));
+        assert!(unsafe { Hpet::read(config_reg) } & TN_FSB_EN_CNF != 0);
+        unsafe { Hpet::write(config_reg, old_config) };
+    }
+
+    #[test_case]
+    fn periodic_mode() {
+        let timer = Hpet::claim_timer().unwrap();
+        let config_reg = timer.general_capabilties_reg_num();
+        let old_config = unsafe { Hpet::read(config_reg) };
+        if !timer.can_be_periodic() {
+            assert_eq!(timer.set_periodic(100), Err(PeriodicNotSupported));
+            assert!(!timer.is_periodic());
+        } else {
+            // not enabled, so it never interrupts
+            let period = Hpet::tick_rate_ms();
+            let before = Hpet::read_main_counter();
+            timer.set_periodic(period).unwrap();
+            assert!(timer.is_periodic());
+            // the first deadline is a period away
+            assert!(timer.read_counter() >= before + period);
+            timer.set_one_shot();
+            assert!(!timer.is_periodic());
+        }
+        unsafe {
+            Hpet::write(config_reg, old_config);
+            Hpet::release_timer(&timer);
+        }
+    }
 }
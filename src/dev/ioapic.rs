@@ -2,29 +2,36 @@ use acpi::madt::{Madt, MadtEntry};
 use spin::Lazy;
 
 use crate::memory::{
+    paging::PageTableEntryFlags,
     physical::PhyAddr,
     virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator, VirtAddr},
 };
 
 pub struct IoApic;
 
+/// Bit of the low half of a redirection entry which masks the irq
+const REDIRECT_MASKED: u32 = 1 << 16;
+
 static IO_APIC_ADDR: Lazy<VirtAddr> = Lazy::new(|| {
-    let madt = crate::acpi::tables().find_table::<Madt>().unwrap();
-    let io_apic_entry = madt
-        .get()
-        .entries()
-        .find(|e| matches!(e, MadtEntry::IoApic(_)))
-        .unwrap();
-    let MadtEntry::IoApic(data) = io_apic_entry else {
-        panic!("not possible");
-    };
-    let ok = data.global_system_interrupt_base;
-    // in the future we should handle this better...
-    assert_eq!(ok, 0);
-    crate::qemu_println!("io apic data: {:?}", data);
-    let io_apic_phy_addr = PhyAddr(data.io_apic_address as u64);
+    crate::memory::assert_memory_ready();
+    let io_apic_phy_addr = crate::acpi::with_tables(|tables| {
+        let madt = tables.find_table::<Madt>().unwrap();
+        let io_apic_entry = madt
+            .get()
+            .entries()
+            .find(|e| matches!(e, MadtEntry::IoApic(_)))
+            .unwrap();
+        let MadtEntry::IoApic(data) = io_apic_entry else {
+            panic!("not possible");
+        };
+        let ok = data.global_system_interrupt_base;
+        // in the future we should handle this better...
+        assert_eq!(ok, 0);
+        crate::qemu_println!("io apic data: {:?}", data);
+        PhyAddr(data.io_apic_address as u64)
+    });
     crate::qemu_println!("io apic phy addr: {:?}", io_apic_phy_addr);
-    unsafe { GLOBAL_PAGE_ALLOCATOR.map_physical(io_apic_phy_addr, 1) }
+    unsafe { GLOBAL_PAGE_ALLOCATOR.map_physical(io_apic_phy_addr, 1, PageTableEntryFlags::mmio()) }
         .unwrap()
         .1
 });
@@ -34,6 +41,11 @@ impl IoApic {
     const IO_WINDOW_OFFSET: u64 = 0x10;
     const IOAPICVER_REG: u32 = 0x1;
     const IOAPIC_ID_REG: u32 = 0;
+
+    pub fn addr() -> VirtAddr {
+        *IO_APIC_ADDR
+    }
+
     unsafe fn reg_select(reg: u32) {
         unsafe {
             core::ptr::write_volatile(
@@ -68,33 +80,80 @@ impl IoApic {
         unsafe { (Self::read_u32(Self::IOAPIC_ID_REG) >> 24) & 0xf }
     }
 
+    /// The register of the low half of the irq's redirection entry, the high half is the one after it
+    fn redirect_reg(irq_num: u8) -> u32 {
+        irq_num as u32 * 2 + 0x10
+    }
+
     /// redirect irq 0-23 into an arbitrary irq number
     /// Note that irqs 0-15 are for legacy irqs. Use 16-23 for arbitrary interrupts.
     pub fn redirect_irq(irq_num: u8, entry: IoApicRedirectEntry) {
         unsafe {
-            let reg_num = irq_num * 2 + 0x10;
+            let reg_num = Self::redirect_reg(irq_num);
             let low = (entry.as_raw().0 & 0xffff_ffff) as u32;
 
             let high = (entry.as_raw().0 >> 32) as u32;
-            Self::write_u32(reg_num as u32, low);
-            Self::write_u32(reg_num as u32 + 1, high);
+            Self::write_u32(reg_num, low);
+            Self::write_u32(reg_num + 1, high);
+        }
+        // the entry has to be in place before the caller goes on to enable whatever raises the irq
+        crate::arch_x86_64::mfence();
+    }
+
+    /// Read the irq's redirection entry back
+    /// ## Panic
+    /// If the entry holds a reserved delivery mode, which neither we nor the firmware write.
+    pub fn read_redirect(irq_num: u8) -> IoApicRedirectEntry {
+        let reg_num = Self::redirect_reg(irq_num);
+        let raw =
+            unsafe { Self::read_u32(reg_num) as u64 | (Self::read_u32(reg_num + 1) as u64) << 32 };
+        IoApicRedirectEntry::from_raw(IoApicRedirectEntryRaw(raw))
+            .expect("reserved value in a redirection entry")
+    }
+
+    /// Whether the irq's redirection entry is masked.
+    /// The entries start out masked, so an unmasked entry belongs to someone.
+    pub fn is_masked(irq_num: u8) -> bool {
+        unsafe { Self::read_u32(Self::redirect_reg(irq_num)) & REDIRECT_MASKED != 0 }
+    }
+
+    /// Mask the irq, i.e. once whoever redirected it is done with it. The rest of the entry is kept.
+    pub fn mask_irq(irq_num: u8) {
+        unsafe {
+            let reg_num = Self::redirect_reg(irq_num);
+            let low = Self::read_u32(reg_num);
+            Self::write_u32(reg_num, low | REDIRECT_MASKED);
+        }
+    }
+
+    /// Unmask the irq, which delivers it as its redirection entry says
+    pub fn unmask_irq(irq_num: u8) {
+        unsafe {
+            let reg_num = Self::redirect_reg(irq_num);
+            let low = Self::read_u32(reg_num);
+            Self::write_u32(reg_num, low & !REDIRECT_MASKED);
         }
+        crate::arch_x86_64::mfence();
     }
+
     pub fn init() {
-        let madt = crate::acpi::tables().find_table::<Madt>().unwrap();
-        for entry in madt.get().entries() {
-            match entry {
-                MadtEntry::InterruptSourceOverride(over) => {
-                    crate::qemu_println!("{:?}", over);
+        crate::memory::assert_memory_ready();
+        crate::acpi::with_tables(|tables| {
+            let madt = tables.find_table::<Madt>().unwrap();
+            for entry in madt.get().entries() {
+                match entry {
+                    MadtEntry::InterruptSourceOverride(over) => {
+                        crate::qemu_println!("{:?}", over);
+                    }
+                    _ => (),
                 }
-                _ => (),
             }
-        }
+        });
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum DeilveryMode {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryMode {
     Fixed = 0,
     LowestPriority = 1,
     Smi = 2,
@@ -103,31 +162,88 @@ pub enum DeilveryMode {
     ExtInit = 7,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[deprecated(note = "misspelled, use DeliveryMode")]
+pub type DeilveryMode = DeliveryMode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DestinationMode {
     Physical = 0,
     Logical = 1,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum InterruptPolarity {
     HighActive = 0,
     LowActive = 1,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TriggerMode {
     EdgeSensetive = 0,
     LevelSensetive = 1,
 }
 
+/// The value isn't a valid (or is a reserved) value of a redirection entry field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidFieldValue(pub u8);
+
+impl TryFrom<u8> for DeliveryMode {
+    type Error = InvalidFieldValue;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DeliveryMode::Fixed),
+            1 => Ok(DeliveryMode::LowestPriority),
+            2 => Ok(DeliveryMode::Smi),
+            4 => Ok(DeliveryMode::Nmi),
+            5 => Ok(DeliveryMode::Init),
+            7 => Ok(DeliveryMode::ExtInit),
+            // 3 and 6 are reserved
+            _ => Err(InvalidFieldValue(value)),
+        }
+    }
+}
+
+impl TryFrom<u8> for DestinationMode {
+    type Error = InvalidFieldValue;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DestinationMode::Physical),
+            1 => Ok(DestinationMode::Logical),
+            _ => Err(InvalidFieldValue(value)),
+        }
+    }
+}
+
+impl TryFrom<u8> for InterruptPolarity {
+    type Error = InvalidFieldValue;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(InterruptPolarity::HighActive),
+            1 => Ok(InterruptPolarity::LowActive),
+            _ => Err(InvalidFieldValue(value)),
+        }
+    }
+}
+
+impl TryFrom<u8> for TriggerMode {
+    type Error = InvalidFieldValue;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TriggerMode::EdgeSensetive),
+            1 => Ok(TriggerMode::LevelSensetive),
+            _ => Err(InvalidFieldValue(value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct IoApicRedirectEntry {
     pub dest: u8,
     pub mask: bool,
     pub trigger_mode: TriggerMode,
     pub interrupt_polarity: InterruptPolarity,
     pub destination_mode: DestinationMode,
-    pub delivery_mode: DeilveryMode,
+    pub delivery_mode: DeliveryMode,
     pub redirected_irq_num: u8,
 }
 struct IoApicRedirectEntryRaw(u64);
@@ -139,8 +255,129 @@ impl IoApicRedirectEntry {
             | ((self.destination_mode as u64) << 11)
             | ((self.interrupt_polarity as u64) << 13)
             | ((self.trigger_mode as u64) << 15)
-            | (if self.mask { 1 } else { 0 } << 16)
-            | ((self.destination_mode as u64) << 56);
+            | ((self.mask as u64) << 16)
+            | ((self.dest as u64) << 56);
         IoApicRedirectEntryRaw(num)
     }
+
+    /// Decode an entry, ignoring the read only delivery status and remote IRR bits
+    fn from_raw(raw: IoApicRedirectEntryRaw) -> Result<Self, InvalidFieldValue> {
+        let bit = |n: u64| (raw.0 >> n & 1) as u8;
+        Ok(Self {
+            dest: (raw.0 >> 56) as u8,
+            mask: bit(16) != 0,
+            trigger_mode: bit(15).try_into()?,
+            interrupt_polarity: bit(13).try_into()?,
+            destination_mode: bit(11).try_into()?,
+            delivery_mode: ((raw.0 >> 8 & 0b111) as u8).try_into()?,
+            redirected_irq_num: raw.0 as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Legacy irq 5, which none of our devices are routed to (the keyboard owns irq 1)
+    const UNUSED_IRQ: u8 = 5;
+
+    #[test_case]
+    fn redirect_read_back() {
+        let low_reg = IoApic::redirect_reg(UNUSED_IRQ);
+        let high_reg = low_reg + 1;
+        let old = unsafe { (IoApic::read_u32(low_reg), IoApic::read_u32(high_reg)) };
+        let entry = IoApicRedirectEntry {
+            dest: 0,
+            mask: true,
+            trigger_mode: TriggerMode::EdgeSensetive,
+            interrupt_polarity: InterruptPolarity::HighActive,
+            destination_mode: DestinationMode::Physical,
+            delivery_mode: DeliveryMode::Fixed,
+            redirected_irq_num: 0x41,
+        };
+        let raw = entry.as_raw().0;
+        IoApic::redirect_irq(UNUSED_IRQ, entry);
+        let (low, high) = unsafe { (IoApic::read_u32(low_reg), IoApic::read_u32(high_reg)) };
+        // delivery status and remote IRR are read only
+        let read_only = (1 << 12) | (1 << 14);
+        assert_eq!(low & !read_only, raw as u32);
+        assert_eq!(high, (raw >> 32) as u32);
+        unsafe {
+            IoApic::write_u32(low_reg, old.0);
+            IoApic::write_u32(high_reg, old.1);
+        }
+    }
+
+    #[test_case]
+    fn redirect_round_trip() {
+        let low_reg = IoApic::redirect_reg(UNUSED_IRQ);
+        let high_reg = low_reg + 1;
+        let old = unsafe { (IoApic::read_u32(low_reg), IoApic::read_u32(high_reg)) };
+        let entry = IoApicRedirectEntry {
+            dest: 3,
+            mask: true,
+            trigger_mode: TriggerMode::LevelSensetive,
+            interrupt_polarity: InterruptPolarity::LowActive,
+            destination_mode: DestinationMode::Logical,
+            delivery_mode: DeliveryMode::LowestPriority,
+            redirected_irq_num: 0x42,
+        };
+        IoApic::redirect_irq(UNUSED_IRQ, entry);
+        assert_eq!(IoApic::read_redirect(UNUSED_IRQ), entry);
+
+        // unmasking only touches the mask. we never set the logical apic ids, so logical destination 3
+        // matches no cpu and nothing is delivered while it's unmasked
+        let entry = IoApicRedirectEntry {
+            trigger_mode: TriggerMode::EdgeSensetive,
+            interrupt_polarity: InterruptPolarity::HighActive,
+            ..entry
+        };
+        IoApic::redirect_irq(UNUSED_IRQ, entry);
+        IoApic::unmask_irq(UNUSED_IRQ);
+        assert!(!IoApic::is_masked(UNUSED_IRQ));
+        assert_eq!(
+            IoApic::read_redirect(UNUSED_IRQ),
+            IoApicRedirectEntry {
+                mask: false,
+                ..entry
+            }
+        );
+        IoApic::mask_irq(UNUSED_IRQ);
+        assert_eq!(IoApic::read_redirect(UNUSED_IRQ), entry);
+        unsafe {
+            IoApic::write_u32(low_reg, old.0);
+            IoApic::write_u32(high_reg, old.1);
+        }
+    }
+
+    #[test_case]
+    fn field_round_trip() {
+        for mode in [
+            DeliveryMode::Fixed,
+            DeliveryMode::LowestPriority,
+            DeliveryMode::Smi,
+            DeliveryMode::Nmi,
+            DeliveryMode::Init,
+            DeliveryMode::ExtInit,
+        ] {
+            assert_eq!(DeliveryMode::try_from(mode as u8), Ok(mode));
+        }
+        assert_eq!(DeliveryMode::try_from(3), Err(InvalidFieldValue(3)));
+        assert_eq!(DeliveryMode::try_from(6), Err(InvalidFieldValue(6)));
+        assert_eq!(DeliveryMode::try_from(8), Err(InvalidFieldValue(8)));
+
+        for mode in [DestinationMode::Physical, DestinationMode::Logical] {
+            assert_eq!(DestinationMode::try_from(mode as u8), Ok(mode));
+        }
+        for polarity in [InterruptPolarity::HighActive, InterruptPolarity::LowActive] {
+            assert_eq!(InterruptPolarity::try_from(polarity as u8), Ok(polarity));
+        }
+        for mode in [TriggerMode::EdgeSensetive, TriggerMode::LevelSensetive] {
+            assert_eq!(TriggerMode::try_from(mode as u8), Ok(mode));
+        }
+        assert!(DestinationMode::try_from(2).is_err());
+        assert!(InterruptPolarity::try_from(2).is_err());
+        assert!(TriggerMode::try_from(2).is_err());
+    }
 }
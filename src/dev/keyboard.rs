@@ -0,0 +1,325 @@
+// The PS/2 keyboard. The firmware already initialized the controller and translates the keyboard's
+// scancodes to set 1, so all we do is read them on irq 1.
+use alloc::boxed::Box;
+use spin::Mutex;
+
+use crate::{
+    dev::{
+        ioapic::{
+            DeliveryMode, DestinationMode, InterruptPolarity, IoApic, IoApicRedirectEntry,
+            TriggerMode,
+        },
+        local_apic::LocalApic,
+    },
+    interrupts::register_handler,
+    io::Port,
+    util::RingBuffer,
+};
+
+const DATA_PORT: Port<u8> = Port::new(0x60);
+const STATUS_PORT: Port<u8> = Port::new(0x64);
+/// Status bit: there's a byte to read from the data port
+const STATUS_OUTPUT_FULL: u8 = 1;
+/// The legacy irq of the keyboard
+const KEYBOARD_IRQ: u8 = 1;
+/// The vector irq 1 is redirected to
+pub const KEYBOARD_VECTOR: u8 = 0x31;
+/// Maximum amount of key events which weren't polled yet, more than that are dropped
+pub const KEY_EVENT_QUEUE_SIZE: usize = 64;
+
+/// Prefix of the scancodes of the keys which were added after the original keyboard
+const EXTENDED_PREFIX: u8 = 0xE0;
+/// Set in the scancode of a key release
+const RELEASED: u8 = 0x80;
+
+// characters of scancodes 0x00..0x3a, 0 for keys which aren't characters
+const UNSHIFTED: &[u8; 0x3a] =
+    b"\0\x001234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 0x3a] =
+    b"\0\0!@#$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A key which types a character, with shift and caps lock applied
+    Char(char),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    /// F1-F12
+    F(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// A key we don't know, by its scancode without the release bit
+    Unknown {
+        extended: bool,
+        scancode: u8,
+    },
+}
+
+/// A key was pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+}
+
+impl KeyEvent {
+    /// The character this event types, None for releases and keys which don't type anything
+    pub fn char(&self) -> Option<char> {
+        if !self.pressed {
+            return None;
+        }
+        match self.key {
+            Key::Char(c) => Some(c),
+            Key::Enter => Some('\n'),
+            Key::Tab => Some('\t'),
+            Key::Backspace => Some('\x08'),
+            _ => None,
+        }
+    }
+}
+
+/// Turns set 1 scancodes into key events, one byte at a time
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// the last byte was EXTENDED_PREFIX
+    extended: bool,
+    left_shift: bool,
+    right_shift: bool,
+    caps_lock: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            left_shift: false,
+            right_shift: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Feed the next byte the keyboard sent. Returns the event once the scancode is complete.
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if byte == EXTENDED_PREFIX {
+            self.extended = true;
+            return None;
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let pressed = byte & RELEASED == 0;
+        let scancode = byte & !RELEASED;
+        let key = if extended {
+            Self::extended_key(scancode)
+        } else {
+            self.key(scancode)
+        };
+        match key {
+            Key::LeftShift => self.left_shift = pressed,
+            Key::RightShift => self.right_shift = pressed,
+            Key::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            _ => (),
+        }
+        Some(KeyEvent { key, pressed })
+    }
+
+    fn key(&self, scancode: u8) -> Key {
+        match scancode {
+            0x01 => Key::Escape,
+            0x0E => Key::Backspace,
+            0x0F => Key::Tab,
+            0x1C => Key::Enter,
+            0x1D => Key::LeftCtrl,
+            0x2A => Key::LeftShift,
+            0x36 => Key::RightShift,
+            0x38 => Key::LeftAlt,
+            0x3A => Key::CapsLock,
+            0x3B..=0x44 => Key::F(scancode - 0x3B + 1),
+            0x57 => Key::F(11),
+            0x58 => Key::F(12),
+            _ => match UNSHIFTED.get(scancode as usize) {
+                Some(&c) if c != 0 => Key::Char(self.character(scancode as usize)),
+                _ => Key::Unknown {
+                    extended: false,
+                    scancode,
+                },
+            },
+        }
+    }
+
+    fn extended_key(scancode: u8) -> Key {
+        match scancode {
+            // the keypad's
+            0x1C => Key::Enter,
+            0x35 => Key::Char('/'),
+            0x1D => Key::RightCtrl,
+            0x38 => Key::RightAlt,
+            0x47 => Key::Home,
+            0x48 => Key::Up,
+            0x49 => Key::PageUp,
+            0x4B => Key::Left,
+            0x4D => Key::Right,
+            0x4F => Key::End,
+            0x50 => Key::Down,
+            0x51 => Key::PageDown,
+            0x52 => Key::Insert,
+            0x53 => Key::Delete,
+            _ => Key::Unknown {
+                extended: true,
+                scancode,
+            },
+        }
+    }
+
+    /// The character of a scancode in UNSHIFTED, with shift and caps lock applied
+    fn character(&self, scancode: usize) -> char {
+        let mut shift = self.left_shift || self.right_shift;
+        // caps lock only affects letters
+        if UNSHIFTED[scancode].is_ascii_alphabetic() {
+            shift ^= self.caps_lock;
+        }
+        if shift {
+            SHIFTED[scancode] as char
+        } else {
+            UNSHIFTED[scancode] as char
+        }
+    }
+}
+
+// only ever locked by the irq handler, which doesn't nest, so it's never contended
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+static EVENTS: RingBuffer<KeyEvent, KEY_EVENT_QUEUE_SIZE> = RingBuffer::new();
+
+pub struct Keyboard;
+
+impl Keyboard {
+    /// Start taking key events on irq 1, on the current cpu
+    pub fn init() {
+        register_handler(KEYBOARD_VECTOR, Box::new(|_| Self::handle_irq()));
+        // a byte which is already waiting would never be followed by an irq
+        while unsafe { STATUS_PORT.read() } & STATUS_OUTPUT_FULL != 0 {
+            unsafe { DATA_PORT.read() };
+        }
+        IoApic::redirect_irq(
+            KEYBOARD_IRQ,
+            IoApicRedirectEntry {
+                dest: LocalApic::id() as u8,
+                mask: false,
+                trigger_mode: TriggerMode::EdgeSensetive,
+                interrupt_polarity: InterruptPolarity::HighActive,
+                destination_mode: DestinationMode::Physical,
+                delivery_mode: DeliveryMode::Fixed,
+                redirected_irq_num: KEYBOARD_VECTOR,
+            },
+        );
+    }
+
+    fn handle_irq() {
+        let byte = unsafe { DATA_PORT.read() };
+        if let Some(event) = DECODER.lock().feed(byte) {
+            // safety: we're the only producer, the irq handler doesn't nest
+            unsafe { EVENTS.push(event) };
+        }
+        LocalApic::eoi();
+    }
+
+    /// Take the oldest key event which wasn't polled yet
+    pub fn poll() -> Option<KeyEvent> {
+        EVENTS.pop()
+    }
+
+    /// Amount of key events dropped since nobody polled them
+    pub fn dropped() -> u64 {
+        EVENTS.dropped()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed_all(decoder: &mut Decoder, bytes: &[u8]) -> Option<KeyEvent> {
+        let (last, rest) = bytes.split_last().unwrap();
+        for byte in rest {
+            assert_eq!(decoder.feed(*byte), None);
+        }
+        decoder.feed(*last)
+    }
+
+    fn pressed(key: Key) -> Option<KeyEvent> {
+        Some(KeyEvent { key, pressed: true })
+    }
+
+    fn released(key: Key) -> Option<KeyEvent> {
+        Some(KeyEvent {
+            key,
+            pressed: false,
+        })
+    }
+
+    #[test_case]
+    fn scancodes() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.feed(0x1E), pressed(Key::Char('a')));
+        assert_eq!(decoder.feed(0x9E), released(Key::Char('a')));
+        assert_eq!(decoder.feed(0x02), pressed(Key::Char('1')));
+        assert_eq!(decoder.feed(0x1C).unwrap().char(), Some('\n'));
+        assert_eq!(decoder.feed(0x44), pressed(Key::F(10)));
+        assert_eq!(decoder.feed(0x58), pressed(Key::F(12)));
+
+        // extended keys, and their releases
+        assert_eq!(feed_all(&mut decoder, &[0xE0, 0x48]), pressed(Key::Up));
+        assert_eq!(feed_all(&mut decoder, &[0xE0, 0xC8]), released(Key::Up));
+        assert_eq!(
+            feed_all(&mut decoder, &[0xE0, 0x1D]),
+            pressed(Key::RightCtrl)
+        );
+        // the prefix only applies to the next byte
+        assert_eq!(decoder.feed(0x1D), pressed(Key::LeftCtrl));
+        assert_eq!(
+            feed_all(&mut decoder, &[0xE0, 0x60]),
+            pressed(Key::Unknown {
+                extended: true,
+                scancode: 0x60
+            })
+        );
+    }
+
+    #[test_case]
+    fn modifiers() {
+        let mut decoder = Decoder::new();
+        decoder.feed(0x2A);
+        assert_eq!(decoder.feed(0x1E), pressed(Key::Char('A')));
+        assert_eq!(decoder.feed(0x02), pressed(Key::Char('!')));
+        assert_eq!(decoder.feed(0x28), pressed(Key::Char('"')));
+        decoder.feed(0xAA);
+        assert_eq!(decoder.feed(0x02), pressed(Key::Char('1')));
+
+        // caps lock toggles on press, and only affects letters
+        decoder.feed(0x3A);
+        decoder.feed(0xBA);
+        assert_eq!(decoder.feed(0x1E), pressed(Key::Char('A')));
+        assert_eq!(decoder.feed(0x02), pressed(Key::Char('1')));
+        decoder.feed(0x36);
+        assert_eq!(decoder.feed(0x1E), pressed(Key::Char('a')));
+        decoder.feed(0xB6);
+        decoder.feed(0x3A);
+        assert_eq!(decoder.feed(0x1E), pressed(Key::Char('a')));
+        assert_eq!(released(Key::Char('a')).unwrap().char(), None);
+    }
+}
@@ -1,19 +1,47 @@
 use acpi::madt::Madt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use spin::Lazy;
 
 use crate::memory::{
+    paging::PageTableEntryFlags,
     physical::PhyAddr,
     virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator, VirtAddr},
 };
 
+/// Whether LOCAL_APIC_ADDRESS was initialized, so that it can be checked without mapping it
+static LOCAL_APIC_MAPPED: AtomicBool = AtomicBool::new(false);
+
 static LOCAL_APIC_ADDRESS: Lazy<VirtAddr> = Lazy::new(|| {
-    let madt = crate::acpi::tables().find_table::<Madt>().unwrap();
-    let lapic_phy_addr = PhyAddr(madt.get().local_apic_address as u64);
-    unsafe { GLOBAL_PAGE_ALLOCATOR.map_physical(lapic_phy_addr, 1) }
-        .unwrap()
-        .1
+    crate::memory::assert_memory_ready();
+    let lapic_phy_addr = crate::acpi::with_tables(|tables| {
+        let madt = tables.find_table::<Madt>().unwrap();
+        PhyAddr(madt.get().local_apic_address as u64)
+    });
+    let addr = unsafe {
+        GLOBAL_PAGE_ALLOCATOR.map_physical(lapic_phy_addr, 1, PageTableEntryFlags::mmio())
+    }
+    .unwrap()
+    .1;
+    LOCAL_APIC_MAPPED.store(true, Ordering::Relaxed);
+    addr
 });
 
+/// Interrupt command register, writing the low half sends the ipi
+const ICR_LOW: u32 = 0x300;
+/// Interrupt command register, the destination apic id is in the top byte
+const ICR_HIGH: u32 = 0x310;
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+/// Set while the ipi wasn't accepted yet
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// Must be set for everything but an INIT level de-assert
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+/// LVT timer bit: reload the initial count once it reaches 0, instead of stopping
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
 pub struct LocalApic;
 
 impl LocalApic {
@@ -28,6 +56,12 @@ impl LocalApic {
         }
     }
 
+    /// Whether the registers were already mapped, i.e. whether using the local apic won't touch ACPI or the page allocator.
+    /// Useful for code which can't afford to fault, like the panic handler.
+    pub fn is_mapped() -> bool {
+        LOCAL_APIC_MAPPED.load(Ordering::Relaxed)
+    }
+
     pub fn addr() -> VirtAddr {
         *LOCAL_APIC_ADDRESS
     }
@@ -64,10 +98,19 @@ impl LocalApic {
         Self::write(0x380, count);
     }
 
+    /// Set the timer's vector. This also puts it in one-shot mode and unmasks it.
     pub fn set_lvt_timer_irq(irq: u32) {
         Self::write(0x320, irq);
     }
 
+    /// Interrupt every init_count ticks of the (divided) timer clock, on the vector set with set_lvt_timer_irq.
+    /// Stops once the initial count is set to 0.
+    pub fn set_timer_periodic(init_count: u32) {
+        let old = Self::read(0x320);
+        Self::write(0x320, old | LVT_TIMER_PERIODIC);
+        Self::set_timer_init_count(init_count);
+    }
+
     pub fn mask_timer() {
         let old = Self::read(0x320);
         Self::write(0x320, old | (1 << 16));
@@ -88,4 +131,126 @@ impl LocalApic {
     pub fn set_lvt_error_irq(irq: u32) {
         Self::write(0x370, irq);
     }
+
+    /// Send the raw ICR value to dest, and wait for it to be accepted.
+    /// The high half must be written first: writing the low half is what sends the ipi.
+    /// Interrupts are disabled in between, so that a handler which sends an ipi itself can't change
+    /// the destination under us.
+    fn send_icr(dest_apic_id: u8, low: u32) {
+        crate::interrupts::without_interrupts(|| {
+            Self::write(ICR_HIGH, (dest_apic_id as u32) << 24);
+            Self::write(ICR_LOW, low);
+            while Self::read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
+    /// Interrupt the cpu with the local apic id dest_apic_id (which may be us) with vector
+    pub fn send_ipi(dest_apic_id: u8, vector: u8) {
+        Self::send_icr(
+            dest_apic_id,
+            ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | vector as u32,
+        );
+    }
+
+    /// Interrupt every cpu but us with vector
+    pub fn send_ipi_all_excluding_self(vector: u8) {
+        // the destination field is ignored with a shorthand
+        Self::send_icr(
+            0,
+            ICR_DELIVERY_FIXED
+                | ICR_LEVEL_ASSERT
+                | ICR_SHORTHAND_ALL_EXCLUDING_SELF
+                | vector as u32,
+        );
+    }
+
+    /// Reset the cpu with the local apic id dest_apic_id, the first step of bringing it up.
+    /// It then waits for a startup ipi.
+    pub fn send_init(dest_apic_id: u8) {
+        Self::send_icr(dest_apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Start the cpu with the local apic id dest_apic_id, after send_init, in real mode at
+    /// physical address start_page * 0x1000.
+    pub fn send_startup(dest_apic_id: u8, start_page: u8) {
+        Self::send_icr(
+            dest_apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | start_page as u32,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interrupts::{SHARED_IDT, register_handler, unregister_handler};
+    use alloc::boxed::Box;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn ipi_to_self() {
+        static IPIS: AtomicUsize = AtomicUsize::new(0);
+        const VECTOR: u8 = 0x82;
+        register_handler(
+            VECTOR,
+            Box::new(|_| {
+                IPIS.fetch_add(1, Ordering::Relaxed);
+                LocalApic::eoi();
+            }),
+        );
+        SHARED_IDT.guard(|idt| unsafe { idt.lock().as_ref().load() });
+        LocalApic::enable();
+        let were_enabled = crate::interrupts::are_enabled();
+        LocalApic::send_ipi(LocalApic::id() as u8, VECTOR);
+        // it's pending until interrupts are enabled
+        unsafe { crate::interrupts::irq_enable() };
+        crate::time::poll_sleep(core::time::Duration::from_millis(1));
+        unsafe { crate::interrupts::irq_disable() };
+        assert_eq!(IPIS.load(Ordering::Relaxed), 1);
+        // without smp there are no other cpus, so nobody gets it
+        #[cfg(not(feature = "smp"))]
+        {
+            LocalApic::send_ipi_all_excluding_self(VECTOR);
+            unsafe { crate::interrupts::irq_enable() };
+            crate::time::poll_sleep(core::time::Duration::from_millis(1));
+            unsafe { crate::interrupts::irq_disable() };
+            assert_eq!(IPIS.load(Ordering::Relaxed), 1);
+        }
+        unregister_handler(VECTOR);
+        if were_enabled {
+            unsafe { crate::interrupts::irq_enable() };
+        }
+    }
+
+    #[test_case]
+    fn periodic_timer() {
+        static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+        const VECTOR: u8 = 0x83;
+        register_handler(
+            VECTOR,
+            Box::new(|_| {
+                INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+                LocalApic::eoi();
+            }),
+        );
+        SHARED_IDT.guard(|idt| unsafe { idt.lock().as_ref().load() });
+        LocalApic::enable();
+        LocalApic::set_lvt_timer_irq(VECTOR as u32);
+        LocalApic::set_timer_div(1);
+        LocalApic::set_timer_periodic(100_000);
+        let were_enabled = crate::interrupts::are_enabled();
+        unsafe { crate::interrupts::irq_enable() };
+        crate::time::poll_sleep(core::time::Duration::from_millis(10));
+        unsafe { crate::interrupts::irq_disable() };
+        LocalApic::set_timer_init_count(0);
+        LocalApic::mask_timer();
+        // a one-shot timer would have interrupted once
+        assert!(INTERRUPTS.load(Ordering::Relaxed) >= 2);
+        unregister_handler(VECTOR);
+        if were_enabled {
+            unsafe { crate::interrupts::irq_enable() };
+        }
+    }
 }
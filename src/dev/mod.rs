@@ -1,3 +1,75 @@
 pub mod hpet;
 pub mod ioapic;
+pub mod keyboard;
 pub mod local_apic;
+pub mod msi;
+pub mod pci;
+pub mod pic;
+pub mod serial;
+
+use acpi::{HpetInfo, madt::Madt};
+
+/// A device the kernel needs is unusable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// The device isn't there at all, i.e. its ACPI table is missing
+    Missing(&'static str),
+}
+
+impl core::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DeviceError::Missing(name) => {
+                write!(f, "no {} was found, the kernel can't run without it", name)
+            }
+        }
+    }
+}
+
+/// Check that the devices we can't boot without exist, before the code which maps them panics.
+pub fn check_required() -> Result<(), DeviceError> {
+    crate::acpi::with_tables(|tables| {
+        // the local apic and the io apic are both found through it
+        if tables.find_table::<Madt>().is_err() {
+            return Err(DeviceError::Missing("MADT (local APIC and I/O APIC)"));
+        }
+        if HpetInfo::new(tables).is_err() {
+            return Err(DeviceError::Missing("HPET"));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hpet::Hpet, ioapic::IoApic, local_apic::LocalApic};
+    use crate::memory::{
+        paging::{Page, PageTable, PageTableEntryFlags},
+        virt::VirtAddr,
+    };
+    use acpi::madt::Madt;
+
+    fn flags_of(addr: VirtAddr) -> PageTableEntryFlags {
+        unsafe { PageTable::current().page_entry(Page::from(addr)) }
+            .unwrap()
+            .flags()
+    }
+
+    #[test_case]
+    fn device_registers_are_uncached() {
+        for addr in [LocalApic::addr(), Hpet::addr(), IoApic::addr()] {
+            let flags = flags_of(addr);
+            assert!(flags.contains(PageTableEntryFlags::NO_CACHE));
+            assert!(flags.contains(PageTableEntryFlags::NO_EXECUTE));
+        }
+    }
+
+    #[test_case]
+    fn acpi_tables_are_cached() {
+        crate::acpi::with_tables(|tables| {
+            let madt = tables.find_table::<Madt>().unwrap();
+            let addr = VirtAddr(madt.virtual_start().as_ptr() as u64);
+            assert!(!flags_of(addr).contains(PageTableEntryFlags::NO_CACHE));
+        });
+    }
+}
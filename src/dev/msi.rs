@@ -0,0 +1,30 @@
+/// Base of the MSI address range, the destination local apic id goes in bits 12-19
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+/// The address/data pair a device writes to in order to deliver a message signaled interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u32,
+    pub data: u32,
+}
+
+/// Compose an MSI which delivers vector to the local apic with the given id.
+/// Uses physical destination mode, fixed delivery mode and edge trigger mode.
+pub fn compose(dest_lapic_id: u8, vector: u8) -> MsiMessage {
+    MsiMessage {
+        address: MSI_ADDRESS_BASE | ((dest_lapic_id as u32) << 12),
+        data: vector as u32,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn compose_message() {
+        let msg = compose(3, 0x40);
+        assert_eq!(msg.address, 0xfee0_3000);
+        assert_eq!(msg.data, 0x40);
+    }
+}
@@ -0,0 +1,164 @@
+// PCI configuration space through the legacy io ports, which every PC has (unlike the ECAM in the MCFG).
+// We only find the devices for now, their drivers come later.
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::io::Port;
+
+const CONFIG_ADDRESS: Port<u32> = Port::new(0xCF8);
+const CONFIG_DATA: Port<u32> = Port::new(0xCFC);
+/// Config address bit: actually access the configuration space
+const CONFIG_ENABLE: u32 = 1 << 31;
+/// The vendor id of a function which isn't there
+const NO_VENDOR: u16 = 0xFFFF;
+/// Header type bit: the device has functions other than function 0
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+// register offsets
+const VENDOR_DEVICE: u8 = 0x00;
+const CLASS: u8 = 0x08;
+/// cache line size, latency timer, header type and BIST
+const HEADER: u8 = 0x0C;
+
+/// Each access takes a write of the address and then the data port, so they can't be interleaved
+pub(crate) static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+pub struct PciConfig;
+
+impl PciConfig {
+    fn address(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+        debug_assert!(slot < 32 && func < 8, "no such function {slot}.{func}");
+        CONFIG_ENABLE
+            | (bus as u32) << 16
+            | (slot as u32) << 11
+            | (func as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Read the dword at offset (rounded down to a multiple of 4) of the function's configuration space.
+    /// Functions which aren't there read as all ones.
+    pub fn read_u32(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            CONFIG_ADDRESS.write(Self::address(bus, slot, func, offset));
+            CONFIG_DATA.read()
+        }
+    }
+
+    /// Write the dword at offset (rounded down to a multiple of 4) of the function's configuration space.
+    /// ## Safety
+    /// The device must expect the write, i.e. it can move its registers (BARs) or start DMA (the command register).
+    pub unsafe fn write_u32(bus: u8, slot: u8, func: u8, offset: u8, val: u32) {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            CONFIG_ADDRESS.write(Self::address(bus, slot, func, offset));
+            CONFIG_DATA.write(val);
+        }
+    }
+}
+
+/// A function of a PCI device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    /// the register level interface within the subclass, i.e. AHCI for SATA controllers
+    pub prog_if: u8,
+}
+
+impl PciDevice {
+    /// The function at bus:slot.func, None if it isn't there
+    pub fn probe(bus: u8, slot: u8, func: u8) -> Option<PciDevice> {
+        let ids = PciConfig::read_u32(bus, slot, func, VENDOR_DEVICE);
+        let vendor_id = ids as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+        let class = PciConfig::read_u32(bus, slot, func, CLASS);
+        Some(PciDevice {
+            bus,
+            slot,
+            func,
+            vendor_id,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+        })
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        let header_type = (PciConfig::read_u32(self.bus, self.slot, self.func, HEADER) >> 16) as u8;
+        header_type & HEADER_MULTIFUNCTION != 0
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.bus,
+            self.slot,
+            self.func,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if
+        )
+    }
+}
+
+/// The functions of the device in bus:slot
+fn functions(bus: u8, slot: u8) -> impl Iterator<Item = PciDevice> {
+    let count = match PciDevice::probe(bus, slot, 0) {
+        None => 0,
+        Some(device) if device.is_multifunction() => 8,
+        Some(_) => 1,
+    };
+    // the functions of a multifunction device don't have to be contiguous
+    (0..count).filter_map(move |func| PciDevice::probe(bus, slot, func))
+}
+
+/// Every function on every bus. Slots are probed one by one, so this takes a while.
+pub fn enumerate() -> impl Iterator<Item = PciDevice> {
+    (0..=255).flat_map(|bus| (0..32).flat_map(move |slot| functions(bus, slot)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn host_bridge() {
+        // there's always a host bridge at 00:00.0
+        let bridge = PciDevice::probe(0, 0, 0).unwrap();
+        assert_eq!((bridge.class, bridge.subclass), (0x06, 0x00));
+        assert_eq!(PciConfig::read_u32(0, 0, 0, 0) as u16, bridge.vendor_id);
+        // reads are rounded down to the dword
+        assert_eq!(
+            PciConfig::read_u32(0, 0, 0, 2),
+            PciConfig::read_u32(0, 0, 0, 0)
+        );
+
+        let devices: alloc::vec::Vec<PciDevice> = enumerate().collect();
+        assert_eq!(devices.first(), Some(&bridge));
+        assert!(devices.iter().all(|device| device.vendor_id != NO_VENDOR));
+        // no function is found twice
+        for (i, device) in devices.iter().enumerate() {
+            assert!(
+                devices[i + 1..]
+                    .iter()
+                    .all(|other| (other.bus, other.slot, other.func)
+                        != (device.bus, device.slot, device.func))
+            );
+        }
+    }
+}
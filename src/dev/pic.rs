@@ -0,0 +1,77 @@
+// The legacy 8259 PICs. We only use the IOAPIC, so all they need is to be moved out of the way and masked.
+use crate::io::{read_u8, write_u8};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// ICW1: initialize, ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 mode
+const ICW4_8086: u8 = 0x01;
+/// The slave is cascaded on irq 2 of the master
+const CASCADE_IRQ: u8 = 2;
+
+/// Vector of irq 0 of the master, the slave's irqs come right after.
+/// At boot they're on vectors 8-15, on top of the cpu exceptions. A masked PIC still raises spurious irqs 7 and 15,
+/// so they're moved to the end of the IDT, away from the vectors we use.
+pub const PIC_VECTOR_BASE: u8 = 0xF0;
+
+// some chipsets need a moment between the initialization words, writing to an unused port takes about that long
+unsafe fn io_wait() {
+    unsafe { write_u8(0x80, 0) };
+}
+
+/// Remap both PICs to PIC_VECTOR_BASE and mask all of their irqs.
+/// Must be called before interrupts are enabled, so that nothing they raised lands on an exception vector.
+pub fn init() {
+    crate::interrupts::without_interrupts(|| unsafe {
+        write_u8(MASTER_COMMAND, ICW1_INIT);
+        io_wait();
+        write_u8(SLAVE_COMMAND, ICW1_INIT);
+        io_wait();
+        // ICW2: vector offsets
+        write_u8(MASTER_DATA, PIC_VECTOR_BASE);
+        io_wait();
+        write_u8(SLAVE_DATA, PIC_VECTOR_BASE + 8);
+        io_wait();
+        // ICW3: the master gets a bitmask of its slave lines, the slave gets its line number
+        write_u8(MASTER_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        write_u8(SLAVE_DATA, CASCADE_IRQ);
+        io_wait();
+        write_u8(MASTER_DATA, ICW4_8086);
+        io_wait();
+        write_u8(SLAVE_DATA, ICW4_8086);
+        io_wait();
+        mask_all();
+    });
+}
+
+/// Mask all the irqs of both PICs
+pub fn mask_all() {
+    unsafe {
+        write_u8(MASTER_DATA, 0xff);
+        write_u8(SLAVE_DATA, 0xff);
+    }
+}
+
+/// The interrupt mask registers, master in the low byte and slave in the high byte. A set bit is a masked irq.
+pub fn masks() -> u16 {
+    unsafe { read_u8(MASTER_DATA) as u16 | (read_u8(SLAVE_DATA) as u16) << 8 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn remap_and_mask() {
+        init();
+        assert_eq!(masks(), 0xffff);
+        // masking again doesn't change anything
+        mask_all();
+        assert_eq!(masks(), 0xffff);
+    }
+}
@@ -0,0 +1,219 @@
+// A 16550 UART, i.e. COM1. Unlike qemu_log's debug port it's there on real hardware too.
+use core::{
+    fmt::{self, Write},
+    time::Duration,
+};
+
+use spin::{Lazy, mutex::SpinMutex};
+
+use crate::io::Port;
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::dev::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+pub const COM1_PORT: u16 = 0x3F8;
+/// The UART's clock, the baud rate is it divided by the divisor
+const BASE_BAUD_RATE: u32 = 115200;
+pub const DEFAULT_BAUD_RATE: u32 = 38400;
+
+// register offsets from the base port
+/// data, or the low byte of the divisor while LCR_DLAB is set
+const DATA: u16 = 0;
+/// interrupt enable, or the high byte of the divisor while LCR_DLAB is set
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// the data and interrupt enable registers are the divisor
+const LCR_DLAB: u8 = 0x80;
+/// 8 data bits, no parity, 1 stop bit
+const LCR_8N1: u8 = 0x03;
+/// enable the fifos, clear them, and interrupt once 14 bytes were received
+const FCR_ENABLE_AND_CLEAR: u8 = 0xC7;
+/// DTR, RTS and OUT2 (which connects the irq line)
+const MCR_NORMAL: u8 = 0x0B;
+/// like MCR_NORMAL, but whatever is sent is received back instead of leaving the chip
+const MCR_LOOPBACK: u8 = 0x1E;
+/// a received byte is waiting in the data register
+const LSR_DATA_READY: u8 = 1;
+/// the transmitter can take another byte
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// The UART only runs at BASE_BAUD_RATE divided by a whole number which fits in 16 bits
+    InvalidBaudRate(u32),
+    /// The loopback test failed, i.e. there's no UART at the port
+    NotPresent,
+}
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// ## Safety
+    /// There must be a 16550 at base, and nobody else may use it.
+    pub const unsafe fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    /// Set the port up at baud_rate, 8N1 with the fifos enabled, then check that the UART is really there
+    /// with a loopback test. A baud rate the UART can't run at is rejected before the port is touched.
+    pub fn init(&mut self, baud_rate: u32) -> Result<(), SerialError> {
+        // the divisor is a whole number, and only 16 bits
+        let divisor = BASE_BAUD_RATE
+            .checked_div(baud_rate)
+            .filter(|divisor| divisor * baud_rate == BASE_BAUD_RATE)
+            .and_then(|divisor| u16::try_from(divisor).ok())
+            .ok_or(SerialError::InvalidBaudRate(baud_rate))?;
+        unsafe {
+            // we poll, so no interrupts
+            self.write_reg(INTERRUPT_ENABLE, 0);
+            self.write_reg(LINE_CONTROL, LCR_DLAB);
+            self.write_reg(DATA, divisor as u8);
+            self.write_reg(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+            self.write_reg(LINE_CONTROL, LCR_8N1);
+            self.write_reg(FIFO_CONTROL, FCR_ENABLE_AND_CLEAR);
+        }
+        self.set_loopback(true);
+        self.write_byte(0xAE);
+        let works = self.try_read_byte() == Some(0xAE);
+        self.set_loopback(false);
+        if works {
+            Ok(())
+        } else {
+            Err(SerialError::NotPresent)
+        }
+    }
+
+    fn set_loopback(&mut self, loopback: bool) {
+        let mcr = if loopback { MCR_LOOPBACK } else { MCR_NORMAL };
+        unsafe { self.write_reg(MODEM_CONTROL, mcr) };
+    }
+
+    fn reg(&self, reg: u16) -> Port<u8> {
+        Port::new(self.base + reg)
+    }
+
+    unsafe fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { self.reg(reg).read() }
+    }
+
+    unsafe fn write_reg(&self, reg: u16, val: u8) {
+        unsafe { self.reg(reg).write(val) }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.read_reg(LINE_STATUS) }
+    }
+
+    /// Send byte, waiting until the transmitter can take it
+    pub fn write_byte(&mut self, byte: u8) {
+        while self.line_status() & LSR_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.write_reg(DATA, byte) };
+    }
+
+    /// Wait for a byte to be received, and return it
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait up to timeout for a byte to be received, None if nothing came in time
+    pub fn read_byte_timeout(&mut self, timeout: Duration) -> Option<u8> {
+        let start = crate::time::elapsed_fs();
+        let timeout_fs = timeout.as_nanos() * 1_000_000;
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return Some(byte);
+            }
+            if crate::time::elapsed_fs().saturating_sub(start) >= timeout_fs {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// The next received byte, None if nothing was received
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        (self.line_status() & LSR_DATA_READY != 0).then(|| unsafe { self.read_reg(DATA) })
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // serial consoles expect a carriage return before the new line
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+pub static COM1: Lazy<SpinMutex<SerialPort>> = Lazy::new(|| {
+    // safety: COM1 is only used through this
+    let mut port = unsafe { SerialPort::new(COM1_PORT) };
+    // if it's not there, writes go nowhere, which is all we can do anyway
+    let _ = port.init(DEFAULT_BAUD_RATE);
+    SpinMutex::new(port)
+});
+
+pub fn _print(args: fmt::Arguments) {
+    COM1.lock().write_fmt(args).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn loopback() {
+        let mut port = COM1.lock();
+        port.set_loopback(true);
+        // whatever was received before
+        while port.try_read_byte().is_some() {}
+        // nothing leaves the chip, so this doesn't show up on the console
+        for byte in *b"hi\n" {
+            port.write_byte(byte);
+            assert_eq!(
+                port.read_byte_timeout(Duration::from_millis(10)),
+                Some(byte)
+            );
+        }
+        assert_eq!(port.try_read_byte(), None);
+        port.set_loopback(false);
+    }
+
+    #[test_case]
+    fn invalid_baud_rates() {
+        // 1 needs a divisor which doesn't fit in 16 bits
+        let mut port = COM1.lock();
+        for baud_rate in [0, 1, BASE_BAUD_RATE + 1, 2 * BASE_BAUD_RATE, 40000] {
+            assert_eq!(
+                port.init(baud_rate),
+                Err(SerialError::InvalidBaudRate(baud_rate))
+            );
+        }
+        assert_eq!(port.init(DEFAULT_BAUD_RATE), Ok(()));
+    }
+}
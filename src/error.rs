@@ -0,0 +1,82 @@
+use core::fmt::{self, Display};
+
+use crate::{acpi::AcpiError, dev::DeviceError, fs::vfs::VfsError, memory::MemoryError};
+
+/// An error of any subsystem, so that ? works across them, i.e. up to boot, which prints it and halts.
+#[derive(Debug, PartialEq)]
+pub enum KernelError {
+    Vfs(VfsError),
+    Memory(MemoryError),
+    Acpi(AcpiError),
+    Device(DeviceError),
+}
+
+impl From<VfsError> for KernelError {
+    fn from(value: VfsError) -> Self {
+        KernelError::Vfs(value)
+    }
+}
+
+impl From<MemoryError> for KernelError {
+    fn from(value: MemoryError) -> Self {
+        KernelError::Memory(value)
+    }
+}
+
+impl From<AcpiError> for KernelError {
+    fn from(value: AcpiError) -> Self {
+        KernelError::Acpi(value)
+    }
+}
+
+impl From<DeviceError> for KernelError {
+    fn from(value: DeviceError) -> Self {
+        KernelError::Device(value)
+    }
+}
+
+impl Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelError::Vfs(e) => write!(f, "vfs: {:?}", e),
+            KernelError::Memory(e) => write!(f, "memory: {}", e),
+            KernelError::Acpi(e) => write!(f, "acpi: {}", e),
+            KernelError::Device(e) => write!(f, "device: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    fn open_missing() -> Result<(), KernelError> {
+        Err(VfsError::PathDoesNotExist)?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn conversion_and_messages() {
+        assert_eq!(
+            open_missing(),
+            Err(KernelError::Vfs(VfsError::PathDoesNotExist))
+        );
+        assert_eq!(
+            format!("{}", KernelError::from(VfsError::PathDoesNotExist)),
+            "vfs: PathDoesNotExist"
+        );
+        assert_eq!(
+            format!("{}", KernelError::from(MemoryError::NoUsableMemory)),
+            "memory: the memory map has no usable memory, give the machine more RAM"
+        );
+        assert_eq!(
+            format!("{}", KernelError::from(AcpiError::NoRsdp)),
+            "acpi: the firmware has no ACPI tables, boot with ACPI enabled"
+        );
+        assert_eq!(
+            format!("{}", KernelError::from(DeviceError::Missing("HPET"))),
+            "device: no HPET was found, the kernel can't run without it"
+        );
+    }
+}
@@ -1,4 +1,75 @@
+use spin::Once;
+
+use path::Path;
+use ramfs::Ramfs;
+use vfs::{BoxedFiles, Result, Vfs};
+
+use crate::fs::modulefs::ModuleFs;
+
+pub mod modulefs;
 pub mod path;
 pub mod ramfs;
 pub mod vfs;
 
+/// The global vfs, set by init
+static VFS: Once<Vfs> = Once::new();
+
+/// Where the Limine modules are mounted, see ModuleFs
+pub const MODULES_PATH: &str = "/modules";
+
+/// Set up the global vfs: an empty ramfs, with the Limine modules mounted at MODULES_PATH.
+/// Calling it again returns the result of the first successful call, or tries again.
+pub fn init() -> Result<()> {
+    VFS.try_call_once(|| {
+        let vfs = Vfs::new(BoxedFiles::new_dyn(Ramfs::new()));
+        vfs.mount(
+            BoxedFiles::new_dyn(ModuleFs::from_limine()),
+            Path::new(MODULES_PATH),
+        )?;
+        Ok(vfs)
+    })?;
+    Ok(())
+}
+
+/// The global vfs, if init succeeded
+pub fn vfs() -> Option<&'static Vfs> {
+    VFS.get()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alloc::vec::Vec;
+    use crate::fs::vfs::{FileSystem, FileType};
+    use crate::{KERNEL_SYMBOL_MODULE, MODULE_REQUEST};
+
+    #[test_case]
+    fn kernel_symbols_in_vfs() {
+        init().unwrap();
+        let vfs = vfs().unwrap();
+        let entries = vfs
+            .open_dir(Path::new(MODULES_PATH))
+            .unwrap()
+            .collect::<Vec<_>>();
+        let symbols = entries
+            .iter()
+            .find(|e| e.path.as_str() == "/modules/kernel.symbols")
+            .expect("kernel.symbols isn't in /modules");
+        assert_eq!(symbols.file_type, FileType::File);
+
+        let module = MODULE_REQUEST
+            .get_response()
+            .unwrap()
+            .modules()
+            .iter()
+            .find(|f| f.path().to_bytes().ends_with(KERNEL_SYMBOL_MODULE.path()))
+            .unwrap();
+        let mut file = vfs.open_file(&symbols.path).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(file.read(&mut buf), Ok(buf.len()));
+        let module_bytes = unsafe { core::slice::from_raw_parts(module.addr(), buf.len()) };
+        assert_eq!(buf, module_bytes);
+        // the very same memory
+        assert_eq!(file.mmap().unwrap().as_ptr(), module.addr() as *const u8);
+    }
+}
@@ -0,0 +1,210 @@
+use super::path::{Path, PathBuf};
+use super::vfs::{
+    DirEntry, File, FileMapping, FileSystem, FileType, Mode, Result, SeekFrom, VfsError,
+};
+use crate::MODULE_REQUEST;
+use crate::alloc::{boxed::Box, string::ToString, vec::Vec};
+
+/// A module Limine loaded for us, i.e. kernel.symbols
+#[derive(Clone, Copy, Debug)]
+struct Module {
+    /// the last component of the module's path
+    name: &'static str,
+    data: &'static [u8],
+}
+
+/// A read only filesystem with the Limine modules as files in its root, i.e. /kernel.symbols.
+/// Files are backed by the modules' memory, so nothing is copied.
+pub struct ModuleFs {
+    modules: Vec<Module>,
+}
+
+impl ModuleFs {
+    /// A filesystem of the given (name, contents) modules
+    pub fn new(modules: impl IntoIterator<Item = (&'static str, &'static [u8])>) -> Self {
+        ModuleFs {
+            modules: modules
+                .into_iter()
+                .map(|(name, data)| Module { name, data })
+                .collect(),
+        }
+    }
+
+    /// A filesystem of the modules Limine loaded. Empty if there's no module response,
+    /// and modules whose path isn't valid utf8 are skipped.
+    pub fn from_limine() -> Self {
+        let Some(response) = MODULE_REQUEST.get_response() else {
+            return Self::new([]);
+        };
+        Self::new(response.modules().iter().filter_map(|module| {
+            let path = str::from_utf8(module.path().to_bytes()).ok()?;
+            let name = path.rsplit('/').next().unwrap();
+            // safety: limine maps the modules for us, and we never reclaim its memory
+            let data =
+                unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) };
+            Some((name, data))
+        }))
+    }
+
+    fn find(&self, path: &Path) -> Result<Module> {
+        if !path.has_root() {
+            return Err(VfsError::PathIsNotAbsolute);
+        }
+        let name = path.relative_to(Path::root()).unwrap();
+        match self.modules.iter().find(|m| m.name == name.as_str()) {
+            Some(module) => Ok(*module),
+            // all the modules are in the root
+            None if path.parent().is_some_and(|p| !p.is_root()) => {
+                match self.find(path.parent().unwrap()) {
+                    Ok(_) => Err(VfsError::NotADirectory),
+                    Err(_) => Err(VfsError::PathDoesNotExist),
+                }
+            }
+            None => Err(VfsError::PathDoesNotExist),
+        }
+    }
+}
+
+/// A read only file over the memory of a module
+#[derive(Debug)]
+pub struct ModuleFile {
+    data: &'static [u8],
+    pos: usize,
+}
+
+impl File for ModuleFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let rest = self.data.get(self.pos..).unwrap_or(&[]);
+        let read = buf.len().min(rest.len());
+        buf[..read].copy_from_slice(&rest[..read]);
+        self.pos += read;
+        Ok(read)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn seek(&mut self, from: SeekFrom) -> Result<usize> {
+        self.pos = from.resolve(self.pos, self.data.len());
+        Ok(self.pos)
+    }
+
+    fn truncate(&mut self, _len: usize) -> Result<()> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn mmap(&self) -> Result<FileMapping<'_>> {
+        Ok(FileMapping::new(self.data))
+    }
+}
+
+impl FileSystem for ModuleFs {
+    type File = ModuleFile;
+
+    fn open_file(&self, path: &Path) -> Result<Self::File> {
+        if path.is_root() {
+            return Err(VfsError::IsADirectory);
+        }
+        let module = self.find(path)?;
+        Ok(ModuleFile {
+            data: module.data,
+            pos: 0,
+        })
+    }
+
+    fn open_file_with(&self, path: &Path, access: Mode) -> Result<Self::File> {
+        let file = self.open_file(path)?;
+        if access.contains(Mode::WRITE) {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(file)
+    }
+
+    fn open_dir(&self, path: &Path) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
+        if !path.is_root() {
+            self.find(path)?;
+            return Err(VfsError::NotADirectory);
+        }
+        let entries = self
+            .modules
+            .iter()
+            .map(|m| DirEntry {
+                path: PathBuf::from("/".to_string() + m.name),
+                file_type: FileType::File,
+            })
+            .collect::<Vec<DirEntry>>();
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn file_type(&self, path: &Path) -> Result<FileType> {
+        if path.is_root() {
+            return Ok(FileType::Directory);
+        }
+        self.find(path).map(|_| FileType::File)
+    }
+
+    fn delete(&self, _path: &Path) -> Result<()> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn create_file(&self, _path: &Path) -> Result<Self::File> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn create_dir(&self, _path: &Path) -> Result<()> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn create_file_with(&self, _path: &Path, _mode: Mode) -> Result<Self::File> {
+        Err(VfsError::PermissionDenied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn read_only_modules() {
+        let fs = ModuleFs::new([("a.txt", &b"hello"[..]), ("b", &b""[..])]);
+        let mut file = fs.open_file(Path::new("/a.txt")).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(file.read(&mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        // reads stop at the end of the module
+        assert_eq!(file.read(&mut buf), Ok(0));
+        assert_eq!(file.write(b"x"), Err(VfsError::PermissionDenied));
+        assert_eq!(file.truncate(0), Err(VfsError::PermissionDenied));
+        assert_eq!(&*file.mmap().unwrap(), b"hello");
+
+        assert!(fs.open_file_with(Path::new("/a.txt"), Mode::READ).is_ok());
+        assert!(matches!(
+            fs.open_file_with(Path::new("/a.txt"), Mode::WRITE),
+            Err(VfsError::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.create_file(Path::new("/c")),
+            Err(VfsError::PermissionDenied)
+        ));
+        assert_eq!(
+            fs.delete(Path::new("/a.txt")),
+            Err(VfsError::PermissionDenied)
+        );
+        assert!(matches!(
+            fs.open_file(Path::new("/missing")),
+            Err(VfsError::PathDoesNotExist)
+        ));
+        assert!(matches!(
+            fs.open_file(Path::new("/a.txt/inner")),
+            Err(VfsError::NotADirectory)
+        ));
+        assert_eq!(fs.file_type(Path::new("/b")), Ok(FileType::File));
+        let names = fs
+            .open_dir(Path::root())
+            .unwrap()
+            .map(|e| e.path)
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(names, [PathBuf::new("/a.txt"), PathBuf::new("/b")]);
+    }
+}
@@ -4,7 +4,7 @@ use core::{
     str,
 };
 
-use alloc::string::ToString;
+use alloc::{format, string::ToString, vec::Vec};
 
 use crate::alloc::string::String;
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -119,6 +119,58 @@ impl Path {
         }
     }
 
+    /// The non empty components of the path, without the root.
+    /// i.e. "usr", "foo" and "bar" for "/usr//foo/bar/", and nothing for "/".
+    pub fn components(&self) -> impl DoubleEndedIterator<Item = &Path> {
+        self.inner
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(Path::new)
+    }
+
+    /// Append other to the path, with exactly one '/' between them whatever slashes they start or end with.
+    /// Joining an empty path changes nothing.
+    pub fn join(&self, other: &Path) -> PathBuf {
+        let other = other.inner.trim_start_matches('/');
+        if other.is_empty() {
+            return PathBuf::from(self);
+        }
+        if self.inner.is_empty() {
+            return PathBuf::new(other);
+        }
+        let base = self.inner.trim_end_matches('/');
+        PathBuf::from(format!("{}/{}", base, other))
+    }
+
+    /// Resolve the "." and ".." components, i.e. "/a/b/../c" is "/a/c", and drop empty components and trailing slashes.
+    /// ".." never goes above the root of an absolute path, while a relative path keeps the ".." it can't resolve, i.e. "../a".
+    pub fn normalize(&self) -> PathBuf {
+        let mut components: Vec<&str> = Vec::new();
+        for component in self.components() {
+            match component.as_str() {
+                "." => {}
+                ".." => match components.last() {
+                    Some(&last) if last != ".." => {
+                        components.pop();
+                    }
+                    _ if self.has_root() => {}
+                    _ => components.push(".."),
+                },
+                name => components.push(name),
+            }
+        }
+        let normalized = components.join("/");
+        if self.has_root() {
+            PathBuf::from(format!("/{}", normalized))
+        } else {
+            PathBuf::from(normalized)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
     pub fn has_root(&self) -> bool {
         self.inner.starts_with('/')
     }
@@ -127,11 +179,45 @@ impl Path {
         &self.inner == "/"
     }
 
+    /// Whether the path starts with prefix. Only whole components match, so "/foobar" doesn't start with "/foo".
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        let trimmed = prefix.inner.trim_end_matches('/');
+        if trimmed.is_empty() {
+            // the root is a prefix of every absolute path, and the empty path is a prefix of everything
+            return !prefix.has_root() || self.has_root();
+        }
+        match self.inner.strip_prefix(trimmed) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Whether the path ends with suffix. Only whole components match, so "/foo/xbar" doesn't end with "bar".
+    /// An absolute suffix only matches the whole path.
+    pub fn ends_with(&self, suffix: &Path) -> bool {
+        let path = self.inner.trim_end_matches('/');
+        let Some(rest) = path.strip_suffix(suffix.inner.trim_end_matches('/')) else {
+            return false;
+        };
+        if suffix.has_root() {
+            rest.is_empty()
+        } else {
+            rest.is_empty() || rest.ends_with('/')
+        }
+    }
+
+    /// Get the rest of the path after prefix, without a leading '/'.
+    /// Returns None if the path doesn't start with prefix, see Path::starts_with.
+    pub fn strip_prefix(&self, prefix: &Path) -> Option<&Path> {
+        if !self.starts_with(prefix) {
+            return None;
+        }
+        let rest = &self.inner[prefix.inner.trim_end_matches('/').len()..];
+        Some(Path::new(rest.trim_start_matches('/')))
+    }
+
     pub fn relative_to(&self, relative: &Path) -> Option<&Path> {
-        self.inner
-            .strip_prefix(&relative.inner)
-            .map(|e| e.strip_prefix("/").unwrap_or(e))
-            .map(Path::new)
+        self.strip_prefix(relative)
     }
 }
 
@@ -150,6 +236,51 @@ mod test {
         assert_eq!(Path::new("hello").parent(), None);
     }
 
+    #[test_case]
+    fn components() {
+        let path = Path::new("/usr/foo/bar");
+        let mut components = path.components();
+        assert_eq!(components.next(), Some(Path::new("usr")));
+        assert_eq!(components.next(), Some(Path::new("foo")));
+        assert_eq!(components.next(), Some(Path::new("bar")));
+        assert_eq!(components.next(), None);
+        assert_eq!(Path::new("usr//foo/").components().count(), 2);
+        assert_eq!(Path::root().components().count(), 0);
+        assert_eq!(Path::new("").components().count(), 0);
+    }
+
+    #[test_case]
+    fn joins() {
+        assert_eq!(
+            Path::new("/usr").join(Path::new("bin")),
+            PathBuf::new("/usr/bin")
+        );
+        assert_eq!(
+            Path::new("/usr/").join(Path::new("/bin")),
+            PathBuf::new("/usr/bin")
+        );
+        assert_eq!(Path::root().join(Path::new("bin")), PathBuf::new("/bin"));
+        assert_eq!(
+            Path::new("usr").join(Path::new("bin/")),
+            PathBuf::new("usr/bin/")
+        );
+        assert_eq!(Path::new("/usr").join(Path::new("")), PathBuf::new("/usr"));
+        assert_eq!(Path::new("").join(Path::new("bin")), PathBuf::new("bin"));
+    }
+
+    #[test_case]
+    fn normalization() {
+        let normalize = |path| Path::new(path).normalize();
+        assert_eq!(normalize("/a/b/../c"), PathBuf::new("/a/c"));
+        assert_eq!(normalize("/../x"), PathBuf::new("/x"));
+        assert_eq!(normalize("a/./b"), PathBuf::new("a/b"));
+        assert_eq!(normalize("/a/b/../../.."), PathBuf::new("/"));
+        assert_eq!(normalize("/"), PathBuf::new("/"));
+        assert_eq!(normalize("../a/../../b/"), PathBuf::new("../../b"));
+        assert_eq!(normalize("a/.."), PathBuf::new(""));
+        assert_eq!(normalize("//a//./b/"), PathBuf::new("/a/b"));
+    }
+
     #[test_case]
     fn top_folders() {
         let path = Path::new("/usr/foo/tmp.html");
@@ -174,4 +305,170 @@ mod test {
         let unrelated_paarent = Path::new("/ok/test");
         assert_eq!(path.relative_to(unrelated_paarent), None);
     }
+
+    #[test_case]
+    fn component_prefixes() {
+        let foo = Path::new("/foo");
+        assert!(Path::new("/foo/bar").starts_with(foo));
+        assert!(!Path::new("/foobar").starts_with(foo));
+        assert!(foo.starts_with(foo));
+        assert!(!foo.starts_with(Path::new("/foo/bar")));
+        assert!(Path::new("/foo/bar").starts_with(Path::root()));
+        assert!(!Path::new("foo/bar").starts_with(Path::root()));
+        assert!(Path::new("/foo/bar").starts_with(Path::new("/foo/")));
+
+        assert_eq!(
+            Path::new("/foo/bar").strip_prefix(foo),
+            Some(Path::new("bar"))
+        );
+        assert_eq!(Path::new("/foobar").strip_prefix(foo), None);
+        assert_eq!(foo.strip_prefix(foo), Some(Path::new("")));
+        assert_eq!(foo.strip_prefix(Path::root()), Some(Path::new("foo")));
+        assert_eq!(Path::root().strip_prefix(Path::root()), Some(Path::new("")));
+    }
+
+    #[test_case]
+    fn component_suffixes() {
+        let path = Path::new("/usr/foo/bar.txt");
+        assert!(path.ends_with(Path::new("bar.txt")));
+        assert!(path.ends_with(Path::new("foo/bar.txt")));
+        assert!(path.ends_with(path));
+        assert!(!path.ends_with(Path::new("ar.txt")));
+        assert!(!path.ends_with(Path::new("/foo/bar.txt")));
+        assert!(!Path::new("bar.txt").ends_with(path));
+    }
+
+    /// xorshift64, so that the fuzzing is deterministic
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            let mut x = self.0;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.0 = x;
+            x
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    const COMPONENTS: [&str; 8] = [
+        "",
+        ".",
+        "..",
+        "a",
+        "usr",
+        "foo.txt",
+        "x y",
+        "long_component_name",
+    ];
+
+    /// A path with a random root, components (including empty ones, i.e. "a//b") and trailing slash
+    fn random_path(rng: &mut Rng) -> String {
+        let mut path = String::new();
+        if rng.below(2) == 0 {
+            path.push('/');
+        }
+        // mostly short paths, and sometimes very long ones
+        let len = if rng.below(16) == 0 {
+            200 + rng.below(200)
+        } else {
+            rng.below(6)
+        };
+        for i in 0..len {
+            if i != 0 {
+                path.push('/');
+            }
+            path.push_str(COMPONENTS[rng.below(COMPONENTS.len() as u64) as usize]);
+        }
+        if rng.below(4) == 0 {
+            path.push('/');
+        }
+        path
+    }
+
+    /// The path without repeated and trailing slashes, so that equivalent spellings compare equal
+    fn collapse(path: &str) -> String {
+        let components = path.split('/').filter(|c| !c.is_empty());
+        let mut collapsed = String::new();
+        for (i, component) in components.enumerate() {
+            if i != 0 || path.starts_with('/') {
+                collapsed.push('/');
+            }
+            collapsed.push_str(component);
+        }
+        if collapsed.is_empty() && path.starts_with('/') {
+            collapsed.push('/');
+        }
+        collapsed
+    }
+
+    fn check_invariants(path: &Path) {
+        let normalized = path.normalize();
+        assert_eq!(normalized.normalize(), normalized, "{:?}", path);
+        assert_eq!(normalized.has_root(), path.has_root(), "{:?}", path);
+        assert!(
+            normalized.components().all(|c| c.as_str() != "."),
+            "{:?}",
+            path
+        );
+        if path.has_root() {
+            assert!(
+                normalized.components().all(|c| c.as_str() != ".."),
+                "{:?}",
+                path
+            );
+        }
+
+        match path.parent() {
+            Some(parent) => {
+                assert!(path.starts_with(parent), "{:?} isn't in {:?}", path, parent);
+                // relative_to(parent) round trips
+                let rest = path.relative_to(parent).unwrap();
+                let joined = parent.as_str().to_string() + "/" + rest.as_str();
+                assert_eq!(collapse(&joined), collapse(path.as_str()), "{:?}", path);
+                if let Some(filename) = path.filename() {
+                    assert!(!filename.as_str().contains('/'), "{:?}", path);
+                    assert_eq!(rest, filename, "{:?}", path);
+                    if !filename.as_str().is_empty() {
+                        assert!(path.ends_with(filename), "{:?}", path);
+                    }
+                }
+            }
+            None => {
+                assert!(!path.as_str().contains('/'), "{:?}", path);
+                assert_eq!(path.filename(), None);
+            }
+        }
+
+        // every ancestor is a prefix, and absolute paths end up at the root
+        let mut ancestor = path;
+        while let Some(parent) = ancestor.parent()
+            && parent != ancestor
+        {
+            assert!(path.starts_with(parent), "{:?} isn't in {:?}", path, parent);
+            assert!(path.strip_prefix(parent).is_some(), "{:?}", path);
+            assert!(parent.as_str().len() < ancestor.as_str().len());
+            ancestor = parent;
+        }
+        if path.has_root() {
+            assert_eq!(ancestor, Path::root(), "{:?}", path);
+            assert_eq!(path.top_folder(), Some(Path::root()));
+        } else if let Some(top) = path.top_folder() {
+            assert!(path.starts_with(top), "{:?} isn't in {:?}", path, top);
+        }
+    }
+
+    #[test_case]
+    fn fuzz_invariants() {
+        let mut rng = Rng(0x5e_ed0f_9a7e_5eed);
+        for _ in 0..4000 {
+            let path = random_path(&mut rng);
+            check_invariants(Path::new(&path));
+        }
+    }
 }
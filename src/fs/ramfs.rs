@@ -1,14 +1,22 @@
 use super::path::{Path, PathBuf};
-use super::vfs::{File, FileSystem, Result, VfsError};
+use super::vfs::{File, FileMapping, FileSystem, Mode, Result, SeekFrom, VfsError};
 use crate::alloc::sync::{Arc, Weak};
-use crate::alloc::{boxed::Box, vec::Vec};
+use crate::alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
 use crate::fs::vfs::{DirEntry, FileType};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU16, Ordering};
 use spin::rwlock::RwLock;
 
 #[derive(Clone, Debug)]
 pub struct RamfsFileHandle {
     inner: Arc<RamfsFile>,
     pos: usize,
+    /// what the file was opened for
+    access: Mode,
 }
 
 impl PartialEq for RamfsFileHandle {
@@ -18,10 +26,19 @@ impl PartialEq for RamfsFileHandle {
 }
 
 impl RamfsFileHandle {
-    fn new(file: Arc<RamfsFile>) -> Self {
+    fn new(file: Arc<RamfsFile>, access: Mode) -> Self {
         RamfsFileHandle {
             inner: file,
             pos: 0,
+            access,
+        }
+    }
+
+    fn check_access(&self, access: Mode) -> Result<()> {
+        if self.access.contains(access) {
+            Ok(())
+        } else {
+            Err(VfsError::PermissionDenied)
         }
     }
 }
@@ -30,62 +47,73 @@ impl RamfsFileHandle {
 struct RamfsFile {
     name: PathBuf,
     data: RwLock<Vec<u8>>,
+    mode: AtomicU16,
     // perhaps we'll use this in the future for RamfsFile::Delete
     #[allow(unused)]
     parent: Weak<Dir>,
+    /// amount of times data was locked for writing, so that tests can check writes don't lock more than needed
+    #[cfg(test)]
+    write_locks: core::sync::atomic::AtomicUsize,
+}
+
+impl RamfsFile {
+    fn new(name: PathBuf, parent: Weak<Dir>, mode: Mode) -> Self {
+        RamfsFile {
+            name,
+            data: RwLock::new(Vec::new()),
+            mode: AtomicU16::new(mode.bits()),
+            parent,
+            #[cfg(test)]
+            write_locks: core::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    fn data_mut(&self) -> spin::RwLockWriteGuard<'_, Vec<u8>> {
+        #[cfg(test)]
+        self.write_locks
+            .fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        self.data.write()
+    }
 }
 
 struct Dir {
     name: PathBuf,
     entries: RwLock<Vec<RamfsDirEntry>>,
+    mode: AtomicU16,
     // perhaps we'll use this in the future for Dir::Delete
     #[allow(unused)]
     parent: Weak<Dir>,
 }
 
 impl Dir {
-    // find a directory relative to a path
-    fn find_dir(&self, path: &Path) -> Option<Arc<Dir>> {
-        let entries: spin::RwLockReadGuard<'_, Vec<RamfsDirEntry>> = self.entries.read();
-        for entry in entries.iter() {
-            if let Some((top, rest)) = path.split_from_top() {
-                // we have a top and a a rest, recursively search
-                if let RamfsDirEntry::Dir(dir) = entry {
-                    if dir.name.as_path() == top {
-                        return dir.find_dir(rest);
-                    }
-                }
-            } else {
-                // we're left with just the file name, dir name
-                if let RamfsDirEntry::Dir(dir) = entry {
-                    if dir.name.as_path() == path {
-                        return Some(dir.clone());
-                    }
-                }
-            }
-        }
-        None
-    }
-    fn find_file(&self, path: &Path) -> Option<RamfsFileHandle> {
-        let entries: spin::RwLockReadGuard<'_, Vec<RamfsDirEntry>> = self.entries.read();
-        for entry in entries.iter() {
-            if let Some((top, rest)) = path.split_from_top() {
-                // we have a top and a a rest, recursively search
-                if let RamfsDirEntry::Dir(dir) = entry {
-                    if dir.name.as_path() == top {
-                        return dir.find_file(rest);
-                    }
-                }
-            } else {
-                // we're left with just the file name, we'll check if it can be found in the current directory
-                if let RamfsDirEntry::File(file) = entry {
-                    if file.name.as_path() == path {
-                        return Some(RamfsFileHandle::new(file.clone()));
-                    }
-                }
-            }
+    /// Fail with VfsError::PermissionDenied if entries can't be created or deleted in this directory
+    fn check_writable(&self) -> Result<()> {
+        if Mode::from_bits_truncate(self.mode.load(Ordering::Relaxed)).contains(Mode::WRITE) {
+            Ok(())
+        } else {
+            Err(VfsError::PermissionDenied)
         }
-        None
+    }
+
+    // find a directory relative to a path, the empty path being this directory
+    fn find_dir(self: &Arc<Self>, path: &Path) -> Option<Arc<Dir>> {
+        path.components()
+            .try_fold(self.clone(), |dir, name| dir.child_dir(name))
+    }
+    fn find_file(self: &Arc<Self>, path: &Path) -> Option<Arc<RamfsFile>> {
+        let mut components = path.components();
+        let name = components.next_back()?;
+        let dir = components.try_fold(self.clone(), |dir, name| dir.child_dir(name))?;
+        dir.entries.read().iter().find_map(|entry| match entry {
+            RamfsDirEntry::File(file) if file.name.as_path() == name => Some(file.clone()),
+            _ => None,
+        })
+    }
+    fn child_dir(&self, name: &Path) -> Option<Arc<Dir>> {
+        self.entries.read().iter().find_map(|entry| match entry {
+            RamfsDirEntry::Dir(dir) if dir.name.as_path() == name => Some(dir.clone()),
+            _ => None,
+        })
     }
 }
 
@@ -108,6 +136,13 @@ impl RamfsDirEntry {
             RamfsDirEntry::File(_) => FileType::File,
         }
     }
+
+    fn mode(&self) -> &AtomicU16 {
+        match self {
+            RamfsDirEntry::Dir(dir) => &dir.mode,
+            RamfsDirEntry::File(file) => &file.mode,
+        }
+    }
 }
 pub struct Ramfs {
     root: Arc<Dir>,
@@ -118,14 +153,51 @@ impl Ramfs {
         let root: Arc<Dir> = Arc::new_cyclic(|this| Dir {
             name: PathBuf::new("/"),
             entries: RwLock::new(Vec::new()),
+            // the root always stays writable, see Ramfs::set_mode
+            mode: AtomicU16::new(Mode::default().bits()),
             parent: this.clone(),
         });
         Ramfs { root }
     }
+
+    fn find_file(&self, path: &Path) -> Result<Arc<RamfsFile>> {
+        if !path.has_root() {
+            return Err(VfsError::PathIsNotAbsolute);
+        }
+        if let Some((_root, rest)) = path.split_from_top() {
+            self.root
+                .find_file(rest)
+                .ok_or_else(|| self.open_error(path, FileType::File))
+        } else {
+            Err(VfsError::PathDoesNotHaveAFilename)
+        }
+    }
+
+    /// Why opening path as the wanted type failed: it's of the other type, one of its parents is a file,
+    /// or it just doesn't exist.
+    fn open_error(&self, path: &Path, wanted: FileType) -> VfsError {
+        match (self.file_type(path), wanted) {
+            (Ok(FileType::Directory), FileType::File) => return VfsError::IsADirectory,
+            (Ok(FileType::File), FileType::Directory) => return VfsError::NotADirectory,
+            _ => {}
+        }
+        let mut parent = path.parent();
+        // the parent of the root is the root itself
+        while let Some(dir) = parent
+            && !dir.is_root()
+        {
+            if self.file_type(dir) == Ok(FileType::File) {
+                return VfsError::NotADirectory;
+            }
+            parent = dir.parent();
+        }
+        VfsError::PathDoesNotExist
+    }
 }
 
 impl File for RamfsFileHandle {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.check_access(Mode::READ)?;
         let mut read = 0;
         for (ptr, byte) in buf
             .iter_mut()
@@ -138,14 +210,42 @@ impl File for RamfsFileHandle {
         Ok(read)
     }
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let mut wrote = 0;
-        let mut vec: spin::RwLockWriteGuard<Vec<u8>> = self.inner.data.write();
-        for byte in buf {
-            vec.insert(self.pos + wrote, *byte);
-            wrote += 1;
+        self.check_access(Mode::WRITE)?;
+        let mut data = self.inner.data_mut();
+        if self.pos > data.len() {
+            // writing past the end leaves a hole of zeroes
+            data.resize(self.pos, 0);
         }
-        self.pos += wrote;
-        Ok(wrote)
+        // overwrite whatever is after pos, and extend with the rest
+        let overwrite = buf.len().min(data.len() - self.pos);
+        data[self.pos..self.pos + overwrite].copy_from_slice(&buf[..overwrite]);
+        data.extend_from_slice(&buf[overwrite..]);
+        self.pos += buf.len();
+        Ok(buf.len())
+    }
+    fn seek(&mut self, from: SeekFrom) -> Result<usize> {
+        self.pos = from.resolve(self.pos, self.inner.data.read().len());
+        Ok(self.pos)
+    }
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.check_access(Mode::WRITE)?;
+        self.inner.data_mut().resize(len, 0);
+        self.pos = self.pos.min(len);
+        Ok(())
+    }
+    fn mmap(&self) -> Result<FileMapping<'_>> {
+        self.check_access(Mode::READ)?;
+        // the read lock keeps writes from reallocating the data under the mapping
+        Ok(FileMapping::new(RamfsMapping(self.inner.data.read())))
+    }
+}
+
+struct RamfsMapping<'a>(spin::RwLockReadGuard<'a, Vec<u8>>);
+
+impl Deref for RamfsMapping<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
     }
 }
 
@@ -181,21 +281,30 @@ impl FileSystem for Ramfs {
             }
         }
     }
+    /// Open a file with all the access its mode allows
     fn open_file(&self, path: &Path) -> Result<Self::File> {
-        if !path.has_root() {
-            return Err(VfsError::PathIsNotAbsolute);
-        }
-        if let Some((_root, rest)) = path.split_from_top() {
-            match self.root.find_file(rest) {
-                Some(file) => Ok(file),
-                None => Err(VfsError::PathDoesNotExist),
-            }
-        } else {
-            Err(VfsError::PathDoesNotHaveAFilename)
+        let file = self.find_file(path)?;
+        let mode = Mode::from_bits_truncate(file.mode.load(Ordering::Relaxed));
+        Ok(RamfsFileHandle::new(file, mode))
+    }
+
+    fn open_file_with(&self, path: &Path, access: Mode) -> Result<Self::File> {
+        let file = self.find_file(path)?;
+        let mode = Mode::from_bits_truncate(file.mode.load(Ordering::Relaxed));
+        if !mode.contains(access) {
+            return Err(VfsError::PermissionDenied);
         }
+        Ok(RamfsFileHandle::new(file, access))
     }
+
     // create a file from an absolute path (path with root)
     fn create_file(&self, path: &Path) -> Result<Self::File> {
+        self.create_file_with(path, Mode::default())
+    }
+
+    /// Create a file with the given mode. Like on unix, the returned handle can read and write it regardless of the mode,
+    /// so that a read only file can still get its initial contents.
+    fn create_file_with(&self, path: &Path, mode: Mode) -> Result<Self::File> {
         if !path.has_root() {
             return Err(VfsError::PathIsNotAbsolute);
         }
@@ -210,14 +319,21 @@ impl FileSystem for Ramfs {
             };
             dir
         };
+        dir.check_writable()?;
 
-        let file = Arc::new(RamfsFile {
-            name: PathBuf::from(path.filename().unwrap()),
-            data: RwLock::new(Vec::new()),
-            parent: Arc::downgrade(&dir),
-        });
-        dir.entries.write().push(RamfsDirEntry::File(file.clone()));
-        Ok(RamfsFileHandle::new(file))
+        let name = path.filename().unwrap();
+        // checked under the same lock as the push, so that two creations can't both succeed
+        let mut entries = dir.entries.write();
+        if entries.iter().any(|e| e.name() == name) {
+            return Err(VfsError::PathAlreadyExists);
+        }
+        let file = Arc::new(RamfsFile::new(
+            PathBuf::from(name),
+            Arc::downgrade(&dir),
+            mode,
+        ));
+        entries.push(RamfsDirEntry::File(file.clone()));
+        Ok(RamfsFileHandle::new(file, Mode::READ | Mode::WRITE))
     }
 
     fn create_dir(&self, path: &Path) -> Result<()> {
@@ -236,12 +352,19 @@ impl FileSystem for Ramfs {
             };
             dir
         };
+        dir.check_writable()?;
+        let name = path.filename().unwrap();
+        let mut entries = dir.entries.write();
+        if entries.iter().any(|e| e.name() == name) {
+            return Err(VfsError::PathAlreadyExists);
+        }
         let new_dir = Arc::new(Dir {
-            name: PathBuf::from(path.filename().unwrap()),
+            name: PathBuf::from(name),
             entries: RwLock::new(Vec::new()),
+            mode: AtomicU16::new(Mode::default().bits()),
             parent: Arc::downgrade(&dir),
         });
-        dir.entries.write().push(RamfsDirEntry::Dir(new_dir));
+        entries.push(RamfsDirEntry::Dir(new_dir));
         Ok(())
     }
 
@@ -267,12 +390,44 @@ impl FileSystem for Ramfs {
                 parent_dir
             };
 
+            parent_dir.check_writable()?;
             let name = path.filename().unwrap();
             parent_dir.entries.write().retain(|e| e.name() != name);
             Ok(())
         }
     }
 
+    /// Change the mode of a file or a directory.
+    /// The root can't be changed, so that the filesystem can always be set up.
+    fn set_mode(&self, path: &Path, mode: Mode) -> Result<()> {
+        if !path.has_root() {
+            return Err(VfsError::PathIsNotAbsolute);
+        }
+        if path.is_root() {
+            return Err(VfsError::PermissionDenied);
+        }
+        let parent = path.parent().unwrap();
+        let parent_dir = if parent.is_root() {
+            self.root.clone()
+        } else {
+            let Some(parent_dir) = self
+                .root
+                .find_dir(parent.relative_to(Path::root()).unwrap())
+            else {
+                return Err(VfsError::PathDoesNotExist);
+            };
+            parent_dir
+        };
+        let name = path.filename().unwrap();
+        match parent_dir.entries.read().iter().find(|e| e.name() == name) {
+            Some(entry) => {
+                entry.mode().store(mode.bits(), Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(VfsError::PathDoesNotExist),
+        }
+    }
+
     fn open_dir(&self, path: &Path) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
         if !path.has_root() {
             return Err(VfsError::PathIsNotAbsolute);
@@ -281,20 +436,44 @@ impl FileSystem for Ramfs {
             self.root.clone()
         } else {
             let Some(dir) = self.root.find_dir(path.relative_to(Path::root()).unwrap()) else {
-                return Err(VfsError::PathDoesNotExist);
+                return Err(self.open_error(path, FileType::Directory));
             };
             dir
         };
-        let entries = dir
-            .entries
-            .read()
-            .iter()
-            .map(|e| DirEntry {
-                path: PathBuf::from(path) + e.name(),
-                file_type: e.file_type(),
-            })
-            .collect::<Vec<DirEntry>>();
-        Ok(Box::new(entries.into_iter()))
+        Ok(Box::new(RamfsDirIter {
+            dir,
+            path: PathBuf::from(path),
+            idx: 0,
+        }))
+    }
+}
+
+/// Lists a directory lazily, only holding the read lock while getting the next entry.
+/// The listing is live rather than a snapshot: entries created while iterating are yielded as well,
+/// but deleting an entry shifts the ones after it, so the entry after it may be skipped.
+struct RamfsDirIter {
+    dir: Arc<Dir>,
+    /// absolute path of dir
+    path: PathBuf,
+    idx: usize,
+}
+
+impl Iterator for RamfsDirIter {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entries = self.dir.entries.read();
+        let entry = entries.get(self.idx)?;
+        self.idx += 1;
+        let path = if self.path.is_root() {
+            String::from(self.path.as_str())
+        } else {
+            self.path.as_str().to_string() + "/"
+        };
+        Some(DirEntry {
+            path: PathBuf::from(path) + entry.name(),
+            file_type: entry.file_type(),
+        })
     }
 }
 
@@ -370,4 +549,278 @@ mod test {
         ramfs.create_dir(dir).unwrap();
         assert_eq!(ramfs.file_type(dir), Ok(FileType::Directory));
     }
+
+    #[test_case]
+    fn truncate() {
+        let ramfs = Ramfs::new();
+        let mut file = ramfs.create_file(Path::new("/t")).unwrap();
+        file.write(b"0123456789").unwrap();
+        file.truncate(4).unwrap();
+        // the position was past the new end
+        assert_eq!(file.seek(SeekFrom::Current(0)), Ok(4));
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0xff; 10];
+        assert_eq!(file.read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"0123");
+
+        // growing fills with zeroes
+        file.truncate(6).unwrap();
+        assert_eq!(*file.inner.data.read(), b"0123\0\0");
+        file.truncate(0).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(0)), Ok(0));
+
+        let mut read_only = ramfs.open_file_with(Path::new("/t"), Mode::READ).unwrap();
+        assert_eq!(read_only.truncate(0), Err(VfsError::PermissionDenied));
+    }
+
+    #[test_case]
+    fn duplicate_creation() {
+        let ramfs = Ramfs::new();
+        let file = Path::new("/foo.txt");
+        ramfs.create_file(file).unwrap().write(b"data").unwrap();
+        assert_eq!(ramfs.create_file(file), Err(VfsError::PathAlreadyExists));
+        assert_eq!(ramfs.create_dir(file), Err(VfsError::PathAlreadyExists));
+        // the original is untouched
+        let mut buf = [0; 4];
+        assert_eq!(ramfs.open_file(file).unwrap().read(&mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+
+        let dir = Path::new("/dir");
+        ramfs.create_dir(dir).unwrap();
+        assert_eq!(ramfs.create_dir(dir), Err(VfsError::PathAlreadyExists));
+        assert_eq!(ramfs.create_file(dir), Err(VfsError::PathAlreadyExists));
+        assert_eq!(ramfs.open_dir(Path::root()).unwrap().count(), 2);
+    }
+
+    #[test_case]
+    fn create_dir_all() {
+        let ramfs = Ramfs::new();
+        ramfs.create_dir_all(Path::new("/a/b/c")).unwrap();
+        for dir in ["/a", "/a/b", "/a/b/c"] {
+            assert_eq!(ramfs.file_type(Path::new(dir)), Ok(FileType::Directory));
+        }
+        // some of it exists already
+        ramfs.create_dir_all(Path::new("/a/b/d/")).unwrap();
+        assert_eq!(
+            ramfs.file_type(Path::new("/a/b/d")),
+            Ok(FileType::Directory)
+        );
+        ramfs.create_dir_all(Path::new("/a/b/c")).unwrap();
+        ramfs.create_dir_all(Path::root()).unwrap();
+        // without creating them again
+        assert_eq!(ramfs.open_dir(Path::new("/a")).unwrap().count(), 1);
+        assert_eq!(ramfs.open_dir(Path::new("/a/b")).unwrap().count(), 2);
+
+        assert_eq!(
+            ramfs.create_dir_all(Path::new("a/b")),
+            Err(VfsError::PathIsNotAbsolute)
+        );
+        ramfs.create_file(Path::new("/a/file")).unwrap();
+        assert_eq!(
+            ramfs.create_dir_all(Path::new("/a/file/e")),
+            Err(VfsError::NotADirectory)
+        );
+    }
+
+    #[test_case]
+    fn coalesced_writes() {
+        use core::sync::atomic::Ordering;
+        let ramfs = Ramfs::new();
+        let mut file = ramfs.create_file(Path::new("/big")).unwrap();
+        let buf = (0..64 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+        assert_eq!(file.write(&buf).unwrap(), buf.len());
+        assert_eq!(file.inner.write_locks.load(Ordering::Relaxed), 1);
+        assert_eq!(*file.inner.data.read(), buf);
+
+        // straddle the end: overwrite the last 2 bytes and extend by 2
+        file.pos = buf.len() - 2;
+        file.write(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(file.inner.write_locks.load(Ordering::Relaxed), 2);
+        let data = file.inner.data.read();
+        assert_eq!(data.len(), buf.len() + 2);
+        assert_eq!(data[..buf.len() - 2], buf[..buf.len() - 2]);
+        assert_eq!(data[buf.len() - 2..], [1, 2, 3, 4]);
+    }
+
+    #[test_case]
+    fn overwrite_in_place() {
+        let ramfs = Ramfs::new();
+        let mut file = ramfs.create_file(Path::new("/a")).unwrap();
+        file.write(b"AAAA").unwrap();
+        assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+        assert_eq!(file.write(b"BB").unwrap(), 2);
+        assert_eq!(file.pos, 2);
+        assert_eq!(*file.inner.data.read(), b"BBAA");
+    }
+
+    #[test_case]
+    fn seek() {
+        let ramfs = Ramfs::new();
+        let mut file = ramfs.create_file(Path::new("/seek")).unwrap();
+        file.write(b"0123456789").unwrap();
+        let mut buf = [0; 3];
+
+        assert_eq!(file.seek(SeekFrom::Start(2)), Ok(2));
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"234");
+        assert_eq!(file.seek(SeekFrom::Current(-4)), Ok(1));
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"123");
+        assert_eq!(file.seek(SeekFrom::Current(2)), Ok(6));
+        assert_eq!(file.seek(SeekFrom::End(-1)), Ok(9));
+        assert_eq!(file.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], b'9');
+
+        // clamped to the file
+        assert_eq!(file.seek(SeekFrom::End(5)), Ok(10));
+        assert_eq!(file.read(&mut buf), Ok(0));
+        assert_eq!(file.seek(SeekFrom::Start(100)), Ok(10));
+        assert_eq!(file.read(&mut buf), Ok(0));
+        assert_eq!(file.seek(SeekFrom::Current(-100)), Ok(0));
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"012");
+    }
+
+    #[test_case]
+    fn streaming_open_dir() {
+        use crate::alloc::format;
+        use crate::memory::allocator::allocation_count;
+        let ramfs = Ramfs::new();
+        ramfs.create_dir(Path::new("/dir")).unwrap();
+        for i in 0..100 {
+            ramfs
+                .create_file(PathBuf::from(format!("/dir/file{}", i)).as_path())
+                .unwrap();
+        }
+        let before = allocation_count();
+        let entries = ramfs.open_dir(Path::new("/dir")).unwrap();
+        // the iterator and its copy of the path, but nothing per entry
+        assert!(allocation_count() - before <= 2);
+
+        let mut count = 0;
+        for (i, entry) in entries.enumerate() {
+            assert_eq!(entry.path.as_str(), format!("/dir/file{}", i));
+            assert_eq!(entry.file_type, FileType::File);
+            count += 1;
+        }
+        assert_eq!(count, 100);
+
+        // root entries don't get a double slash
+        let root_entries = ramfs.open_dir(Path::root()).unwrap().collect::<Vec<_>>();
+        assert_eq!(root_entries.len(), 1);
+        assert_eq!(root_entries[0].path.as_str(), "/dir");
+    }
+
+    #[test_case]
+    fn mmap_without_copying() {
+        let ramfs = Ramfs::new();
+        let mut file = ramfs.create_file(Path::new("/image")).unwrap();
+        let wrote = b"\x7fELF and some more";
+        file.write_all(wrote).unwrap();
+        {
+            let mapping = file.mmap().unwrap();
+            assert_eq!(&*mapping, wrote);
+            // the very same memory
+            assert_eq!(mapping.as_ptr(), file.inner.data.read().as_ptr());
+            // writes have to wait for the mapping
+            assert!(file.inner.data.try_write().is_none());
+        }
+        assert!(file.inner.data.try_write().is_some());
+    }
+
+    #[test_case]
+    fn wrong_file_type_errors() {
+        let ramfs = Ramfs::new();
+        ramfs.create_dir(Path::new("/dir")).unwrap();
+        ramfs.create_file(Path::new("/dir/file")).unwrap();
+        assert_eq!(
+            ramfs.open_file(Path::new("/dir")),
+            Err(VfsError::IsADirectory)
+        );
+        let Err(e) = ramfs.open_dir(Path::new("/dir/file")) else {
+            panic!("opened a file as a directory")
+        };
+        assert_eq!(e, VfsError::NotADirectory);
+
+        // a path inside a file
+        assert_eq!(
+            ramfs.open_file(Path::new("/dir/file/inner")),
+            Err(VfsError::NotADirectory)
+        );
+        let Err(e) = ramfs.open_dir(Path::new("/dir/file/inner/deeper")) else {
+            panic!("opened a directory inside a file")
+        };
+        assert_eq!(e, VfsError::NotADirectory);
+
+        // missing paths are still missing
+        assert_eq!(
+            ramfs.open_file(Path::new("/dir/nothing")),
+            Err(VfsError::PathDoesNotExist)
+        );
+        let Err(e) = ramfs.open_dir(Path::new("/nothing/deeper")) else {
+            panic!("opened a missing directory")
+        };
+        assert_eq!(e, VfsError::PathDoesNotExist);
+    }
+
+    #[test_case]
+    fn permissions() {
+        let ramfs = Ramfs::new();
+        let path = Path::new("/readonly");
+        {
+            // the creator can still fill it in
+            let mut file = ramfs.create_file_with(path, Mode::READ).unwrap();
+            file.write_all(b"data").unwrap();
+        }
+        assert_eq!(
+            ramfs.open_file_with(path, Mode::WRITE),
+            Err(VfsError::PermissionDenied)
+        );
+        assert_eq!(
+            ramfs.open_file_with(path, Mode::READ | Mode::WRITE),
+            Err(VfsError::PermissionDenied)
+        );
+        let mut file = ramfs.open_file_with(path, Mode::READ).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(file.read(&mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+        // a plain open gets what the mode allows
+        let mut file = ramfs.open_file(path).unwrap();
+        assert_eq!(file.write(b"more"), Err(VfsError::PermissionDenied));
+
+        // a write only handle can't read
+        ramfs.set_mode(path, Mode::WRITE).unwrap();
+        let mut file = ramfs.open_file_with(path, Mode::WRITE).unwrap();
+        assert_eq!(file.read(&mut buf), Err(VfsError::PermissionDenied));
+        assert!(file.mmap().is_err());
+
+        // entries can't be added to or removed from a read only directory
+        ramfs.create_dir(Path::new("/dir")).unwrap();
+        ramfs.create_file(Path::new("/dir/file")).unwrap();
+        ramfs.set_mode(Path::new("/dir"), Mode::READ).unwrap();
+        assert_eq!(
+            ramfs.create_file(Path::new("/dir/other")),
+            Err(VfsError::PermissionDenied)
+        );
+        assert_eq!(
+            ramfs.create_dir(Path::new("/dir/other")),
+            Err(VfsError::PermissionDenied)
+        );
+        assert_eq!(
+            ramfs.delete(Path::new("/dir/file")),
+            Err(VfsError::PermissionDenied)
+        );
+        assert!(ramfs.open_file(Path::new("/dir/file")).is_ok());
+
+        // the root stays writable
+        assert_eq!(
+            ramfs.set_mode(Path::root(), Mode::READ),
+            Err(VfsError::PermissionDenied)
+        );
+        ramfs.create_file(Path::new("/another")).unwrap();
+        assert_eq!(
+            ramfs.set_mode(Path::new("/missing"), Mode::READ),
+            Err(VfsError::PathDoesNotExist)
+        );
+    }
 }
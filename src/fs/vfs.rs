@@ -1,6 +1,7 @@
 use super::path::Path;
 use crate::{alloc::boxed::Box, fs::path::PathBuf};
 use alloc::vec::Vec;
+use core::ops::Deref;
 use spin::RwLock;
 pub type Result<T> = core::result::Result<T, VfsError>;
 
@@ -22,10 +23,120 @@ pub enum VfsError {
     PathIsNotAbsolute,
     /// The given path does not have a filename. Should be thrown in FileSystem::open_file and FileSystem::create_file.
     PathDoesNotHaveAFilename,
+    /// The file doesn't support this operation. Thrown by the default File::mmap.
+    Unsupported,
+    /// The path is a directory, but a file was expected. Should be thrown in FileSystem::open_file.
+    IsADirectory,
+    /// The path, or one of its parents, is a file, but a directory was expected.
+    /// Should be thrown in FileSystem::open_dir, and in FileSystem::open_file when a parent is a file.
+    NotADirectory,
+    /// The mode of the file or directory doesn't allow the operation, see Mode.
+    PermissionDenied,
 }
+
+bitflags::bitflags! {
+    /// Permissions of a file or a directory. There's a single owner for now, so these are the owner's bits of a unix mode.
+    /// When opening a file, the same bits describe the requested access.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Mode: u16 {
+        const READ = 0o400;
+        const WRITE = 0o200;
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::READ | Mode::WRITE
+    }
+}
+
+/// Where File::seek moves to, relative to the start, the current position or the end of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+impl SeekFrom {
+    /// The absolute position this points to in a file of len bytes whose position is pos,
+    /// clamped to the file, i.e. between 0 and len.
+    pub fn resolve(self, pos: usize, len: usize) -> usize {
+        let target = match self {
+            SeekFrom::Start(offset) => return offset.min(len),
+            SeekFrom::Current(offset) => pos.saturating_add_signed(offset),
+            SeekFrom::End(offset) => len.saturating_add_signed(offset),
+        };
+        target.min(len)
+    }
+}
+
 pub trait File {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
+    /// Move the position the next read or write starts at. Returns the new position, from the start of the file.
+    fn seek(&mut self, from: SeekFrom) -> Result<usize>;
+    /// Resize the file to len bytes, dropping the bytes after it or adding zeroes.
+    /// The position is clamped to the new length.
+    fn truncate(&mut self, len: usize) -> Result<()>;
+
+    /// Write the whole buffer, calling File::write until everything was written.
+    /// Fails with VfsError::WriteFailed if a write makes no progress.
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(VfsError::WriteFailed),
+                wrote => buf = &buf[wrote..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the whole contents of the file without copying them. The file can't be written to while the mapping lives.
+    /// Not every file can do this, in which case it fails with VfsError::Unsupported.
+    fn mmap(&self) -> Result<FileMapping<'_>> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+/// A read only view of the contents of a file, see File::mmap.
+/// Holds whatever keeps the contents in place (i.e. a read lock) until it's dropped.
+pub struct FileMapping<'a> {
+    data: Box<dyn Deref<Target = [u8]> + 'a>,
+}
+
+impl<'a> FileMapping<'a> {
+    pub fn new(data: impl Deref<Target = [u8]> + 'a) -> Self {
+        FileMapping {
+            data: Box::new(data),
+        }
+    }
+}
+
+impl Deref for FileMapping<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Adapter for writing formatted text into a file, i.e. write!(FileWriter::new(&mut file), "{}", 5)
+pub struct FileWriter<'a, F: File + ?Sized> {
+    file: &'a mut F,
+}
+
+impl<'a, F: File + ?Sized> FileWriter<'a, F> {
+    pub fn new(file: &'a mut F) -> Self {
+        FileWriter { file }
+    }
+}
+
+impl<F: File + ?Sized> core::fmt::Write for FileWriter<'_, F> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.file
+            .write_all(s.as_bytes())
+            .map_err(|_| core::fmt::Error)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -51,24 +162,153 @@ pub trait FileSystem {
     fn exists(&self, path: &Path) -> bool {
         self.file_type(path).is_ok()
     }
+
+    /// Create the directory and every missing directory above it, like mkdir -p.
+    /// Directories which already exist are fine, but a file in the way fails with VfsError::NotADirectory.
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if !path.has_root() {
+            return Err(VfsError::PathIsNotAbsolute);
+        }
+        // i.e. "/a", "/a/b" and "/a/b/c" for "/a/b/c". The root always exists
+        let mut dir = PathBuf::from(Path::root());
+        for component in path.components() {
+            dir = dir.join(component);
+            let dir = dir.as_path();
+            match self.file_type(dir) {
+                Ok(FileType::Directory) => continue,
+                Ok(FileType::File) => return Err(VfsError::NotADirectory),
+                Err(_) => {}
+            }
+            match self.create_dir(dir) {
+                // someone else created it in the meantime
+                Ok(()) | Err(VfsError::PathAlreadyExists) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Open a file for the given access, failing with VfsError::PermissionDenied if its mode doesn't allow it.
+    /// Filesystems without permissions allow everything.
+    fn open_file_with(&self, path: &Path, _access: Mode) -> Result<Self::File> {
+        self.open_file(path)
+    }
+
+    /// Create a file with the given mode.
+    /// Filesystems without permissions only support the default mode, and fail with VfsError::Unsupported otherwise.
+    fn create_file_with(&self, path: &Path, mode: Mode) -> Result<Self::File> {
+        if mode != Mode::default() {
+            return Err(VfsError::Unsupported);
+        }
+        self.create_file(path)
+    }
+
+    /// Change the mode of a file or a directory
+    fn set_mode(&self, _path: &Path, _mode: Mode) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+/// A boxed filesystem, shareable between cpus so that it can be mounted in the global vfs
+pub type DynFileSystem = Box<dyn FileSystem<File = Box<dyn File>> + Send + Sync>;
+
+/// Wraps a FileSystem so that its files are boxed, i.e. so that it can be used as a DynFileSystem.
+pub struct BoxedFiles<T>(pub T);
+
+impl<T: FileSystem> BoxedFiles<T>
+where
+    T::File: File + 'static,
+    T: Send + Sync + 'static,
+{
+    pub fn new_dyn(fs: T) -> DynFileSystem {
+        Box::new(BoxedFiles(fs))
+    }
+}
+
+impl<T: FileSystem> FileSystem for BoxedFiles<T>
+where
+    T::File: File + 'static,
+{
+    type File = Box<dyn File>;
+
+    fn open_file(&self, path: &Path) -> Result<Self::File> {
+        self.0.open_file(path).map(|f| Box::new(f) as Box<dyn File>)
+    }
+    fn open_dir(&self, path: &Path) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
+        self.0.open_dir(path)
+    }
+    fn file_type(&self, path: &Path) -> Result<FileType> {
+        self.0.file_type(path)
+    }
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.0.delete(path)
+    }
+    fn create_file(&self, path: &Path) -> Result<Self::File> {
+        self.0
+            .create_file(path)
+            .map(|f| Box::new(f) as Box<dyn File>)
+    }
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        self.0.create_dir(path)
+    }
+    fn open_file_with(&self, path: &Path, access: Mode) -> Result<Self::File> {
+        self.0
+            .open_file_with(path, access)
+            .map(|f| Box::new(f) as Box<dyn File>)
+    }
+    fn create_file_with(&self, path: &Path, mode: Mode) -> Result<Self::File> {
+        self.0
+            .create_file_with(path, mode)
+            .map(|f| Box::new(f) as Box<dyn File>)
+    }
+    fn set_mode(&self, path: &Path, mode: Mode) -> Result<()> {
+        self.0.set_mode(path, mode)
+    }
+}
+
+impl<T: File + ?Sized> File for Box<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+    fn seek(&mut self, from: SeekFrom) -> Result<usize> {
+        (**self).seek(from)
+    }
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        (**self).truncate(len)
+    }
+    fn mmap(&self) -> Result<FileMapping<'_>> {
+        (**self).mmap()
+    }
 }
 
-pub type DynFileSystem = Box<dyn FileSystem<File = Box<dyn File>>>;
 struct Mount {
     path: PathBuf,
-    filesystem: Box<dyn FileSystem<File = Box<dyn File>>>,
+    filesystem: DynFileSystem,
 }
 
 pub struct Vfs {
-    root: Box<dyn FileSystem<File = Box<dyn File>>>,
+    root: DynFileSystem,
     mounts: RwLock<Vec<Mount>>,
 }
 
 impl Vfs {
+    pub fn new(root: DynFileSystem) -> Self {
+        Vfs {
+            root,
+            mounts: RwLock::new(Vec::new()),
+        }
+    }
+
     pub fn mount(&self, fs: DynFileSystem, path: &Path) -> Result<()> {
         if !path.has_root() {
             return Err(VfsError::PathIsNotAbsolute);
         }
+        // so that open_dir can match it against normalized paths
+        let path = path.normalize();
+        let path = path.as_path();
         if self.root.exists(path) {
             return Err(VfsError::PathAlreadyExists);
         }
@@ -85,13 +325,53 @@ impl Vfs {
         }
     }
 
-    fn open_file_in_mounts(&self, path: &Path) -> Result<Box<dyn File>> {
-        for mount in self.mounts.read().iter() {
-            if let Some(path) = path.relative_to(&mount.path) {
-                return mount.filesystem.open_file(path);
-            }
+    /// Open a file in the filesystem it's in. With access, see FileSystem::open_file_with, otherwise FileSystem::open_file.
+    fn open(&self, path: &Path, access: Option<Mode>) -> Result<Box<dyn File>> {
+        if !path.has_root() {
+            return Err(VfsError::PathIsNotAbsolute);
         }
-        Err(VfsError::PathDoesNotExist)
+        let in_root = match access {
+            Some(access) => self.root.open_file_with(path, access),
+            None => self.root.open_file(path),
+        };
+        in_root.or_else(|e| match self.open_file_in_mounts(path, access) {
+            // keep the more accurate error of the root, i.e. VfsError::IsADirectory
+            Err(VfsError::PathDoesNotExist) => Err(e),
+            result => result,
+        })
+    }
+
+    fn open_file_in_mounts(&self, path: &Path, access: Option<Mode>) -> Result<Box<dyn File>> {
+        let mounts = self.mounts.read();
+        // the innermost mount wins, i.e. /foo/bar over /foo
+        let Some(mount) = mounts
+            .iter()
+            .filter(|m| path.starts_with(&m.path))
+            .max_by_key(|m| m.path.as_str().len())
+        else {
+            return Err(VfsError::PathDoesNotExist);
+        };
+        // filesystems expect absolute paths, relative to their own root
+        let path = PathBuf::from(Path::root()) + path.strip_prefix(&mount.path).unwrap();
+        match access {
+            Some(access) => mount.filesystem.open_file_with(&path, access),
+            None => mount.filesystem.open_file(&path),
+        }
+    }
+
+    /// List a directory inside a mount (or the mount's root), with paths as seen from the vfs.
+    fn open_dir_in_mount(
+        &self,
+        mount: &Mount,
+        path: &Path,
+    ) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
+        let inner_path = PathBuf::from(Path::root()) + path.strip_prefix(&mount.path).unwrap();
+        let entries = mount.filesystem.open_dir(&inner_path)?;
+        let mount_path = PathBuf::from(mount.path.as_path());
+        Ok(Box::new(entries.map(move |e| DirEntry {
+            path: PathBuf::from(mount_path.as_path()) + e.path.as_path(),
+            file_type: e.file_type,
+        })))
     }
 }
 
@@ -102,10 +382,11 @@ impl FileSystem for Vfs {
         todo!()
     }
     fn open_file(&self, path: &Path) -> Result<Self::File> {
-        if !path.has_root() {
-            return Err(VfsError::PathIsNotAbsolute);
-        }
-        self.root.open_file(path).or(self.open_file_in_mounts(path))
+        self.open(path, None)
+    }
+
+    fn open_file_with(&self, path: &Path, access: Mode) -> Result<Self::File> {
+        self.open(path, Some(access))
     }
 
     fn create_file(&self, _path: &Path) -> Result<Self::File> {
@@ -120,7 +401,128 @@ impl FileSystem for Vfs {
         todo!()
     }
 
-    fn open_dir(&self, _path: &Path) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
-        todo!()
+    /// List a directory, merging the entries of the filesystem it's in with the mounts directly inside it.
+    /// A mount shadows an entry of the same name.
+    fn open_dir(&self, path: &Path) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
+        if !path.has_root() {
+            return Err(VfsError::PathIsNotAbsolute);
+        }
+        // mounts are matched by their normalized paths, i.e. "/b/" lists the mount at "/b"
+        let path = path.normalize();
+        let path = path.as_path();
+        let mounts = self.mounts.read();
+        // the innermost mount containing the directory, if any, backs it
+        let backing = match mounts
+            .iter()
+            .filter(|m| path.starts_with(&m.path))
+            .max_by_key(|m| m.path.as_str().len())
+        {
+            Some(mount) => self.open_dir_in_mount(mount, path),
+            None => self.root.open_dir(path),
+        };
+        // the mounts are the only part which has to be copied, since the lock can't outlive this call
+        let child_mounts = mounts
+            .iter()
+            .filter(|m| m.path.parent() == Some(path))
+            .map(|m| DirEntry {
+                path: PathBuf::from(m.path.as_path()),
+                file_type: FileType::Directory,
+            })
+            .collect::<Vec<DirEntry>>();
+        let backing = match backing {
+            Ok(entries) => entries,
+            // the directory may exist only because there are mounts inside it
+            Err(VfsError::PathDoesNotExist) if !child_mounts.is_empty() => {
+                Box::new(core::iter::empty())
+            }
+            Err(e) => return Err(e),
+        };
+        let shadowed = child_mounts
+            .iter()
+            .map(|m| PathBuf::from(m.path.as_path()))
+            .collect::<Vec<PathBuf>>();
+        let merged = backing
+            .filter(move |e| !shadowed.iter().any(|m| m.filename() == e.path.filename()))
+            .chain(child_mounts);
+        Ok(Box::new(merged))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::ramfs::Ramfs;
+
+    fn names(vfs: &Vfs, path: &Path) -> Vec<PathBuf> {
+        let mut names = vfs
+            .open_dir(path)
+            .unwrap()
+            .map(|e| e.path)
+            .collect::<Vec<PathBuf>>();
+        names.sort();
+        names
+    }
+
+    #[test_case]
+    fn merged_open_dir() {
+        let root = Ramfs::new();
+        root.create_file(Path::new("/a")).unwrap();
+        let vfs = Vfs::new(BoxedFiles::new_dyn(root));
+        let mounted = Ramfs::new();
+        mounted.create_file(Path::new("/c")).unwrap();
+        vfs.mount(BoxedFiles::new_dyn(mounted), Path::new("/b"))
+            .unwrap();
+
+        assert_eq!(
+            names(&vfs, Path::root()),
+            [PathBuf::new("/a"), PathBuf::new("/b")]
+        );
+        // a mount exactly at the listed directory
+        assert_eq!(names(&vfs, Path::new("/b")), [PathBuf::new("/b/c")]);
+        // paths are normalized before they're matched against the mounts
+        assert_eq!(names(&vfs, Path::new("/b/")), [PathBuf::new("/b/c")]);
+        assert_eq!(names(&vfs, Path::new("/b/c/..")), [PathBuf::new("/b/c")]);
+        assert_eq!(
+            names(&vfs, Path::new("//")),
+            [PathBuf::new("/a"), PathBuf::new("/b")]
+        );
+        let trailing = Vfs::new(BoxedFiles::new_dyn(Ramfs::new()));
+        trailing
+            .mount(BoxedFiles::new_dyn(Ramfs::new()), Path::new("/d/"))
+            .unwrap();
+        assert_eq!(names(&trailing, Path::root()), [PathBuf::new("/d")]);
+    }
+
+    #[test_case]
+    fn formatted_file_writes() {
+        use core::fmt::Write;
+        let ramfs = Ramfs::new();
+        let path = Path::new("/status");
+        {
+            let mut file = ramfs.create_file(path).unwrap();
+            let mut writer = FileWriter::new(&mut file);
+            writeln!(writer, "cpus: {}", 4).unwrap();
+            write!(writer, "uptime: {}s", 12).unwrap();
+        }
+        let expected = b"cpus: 4\nuptime: 12s";
+        let mut buf = [0; 32];
+        let read = ramfs.open_file(path).unwrap().read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], expected);
+    }
+
+    #[test_case]
+    fn mount_shadows_entry() {
+        let root = Ramfs::new();
+        let vfs = Vfs::new(BoxedFiles::new_dyn(root));
+        vfs.mount(BoxedFiles::new_dyn(Ramfs::new()), Path::new("/b"))
+            .unwrap();
+        // can't create it through the vfs yet, so sneak it into the backing fs
+        vfs.root.create_file(Path::new("/b")).unwrap();
+        let entries = vfs
+            .open_dir(Path::root())
+            .unwrap()
+            .collect::<Vec<DirEntry>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file_type, FileType::Directory);
     }
 }
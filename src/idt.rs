@@ -5,9 +5,52 @@ use core::pin::Pin;
 
 use crate::arch_x86_64::{self, lidt};
 
-type InterruptHandlerFn = unsafe extern "C" fn() -> !;
+pub type InterruptHandlerFn = unsafe extern "C" fn() -> !;
 type TrapHandlerFn = unsafe extern "C" fn() -> !;
 
+/// Amount of registers pushed by push_scratch_registers!.
+/// Whatever the cpu pushed before them (i.e. the error code) is at [rsp + 8 * SCRATCH_REGISTER_COUNT].
+pub const SCRATCH_REGISTER_COUNT: usize = 9;
+
+/// Save the registers which are not saved by the C abi, shared by all the handler macros so that they don't drift apart.
+/// They're pushed in exactly the following order:
+/// rdi, rsi, rdx, rcx, rax, r8, r9, r10, r11
+/// where left = pushed first.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! push_scratch_registers {
+    () => {
+        "
+        push rdi;
+        push rsi;
+        push rdx;
+        push rcx;
+        push rax;
+        push r8;
+        push r9;
+        push r10;
+        push r11;"
+    };
+}
+
+/// Restore the registers saved by push_scratch_registers!
+#[doc(hidden)]
+#[macro_export]
+macro_rules! pop_scratch_registers {
+    () => {
+        "
+        pop r11;
+        pop r10;
+        pop r9;
+        pop r8;
+        pop rax;
+        pop rcx;
+        pop rdx;
+        pop rsi;
+        pop rdi;"
+    };
+}
+
 /// NOTE: ALLOCATIONS/ANY REASOURCE WHICH REQUIRES A LOCK IS NOT ALLOWED IN HERE EXCEPT A PANIC.
 #[macro_export]
 macro_rules! interrupt_handler_fn {
@@ -21,37 +64,16 @@ macro_rules! interrupt_handler_fn {
 
                 // and call the C function
                 naked_asm!(
-
+                    $crate::push_scratch_registers!(),
                     "
-                    // save the registers which are not saved by C abi
-                    push rdi;
-                    push rsi;
-                    push rdx;
-                    push rcx;
-                    push rax;
-                    push r8;
-                    push r9;
-                    push r10;
-                    push r11;
                     // c abi requires cld
                     cld;
-                    // c abi requires stack alignment of 16 bytes
-                    // we push 9, 8 bytes ptr, and the cpu aligns to 16 bytes without error code
-                    sub rsp, 8
+                    // c abi requires stack alignment of 16 bytes: the cpu aligned the stack before pushing
+                    // the 5 values of the frame, and we pushed 9, so it's aligned again
                     // call the actual handler
-                    call {};
-                    // restore the stack
-                    add rsp, 8
-                    pop r11;
-                    pop r10;
-                    pop r9;
-                    pop r8;
-                    pop rax;
-                    pop rcx;
-                    pop rdx;
-                    pop rsi;
-                    pop rdi;
-                    iretq;",
+                    call {};",
+                    $crate::pop_scratch_registers!(),
+                    "iretq;",
                     sym ignore
                 )
 
@@ -61,10 +83,7 @@ macro_rules! interrupt_handler_fn {
 }
 /// Create a new trap handler
 /// A trap may not return. If you wish to recover from a trap, do it by your own code.
-/// To assist with that, registers not preserved by the C abi are preserved. The1y're pushed to the stack in exactly the following order:
-/// rdi, rsi, rdx, rcx, rax, r8, r9, r10, r11
-/// where left = pushed first.
-/// Additionally, 8 bytes of junk are pushed to the stack after r11 for alignment purposes.
+/// To assist with that, registers not preserved by the C abi are preserved, see push_scratch_registers!
 /// NOTE: ALLOCATIONS/ANY REASOURCE WHICH REQUIRES A LOCK IS NOT ALLOWED IN HERE EXCEPT A PANIC.
 #[macro_export]
 macro_rules! trap_handler_fn {
@@ -77,23 +96,11 @@ macro_rules! trap_handler_fn {
             }
                 // and call the C function
                 naked_asm!(
-
+                    $crate::push_scratch_registers!(),
                     "
-                    // save the registers which are not saved by C abi
-                    push rdi;
-                    push rsi;
-                    push rdx;
-                    push rcx;
-                    push rax;
-                    push r8;
-                    push r9;
-                    push r10;
-                    push r11;
                     // c abi requires cld
                     cld;
-                    // c abi requires stack alignment of 16 bytes
-                    // we push 9, 8 bytes ptr, and the cpu aligns to 16 bytes without error code
-                    sub rsp, 8
+                    // aligned to 16 bytes, same as interrupt_handler_fn!
                     // call the actual handler
                     call {};",
                     sym ignore
@@ -128,9 +135,7 @@ macro_rules! insert_trap {
 
 /// Create a new trap handler function which also handles error codes.
 /// A trap may not return. If you wish to recover from a trap, do it by your own code.  
-/// To assist with that, registers not preserved by the C abi are preserved. They're pushed to the stack in exactly the following order:  
-/// rdi, rsi, rdx, rcx, rax, r8, r9, r10, r11  
-/// where left = pushed first.
+/// To assist with that, registers not preserved by the C abi are preserved, see push_scratch_registers!
 /// NOTE: ALLOCATIONS/ANY REASOURCE WHICH REQUIRES A LOCK IS NOT ALLOWED IN HERE EXCEPT A PANIC.
 #[macro_export]
 macro_rules! trap_handler_fn_with_error {
@@ -144,26 +149,136 @@ macro_rules! trap_handler_fn_with_error {
 
                 // and call the C function
                 naked_asm!(
-
+                    $crate::push_scratch_registers!(),
                     "
-                    // save the registers which are not saved by C abi
-                    push rdi;
-                    push rsi;
-                    push rdx;
-                    push rcx;
-                    push rax;
-                    push r8;
-                    push r9;
-                    push r10;
-                    push r11; 
                     // move the error code to the first arg
-                    mov rdi, [rsp + 8 * 9]
+                    mov rdi, [rsp + 8 * {scratch}]
                     // c abi requires cld
                     cld;
-                    // DUE TO THE ERROR CODE, THIS IS 16 BYTE ALIGNED: 9 * 8 + 8 = 5 * 16
+                    // the cpu aligned the stack to 16 bytes before pushing 5 + 1 values, and we pushed 9
+                    sub rsp, 8
                     // call the actual handler
-                    call {};",
-                    sym ignore
+                    call {handler};",
+                    scratch = const $crate::idt::SCRATCH_REGISTER_COUNT,
+                    handler = sym ignore,
+                )
+
+        }
+        wrapper
+    }};
+}
+
+/// What the cpu pushes when it enters a handler, right above the error code (if there's one).
+/// Returning from the handler resumes at rip, so changing it changes where we return to.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptStackFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Create a new interrupt handler which gets the interrupt stack frame, i.e. to report what it interrupted.
+/// Interrupts resume at frame.rip when the handler returns, which for an interrupt is the next instruction.
+/// Registers not preserved by the C abi are preserved, see push_scratch_registers!
+/// NOTE: ALLOCATIONS/ANY REASOURCE WHICH REQUIRES A LOCK IS NOT ALLOWED IN HERE EXCEPT A PANIC.
+#[macro_export]
+macro_rules! interrupt_handler_fn_with_frame {
+    (|$frame: ident| $func: block) => {{
+        use core::arch::naked_asm;
+        #[unsafe(naked)]
+        extern "C" fn wrapper() -> ! {
+            extern "C" fn ignore($frame: &mut $crate::idt::InterruptStackFrame) {
+                $func
+            }
+
+                naked_asm!(
+                    $crate::push_scratch_registers!(),
+                    "
+                    // the frame is right above the registers we pushed
+                    lea rdi, [rsp + 8 * {scratch}]
+                    // c abi requires cld
+                    cld;
+                    // aligned to 16 bytes, same as interrupt_handler_fn!
+                    call {handler};",
+                    $crate::pop_scratch_registers!(),
+                    "iretq;",
+                    scratch = const $crate::idt::SCRATCH_REGISTER_COUNT,
+                    handler = sym ignore,
+                )
+
+        }
+        wrapper
+    }};
+}
+
+/// Create a new trap handler which gets the interrupt stack frame, i.e. to report the faulting instruction.
+/// A trap may not return, see trap_handler_fn!
+/// NOTE: ALLOCATIONS/ANY REASOURCE WHICH REQUIRES A LOCK IS NOT ALLOWED IN HERE EXCEPT A PANIC.
+#[macro_export]
+macro_rules! trap_handler_fn_with_frame {
+    (|$frame: ident| $func: block) => {{
+        use core::arch::naked_asm;
+        #[unsafe(naked)]
+        extern "C" fn wrapper() -> ! {
+            extern "C" fn ignore($frame: &$crate::idt::InterruptStackFrame) -> ! {
+                $func
+            }
+
+                naked_asm!(
+                    $crate::push_scratch_registers!(),
+                    "
+                    // the frame is right above the registers we pushed
+                    lea rdi, [rsp + 8 * {scratch}]
+                    // c abi requires cld
+                    cld;
+                    // aligned to 16 bytes, same as interrupt_handler_fn!
+                    call {handler};",
+                    scratch = const $crate::idt::SCRATCH_REGISTER_COUNT,
+                    handler = sym ignore,
+                )
+
+        }
+        wrapper
+    }};
+}
+
+/// Create a handler for a fault which pushes an error code, which may return.
+/// The handler gets the error code and the interrupt stack frame. If it returns, the error code is popped
+/// and we return to frame.rip, which for a fault is the instruction which faulted, i.e. it's retried.
+/// Registers not preserved by the C abi are preserved, see push_scratch_registers!
+/// NOTE: ALLOCATIONS/ANY REASOURCE WHICH REQUIRES A LOCK IS NOT ALLOWED IN HERE EXCEPT A PANIC.
+#[macro_export]
+macro_rules! fault_handler_fn_with_error {
+    (|$frame: ident, $num: ident| $func: block) => {{
+        use core::arch::naked_asm;
+        #[unsafe(naked)]
+        extern "C" fn wrapper() -> ! {
+            extern "C" fn ignore($num: u64, $frame: &mut $crate::idt::InterruptStackFrame) {
+                $func
+            }
+
+                naked_asm!(
+                    $crate::push_scratch_registers!(),
+                    "
+                    // the error code is the first arg, and the frame above it the second
+                    mov rdi, [rsp + 8 * {scratch}]
+                    lea rsi, [rsp + 8 * {scratch} + 8]
+                    // c abi requires cld
+                    cld;
+                    // the cpu aligned the stack to 16 bytes before pushing 5 + 1 values, and we pushed 9
+                    sub rsp, 8
+                    call {handler};
+                    add rsp, 8",
+                    $crate::pop_scratch_registers!(),
+                    "
+                    // pop the error code, iretq expects the frame at the top
+                    add rsp, 8
+                    iretq;",
+                    scratch = const $crate::idt::SCRATCH_REGISTER_COUNT,
+                    handler = sym ignore,
                 )
 
         }
@@ -178,6 +293,8 @@ pub struct IdtEntry {
     entry_type: IdtEntryType,
     /// The kernel code segment. If IdtEntry::new is used in kernel context, you might want to simply use arch_x86_64::cs() for this value.
     gdt_kernel_cs: u16,
+    /// The IST entry of the TSS whose stack the handler runs on, 0 to stay on the current stack
+    ist: u8,
 }
 
 /// The type of entry. Trap and Interrupt have minor differences; read their documentation
@@ -250,16 +367,41 @@ impl Idt {
     pub fn insert(self: Pin<&mut Self>, index: usize, entry: IdtEntry) {
         unsafe { *self.get_unchecked_mut().raw.0.get_mut(index).unwrap() = entry.to_raw() }
     }
+
+    /// Insert entry at index, and return the entry which was there so that it can be put back with restore.
+    pub fn replace(self: Pin<&mut Self>, index: usize, entry: IdtEntry) -> SavedIdtEntry {
+        let saved = SavedIdtEntry(self.raw.0[index]);
+        self.insert(index, entry);
+        saved
+    }
+
+    /// Put back an entry returned by replace
+    pub fn restore(self: Pin<&mut Self>, index: usize, saved: SavedIdtEntry) {
+        unsafe { *self.get_unchecked_mut().raw.0.get_mut(index).unwrap() = saved.0 }
+    }
 }
 
+/// An entry which was replaced in the IDT, as the cpu saw it
+#[derive(Debug, Clone, Copy)]
+pub struct SavedIdtEntry(IdtEntryRaw);
+
 impl IdtEntry {
     pub fn new(entry_type: IdtEntryType, gdt_kernel_cs: u16) -> Self {
         Self {
             entry_type,
             gdt_kernel_cs,
+            ist: 0,
         }
     }
 
+    /// Run the handler on the stack of IST entry ist (1-7), i.e. for faults which may come from a broken stack.
+    /// The TSS of every cpu the IDT is loaded on must have it, see cpu::gdt.
+    pub fn with_ist(mut self, ist: u8) -> Self {
+        assert!(ist <= 7);
+        self.ist = ist;
+        self
+    }
+
     pub fn new_with_current_cs(entry_type: IdtEntryType) -> Self {
         Self::new(entry_type, arch_x86_64::cs())
     }
@@ -279,7 +421,7 @@ impl IdtEntry {
         let raw = IdtEntryRaw {
             fn_ptr_low,
             gdt_kernel_cs: self.gdt_kernel_cs,
-            options: options,
+            options: options | self.ist as u16,
             fn_ptr_mid,
             fn_ptr_high,
             reserved: 0,
@@ -310,3 +452,128 @@ pub struct IdtPtr {
 }
 
 unsafe impl Send for IdtPtr {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator};
+    use crate::{interrupts::SHARED_IDT, should_panic_with};
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    static HITS: AtomicU64 = AtomicU64::new(0);
+    static FRAME_RIP: AtomicU64 = AtomicU64::new(0);
+    static FRAME_CS: AtomicU64 = AtomicU64::new(0);
+    static STACK_ALIGNED: AtomicBool = AtomicBool::new(false);
+
+    #[test_case]
+    fn interrupt_handler_returns() {
+        SHARED_IDT.guard(|idt| {
+            let mut idt = idt.lock();
+            insert_interrupt!(
+                idt,
+                0x80,
+                interrupt_handler_fn!(|| {
+                    HITS.fetch_add(1, Ordering::Relaxed);
+                })
+            );
+            unsafe { idt.as_ref().load() };
+        });
+        let (rdi, rax, r11): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "int 0x80",
+                inout("rdi") 0x1111_u64 => rdi,
+                inout("rax") 0x2222_u64 => rax,
+                inout("r11") 0x3333_u64 => r11,
+            );
+        }
+        assert_eq!(HITS.load(Ordering::Relaxed), 1);
+        // the scratch registers survive the handler
+        assert_eq!((rdi, rax, r11), (0x1111, 0x2222, 0x3333));
+    }
+
+    #[test_case]
+    fn interrupt_handler_gets_frame() {
+        SHARED_IDT.guard(|idt| {
+            let mut idt = idt.lock();
+            insert_interrupt!(
+                idt,
+                0x81,
+                interrupt_handler_fn_with_frame!(|frame| {
+                    // u128 is aligned to 16 bytes
+                    let local = 0u128;
+                    // black_box, otherwise the compiler assumes it's aligned
+                    let addr = core::hint::black_box(&raw const local).addr();
+                    STACK_ALIGNED.store(addr.is_multiple_of(16), Ordering::Relaxed);
+                    FRAME_RIP.store(frame.rip, Ordering::Relaxed);
+                    FRAME_CS.store(frame.cs, Ordering::Relaxed);
+                })
+            );
+            unsafe { idt.as_ref().load() };
+        });
+        let after_int: u64;
+        unsafe {
+            core::arch::asm!(
+                "lea {}, [rip + 2f]",
+                "int 0x81",
+                "2:",
+                out(reg) after_int,
+            );
+        }
+        // an interrupt returns to the next instruction
+        assert_eq!(FRAME_RIP.load(Ordering::Relaxed), after_int);
+        assert_eq!(
+            FRAME_CS.load(Ordering::Relaxed),
+            crate::arch_x86_64::cs() as u64
+        );
+        assert!(STACK_ALIGNED.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn trap_handler_with_error_runs() {
+        // the error code of a non canonical access is 0
+        should_panic_with!("general protection fault; err code: 0");
+        // a non canonical address is a general protection fault, which pushes an error code
+        unsafe { (0x8000_0000_0000_0000 as *const u64).read_volatile() };
+    }
+
+    // each of these faults ends its test with a panic from the handler, and the next test starts fresh from the panic handler
+
+    #[test_case]
+    fn divide_by_zero_exception() {
+        should_panic_with!("divide by 0 exception (0)");
+        unsafe {
+            core::arch::asm!(
+                "xor edx, edx",
+                "div ecx",
+                in("ecx") 0,
+                inout("eax") 1 => _,
+                out("edx") _,
+            )
+        };
+    }
+
+    #[test_case]
+    fn breakpoint_exception() {
+        should_panic_with!("exception 3; breakpoint");
+        unsafe { core::arch::asm!("int3") };
+    }
+
+    #[test_case]
+    fn invalid_opcode_exception() {
+        should_panic_with!("exception 6; invalid opcode");
+        unsafe { core::arch::asm!("ud2") };
+    }
+
+    #[test_case]
+    fn page_fault_exception() {
+        // a page we know isn't mapped anymore
+        let addr = unsafe {
+            let alloc = GLOBAL_PAGE_ALLOCATOR.alloc_pages(1).unwrap();
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+            alloc.as_virt_addr()
+        };
+        should_panic_with!("page protection fault");
+        unsafe { (addr.0 as *const u64).read_volatile() };
+    }
+}
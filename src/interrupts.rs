@@ -1,12 +1,16 @@
-use core::pin::Pin;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
 
 use alloc::boxed::Box;
-use spin::{Lazy, Mutex};
+use spin::{Lazy, Mutex, RwLock};
 
 use crate::{
-    arch_x86_64::{cli, rflags, sti},
+    arch_x86_64::{cli, cpuid, cr2, rflags, sti},
     create_init_idt,
-    idt::Idt,
+    idt::{Idt, IdtEntry, IdtEntryType, InterruptHandlerFn, InterruptStackFrame, SavedIdtEntry},
+    memory::virt::VirtAddr,
 };
 
 pub unsafe fn irq_disable() {
@@ -29,13 +33,19 @@ pub static SHARED_IDT: Lazy<InterruptGuard<Mutex<Pin<&mut Idt>>>> = Lazy::new(||
     InterruptGuard::new(Mutex::new(idt))
 });
 
+/// RFLAGS.IF: maskable interrupts are enabled
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Whether maskable interrupts are enabled on this cpu
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let rflags = unsafe { rflags() };
+    rflags & RFLAGS_IF != 0
+}
+
 #[inline(always)]
 pub fn irq_is_enabled() -> bool {
-    if unsafe { rflags() } & 0x0200 == 1 {
-        true
-    } else {
-        false
-    }
+    are_enabled()
 }
 
 /// Temporarily stop interrupts in the given function.
@@ -45,7 +55,7 @@ where
     F: FnOnce() -> R,
 {
     unsafe {
-        let interrupts_enabled = irq_is_enabled();
+        let interrupts_enabled = are_enabled();
         if interrupts_enabled {
             cli();
         }
@@ -57,6 +67,15 @@ where
     }
 }
 
+/// Run f with interrupts disabled, and return its result. Same as interrupt_guard.
+/// Nestable: interrupts are only enabled again when the outermost call returns, and only if they were enabled before it.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    interrupt_guard(f)
+}
+
 /// Guard a type from being modified with interrupts enabled.
 /// Functions in a nested manner.
 pub struct InterruptGuard<T> {
@@ -81,3 +100,355 @@ impl<T> InterruptGuard<T> {
         interrupt_guard(|| func(&mut self.inner))
     }
 }
+
+/// A handler registered at runtime with register_handler.
+/// It runs in an interrupt handler, so it shouldn't lock or allocate, see softirq::raise.
+pub type DynamicHandler = Box<dyn Fn(&InterruptStackFrame) + Send + Sync>;
+
+/// The handlers registered at runtime, by vector.
+/// Each vector they're registered on points to its trampoline, which calls the handler of that vector.
+pub struct Interrupts {
+    /// the handler, and the IDT entry it replaced
+    handlers: [RwLock<Option<(DynamicHandler, SavedIdtEntry)>>; 256],
+}
+
+impl Interrupts {
+    const fn new() -> Self {
+        Self {
+            handlers: [const { RwLock::new(None) }; 256],
+        }
+    }
+
+    /// Called by the trampoline of vector
+    fn dispatch(&self, vector: u8, frame: &InterruptStackFrame) {
+        // None if another cpu unregistered it while the interrupt was on the way
+        if let Some((handler, _)) = &*self.handlers[vector as usize].read() {
+            handler(frame);
+        }
+    }
+}
+
+static INTERRUPTS: Interrupts = Interrupts::new();
+
+extern "C" fn dispatch<const VECTOR: u8>(frame: &InterruptStackFrame) {
+    INTERRUPTS.dispatch(VECTOR, frame);
+}
+
+/// The entry of a vector with a registered handler, same as interrupt_handler_fn_with_frame!
+#[unsafe(naked)]
+extern "C" fn trampoline<const VECTOR: u8>() -> ! {
+    core::arch::naked_asm!(
+        crate::push_scratch_registers!(),
+        "
+        // the frame is right above the registers we pushed
+        lea rdi, [rsp + 8 * {scratch}]
+        // c abi requires cld
+        cld;
+        // aligned to 16 bytes, same as interrupt_handler_fn!
+        call {handler};",
+        crate::pop_scratch_registers!(),
+        "iretq;",
+        scratch = const crate::idt::SCRATCH_REGISTER_COUNT,
+        handler = sym dispatch::<VECTOR>,
+    )
+}
+
+macro_rules! trampolines {
+    ($high: literal) => {
+        [
+            trampoline::<{ $high * 16 }>,
+            trampoline::<{ $high * 16 + 1 }>,
+            trampoline::<{ $high * 16 + 2 }>,
+            trampoline::<{ $high * 16 + 3 }>,
+            trampoline::<{ $high * 16 + 4 }>,
+            trampoline::<{ $high * 16 + 5 }>,
+            trampoline::<{ $high * 16 + 6 }>,
+            trampoline::<{ $high * 16 + 7 }>,
+            trampoline::<{ $high * 16 + 8 }>,
+            trampoline::<{ $high * 16 + 9 }>,
+            trampoline::<{ $high * 16 + 10 }>,
+            trampoline::<{ $high * 16 + 11 }>,
+            trampoline::<{ $high * 16 + 12 }>,
+            trampoline::<{ $high * 16 + 13 }>,
+            trampoline::<{ $high * 16 + 14 }>,
+            trampoline::<{ $high * 16 + 15 }>,
+        ]
+    };
+}
+
+/// The trampoline of every vector, by the high and low 4 bits of the vector
+static TRAMPOLINES: [[InterruptHandlerFn; 16]; 16] = [
+    trampolines!(0),
+    trampolines!(1),
+    trampolines!(2),
+    trampolines!(3),
+    trampolines!(4),
+    trampolines!(5),
+    trampolines!(6),
+    trampolines!(7),
+    trampolines!(8),
+    trampolines!(9),
+    trampolines!(10),
+    trampolines!(11),
+    trampolines!(12),
+    trampolines!(13),
+    trampolines!(14),
+    trampolines!(15),
+];
+
+/// The exceptions for which the cpu pushes an error code, which the trampolines don't expect
+const ERROR_CODE_VECTORS: [u8; 10] = [8, 10, 11, 12, 13, 14, 17, 21, 29, 30];
+
+/// Call handler on every interrupt with vector, on every cpu, and return the handler it replaced.
+/// The handler is boxed here, so that the interrupt handler doesn't have to allocate.
+/// Until it's unregistered, it replaces whatever the IDT had for vector, which may be an exception handler
+/// as long as the exception has no error code.
+/// ## Panic
+/// Panics if the vector is an exception with an error code: the frame would be off by the error code,
+/// and returning would pop it as the rip.
+/// The handler may not register or unregister handlers itself, that spins forever.
+pub fn register_handler(vector: u8, handler: DynamicHandler) -> Option<DynamicHandler> {
+    assert!(
+        !ERROR_CODE_VECTORS.contains(&vector),
+        "exception {} has an error code, it can't have a registered handler",
+        vector
+    );
+    let trampoline = TRAMPOLINES[vector as usize >> 4][vector as usize & 0xf];
+    SHARED_IDT.guard(|idt| {
+        let mut idt = idt.lock();
+        let mut slot = INTERRUPTS.handlers[vector as usize].write();
+        match slot.take() {
+            Some((old, saved)) => {
+                *slot = Some((handler, saved));
+                Some(old)
+            }
+            None => {
+                let saved = idt.as_mut().replace(
+                    vector as usize,
+                    IdtEntry::new_with_current_cs(IdtEntryType::Interrupt(trampoline)),
+                );
+                *slot = Some((handler, saved));
+                None
+            }
+        }
+    })
+}
+
+/// Remove the handler of vector, and put back the IDT entry it replaced.
+/// Returns the handler, None if there was none.
+pub fn unregister_handler(vector: u8) -> Option<DynamicHandler> {
+    SHARED_IDT.guard(|idt| {
+        let mut idt = idt.lock();
+        let (handler, saved) = INTERRUPTS.handlers[vector as usize].write().take()?;
+        idt.as_mut().restore(vector as usize, saved);
+        Some(handler)
+    })
+}
+
+bitflags::bitflags! {
+    /// The error code of a page fault
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct PageFaultError: u64 {
+        /// The page was present, i.e. this is a protection violation. Otherwise, the page isn't mapped.
+        const PRESENT = 1;
+        /// Caused by a write, otherwise by a read
+        const WRITE = 1 << 1;
+        /// Caused while in user mode
+        const USER = 1 << 2;
+        /// A reserved bit was set in one of the page table entries
+        const RESERVED_BIT = 1 << 3;
+        const INSTRUCTION_FETCH = 1 << 4;
+        const PROTECTION_KEY = 1 << 5;
+        const SHADOW_STACK = 1 << 6;
+    }
+}
+
+/// Tries to resolve a page fault at addr, i.e. by mapping the page.
+/// Returns whether it did, in which case the faulting instruction is retried.
+pub type PageFaultHandler =
+    fn(addr: VirtAddr, error: PageFaultError, frame: &InterruptStackFrame) -> bool;
+
+/// PageFaultHandler as usize, 0 if there's none
+static PAGE_FAULT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+/// The error of the page fault which is being panicked on, u64::MAX if none is. See fatal_page_fault.
+static FATAL_PAGE_FAULT: AtomicU64 = AtomicU64::new(u64::MAX);
+/// initial apic id + 1 of the cpu which is running the page fault handler, 0 if none is
+static RESOLVING_CPU: AtomicU32 = AtomicU32::new(0);
+
+/// Set the function page faults are given to before they're considered fatal, and return the previous one.
+/// None makes every page fault fatal again.
+pub fn set_page_fault_handler(handler: Option<PageFaultHandler>) -> Option<PageFaultHandler> {
+    let old = PAGE_FAULT_HANDLER.swap(handler.map_or(0, |f| f as usize), Ordering::AcqRel);
+    // safety: PAGE_FAULT_HANDLER only ever holds 0 or PageFaultHandlers
+    (old != 0).then(|| unsafe { core::mem::transmute::<usize, PageFaultHandler>(old) })
+}
+
+/// The error of the page fault which couldn't be resolved, if a page fault is what we're panicking on.
+/// Its address is still in cr2, since nothing faults on the way to the panic handler.
+pub fn fatal_page_fault() -> Option<PageFaultError> {
+    let error = FATAL_PAGE_FAULT.load(Ordering::Relaxed);
+    (error != u64::MAX).then(|| PageFaultError::from_bits_retain(error))
+}
+
+/// Called by the page fault entry in the IDT. Returning retries the faulting instruction.
+/// ## Panic
+/// Panics if there's no page fault handler or it declines, and if the fault can't be resolved at all:
+/// a reserved bit is set (the page tables are broken) or the handler itself faulted.
+pub fn handle_page_fault(error: u64, frame: &InterruptStackFrame) {
+    // before anything else can fault and overwrite it
+    let addr = cr2();
+    let error = PageFaultError::from_bits_retain(error);
+    let fatal = |reason: &str| -> ! {
+        FATAL_PAGE_FAULT.store(error.bits(), Ordering::Relaxed);
+        panic!(
+            "page protection fault; addr: 0x{:x}; err_code: {:b} ({:?}); rip: 0x{:x}{}",
+            addr,
+            error.bits(),
+            error,
+            frame.rip,
+            reason
+        );
+    };
+    if error.contains(PageFaultError::RESERVED_BIT) {
+        fatal("; reserved bit set");
+    }
+    let handler = PAGE_FAULT_HANDLER.load(Ordering::Acquire);
+    if handler == 0 {
+        fatal("");
+    }
+    // safety: PAGE_FAULT_HANDLER only ever holds 0 or PageFaultHandlers
+    let handler = unsafe { core::mem::transmute::<usize, PageFaultHandler>(handler) };
+    // the id of the current cpu without touching memory, like sync::DebugMutex
+    let cpu = (cpuid(1, 0).ebx >> 24) + 1;
+    let first = RESOLVING_CPU.compare_exchange(0, cpu, Ordering::Acquire, Ordering::Relaxed);
+    if first == Err(cpu) {
+        // retrying would fault again, forever
+        fatal("; inside the page fault handler");
+    }
+    let resolved = handler(VirtAddr(addr), error, frame);
+    if first.is_ok() {
+        RESOLVING_CPU.store(0, Ordering::Release);
+    }
+    if !resolved {
+        fatal("");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn nested_without_interrupts() {
+        let was_enabled = are_enabled();
+        for outer_enabled in [true, false] {
+            unsafe { if outer_enabled { sti() } else { cli() } }
+            let result = without_interrupts(|| {
+                assert!(!are_enabled());
+                let inner = without_interrupts(|| {
+                    assert!(!are_enabled());
+                    1
+                });
+                // the inner call doesn't enable them early
+                assert!(!are_enabled());
+                inner + 1
+            });
+            assert_eq!(result, 2);
+            assert_eq!(are_enabled(), outer_enabled);
+        }
+        unsafe { if was_enabled { sti() } else { cli() } }
+    }
+
+    #[test_case]
+    fn irq_is_enabled_follows_if() {
+        let was_enabled = irq_is_enabled();
+        unsafe { irq_enable() };
+        assert!(irq_is_enabled());
+        unsafe { irq_disable() };
+        assert!(!irq_is_enabled());
+        // interrupt_guard restores what it disabled
+        unsafe { irq_enable() };
+        interrupt_guard(|| assert!(!irq_is_enabled()));
+        assert!(irq_is_enabled());
+        unsafe {
+            if was_enabled {
+                irq_enable()
+            } else {
+                irq_disable()
+            }
+        }
+    }
+
+    #[test_case]
+    fn page_fault_resolved() {
+        use crate::memory::{
+            paging::{Page, PageTable, PageTableEntryFlags},
+            physical::PhysicalAllocator,
+            virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocation, PageAllocator},
+        };
+        static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+        // an address which is definitely not mapped
+        let alloc = unsafe { GLOBAL_PAGE_ALLOCATOR.alloc_pages(1).unwrap() };
+        unsafe { GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc) };
+        let ptr = (alloc.as_virt_addr().0 + 0x10) as *mut u64;
+
+        fn map_on_demand(addr: VirtAddr, error: PageFaultError, _: &InterruptStackFrame) -> bool {
+            assert!(!error.contains(PageFaultError::PRESENT));
+            assert!(error.contains(PageFaultError::WRITE));
+            FAULTS.fetch_add(1, Ordering::Relaxed);
+            let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+            unsafe {
+                let frame = inner.physical_allocator.allocate_frame().unwrap();
+                PageTable::current_mut().map_page_unchecked(
+                    Page::from(addr),
+                    frame,
+                    PageTableEntryFlags::kernel_data(),
+                    &mut inner.physical_allocator,
+                );
+            }
+            true
+        }
+        let old = set_page_fault_handler(Some(map_on_demand));
+        unsafe {
+            ptr.write_volatile(0x1234_5678);
+            assert_eq!(ptr.read_volatile(), 0x1234_5678);
+        }
+        set_page_fault_handler(old);
+        assert_eq!(FAULTS.load(Ordering::Relaxed), 1);
+        // the page is a normal allocated page now
+        unsafe {
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&PageAllocation::new(VirtAddr(ptr as u64), 1));
+        }
+        assert!(!unsafe { PageTable::current() }.is_present(alloc.first_page));
+    }
+
+    #[test_case]
+    fn registered_handler() {
+        static BREAKPOINT_RIP: AtomicU64 = AtomicU64::new(0);
+        let old = register_handler(
+            3,
+            Box::new(|frame| BREAKPOINT_RIP.store(frame.rip, Ordering::Relaxed)),
+        );
+        assert!(old.is_none());
+        SHARED_IDT.guard(|idt| unsafe { idt.lock().as_ref().load() });
+        let after_int3: u64;
+        unsafe {
+            core::arch::asm!(
+                "lea {}, [rip + 2f]",
+                "int3",
+                "2:",
+                out(reg) after_int3,
+            );
+        }
+        assert_eq!(BREAKPOINT_RIP.load(Ordering::Relaxed), after_int3);
+        assert!(unregister_handler(3).is_some());
+        assert!(unregister_handler(3).is_none());
+    }
+
+    #[test_case]
+    fn error_code_vector_rejected() {
+        crate::should_panic_with!("exception 14 has an error code");
+        register_handler(14, Box::new(|_| ()));
+    }
+}
@@ -1,29 +1,126 @@
-use core::arch::asm;
-// todo: we can slightly anstract it into a Port structure
-// which has read<u8>, read<u16>, read<u32>, write<u8>, write<u16>,
-// write<u32>
+use core::{arch::asm, marker::PhantomData};
+
+/// A value which can be read from and written to an io port
+pub trait PortValue: Copy {
+    /// # Safety
+    /// Same as Port::read, reading a port can have side effects on the device behind it.
+    unsafe fn read_from(port: u16) -> Self;
+    /// # Safety
+    /// Same as Port::write, the device behind the port must expect the write.
+    unsafe fn write_to(port: u16, val: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_from(port: u16) -> Self {
+        let out: u8;
+        unsafe { asm!("in al, dx", out("al") out, in("dx") port) };
+        out
+    }
+
+    unsafe fn write_to(port: u16, val: Self) {
+        unsafe { asm!("out dx, al", in("dx") port, in("al") val) }
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from(port: u16) -> Self {
+        let out: u16;
+        unsafe { asm!("in ax, dx", out("ax") out, in("dx") port) };
+        out
+    }
+
+    unsafe fn write_to(port: u16, val: Self) {
+        unsafe { asm!("out dx, ax", in("dx") port, in("ax") val) }
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from(port: u16) -> Self {
+        let out: u32;
+        unsafe { asm!("in eax, dx", out("eax") out, in("dx") port) };
+        out
+    }
+
+    unsafe fn write_to(port: u16, val: Self) {
+        unsafe { asm!("out dx, eax", in("dx") port, in("eax") val) }
+    }
+}
+
+/// An io port which is accessed with values of type T
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T: PortValue>(u16, PhantomData<T>);
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Self(port, PhantomData)
+    }
+
+    pub const fn number(&self) -> u16 {
+        self.0
+    }
+
+    /// ## Safety
+    /// Reading a port can have side effects on the device behind it, i.e. taking a received byte.
+    pub unsafe fn read(&self) -> T {
+        unsafe { T::read_from(self.0) }
+    }
+
+    /// ## Safety
+    /// The device behind the port must expect the write.
+    pub unsafe fn write(&self, val: T) {
+        unsafe { T::write_to(self.0, val) }
+    }
+}
+
 pub unsafe fn read_u8(port: u16) -> u8 {
-    let out: u8;
-    unsafe { asm!("in {}, dx", out(reg_byte) out, in("dx") port) };
-    out
+    unsafe { Port::new(port).read() }
 }
 
 pub unsafe fn read_u16(port: u16) -> u16 {
-    let out: u16;
-    unsafe { asm!("in {0:x}, dx", out(reg) out, in("dx") port,) };
-    out
+    unsafe { Port::new(port).read() }
 }
 
 pub unsafe fn read_u32(port: u16) -> u32 {
-    let out: u32;
-    unsafe { asm!("in {0:e}, dx", out(reg) out, in("dx") port) }
-    out
+    unsafe { Port::new(port).read() }
 }
 
 pub unsafe fn write_u8(port: u16, val: u8) {
-    unsafe { asm!("out dx, al", in("dx") port, in("al") val) }
+    unsafe { Port::new(port).write(val) }
+}
+
+/// # Safety
+/// Same as Port::write, the device behind the port must expect the write.
+pub unsafe fn write_u16(port: u16, val: u16) {
+    unsafe { Port::new(port).write(val) }
 }
 
 pub unsafe fn write_u32(port: u16, val: u32) {
-    unsafe { asm!("out dx, eax", in("dx") port, in("eax") val) }
+    unsafe { Port::new(port).write(val) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn port_round_trip() {
+        // the scratch register of COM1 holds whatever is written to it
+        let scratch = Port::<u8>::new(0x3FF);
+        unsafe {
+            scratch.write(0x5a);
+            assert_eq!(scratch.read(), 0x5a);
+            write_u8(scratch.number(), 0xa5);
+            assert_eq!(read_u8(scratch.number()), 0xa5);
+        }
+        // so does the PCI config address, as long as the enable bit is set.
+        // holding the lock keeps config space accesses from using it meanwhile
+        let _lock = crate::dev::pci::CONFIG_LOCK.lock();
+        let config_address = Port::<u32>::new(0xCF8);
+        unsafe {
+            let old = config_address.read();
+            config_address.write(0x8000_0804);
+            assert_eq!(config_address.read(), 0x8000_0804);
+            config_address.write(old);
+        }
+    }
 }
@@ -5,9 +5,10 @@
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test::test_runner)]
 #![reexport_test_harness_main = "lib_test"]
-use core::{cell::LazyCell, fmt::Write, mem::MaybeUninit};
+use core::{fmt::Write, mem::MaybeUninit};
 
-use console::{Console, ThreadSafeConsole};
+use console::ThreadSafeConsole;
+#[cfg(feature = "smp")]
 use limine::request::MpRequest;
 use limine::{
     BaseRevision,
@@ -21,27 +22,30 @@ use limine::{
     },
 };
 
-use screen::Screen;
-
 pub mod arch_x86_64;
 pub mod console;
 pub mod cpu;
 pub mod dev;
+pub mod error;
 pub mod fs;
 pub mod hexdump;
 pub mod idt;
 pub mod interrupts;
 pub mod io;
+pub mod logo;
 pub mod memory;
 pub mod msr;
-#[cfg(not(test))]
 pub mod panic;
 pub mod qemu_log;
 pub mod screen;
+pub mod softirq;
 pub mod stack_trace;
+pub mod sync;
 #[cfg(test)]
 mod test;
 pub mod time;
+pub mod util;
+pub mod watchdog;
 pub extern crate alloc;
 pub mod acpi;
 
@@ -84,6 +88,7 @@ pub static HIGHER_HALF_DIRECT_MAP: HhdmRequest = HhdmRequest::new();
 #[unsafe(link_section = ".requests")]
 pub static LIMINE_MEMORY_MAP: MemoryMapRequest = MemoryMapRequest::new();
 
+#[cfg(feature = "smp")]
 #[used]
 #[unsafe(link_section = ".requests")]
 pub static LIMINE_CPU_REQUEST: MpRequest = MpRequest::new();
@@ -118,31 +123,20 @@ pub fn kernel_virt_end() -> u64 {
 pub fn kernel_phy_begin() -> u64 {
     EXECUTABLE_REQUEST.get_response().unwrap().physical_base()
 }
-/// Screen to draw pixels in
-pub const SCREEN: LazyCell<Screen> = LazyCell::new(|| unsafe {
-    // safety: limine protocol should give us accurate data
-    // and also this cannot panic since main.rs ensure we die if there isn't at least one framebuffer
-    Screen::new(
-        FRAMEBUFFER_REQUEST
-            .get_response()
-            .unwrap()
-            .framebuffers()
-            .next()
-            .unwrap(),
-    )
-});
+/// global console, set by screen::init once we have a screen
+pub static CONSOLE: spin::Once<ThreadSafeConsole> = spin::Once::new();
 
-/// global console
-pub static CONSOLE: spin::Lazy<ThreadSafeConsole> = spin::Lazy::new(|| {
-    ThreadSafeConsole::new(Console::new(
-        SCREEN.clone(),
-        crate::screen::Color::black(),
-        crate::screen::Color::blue(),
-    ))
-});
+/// The global console, or None if screen::init didn't succeed (yet)
+pub fn console() -> Option<&'static ThreadSafeConsole> {
+    CONSOLE.get()
+}
 
 pub fn _console_print(args: core::fmt::Arguments) {
-    CONSOLE.lock().write_fmt(args).unwrap();
+    match console() {
+        Some(console) => console.lock().write_fmt(args).unwrap(),
+        // no screen, the qemu log is all we have
+        None => qemu_log::_print(args),
+    }
 }
 
 #[macro_export]
@@ -173,8 +167,8 @@ pub fn create_init_idt(uninit: Pin<&mut MaybeUninit<Idt>>) -> Pin<&mut Idt> {
     */
     idt.as_mut().insert(
         0,
-        IdtEntry::new_with_current_cs(IdtEntryType::Trap(trap_handler_fn!(|| {
-            panic!("divide by 0 exception (0)");
+        IdtEntry::new_with_current_cs(IdtEntryType::Trap(trap_handler_fn_with_frame!(|frame| {
+            panic!("divide by 0 exception (0); rip: {:#x}", frame.rip);
         }))),
     );
     idt.as_mut().insert(
@@ -186,13 +180,19 @@ pub fn create_init_idt(uninit: Pin<&mut MaybeUninit<Idt>>) -> Pin<&mut Idt> {
     idt.as_mut().insert(
         2,
         IdtEntry::new_with_current_cs(IdtEntryType::Interrupt(interrupt_handler_fn!(|| {
+            if watchdog::expired() {
+                watchdog::disarm();
+                panic!("{}", watchdog::TIMEOUT_MESSAGE);
+            }
             panic!("NMI interrupt? (2)");
         }))),
     );
     insert_trap!(
         idt,
         3,
-        trap_handler_fn!(|| { panic!("exception 3; breakpoint") })
+        trap_handler_fn_with_frame!(|frame| {
+            panic!("exception 3; breakpoint; rip: {:#x}", frame.rip)
+        })
     );
     insert_trap!(
         idt,
@@ -207,19 +207,22 @@ pub fn create_init_idt(uninit: Pin<&mut MaybeUninit<Idt>>) -> Pin<&mut Idt> {
     insert_trap!(
         idt,
         6,
-        trap_handler_fn!(|| { panic!("exception 6; invalid opcode") })
+        trap_handler_fn_with_frame!(|frame| {
+            panic!("exception 6; invalid opcode; rip: {:#x}", frame.rip)
+        })
     );
     insert_trap!(
         idt,
         7,
         trap_handler_fn!(|| { panic!("exception 7; device not available") })
     );
-    insert_trap!(
-        idt,
+    // on its own stack: a double fault is often a fault which couldn't be delivered on a broken stack
+    idt.as_mut().insert(
         8,
-        trap_handler_fn_with_error!(|err| {
+        IdtEntry::new_with_current_cs(IdtEntryType::Trap(trap_handler_fn_with_error!(|err| {
             panic!("exception 8; double fault; err code: {}", err)
-        })
+        })))
+        .with_ist(cpu::gdt::DOUBLE_FAULT_IST),
     );
     insert_trap!(
         idt,
@@ -365,15 +368,15 @@ pub fn create_init_idt(uninit: Pin<&mut MaybeUninit<Idt>>) -> Pin<&mut Idt> {
         31,
         trap_handler_fn!(|| { panic!("exception 31; reserved") })
     );
+    // an interrupt gate, so that nothing (i.e. an interrupt whose handler faults) can overwrite cr2
+    // before handle_page_fault reads it
     idt.as_mut().insert(
         14,
-        IdtEntry::new_with_current_cs(IdtEntryType::Trap(trap_handler_fn_with_error!(|err| {
-            panic!(
-                "page protection fault; addr: 0x{:x}; err_code: {:b}",
-                arch_x86_64::cr2(),
-                err
-            );
-        }))),
+        IdtEntry::new_with_current_cs(IdtEntryType::Interrupt(fault_handler_fn_with_error!(
+            |frame, err| {
+                interrupts::handle_page_fault(err, frame);
+            }
+        ))),
     );
 
     idt
@@ -0,0 +1,33 @@
+use crate::screen::{Color, Image};
+
+const LOGO_SIZE: usize = 32;
+
+/// A ring, generated at compile time so that we don't need to ship an image file
+const LOGO_PIXELS: [u32; LOGO_SIZE * LOGO_SIZE] = {
+    let mut pixels = [Color::transparent().as_u32(); LOGO_SIZE * LOGO_SIZE];
+    let center = LOGO_SIZE as i64 / 2;
+    let mut y = 0;
+    while y < LOGO_SIZE {
+        let mut x = 0;
+        while x < LOGO_SIZE {
+            let dx = x as i64 - center;
+            let dy = y as i64 - center;
+            let dist_sq = dx * dx + dy * dy;
+            if dist_sq < 15 * 15 && dist_sq >= 11 * 11 {
+                pixels[y * LOGO_SIZE + x] = Color::from_rgb(0xff, 0xff, 0xff).as_u32();
+            } else if dist_sq < 11 * 11 && dist_sq >= 8 * 8 {
+                pixels[y * LOGO_SIZE + x] = Color::from_rgb(0x30, 0x90, 0xff).as_u32();
+            }
+            x += 1;
+        }
+        y += 1;
+    }
+    pixels
+};
+
+/// The logo drawn on boot
+pub const BOOT_LOGO: Image<'static> = Image {
+    width: LOGO_SIZE,
+    height: LOGO_SIZE,
+    pixels: &LOGO_PIXELS,
+};
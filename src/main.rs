@@ -7,9 +7,12 @@ use core::mem::MaybeUninit;
 use core::pin::pin;
 
 use os_test::arch_x86_64::hlt;
+use os_test::error::KernelError;
+use os_test::logo::BOOT_LOGO;
+use os_test::screen::{self, NoFramebuffer};
 use os_test::{
-    BASE_REVISION, FRAMEBUFFER_REQUEST, console_println, create_init_idt, kernel_phy_begin,
-    kernel_virt_begin, memory,
+    BASE_REVISION, console_println, create_init_idt, kernel_phy_begin, kernel_virt_begin, memory,
+    qemu_println, softirq,
 };
 
 #[unsafe(naked)]
@@ -25,29 +28,44 @@ unsafe extern "C" fn kmain() -> ! {
 
 #[unsafe(no_mangle)]
 unsafe extern "C" fn kmain_rs() -> ! {
-    // ensure that the screen is functional
-    if FRAMEBUFFER_REQUEST.get_response().is_none()
-        || FRAMEBUFFER_REQUEST
-            .get_response()
-            .is_some_and(|r| r.framebuffers().next().is_none())
-    {
-        // WE HAVE NO SCREEN TO WRITE TO. WE CAN'T NOTIFY THE USER OF ANYTHING BASICALLY. CURRENTLY THE OS IS USELESS IF IT DOESN'T HAVE A SCREEN.
-        // PERHAPS IN THE FUTURE REMOVE THE REQUIREMENT OF A SCREEN
-        unsafe {
-            hlt();
-        }
-    }
+    // write protection and global pages should hold for everything we map from here on
+    os_test::arch_x86_64::enable_cpu_features();
+    os_test::cpu::fpu_init();
     // All limine requests must also be referenced in a called function, otherwise they may be
     // removed by the linker.
     assert!(BASE_REVISION.is_supported());
 
+    // the console clears the screen when it's created, so create it before drawing the logo
+    match screen::init() {
+        Ok(()) => {
+            let mut screen = screen::screen().unwrap();
+            let logo_x = screen.width.saturating_sub(BOOT_LOGO.width);
+            screen.draw_image(logo_x, 0, &BOOT_LOGO);
+        }
+        // the console falls back to the qemu log
+        Err(NoFramebuffer) => qemu_println!("no framebuffer, running without a screen"),
+    }
+
+    console_println!(
+        "cpu features: {:?}",
+        os_test::arch_x86_64::CpuFeatures::detect()
+    );
+    // the idt needs the tss for the double fault stack
+    os_test::cpu::gdt::init();
     // create initial idt
     let uninit_idt = pin!(MaybeUninit::uninit());
     let init = create_init_idt(uninit_idt);
     unsafe { init.as_ref().load() };
     console_println!("IDT has been loaded");
-    memory::init();
+    if let Err(e) = memory::init() {
+        halt_with(e);
+    }
     console_println!("memory has been loaded!");
+    os_test::stack_trace::load_symbols();
+    match os_test::fs::init() {
+        Ok(()) => console_println!("vfs initialized!"),
+        Err(e) => console_println!("failed to initialize the vfs: {:?}", e),
+    }
 
     console_println!(
         "kernel phy_addr: {:x}, kernel virt_addr: {:x}",
@@ -55,11 +73,23 @@ unsafe extern "C" fn kmain_rs() -> ! {
         kernel_virt_begin()
     );
 
-    os_test::cpu::init();
+    if let Err(e) = os_test::cpu::init() {
+        halt_with(e);
+    }
 
-    unsafe {
-        loop {
+    loop {
+        // run the work deferred by the interrupts which woke us up
+        softirq::run_pending();
+        unsafe {
             hlt();
         }
     }
 }
+
+/// Boot can't go on, say why and stop
+fn halt_with(error: KernelError) -> ! {
+    console_println!("boot failed: {}", error);
+    loop {
+        unsafe { hlt() };
+    }
+}
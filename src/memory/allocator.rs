@@ -1,45 +1,208 @@
-use core::alloc::GlobalAlloc;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::null_mut,
+};
 
-use crate::memory::{
-    paging::Page,
-    physical::BasicPhysicalAllocator,
-    virt::{BasicPageAllocator, GLOBAL_PAGE_ALLOCATOR, PageAllocation, PageAllocator, VirtAddr},
+use crate::{
+    memory::{
+        buddy::BuddyPhysicalAllocator,
+        paging::Page,
+        virt::{
+            BasicPageAllocator, GLOBAL_PAGE_ALLOCATOR, PageAllocation, PageAllocator, VirtAddr,
+        },
+    },
+    sync::CheckedMutex,
 };
 
-// TODO: Make a proper allocator instead of using the virtual page allocator
 #[global_allocator]
-static GLOBAL_ALLOCATOR: Allocator<BasicPageAllocator<BasicPhysicalAllocator>> = Allocator {
+static GLOBAL_ALLOCATOR: Allocator<BasicPageAllocator<BuddyPhysicalAllocator>> = Allocator {
     page_allocator: &GLOBAL_PAGE_ALLOCATOR,
+    heap: CheckedMutex::new(Heap::new()),
 };
 
+/// Every heap block is a multiple of this and aligned to it, so that a free one always has room for a FreeRegion
+const BLOCK_SIZE: usize = 16;
+/// Minimum amount of pages the heap grows by, so that small allocations don't each map a page
+const HEAP_GROW_PAGES: usize = 16;
+
+/// amount of allocations made so far, so that tests can check something doesn't allocate more than needed
+#[cfg(test)]
+static ALLOCATIONS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+pub fn allocation_count() -> usize {
+    ALLOCATIONS.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+/// Allocations smaller than a page are carved out of the heap, bigger ones get pages of their own.
 struct Allocator<T: PageAllocator + 'static> {
     page_allocator: &'static T,
+    heap: CheckedMutex<Heap>,
+}
+
+/// Written at the start of every free region of the heap
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+/// A first fit free list. The free regions are sorted by address, so that freeing can merge a block with its neighbours.
+struct Heap {
+    head: *mut FreeRegion,
+}
+
+/// safety: the free regions are only accessed through the heap
+unsafe impl Send for Heap {}
+
+impl Heap {
+    const fn new() -> Self {
+        Heap { head: null_mut() }
+    }
+
+    /// Size of the block an allocation of the layout takes
+    fn block_size(layout: Layout) -> usize {
+        layout.size().max(1).next_multiple_of(BLOCK_SIZE)
+    }
+
+    /// Take a block for the layout out of the first free region which fits it.
+    /// Returns None if there's no such region.
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        let size = Self::block_size(layout);
+        let align = layout.align().max(BLOCK_SIZE);
+        let mut link: *mut *mut FreeRegion = &mut self.head;
+        unsafe {
+            while let Some(region) = (*link).as_mut() {
+                let start = region as *mut FreeRegion as usize;
+                let end = start + region.size;
+                let block = start.next_multiple_of(align);
+                if block + size > end {
+                    link = &mut region.next;
+                    continue;
+                }
+                // the part after the block stays free
+                let after = if block + size < end {
+                    let rest = (block + size) as *mut FreeRegion;
+                    rest.write(FreeRegion {
+                        size: end - block - size,
+                        next: region.next,
+                    });
+                    rest
+                } else {
+                    region.next
+                };
+                if block == start {
+                    *link = after;
+                } else {
+                    // and so does the part before it, which is at least BLOCK_SIZE since both are aligned to it
+                    region.size = block - start;
+                    region.next = after;
+                }
+                return Some(block as *mut u8);
+            }
+        }
+        None
+    }
+
+    /// Add [start, start + size) to the free regions, merging it with the ones right before and after it.
+    /// ## Safety
+    /// The memory must be unused, writable and aligned to BLOCK_SIZE, and size a multiple of it.
+    unsafe fn free(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeRegion = null_mut();
+        let mut next = self.head;
+        unsafe {
+            while !next.is_null() && (next as usize) < start {
+                prev = next;
+                next = (*next).next;
+            }
+            debug_assert!(
+                (next.is_null() || start + size <= next as usize)
+                    && (prev.is_null() || prev as usize + (*prev).size <= start),
+                "freeing a heap block which is already free: {:x}",
+                start
+            );
+            let region = start as *mut FreeRegion;
+            region.write(FreeRegion { size, next });
+            if !next.is_null() && start + size == next as usize {
+                (*region).size += (*next).size;
+                (*region).next = (*next).next;
+            }
+            if prev.is_null() {
+                self.head = region;
+            } else if prev as usize + (*prev).size == start {
+                (*prev).size += (*region).size;
+                (*prev).next = (*region).next;
+            } else {
+                (*prev).next = region;
+            }
+        }
+    }
 }
 
 unsafe impl<T: PageAllocator> GlobalAlloc for Allocator<T> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let page_amount = ((layout.size() + (self.page_allocator.page_size() % layout.align()))
-            / self.page_allocator.page_size())
-            + 1;
+        #[cfg(test)]
+        ALLOCATIONS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        let page_size = self.page_allocator.page_size();
+        if layout.size() < page_size {
+            let mut heap = self.heap.lock();
+            unsafe {
+                if let Some(block) = heap.alloc(layout) {
+                    return block;
+                }
+                // enough for the block, even if its start has to be aligned
+                let needed = Heap::block_size(layout) + layout.align().max(BLOCK_SIZE);
+                let page_amount = needed.div_ceil(page_size).max(HEAP_GROW_PAGES);
+                let Some(allocation) = self.page_allocator.alloc_pages(page_amount) else {
+                    return null_mut();
+                };
+                heap.free(
+                    allocation.as_virt_addr().0 as usize,
+                    page_amount * page_size,
+                );
+                return heap.alloc(layout).unwrap_or(null_mut());
+            }
+        }
+        let page_amount = layout.size().div_ceil(page_size);
+        // pages are aligned to the page size (a power of two), so only bigger alignments need room to move the start
+        let padding_pages = (layout.align() / page_size).saturating_sub(1);
         unsafe {
-            let Some(allocation) = self.page_allocator.alloc_pages(page_amount) else {
-                return core::ptr::null_mut::<u8>();
+            let Some(allocation) = self.page_allocator.alloc_pages(page_amount + padding_pages)
+            else {
+                return null_mut::<u8>();
             };
-            allocation
-                .as_virt_addr()
-                .0
-                .next_multiple_of(layout.align() as u64) as *mut u8
+            let start = allocation.as_virt_addr().0;
+            let aligned = start.next_multiple_of(layout.align() as u64);
+            // give the padding back, so that dealloc only has to free page_amount pages from the pointer
+            let pages_before = ((aligned - start) / page_size as u64) as usize;
+            if pages_before > 0 {
+                self.page_allocator.dealloc_pages(&PageAllocation {
+                    first_page: allocation.first_page,
+                    page_amount: pages_before,
+                });
+            }
+            if padding_pages > pages_before {
+                self.page_allocator.dealloc_pages(&PageAllocation {
+                    first_page: Page::from(VirtAddr(aligned + (page_amount * page_size) as u64)),
+                    page_amount: padding_pages - pages_before,
+                });
+            }
+            aligned as *mut u8
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let page_amount = ((layout.size() + (self.page_allocator.page_size() % layout.align()))
-            / self.page_allocator.page_size())
-            + 1;
+        if layout.size() < self.page_allocator.page_size() {
+            unsafe {
+                self.heap
+                    .lock()
+                    .free(ptr as usize, Heap::block_size(layout))
+            };
+            return;
+        }
         unsafe {
             let allocation = PageAllocation {
                 first_page: Page::from(VirtAddr(ptr as u64)),
-                page_amount,
+                page_amount: layout.size().div_ceil(self.page_allocator.page_size()),
             };
             self.page_allocator.dealloc_pages(&allocation);
         }
@@ -48,7 +211,8 @@ unsafe impl<T: PageAllocator> GlobalAlloc for Allocator<T> {
 
 #[cfg(test)]
 mod test {
-    use alloc::{boxed::Box, vec};
+    use super::*;
+    use alloc::{boxed::Box, vec, vec::Vec};
 
     #[test_case]
     fn basic_alloc() {
@@ -72,4 +236,145 @@ mod test {
         big_box[0] = 1;
         big_box[big_box.len() - 1] = -3321;
     }
+
+    /// A pattern which depends on the size of the allocation, so that overlapping allocations corrupt each other's
+    fn pattern(size: usize, i: usize) -> u8 {
+        (size.wrapping_mul(31) ^ i.wrapping_mul(7)) as u8
+    }
+
+    fn check(allocation: &[u8]) {
+        for (i, byte) in allocation.iter().enumerate() {
+            assert_eq!(
+                *byte,
+                pattern(allocation.len(), i),
+                "allocation of {} bytes corrupted at {}",
+                allocation.len(),
+                i
+            );
+        }
+    }
+
+    #[test_case]
+    fn fragmentation_stress() {
+        // xorshift64, so that the test is deterministic
+        let mut seed = 0x0a11_0c47_e5d1_5eedu64;
+        let mut random = move |below: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize % below
+        };
+        let mut live: Vec<Box<[u8]>> = Vec::new();
+        // small, page sized and multi page allocations
+        let sizes = [1, 24, 100, 4095, 4096, 4097, 9000];
+        for round in 0..8 {
+            for _ in 0..48 {
+                let size = sizes[random(sizes.len())] + random(64);
+                let allocation = (0..size).map(|i| pattern(size, i)).collect::<Box<[u8]>>();
+                live.push(allocation);
+            }
+            // free a random subset, leaving holes for the next round to reuse
+            let mut i = 0;
+            while i < live.len() {
+                if random(2) == 0 {
+                    check(&live.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            // growing vectors reallocate over the holes
+            let mut grown = Vec::new();
+            for i in 0..(round + 1) * 1000 {
+                grown.push(i as u64);
+            }
+            assert!(grown.iter().enumerate().all(|(i, v)| *v == i as u64));
+            live.iter().for_each(|allocation| check(allocation));
+        }
+        // don't leak into the other tests
+        live.iter().for_each(|allocation| check(allocation));
+        drop(live);
+    }
+
+    #[test_case]
+    fn many_small_allocations() {
+        let free_frames = || {
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .free_frames()
+        };
+        let before = free_frames();
+        let boxes = (0..10000u64).map(Box::new).collect::<Vec<_>>();
+        // 10000 * BLOCK_SIZE bytes is 40 pages, a page per allocation would be 10000
+        assert!(before - free_frames() < 200);
+        assert!(boxes.iter().enumerate().all(|(i, b)| **b == i as u64));
+        assert!(boxes.windows(2).all(|w| !core::ptr::eq(&*w[0], &*w[1])));
+        drop(boxes);
+
+        // aligned blocks in the middle of a free region
+        let small = Box::new(1u8);
+        let aligned = Box::new(Aligned([7; 8]));
+        assert!((&*aligned as *const Aligned).addr().is_multiple_of(256));
+        assert_eq!(*small, 1);
+        assert_eq!(aligned.0, [7; 8]);
+    }
+
+    #[repr(align(256))]
+    struct Aligned([u8; 8]);
+
+    #[repr(align(4096))]
+    struct PageAligned([u8; 4096]);
+
+    #[repr(align(8192))]
+    struct TwoPagesAligned([u8; 5000]);
+
+    #[test_case]
+    fn page_aligned_allocations() {
+        let free_frames = || {
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .free_frames()
+        };
+        let before = free_frames();
+        let page = Box::new(PageAligned([1; 4096]));
+        let big = Box::new(TwoPagesAligned([2; 5000]));
+        assert!((&*page as *const PageAligned).addr().is_multiple_of(4096));
+        assert!(
+            (&*big as *const TwoPagesAligned)
+                .addr()
+                .is_multiple_of(8192)
+        );
+        assert!(page.0.iter().all(|&b| b == 1));
+        assert!(big.0.iter().all(|&b| b == 2));
+        drop(page);
+        drop(big);
+        // every page was given back, the padding included
+        assert!(free_frames() >= before);
+    }
+
+    #[test_case]
+    fn heap_merges_neighbours() {
+        #[repr(align(16))]
+        struct Buffer([u8; 256]);
+        let mut buffer = Buffer([0; 256]);
+        let start = buffer.0.as_mut_ptr() as usize;
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let mut heap = Heap::new();
+        unsafe {
+            heap.free(start, 256);
+            let blocks = [(); 4].map(|_| heap.alloc(layout).unwrap());
+            assert!(heap.alloc(layout).is_none());
+            // out of order, so that a block is merged with both of its neighbours
+            for i in [0, 2, 1, 3] {
+                heap.free(blocks[i] as usize, 64);
+            }
+            assert_eq!(
+                heap.alloc(Layout::from_size_align(256, 16).unwrap()),
+                Some(start as *mut u8)
+            );
+        }
+    }
 }
@@ -0,0 +1,526 @@
+use core::ops::Range;
+
+use crate::{
+    kernel_phy_begin, kernel_virt_begin,
+    memory::physical::{PhyAddr, PhysicalAllocator},
+};
+
+/// Blocks are 2^order frames, up to 2^MAX_ORDER frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+const ORDER_COUNT: usize = MAX_ORDER + 1;
+/// Same as the bitmap of BasicPhysicalAllocator, 32GiB of ram
+const MAX_FRAMES: usize = 8388608;
+/// Maximum amount of discontiguous regions, see set_regions
+pub const MAX_REGIONS: usize = 64;
+/// Marks the end of a free list
+const NO_BLOCK: u64 = u64::MAX;
+const FRAME_SIZE: u64 = 4096;
+
+const GLOBAL_BITMAP_WORDS: usize = BuddyPhysicalAllocator::metadata_words(MAX_FRAMES);
+static mut FREE_BITMAP: [u64; GLOBAL_BITMAP_WORDS] = [0; GLOBAL_BITMAP_WORDS];
+
+/// Written at the start of every free block (through the higher half direct map), links the free list of its order
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// A buddy allocator: free memory is kept in blocks of 2^order frames, with a free list per order.
+/// Allocating splits a bigger block in halves (buddies) until it has the right order,
+/// and freeing merges a block with its buddy for as long as the buddy is free as well.
+/// The free lists live inside the free frames themselves, next to them there's only a bitmap of the free blocks.
+pub struct BuddyPhysicalAllocator {
+    /// One bit per block of every order, set if the block is free (and in its free list).
+    /// The bits of order k start at word order_starts[k].
+    free_bitmap: *mut [u64],
+    order_starts: [usize; ORDER_COUNT],
+    /// Amount of frames the bitmap has room for
+    capacity: usize,
+    /// Physical address of the first block in the free list of every order
+    free_lists: [u64; ORDER_COUNT],
+    free_frames: usize,
+    offset: PhyAddr,
+    limit: u64,
+    /// The usable parts of the area, see set_regions. Without any, the whole area is usable.
+    regions: [(PhyAddr, u64); MAX_REGIONS],
+    region_count: usize,
+    /// offset and limit the free lists were built for, None if they need to be rebuilt. See ensure_built
+    built_for: Option<(PhyAddr, u64)>,
+}
+
+impl BuddyPhysicalAllocator {
+    /// Amount of u64s the free bitmap needs for frame_count frames
+    pub const fn metadata_words(frame_count: usize) -> usize {
+        let mut words = 0;
+        let mut order = 0;
+        while order < ORDER_COUNT {
+            words += Self::blocks(frame_count, order).div_ceil(64);
+            order += 1;
+        }
+        words
+    }
+
+    /// Amount of blocks of the order there can be in frame_count frames, including a partial one at the end
+    const fn blocks(frame_count: usize, order: usize) -> usize {
+        frame_count.div_ceil(1 << order)
+    }
+
+    const fn order_starts(capacity: usize) -> [usize; ORDER_COUNT] {
+        let mut starts = [0; ORDER_COUNT];
+        let mut order = 1;
+        while order < ORDER_COUNT {
+            starts[order] = starts[order - 1] + Self::blocks(capacity, order - 1).div_ceil(64);
+            order += 1;
+        }
+        starts
+    }
+
+    /// create the global BuddyPhysicalAllocator. Like BasicPhysicalAllocator::init, it owns a static bitmap.
+    /// It manages nothing until it's configured with set_offset and limit_mut.
+    /// ## Safety
+    /// DO NOT CREATE MULTIPLE GLOBAL BUDDY PHYSICAL ALLOCATORS!
+    pub const unsafe fn init(offset: PhyAddr) -> Self {
+        Self::with_bitmap(&raw mut FREE_BITMAP, MAX_FRAMES, offset)
+    }
+
+    /// Create a BuddyPhysicalAllocator which manages the frames in [offset, offset + frame_count frames)
+    /// with its own bitmap, i.e. which is completely independent of the global one.
+    /// ## Safety
+    /// The bitmap must stay valid for as long as the allocator is used, and no one else may use it in the meantime.
+    /// The frames must be usable memory which is mapped by the higher half direct map: the free lists are written into them.
+    /// ## Panic
+    /// Panics if the bitmap is smaller than metadata_words(frame_count), or it overlaps the global allocator's bitmap.
+    pub unsafe fn new(bitmap: *mut [u64], offset: PhyAddr, frame_count: usize) -> Self {
+        let global = (&raw const FREE_BITMAP).addr()
+            ..(&raw const FREE_BITMAP).addr() + size_of::<[u64; GLOBAL_BITMAP_WORDS]>();
+        let start = bitmap.addr();
+        assert!(
+            start + bitmap.len() * size_of::<u64>() <= global.start || start >= global.end,
+            "the bitmap aliases the global one"
+        );
+        assert!(bitmap.len() >= Self::metadata_words(frame_count));
+        let mut allocator = Self::with_bitmap(bitmap, frame_count, offset);
+        unsafe { allocator.set_offset(offset) };
+        allocator.limit = frame_count as u64 * FRAME_SIZE;
+        allocator
+    }
+
+    const fn with_bitmap(bitmap: *mut [u64], capacity: usize, offset: PhyAddr) -> Self {
+        BuddyPhysicalAllocator {
+            free_bitmap: bitmap,
+            order_starts: Self::order_starts(capacity),
+            capacity,
+            free_lists: [NO_BLOCK; ORDER_COUNT],
+            free_frames: 0,
+            offset,
+            limit: 0,
+            regions: [(PhyAddr(0), 0); MAX_REGIONS],
+            region_count: 0,
+            built_for: Some((offset, 0)),
+        }
+    }
+
+    /// Must be called before anything is allocated, the free lists are built for the new area on the next use.
+    /// ## Safety
+    /// The whole area must be usable memory mapped by the higher half direct map, since the free lists are written into it.
+    pub unsafe fn set_offset(&mut self, offset: PhyAddr) {
+        assert!(offset.0.is_multiple_of(Self::frame_size()));
+        self.offset = offset;
+        // a single area again
+        self.region_count = 0;
+    }
+
+    /// Size in bytes of the area after the offset to manage. Like set_offset, must be set before anything is allocated.
+    /// ## Safety
+    /// Same as set_offset, for the whole area up to the new limit.
+    pub unsafe fn limit_mut(&mut self) -> &mut u64 {
+        &mut self.limit
+    }
+
+    /// Manage several discontiguous regions (i.e. all the usable entries of the memory map) instead of a single area.
+    /// The frames between them are never allocated and aren't ours, just like the ones outside of a single area.
+    /// Replaces the area set by set_offset and limit_mut, and like them must be called before anything is allocated.
+    /// Only whole frames are used, and the parts of the regions which are further than the bitmap's capacity from the first one are ignored.
+    /// ## Safety
+    /// Same as set_offset, for every region. The regions must not overlap.
+    /// ## Panic
+    /// Panics if there are more than MAX_REGIONS (non empty) regions.
+    pub unsafe fn set_regions(&mut self, regions: impl IntoIterator<Item = (PhyAddr, u64)>) {
+        self.region_count = 0;
+        let mut start = u64::MAX;
+        let mut end = 0;
+        for (region_start, size) in regions {
+            let region_end = PhyAddr(region_start.0 + size).align_down(FRAME_SIZE as usize);
+            let region_start = region_start.align_up(FRAME_SIZE as usize);
+            if region_end.0 <= region_start.0 {
+                continue;
+            }
+            assert!(
+                self.region_count < MAX_REGIONS,
+                "more than {} memory regions",
+                MAX_REGIONS
+            );
+            self.regions[self.region_count] = (region_start, region_end.0 - region_start.0);
+            self.region_count += 1;
+            start = start.min(region_start.0);
+            end = end.max(region_end.0);
+        }
+        if self.region_count == 0 {
+            start = self.offset.0;
+            end = start;
+        }
+        self.offset = PhyAddr(start);
+        self.limit = end - start;
+        self.built_for = None;
+    }
+
+    /// Mark every frame which overlaps [start, start + size) as used, so that it's never allocated.
+    /// Frames which are only partially covered are reserved as a whole, and frames we don't manage are ignored.
+    /// ## Safety
+    /// The area must be set up as set_offset or set_regions describe, the free lists are built in it on first use.
+    pub unsafe fn reserve(&mut self, start: PhyAddr, size: u64) {
+        self.ensure_built();
+        if size == 0 {
+            return;
+        }
+        let first = start.align_down(FRAME_SIZE as usize).0;
+        let end = PhyAddr(start.0 + size).align_up(FRAME_SIZE as usize).0;
+        for frame in (first..end).step_by(FRAME_SIZE as usize) {
+            if self.manages_frame(PhyAddr(frame)) {
+                // already used frames stay used
+                unsafe { self.take_frame(PhyAddr(frame)) };
+            }
+        }
+    }
+
+    /// The physical memory the bitmap itself lives in.
+    /// Only meaningful for the allocator created by init, whose bitmap is part of the kernel image.
+    pub fn bitmap_region(&self) -> (PhyAddr, u64) {
+        let virt_addr = self.free_bitmap.addr() as u64;
+        (
+            PhyAddr(virt_addr - kernel_virt_begin() + kernel_phy_begin()),
+            (self.free_bitmap.len() * size_of::<u64>()) as u64,
+        )
+    }
+
+    /// Amount of frames which can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocate 2^order contiguous frames, aligned to 2^order frames (relative to the offset).
+    /// Returns None if there's no free block that big.
+    /// ## Safety
+    /// Same as reserve.
+    pub unsafe fn allocate_block(&mut self, order: usize) -> Option<PhyAddr> {
+        assert!(order <= MAX_ORDER);
+        self.ensure_built();
+        let mut block_order = (order..ORDER_COUNT).find(|&o| self.free_lists[o] != NO_BLOCK)?;
+        let block = self.free_lists[block_order];
+        unsafe { self.remove(block_order, block) };
+        // give back the upper halves until the block is small enough
+        while block_order > order {
+            block_order -= 1;
+            unsafe { self.push(block_order, block + (FRAME_SIZE << block_order)) };
+        }
+        Some(PhyAddr(block))
+    }
+
+    /// Free a block allocated by allocate_block with the same order.
+    /// ## Safety
+    /// Nothing may use the block anymore: the free list links are written into it.
+    pub unsafe fn free_block(&mut self, block: PhyAddr, order: usize) {
+        assert!(order <= MAX_ORDER);
+        self.ensure_built();
+        assert!(
+            block.0.is_multiple_of(FRAME_SIZE) && self.manages_frame(block),
+            "freeing a block which isn't ours: {:?}",
+            block
+        );
+        if self.free_order_of(block).is_some() {
+            panic!("double free of block at {:?}", block);
+        }
+        let mut index = self.frame_index(block) >> order;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = index ^ 1;
+            if !self.is_free(order, buddy) {
+                break;
+            }
+            unsafe { self.remove(order, self.block_addr(order, buddy)) };
+            index >>= 1;
+            order += 1;
+        }
+        unsafe { self.push(order, self.block_addr(order, index)) };
+    }
+
+    /// Build the free lists if the area changed since they were last built
+    fn ensure_built(&mut self) {
+        if self.built_for == Some((self.offset, self.limit)) {
+            return;
+        }
+        self.built_for = Some((self.offset, self.limit));
+        let bitmap = unsafe { self.free_bitmap.as_mut().unwrap() };
+        bitmap.fill(0);
+        self.free_lists = [NO_BLOCK; ORDER_COUNT];
+        self.free_frames = 0;
+        for region in 0..self.region_count.max(1) {
+            let frames = self.region_frames(region);
+            let mut index = frames.start;
+            while index < frames.end {
+                // the biggest block which is aligned and fits
+                let order = (0..ORDER_COUNT)
+                    .rev()
+                    .find(|&o| index.is_multiple_of(1 << o) && index + (1 << o) <= frames.end)
+                    .unwrap();
+                unsafe { self.push(order, self.block_addr(order, index >> order)) };
+                index += 1 << order;
+            }
+        }
+    }
+
+    /// Amount of frames the area spans, including the ones between the regions
+    fn frame_count(&self) -> usize {
+        ((self.limit / FRAME_SIZE) as usize).min(self.capacity)
+    }
+
+    /// Indices of the frames in the region. Without regions, region 0 is the whole area.
+    fn region_frames(&self, region: usize) -> Range<usize> {
+        let frame_count = self.frame_count();
+        if self.region_count == 0 {
+            return 0..frame_count;
+        }
+        let (start, size) = self.regions[region];
+        let first = self.frame_index(start).min(frame_count);
+        first..(first + (size / FRAME_SIZE) as usize).min(frame_count)
+    }
+
+    fn frame_index(&self, frame: PhyAddr) -> usize {
+        ((frame.0 - self.offset.0) / FRAME_SIZE) as usize
+    }
+
+    fn block_addr(&self, order: usize, index: usize) -> u64 {
+        self.offset.0 + ((index << order) as u64) * FRAME_SIZE
+    }
+
+    fn is_free(&self, order: usize, index: usize) -> bool {
+        if index >= Self::blocks(self.frame_count(), order) {
+            return false;
+        }
+        let bitmap = unsafe { self.free_bitmap.as_ref().unwrap() };
+        let word = bitmap[self.order_starts[order] + index / 64];
+        word & (1 << (index % 64)) != 0
+    }
+
+    fn set_free(&mut self, order: usize, index: usize, free: bool) {
+        let bitmap = unsafe { self.free_bitmap.as_mut().unwrap() };
+        let word = &mut bitmap[self.order_starts[order] + index / 64];
+        if free {
+            *word |= 1 << (index % 64);
+        } else {
+            *word &= !(1 << (index % 64));
+        }
+    }
+
+    /// The order of the free block the frame is in, or None if it's allocated
+    fn free_order_of(&self, frame: PhyAddr) -> Option<usize> {
+        let index = self.frame_index(frame);
+        (0..ORDER_COUNT).find(|&order| self.is_free(order, index >> order))
+    }
+
+    fn block(addr: u64) -> *mut FreeBlock {
+        PhyAddr(addr).as_virtual().0 as *mut FreeBlock
+    }
+
+    /// Add a block to the front of the free list of the order
+    unsafe fn push(&mut self, order: usize, addr: u64) {
+        let head = self.free_lists[order];
+        unsafe {
+            Self::block(addr).write(FreeBlock {
+                next: head,
+                prev: NO_BLOCK,
+            });
+            if head != NO_BLOCK {
+                (*Self::block(head)).prev = addr;
+            }
+        }
+        self.free_lists[order] = addr;
+        self.set_free(order, self.frame_index(PhyAddr(addr)) >> order, true);
+        self.free_frames += 1 << order;
+    }
+
+    /// Remove a block from the free list of the order. It must be in it.
+    unsafe fn remove(&mut self, order: usize, addr: u64) {
+        let FreeBlock { next, prev } = unsafe { Self::block(addr).read() };
+        unsafe {
+            if prev == NO_BLOCK {
+                self.free_lists[order] = next;
+            } else {
+                (*Self::block(prev)).next = next;
+            }
+            if next != NO_BLOCK {
+                (*Self::block(next)).prev = prev;
+            }
+        }
+        self.set_free(order, self.frame_index(PhyAddr(addr)) >> order, false);
+        self.free_frames -= 1 << order;
+    }
+
+    /// Allocate a specific frame. Returns false if it's already allocated.
+    unsafe fn take_frame(&mut self, frame: PhyAddr) -> bool {
+        let Some(mut order) = self.free_order_of(frame) else {
+            return false;
+        };
+        let index = self.frame_index(frame);
+        unsafe { self.remove(order, self.block_addr(order, index >> order)) };
+        // split it, giving back the halves the frame isn't in
+        while order > 0 {
+            order -= 1;
+            let other_half = (index >> order) ^ 1;
+            unsafe { self.push(order, self.block_addr(order, other_half)) };
+        }
+        true
+    }
+}
+
+/// safety: you need unsafe to use the pointer anyways
+unsafe impl Send for BuddyPhysicalAllocator {}
+
+unsafe impl PhysicalAllocator for BuddyPhysicalAllocator {
+    unsafe fn allocate_frame(&mut self) -> Option<PhyAddr> {
+        unsafe { self.allocate_block(0) }
+    }
+
+    unsafe fn free_frame(&mut self, frame: PhyAddr) {
+        if !frame.0.is_multiple_of(Self::frame_size()) {
+            panic!("WHAT IS THIS ALIGNMENT?! ptr: {:x}", frame.0);
+        }
+        unsafe { self.free_block(frame, 0) };
+    }
+
+    /// Frames we don't manage (i.e. device memory) are always available, since we don't keep track of them.
+    unsafe fn alloc_phy_addr(&mut self, phy_addr: PhyAddr, frame_count: usize) -> Option<PhyAddr> {
+        if !phy_addr.0.is_multiple_of(Self::frame_size()) {
+            panic!("bad alignment. ptr: {:?}", phy_addr);
+        }
+        self.ensure_built();
+        let frames = || (0..frame_count as u64).map(|i| PhyAddr(phy_addr.0 + i * FRAME_SIZE));
+        if frames().any(|frame| self.manages_frame(frame) && self.free_order_of(frame).is_none()) {
+            return None;
+        }
+        for frame in frames() {
+            if self.manages_frame(frame) {
+                unsafe { self.take_frame(frame) };
+            }
+        }
+        Some(phy_addr)
+    }
+
+    fn manages_frame(&self, frame: PhyAddr) -> bool {
+        if frame.0 < self.offset.0 {
+            return false;
+        }
+        let index = self.frame_index(frame);
+        (0..self.region_count.max(1)).any(|region| self.region_frames(region).contains(&index))
+    }
+
+    fn frame_size() -> u64 {
+        FRAME_SIZE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::virt::GLOBAL_PAGE_ALLOCATOR;
+    use crate::should_panic_with;
+
+    const FRAMES: usize = 64;
+
+    /// Run f with an allocator of its own which manages FRAMES frames taken from the global one.
+    /// The global lock isn't held while f runs.
+    fn with_isolated(f: impl FnOnce(&mut BuddyPhysicalAllocator, PhyAddr)) {
+        let region = unsafe {
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .allocate_block(6)
+        }
+        .unwrap();
+        let mut bitmap = [0u64; BuddyPhysicalAllocator::metadata_words(FRAMES)];
+        let mut allocator = unsafe { BuddyPhysicalAllocator::new(&raw mut bitmap, region, FRAMES) };
+        f(&mut allocator, region);
+        unsafe {
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .free_block(region, 6)
+        };
+    }
+
+    #[test_case]
+    fn frame_round_trip() {
+        with_isolated(|allocator, region| {
+            let region_range = region.0..region.0 + FRAMES as u64 * FRAME_SIZE;
+            assert_eq!(allocator.free_frames(), FRAMES);
+            let mut frames = [PhyAddr(0); FRAMES];
+            for i in 0..FRAMES {
+                let frame = unsafe { allocator.allocate_frame() }.unwrap();
+                assert!(region_range.contains(&frame.0));
+                assert!(!frames[..i].contains(&frame));
+                frames[i] = frame;
+            }
+            // out of memory
+            assert_eq!(unsafe { allocator.allocate_frame() }, None);
+            assert_eq!(allocator.free_frames(), 0);
+            for frame in frames {
+                unsafe { allocator.free_frame(frame) };
+            }
+            assert_eq!(allocator.free_frames(), FRAMES);
+            // everything merged back into a single block
+            assert_eq!(unsafe { allocator.allocate_block(6) }, Some(region));
+        });
+    }
+
+    #[test_case]
+    fn contiguous_frames() {
+        with_isolated(|allocator, region| {
+            let start = PhyAddr(region.0 + 5 * FRAME_SIZE);
+            assert_eq!(unsafe { allocator.alloc_phy_addr(start, 3) }, Some(start));
+            assert_eq!(allocator.free_frames(), FRAMES - 3);
+            assert_eq!(unsafe { allocator.alloc_phy_addr(start, 3) }, None);
+            // overlaps the allocated frames
+            assert_eq!(
+                unsafe { allocator.alloc_phy_addr(PhyAddr(start.0 - FRAME_SIZE), 2) },
+                None
+            );
+
+            let block = unsafe { allocator.allocate_block(3) }.unwrap();
+            assert!((block.0 - region.0).is_multiple_of(8 * FRAME_SIZE));
+            let block_range = block.0..block.0 + 8 * FRAME_SIZE;
+            for i in 0..3 {
+                assert!(!block_range.contains(&(start.0 + i * FRAME_SIZE)));
+            }
+
+            unsafe { allocator.free_block(block, 3) };
+            for i in 0..3 {
+                unsafe { allocator.free_frame(PhyAddr(start.0 + i * FRAME_SIZE)) };
+            }
+            assert_eq!(unsafe { allocator.allocate_block(6) }, Some(region));
+        });
+    }
+
+    #[test_case]
+    fn double_free() {
+        with_isolated(|allocator, _| {
+            let frame = unsafe { allocator.allocate_frame() }.unwrap();
+            unsafe { allocator.free_frame(frame) };
+            // the test ends here, so the region is never given back to the global allocator
+            should_panic_with!("double free of block at");
+            unsafe { allocator.free_frame(frame) };
+        });
+    }
+}
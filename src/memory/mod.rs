@@ -1,8 +1,229 @@
 pub mod allocator;
+pub mod buddy;
 pub mod paging;
 pub mod physical;
+pub mod tlb;
 pub mod virt;
 
-pub fn init() {
-    virt::init();
+use core::{
+    fmt::{Display, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use limine::memory_map::{Entry, EntryType};
+
+use crate::{
+    LIMINE_MEMORY_MAP,
+    arch_x86_64::CpuFeatures,
+    console_print,
+    error::KernelError,
+    msr::{EFER, EFER_NXE, rdmsr, wrmsr},
+    util::HumanBytes,
+};
+
+/// Whether EFER.NXE was enabled, i.e. whether PageTableEntryFlags::NO_EXECUTE may be used
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether memory::init was called, i.e. whether physical memory can be mapped
+static MEMORY_READY: AtomicBool = AtomicBool::new(false);
+
+/// Why memory couldn't be initialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The bootloader didn't give us a memory map
+    NoMemoryMap,
+    /// The memory map doesn't have a single usable entry
+    NoUsableMemory,
+}
+
+impl Display for MemoryError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MemoryError::NoMemoryMap => write!(
+                f,
+                "the bootloader didn't provide a memory map, check that it supports the Limine protocol"
+            ),
+            MemoryError::NoUsableMemory => write!(
+                f,
+                "the memory map has no usable memory, give the machine more RAM"
+            ),
+        }
+    }
+}
+
+pub fn init() -> Result<(), KernelError> {
+    #[cfg(feature = "verbose")]
+    print_memory_map();
+    enable_no_execute();
+    virt::init()?;
+    MEMORY_READY.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Panic with a clear message if memory::init wasn't called yet, rather than faulting somewhere in map_physical.
+/// Devices should call it before they map their registers.
+#[track_caller]
+pub fn assert_memory_ready() {
+    assert_ready(&MEMORY_READY);
+}
+
+#[track_caller]
+fn assert_ready(ready: &AtomicBool) {
+    if !ready.load(Ordering::Relaxed) {
+        panic!("memory not initialized");
+    }
+}
+
+/// Allow PageTableEntryFlags::NO_EXECUTE to be used, if the cpu supports it.
+/// Without it, bit 63 of a page table entry is reserved and setting it causes a page fault.
+/// Must be called on every cpu (EFER is per cpu) before it uses pages mapped with NO_EXECUTE.
+pub fn enable_no_execute() {
+    if !CpuFeatures::detect().has_nx {
+        return;
+    }
+    unsafe {
+        let efer = rdmsr(EFER);
+        wrmsr(EFER, efer | EFER_NXE);
+    }
+    NO_EXECUTE_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether PageTableEntryFlags::NO_EXECUTE can be used. If not, mapping functions strip it.
+pub fn no_execute_enabled() -> bool {
+    NO_EXECUTE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Every type a Limine memory map entry can have, with its name
+const ENTRY_TYPES: [(EntryType, &str); 8] = [
+    (EntryType::USABLE, "USABLE"),
+    (EntryType::RESERVED, "RESERVED"),
+    (EntryType::ACPI_RECLAIMABLE, "ACPI_RECLAIMABLE"),
+    (EntryType::ACPI_NVS, "ACPI_NVS"),
+    (EntryType::BAD_MEMORY, "BAD_MEMORY"),
+    (EntryType::BOOTLOADER_RECLAIMABLE, "BOOTLOADER_RECLAIMABLE"),
+    (EntryType::EXECUTABLE_AND_MODULES, "EXECUTABLE_AND_MODULES"),
+    (EntryType::FRAMEBUFFER, "FRAMEBUFFER"),
+];
+/// Entries with a type newer than this kernel are totaled here, after the known ones
+const UNKNOWN_ENTRY_TYPE: usize = ENTRY_TYPES.len();
+
+fn entry_type_index(entry_type: EntryType) -> usize {
+    ENTRY_TYPES
+        .iter()
+        .position(|&(t, _)| t == entry_type)
+        .unwrap_or(UNKNOWN_ENTRY_TYPE)
+}
+
+fn entry_type_name(index: usize) -> &'static str {
+    ENTRY_TYPES.get(index).map_or("UNKNOWN", |&(_, name)| name)
+}
+
+/// Write one line per entry, then the total size of every type which appears in the map.
+fn write_memory_map(out: &mut impl Write, entries: &[&Entry]) -> core::fmt::Result {
+    let mut totals = [0u64; ENTRY_TYPES.len() + 1];
+    for entry in entries {
+        let index = entry_type_index(entry.entry_type);
+        totals[index] += entry.length;
+        writeln!(
+            out,
+            "0x{:016x}-0x{:016x} {:<22} {}",
+            entry.base,
+            entry.base + entry.length,
+            entry_type_name(index),
+            HumanBytes(entry.length)
+        )?;
+    }
+    for (index, total) in totals.into_iter().enumerate() {
+        if total != 0 {
+            writeln!(
+                out,
+                "total {:<22} {}",
+                entry_type_name(index),
+                HumanBytes(total)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the memory map Limine gave us, i.e. to find out why there's less usable memory than expected.
+pub fn print_memory_map() {
+    struct ConsoleWriter;
+    impl Write for ConsoleWriter {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            console_print!("{}", s);
+            Ok(())
+        }
+    }
+    let Some(response) = LIMINE_MEMORY_MAP.get_response() else {
+        console_print!("no memory map\n");
+        return;
+    };
+    write_memory_map(&mut ConsoleWriter, response.entries()).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{
+        paging::{PageTable, PageTableEntryFlags},
+        virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator},
+    };
+    use crate::should_panic_at;
+
+    #[test_case]
+    fn no_execute_data_access() {
+        assert!(no_execute_enabled());
+        assert!(unsafe { rdmsr(EFER) } & EFER_NXE != 0);
+        unsafe {
+            let alloc = GLOBAL_PAGE_ALLOCATOR.alloc_pages(1).unwrap();
+            let entry = PageTable::current().page_entry(alloc.first_page).unwrap();
+            assert!(entry.flags().contains(PageTableEntryFlags::NO_EXECUTE));
+            // reading and writing a no execute page shouldn't fault
+            let ptr = alloc.as_virt_addr().0 as *mut u64;
+            ptr.write_volatile(0xdead_beef);
+            assert_eq!(ptr.read_volatile(), 0xdead_beef);
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+        }
+    }
+
+    #[test_case]
+    fn memory_ready_guard() {
+        assert_memory_ready();
+        // memory is already initialized, so pretend it isn't
+        let not_ready = AtomicBool::new(false);
+        should_panic_at!(line!() + 1);
+        assert_ready(&not_ready);
+    }
+
+    #[test_case]
+    fn memory_map_lines_and_totals() {
+        use alloc::string::String;
+        let entry = |base, length, entry_type| Entry {
+            base,
+            length,
+            entry_type,
+        };
+        let entries = [
+            entry(0, 0x9f000, EntryType::USABLE),
+            entry(0x9f000, 0x1000, EntryType::RESERVED),
+            entry(0x100000, 0x700000, EntryType::USABLE),
+            entry(0x800000, 0x2000, EntryType::ACPI_NVS),
+            entry(0xfd000000, 0x400000, EntryType::FRAMEBUFFER),
+            entry(0x1_0000_0000, 0x1000, EntryType::from(0x1234)),
+        ];
+        let entries: [&Entry; 6] = entries.each_ref();
+        let mut out = String::new();
+        write_memory_map(&mut out, &entries).unwrap();
+        let lines: alloc::vec::Vec<&str> = out.lines().collect();
+        // one per entry, then one per type
+        assert_eq!(lines.len(), 6 + 5);
+        assert!(lines[0].starts_with("0x0000000000000000-0x000000000009f000 USABLE"));
+        assert!(lines[0].ends_with(" 636 KiB"));
+        assert!(lines[5].contains("UNKNOWN"));
+        assert!(lines[6].starts_with("total USABLE") && lines[6].ends_with(" 7.6 MiB"));
+        assert!(lines[7].starts_with("total RESERVED") && lines[7].ends_with(" 4 KiB"));
+        assert!(lines[8].starts_with("total ACPI_NVS") && lines[8].ends_with(" 8 KiB"));
+        assert!(lines[9].starts_with("total FRAMEBUFFER") && lines[9].ends_with(" 4 MiB"));
+        assert!(lines[10].starts_with("total UNKNOWN") && lines[10].ends_with(" 4 KiB"));
+    }
 }
@@ -1,7 +1,7 @@
-use core::fmt::Debug;
+use core::fmt::{Debug, Display, Write};
 
 use crate::{
-    arch_x86_64::cr3,
+    arch_x86_64::{cr3, invlpg},
     memory::{
         physical::{PhyAddr, PhysicalAllocator},
         virt::VirtAddr,
@@ -15,7 +15,38 @@ pub struct PageTableEntry {
 
 impl Debug for PageTableEntry {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", PageTableEntryFlags::from_bits_retain(self.entry))
+        if self.entry == 0 {
+            return write!(f, "PTE {{ not present }}");
+        }
+        write!(f, "PTE {{ phys: {:?}, flags: ", self.addr())?;
+        bitflags::parser::to_writer(&self.flags(), &mut *f)?;
+        write!(f, " }}")
+    }
+}
+
+/// One character per flag, '-' if it isn't set, i.e. "PW---A--GX" for kernel data which was accessed
+impl Display for PageTableEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.entry == 0 {
+            return write!(f, "not present");
+        }
+        const FLAG_CHARS: [(PageTableEntryFlags, char); 10] = [
+            (PageTableEntryFlags::PRESENT, 'P'),
+            (PageTableEntryFlags::WRITABLE, 'W'),
+            (PageTableEntryFlags::USER_ALLOWED, 'U'),
+            (PageTableEntryFlags::CACHE_WRITE_THROUGH, 'T'),
+            (PageTableEntryFlags::NO_CACHE, 'C'),
+            (PageTableEntryFlags::ACCESSED, 'A'),
+            (PageTableEntryFlags::DIRTY, 'D'),
+            (PageTableEntryFlags::HUGE_PAGE, 'H'),
+            (PageTableEntryFlags::GLOBAL, 'G'),
+            (PageTableEntryFlags::NO_EXECUTE, 'X'),
+        ];
+        let flags = self.flags();
+        for (flag, c) in FLAG_CHARS {
+            f.write_char(if flags.contains(flag) { c } else { '-' })?;
+        }
+        Ok(())
     }
 }
 
@@ -94,6 +125,46 @@ bitflags::bitflags! {
 
 }
 
+impl PageTableEntryFlags {
+    /// Kernel data: writable, not executable, and global since every address space shares the kernel.
+    pub const fn kernel_data() -> Self {
+        Self::PRESENT
+            .union(Self::WRITABLE)
+            .union(Self::NO_EXECUTE)
+            .union(Self::GLOBAL)
+    }
+
+    /// Kernel code: executable, but not writable.
+    pub const fn kernel_code() -> Self {
+        Self::PRESENT.union(Self::GLOBAL)
+    }
+
+    /// Memory mapped device registers: must not be cached, and should never be executed.
+    pub const fn mmio() -> Self {
+        Self::PRESENT
+            .union(Self::WRITABLE)
+            .union(Self::NO_CACHE)
+            .union(Self::NO_EXECUTE)
+    }
+
+    /// Data accessible by user mode: writable and not executable.
+    pub const fn user_data() -> Self {
+        Self::PRESENT
+            .union(Self::WRITABLE)
+            .union(Self::USER_ALLOWED)
+            .union(Self::NO_EXECUTE)
+    }
+
+    /// Flags for the level4/level3/level2 entries which point at a page table.
+    /// These are as permissive as possible since they are shared by every page under them,
+    /// the actual restrictions are done by the level1 entries.
+    const fn page_table(leaf_flags: Self) -> Self {
+        Self::PRESENT
+            .union(Self::WRITABLE)
+            .union(leaf_flags.intersection(Self::USER_ALLOWED))
+    }
+}
+
 pub const PAGE_TABLE_ENTRY_NUM: usize = 512;
 
 #[repr(align(4096))]
@@ -107,6 +178,8 @@ pub struct Page {
 }
 
 pub const PAGE_SIZE: u64 = 0x1000;
+/// Size of a page mapped directly by a level 2 entry, see PageTable::map_huge_page_unchecked
+pub const HUGE_PAGE_SIZE: u64 = PAGE_SIZE * PAGE_TABLE_ENTRY_NUM as u64;
 
 impl From<VirtAddr> for Page {
     fn from(value: VirtAddr) -> Self {
@@ -118,8 +191,10 @@ impl From<VirtAddr> for Page {
 }
 
 impl From<Page> for VirtAddr {
+    /// Note: the resulting address is always canonical (bit 47 is sign extended),
+    /// so higher half pages round-trip correctly even if they were created from their canonical number.
     fn from(value: Page) -> Self {
-        VirtAddr(value.num * PAGE_SIZE)
+        VirtAddr(value.num.wrapping_mul(PAGE_SIZE)).canonicalize()
     }
 }
 
@@ -173,14 +248,40 @@ impl Page {
             None
         }
     }
+
+    /// The pages from start up to, but not including, end. Like start..end
+    pub fn range(start: Page, end: Page) -> PageIter {
+        if end <= start {
+            let mut empty = PageIter::new(start, start);
+            empty.done = true;
+            return empty;
+        }
+        PageIter::new(start, Page { num: end.num - 1 })
+    }
+
+    /// The pages from start to end, including end. Like start..=end
+    pub fn range_inclusive(start: Page, end: Page) -> PageIter {
+        PageIter::new(start, end)
+    }
 }
 
+/// Iterates the pages from start to end, inclusive. See Page::range and Page::range_inclusive.
 #[derive(Debug)]
 pub struct PageIter {
     pub start: Page,
     pub end: Page,
+    // set once end was yielded, since end may be the last page, which has no next page to move start to
+    done: bool,
 }
 impl PageIter {
+    pub fn new(start: Page, end: Page) -> Self {
+        PageIter {
+            start,
+            end,
+            done: false,
+        }
+    }
+
     pub fn first(&self) -> Page {
         self.start
     }
@@ -192,21 +293,25 @@ impl PageIter {
 impl Iterator for PageIter {
     type Item = Page;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.start <= self.end {
-            let out = self.start;
-            // this unwrap will panic if self.end == limit
-            self.start = self.start.next().unwrap();
-            Some(out)
-        } else {
-            None
+        if self.done || self.start > self.end {
+            return None;
         }
+        let out = self.start;
+        match self.start.next() {
+            Some(next) if out != self.end => self.start = next,
+            _ => self.done = true,
+        }
+        Some(out)
     }
 }
 
 #[derive(Debug)]
 pub enum PageEntryError {
+    /// The page is inside a 1 GiB page, which we don't support
     HugePage,
-    PageTableLevelIsNotPresent { level: usize },
+    PageTableLevelIsNotPresent {
+        level: usize,
+    },
 }
 
 impl PageTable {
@@ -247,6 +352,8 @@ impl PageTable {
         unsafe { page_table.as_mut().unwrap() }
     }
 
+    /// The entry which maps the page. For a page inside a 2 MiB page, it's the level 2 entry
+    /// (with PageTableEntryFlags::HUGE_PAGE), whose address is the start of the 2 MiB page.
     pub fn page_entry(&self, page: Page) -> Result<&PageTableEntry, PageEntryError> {
         let page_dir_table_ptr_entry = self.entries[page.level4_idx()];
         let mut page_level = 4;
@@ -255,16 +362,28 @@ impl PageTable {
             unsafe {
                 let page_dir_entry =
                     page_dir_table_ptr_entry.as_page_table().entries[page.level3_idx()];
+                if page_dir_entry
+                    .flags()
+                    .contains(PageTableEntryFlags::HUGE_PAGE)
+                {
+                    return Err(PageEntryError::HugePage);
+                }
 
                 if page_dir_entry.present() {
                     page_level = 2;
-                    let page_table_entry =
-                        page_dir_entry.as_page_table().entries[page.level2_idx()];
+                    let page_table_entry = page_dir_entry
+                        .as_page_table()
+                        .entries
+                        .get(page.level2_idx())
+                        // this is safe since the lifetime of this is tied to the pagetable anyways
+                        .map(|p| (p as *const PageTableEntry).as_ref().unwrap())
+                        .unwrap();
                     if page_table_entry
                         .flags()
                         .contains(PageTableEntryFlags::HUGE_PAGE)
                     {
-                        return Err(PageEntryError::HugePage);
+                        // the whole 2 MiB page is mapped by it
+                        return Ok(page_table_entry);
                     }
                     if page_table_entry.present() {
                         return Ok(page_table_entry
@@ -281,6 +400,37 @@ impl PageTable {
         Err(PageEntryError::PageTableLevelIsNotPresent { level: page_level })
     }
 
+    /// The physical address addr is mapped to, or None if it isn't mapped (or isn't canonical).
+    /// Huge pages (both 2 MiB and 1 GiB) are supported.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhyAddr> {
+        if !addr.is_valid() {
+            return None;
+        }
+        let page = Page::from(addr);
+        let indices = [
+            page.level4_idx(),
+            page.level3_idx(),
+            page.level2_idx(),
+            page.level1_idx(),
+        ];
+        let mut table = self;
+        for (level, idx) in (1..=4).rev().zip(indices) {
+            let entry = &table.entries[idx];
+            if !entry.present() {
+                return None;
+            }
+            let is_huge = level != 4 && entry.flags().contains(PageTableEntryFlags::HUGE_PAGE);
+            if level == 1 || is_huge {
+                let size = PAGE_SIZE << (9 * (level - 1));
+                // the low bits of a huge entry's address are flags (PAT), not part of the address
+                return Some(PhyAddr((entry.addr().0 & !(size - 1)) + addr.0 % size));
+            }
+            table = unsafe { entry.as_page_table() };
+        }
+        unreachable!()
+    }
+
+    /// Like page_entry, but None for pages inside 1 GiB pages as well.
     pub fn page_entry_mut(&mut self, page: Page) -> Option<&mut PageTableEntry> {
         let page_dir_table_ptr_entry = self.entries.get_mut(page.level4_idx()).unwrap();
 
@@ -292,13 +442,24 @@ impl PageTable {
                     .get_mut(page.level3_idx())
                     .unwrap();
 
-                if page_dir_entry.present() {
+                if page_dir_entry.present()
+                    && !page_dir_entry
+                        .flags()
+                        .contains(PageTableEntryFlags::HUGE_PAGE)
+                {
                     let page_table_entry = page_dir_entry
                         .as_page_table_mut()
                         .entries
                         .get_mut(page.level2_idx())
+                        .map(|p| (p as *mut PageTableEntry).as_mut().unwrap())
                         .unwrap();
 
+                    if page_table_entry
+                        .flags()
+                        .contains(PageTableEntryFlags::HUGE_PAGE)
+                    {
+                        return Some(page_table_entry);
+                    }
                     if page_table_entry.present() {
                         return page_table_entry
                             .as_page_table_mut()
@@ -326,6 +487,9 @@ impl PageTable {
     /// ## Safety:
     /// the PhysicalAllocator should be valid, and you are responsible for making sure you're not overriding some important page.
     /// Note: this does not check if the given page is already present/have some flags. You're responsible for that.
+    /// Note: newly created page tables get PageTableEntryFlags::page_table(flags) rather than flags,
+    /// so that restrictive flags (i.e. NO_EXECUTE) don't leak into other pages which share the same tables.
+    /// Note: NO_EXECUTE is dropped if the cpu doesn't support it (see memory::enable_no_execute).
     pub unsafe fn map_page_unchecked(
         &mut self,
         page: Page,
@@ -334,65 +498,200 @@ impl PageTable {
         phy_mem_alloc: &mut impl PhysicalAllocator,
     ) {
         assert!(phy_addr.0.is_multiple_of(PAGE_SIZE));
-        let page_dir_ptr_table_entry = self.entries.get_mut(page.level4_idx()).unwrap();
+        let flags = if crate::memory::no_execute_enabled() {
+            flags
+        } else {
+            flags.difference(PageTableEntryFlags::NO_EXECUTE)
+        };
+        let table_flags = PageTableEntryFlags::page_table(flags);
+        let page_table = unsafe {
+            let page_dir_ptr_table = Self::table_or_create(
+                &mut self.entries[page.level4_idx()],
+                table_flags,
+                phy_mem_alloc,
+            );
+            let page_dir = Self::table_or_create(
+                &mut page_dir_ptr_table.entries[page.level3_idx()],
+                table_flags,
+                phy_mem_alloc,
+            );
+            Self::table_or_create(
+                &mut page_dir.entries[page.level2_idx()],
+                table_flags,
+                phy_mem_alloc,
+            )
+        };
+        let page_entry = page_table.entries.get_mut(page.level1_idx()).unwrap();
+        page_entry.set_addr(phy_addr, flags);
+    }
 
-        if !page_dir_ptr_table_entry.present() {
-            let frame = unsafe { phy_mem_alloc.allocate_frame() };
-            page_dir_ptr_table_entry.set_addr(frame, flags);
-            unsafe {
-                page_dir_ptr_table_entry
-                    .as_page_table_mut()
-                    .clear_all_entries();
-            }
-        }
-        let page_dir_entry = unsafe {
-            page_dir_ptr_table_entry
-                .as_page_table_mut()
-                .entries
-                .get_mut(page.level3_idx())
-                .unwrap()
+    /// Map a 2 MiB page, starting at page, directly from a level 2 entry. Same as map_page_unchecked otherwise.
+    /// Note: whatever the level 2 entry pointed to before (i.e. a level 1 table) is simply forgotten.
+    ///
+    /// # Safety
+    /// Same as map_page_unchecked: the PhysicalAllocator should be valid, and nothing important may be mapped
+    /// in the 2 MiB starting at page, since all of it gets replaced.
+    /// ## Panic
+    /// Panics if page or phy_addr aren't 2 MiB aligned.
+    pub unsafe fn map_huge_page_unchecked(
+        &mut self,
+        page: Page,
+        phy_addr: PhyAddr,
+        flags: PageTableEntryFlags,
+        phy_mem_alloc: &mut impl PhysicalAllocator,
+    ) {
+        assert!(phy_addr.0.is_multiple_of(HUGE_PAGE_SIZE));
+        assert_eq!(page.level1_idx(), 0);
+        let flags = if crate::memory::no_execute_enabled() {
+            flags
+        } else {
+            flags.difference(PageTableEntryFlags::NO_EXECUTE)
+        };
+        let table_flags = PageTableEntryFlags::page_table(flags);
+        let page_dir = unsafe {
+            let page_dir_ptr_table = Self::table_or_create(
+                &mut self.entries[page.level4_idx()],
+                table_flags,
+                phy_mem_alloc,
+            );
+            Self::table_or_create(
+                &mut page_dir_ptr_table.entries[page.level3_idx()],
+                table_flags,
+                phy_mem_alloc,
+            )
         };
+        page_dir.entries[page.level2_idx()]
+            .set_addr(phy_addr, flags | PageTableEntryFlags::HUGE_PAGE);
+    }
 
-        if !page_dir_entry.present() {
-            let frame = unsafe { phy_mem_alloc.allocate_frame() };
-            page_dir_entry.set_addr(frame, flags);
+    /// The table the entry points to. If it isn't present, a new empty table is allocated for it.
+    /// ## Panic
+    /// Panics if phy_mem_alloc is out of frames for the new table.
+    unsafe fn table_or_create<'a>(
+        entry: &'a mut PageTableEntry,
+        table_flags: PageTableEntryFlags,
+        phy_mem_alloc: &mut impl PhysicalAllocator,
+    ) -> &'a mut PageTable {
+        if !entry.present() {
+            let frame =
+                unsafe { phy_mem_alloc.allocate_frame() }.expect("out of memory for page tables");
+            entry.set_addr(frame, table_flags);
             unsafe {
-                page_dir_entry.as_page_table_mut().clear_all_entries();
+                entry.as_page_table_mut().clear_all_entries();
             }
         }
+        unsafe { entry.as_page_table_mut() }
+    }
 
-        let page_table_entry = unsafe {
-            page_dir_entry
-                .as_page_table_mut()
-                .entries
-                .get_mut(page.level2_idx())
-                .unwrap()
-        };
+    /// Unmap a single page, and free the page tables which are left without any present entry.
+    /// Returns the frame the page was mapped to, or None if it wasn't mapped. The frame itself isn't freed.
+    /// Note: tables which phy_mem_alloc doesn't manage (i.e. the ones the bootloader created) are never freed.
+    /// The page is only dropped from the TLB of the current cpu, see tlb::shootdown for the others.
+    ///
+    /// # Safety
+    /// the PhysicalAllocator should be the one the page tables were allocated with,
+    /// and no one may use the page after it's unmapped.
+    pub unsafe fn unmap_page(
+        &mut self,
+        page: Page,
+        phy_mem_alloc: &mut impl PhysicalAllocator,
+    ) -> Option<PhyAddr> {
+        let page_dir_ptr_table_entry = self.entries.get_mut(page.level4_idx()).unwrap();
+        if !page_dir_ptr_table_entry.present() {
+            return None;
+        }
+        let page_dir_ptr_table = unsafe { page_dir_ptr_table_entry.as_page_table_mut() };
+        let page_dir_entry = page_dir_ptr_table
+            .entries
+            .get_mut(page.level3_idx())
+            .unwrap();
+        if !page_dir_entry.present()
+            || page_dir_entry
+                .flags()
+                .contains(PageTableEntryFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        let page_dir = unsafe { page_dir_entry.as_page_table_mut() };
+        let page_table_entry = page_dir.entries.get_mut(page.level2_idx()).unwrap();
+        if !page_table_entry.present()
+            || page_table_entry
+                .flags()
+                .contains(PageTableEntryFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        let page_table = unsafe { page_table_entry.as_page_table_mut() };
+        let page_entry = page_table.entries.get_mut(page.level1_idx()).unwrap();
+        if !page_entry.present() {
+            return None;
+        }
+        let frame = page_entry.addr();
+        page_entry.clear();
 
-        if !page_table_entry.present() {
-            let frame = unsafe { phy_mem_alloc.allocate_frame() };
-            page_table_entry.set_addr(frame, flags);
-            unsafe {
-                page_table_entry.as_page_table_mut().clear_all_entries();
-            }
+        // from the bottom up, a table can only be empty if the one below it was freed
+        unsafe {
+            let _ = page_table.is_empty()
+                && Self::free_table(page_table_entry, phy_mem_alloc)
+                && page_dir.is_empty()
+                && Self::free_table(page_dir_entry, phy_mem_alloc)
+                && page_dir_ptr_table.is_empty()
+                && Self::free_table(page_dir_ptr_table_entry, phy_mem_alloc);
         }
-        let page_entry = unsafe {
-            page_table_entry
-                .as_page_table_mut()
-                .entries
-                .get_mut(page.level1_idx())
-                .unwrap()
-        };
-        page_entry.set_addr(phy_addr, flags);
+        // also drops the cached entries of the tables we freed
+        unsafe { invlpg(VirtAddr::from(page).0) };
+        Some(frame)
+    }
+
+    /// Whether none of the entries is present
+    fn is_empty(&self) -> bool {
+        self.entries.iter().all(|e| !e.present())
+    }
+
+    /// Free the table the entry points to and clear the entry, unless phy_mem_alloc doesn't manage it.
+    /// Returns whether it was freed.
+    unsafe fn free_table(
+        entry: &mut PageTableEntry,
+        phy_mem_alloc: &mut impl PhysicalAllocator,
+    ) -> bool {
+        let frame = entry.addr();
+        if !phy_mem_alloc.manages_frame(frame) {
+            return false;
+        }
+        entry.clear();
+        unsafe { phy_mem_alloc.free_frame(frame) };
+        true
     }
 
     pub fn find_free_pages(&self, start_page: Page, num_pages: usize) -> Option<PageIter> {
+        self.find_free_pages_in(start_page, None, num_pages)
+    }
+
+    /// Like find_free_pages, but all the pages found come before end.
+    pub fn find_free_pages_before(
+        &self,
+        start_page: Page,
+        end: Page,
+        num_pages: usize,
+    ) -> Option<PageIter> {
+        if start_page >= end {
+            return None;
+        }
+        self.find_free_pages_in(start_page, Some(end), num_pages)
+    }
+
+    fn find_free_pages_in(
+        &self,
+        start_page: Page,
+        end: Option<Page>,
+        num_pages: usize,
+    ) -> Option<PageIter> {
         // we don't start with num 0 for obvious reasons
         let mut first_page = start_page;
         let mut free_page_count = if self.is_present(first_page) { 0 } else { 1 };
         let mut page = first_page;
         while free_page_count < num_pages
-            && let Some(next_page) = page.next()
+            && let Some(next_page) = page.next().filter(|p| end.is_none_or(|end| *p < end))
         {
             if !self.is_present(next_page) {
                 if free_page_count == 0 {
@@ -405,10 +704,7 @@ impl PageTable {
             page = next_page;
         }
         if free_page_count == num_pages {
-            Some(PageIter {
-                start: first_page,
-                end: page,
-            })
+            Some(Page::range_inclusive(first_page, page))
         } else {
             None
         }
@@ -419,6 +715,42 @@ impl PageTable {
 mod test {
     use super::*;
     use crate::should_panic;
+    use alloc::format;
+
+    #[test_case]
+    fn page_ranges() {
+        let start = Page::new(10);
+        let end = Page::new(13);
+        let exclusive = Page::range(start, end)
+            .map(|p| p.num())
+            .collect::<alloc::vec::Vec<_>>();
+        assert_eq!(exclusive, [10, 11, 12]);
+        let inclusive = Page::range_inclusive(start, end).map(|p| p.num());
+        assert!(inclusive.eq([10, 11, 12, 13]));
+        // empty
+        assert_eq!(Page::range(start, start).count(), 0);
+        assert_eq!(Page::range(end, start).count(), 0);
+        assert_eq!(Page::range_inclusive(start, start).count(), 1);
+        assert_eq!(Page::range_inclusive(end, start).count(), 0);
+    }
+
+    #[test_case]
+    fn entry_formatting() {
+        let mut entry = PageTableEntry::new();
+        assert_eq!(format!("{:?}", entry), "PTE { not present }");
+        assert_eq!(format!("{}", entry), "not present");
+        entry.set_addr(
+            PhyAddr(0x1234000),
+            PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+        );
+        assert_eq!(
+            format!("{:?}", entry),
+            "PTE { phys: 0x1234000, flags: PRESENT | WRITABLE }"
+        );
+        assert_eq!(format!("{}", entry), "PW--------");
+        entry.set_flags(PageTableEntryFlags::kernel_data() | PageTableEntryFlags::ACCESSED);
+        assert_eq!(format!("{}", entry), "PW---A--GX");
+    }
 
     #[test_case]
     fn page_to_idx() {
@@ -492,6 +824,28 @@ mod test {
         );
     }
 
+    #[test_case]
+    fn higher_half_round_trip() {
+        let kernel_addr = VirtAddr(0xffffffff80000000);
+        assert_eq!(VirtAddr::from(Page::from(kernel_addr)), kernel_addr);
+        // a page created from its canonical number should still give back the higher half address
+        let canonical = Page::new(Page::from(kernel_addr).canonical_num() as u64);
+        assert_eq!(VirtAddr::from(canonical), kernel_addr);
+        // the boundary at bit 47: the last lower half page and the first higher half page
+        let last_lower = Page::new(0x7_ffff_ffff);
+        assert_eq!(VirtAddr::from(last_lower), VirtAddr(0x7fff_ffff_f000));
+        let first_higher = Page::new(0x8_0000_0000);
+        assert_eq!(
+            VirtAddr::from(first_higher),
+            VirtAddr(0xffff_8000_0000_0000)
+        );
+        assert_eq!(
+            VirtAddr(0x0000_8000_0000_1234).canonicalize(),
+            VirtAddr(0xffff_8000_0000_1234)
+        );
+        assert_eq!(VirtAddr(0x1234).canonicalize(), VirtAddr(0x1234));
+    }
+
     #[test_case]
     fn virtual_to_page() {
         let virt_addr = VirtAddr(0x1000);
@@ -545,6 +899,23 @@ mod test {
         }
     }
 
+    #[test_case]
+    fn flag_presets() {
+        let mmio = PageTableEntryFlags::mmio();
+        assert!(mmio.contains(PageTableEntryFlags::NO_CACHE | PageTableEntryFlags::NO_EXECUTE));
+        assert!(!mmio.contains(PageTableEntryFlags::USER_ALLOWED));
+        assert!(!PageTableEntryFlags::kernel_code().contains(PageTableEntryFlags::WRITABLE));
+        assert!(!PageTableEntryFlags::kernel_code().contains(PageTableEntryFlags::NO_EXECUTE));
+        assert!(PageTableEntryFlags::kernel_data().contains(PageTableEntryFlags::NO_EXECUTE));
+        assert!(PageTableEntryFlags::user_data().contains(PageTableEntryFlags::USER_ALLOWED));
+        // page tables never inherit the restrictions of the leaf
+        let table = PageTableEntryFlags::page_table(PageTableEntryFlags::kernel_code());
+        assert_eq!(
+            table,
+            PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE
+        );
+    }
+
     #[test_case]
     fn page_present() {
         unsafe {
@@ -567,4 +938,156 @@ mod test {
             // need more through checking,
         }
     }
+
+    #[test_case]
+    fn page_iter_at_the_top() {
+        let last = Page::new((Page::MAX_PAGE_CANOINCAL_NUM - 1) as u64);
+        assert!(last.next().is_none());
+        let iter = PageIter::new(Page::new((Page::MAX_PAGE_CANOINCAL_NUM - 3) as u64), last);
+        assert_eq!(iter.count(), 3);
+        // only the last page
+        let mut iter = PageIter::new(last, last);
+        assert_eq!(iter.next(), Some(last));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test_case]
+    fn unmap_frees_empty_tables() {
+        use crate::memory::virt::GLOBAL_PAGE_ALLOCATOR;
+        unsafe {
+            let page_table = PageTable::current_mut();
+            let empty_page_dir_idx = page_table
+                .entries
+                .iter()
+                .take(PAGE_TABLE_ENTRY_NUM / 2)
+                .position(|e| !e.present())
+                .unwrap();
+            // nothing else is mapped in it, so mapping creates 3 tables
+            let page = Page::new(
+                empty_page_dir_idx as u64
+                    * PAGE_TABLE_ENTRY_NUM as u64
+                    * PAGE_TABLE_ENTRY_NUM as u64
+                    * PAGE_TABLE_ENTRY_NUM as u64
+                    + 3,
+            );
+            let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+            let phy_alloc = &mut inner.physical_allocator;
+            let free_before = phy_alloc.free_frames();
+            let frame = phy_alloc.allocate_frame().unwrap();
+            page_table.map_page_unchecked(
+                page,
+                frame,
+                PageTableEntryFlags::kernel_data(),
+                phy_alloc,
+            );
+            assert_eq!(phy_alloc.free_frames(), free_before - 4);
+            assert!(page_table.is_present(page));
+
+            assert_eq!(page_table.unmap_page(page, phy_alloc), Some(frame));
+            assert!(!page_table.is_present(page));
+            assert!(!page_table.entries[empty_page_dir_idx].present());
+            phy_alloc.free_frame(frame);
+            assert_eq!(phy_alloc.free_frames(), free_before);
+            // already unmapped
+            assert_eq!(page_table.unmap_page(page, phy_alloc), None);
+        }
+    }
+
+    #[test_case]
+    fn huge_page_entry() {
+        use crate::memory::virt::GLOBAL_PAGE_ALLOCATOR;
+        unsafe {
+            let page_table = PageTable::current_mut();
+            let empty_page_dir_idx = page_table
+                .entries
+                .iter()
+                .take(PAGE_TABLE_ENTRY_NUM / 2)
+                .position(|e| !e.present())
+                .unwrap();
+            let page = Page::new(
+                empty_page_dir_idx as u64
+                    * PAGE_TABLE_ENTRY_NUM as u64
+                    * PAGE_TABLE_ENTRY_NUM as u64
+                    * PAGE_TABLE_ENTRY_NUM as u64
+                    + PAGE_TABLE_ENTRY_NUM as u64 * 3,
+            );
+            // the second 2 MiB of physical memory, which the higher half direct map maps as well
+            let phy_addr = PhyAddr(HUGE_PAGE_SIZE);
+            let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+            let phy_alloc = &mut inner.physical_allocator;
+            page_table.map_huge_page_unchecked(
+                page,
+                phy_addr,
+                PageTableEntryFlags::kernel_data(),
+                phy_alloc,
+            );
+
+            let inside = page.next_by(300).unwrap();
+            for page in [page, inside] {
+                let entry = page_table.page_entry(page).unwrap();
+                assert!(entry.flags().contains(PageTableEntryFlags::HUGE_PAGE));
+                assert_eq!(entry.addr(), phy_addr);
+                assert!(page_table.is_present(page));
+            }
+            assert!(!page_table.is_present(page.next_by(PAGE_TABLE_ENTRY_NUM as u64).unwrap()));
+            let offset = 300 * PAGE_SIZE + 8;
+            let through_huge = ((VirtAddr::from(page).0 + offset) as *const u64).read_volatile();
+            let through_hhdm =
+                (PhyAddr(phy_addr.0 + offset).as_virtual().0 as *const u64).read_volatile();
+            assert_eq!(through_huge, through_hhdm);
+
+            // tear it down by hand, unmap_page doesn't support huge pages
+            let page_dir_ptr_table_entry = &mut page_table.entries[empty_page_dir_idx];
+            let page_dir_ptr_table = page_dir_ptr_table_entry.as_page_table_mut();
+            let page_dir_entry = &mut page_dir_ptr_table.entries[page.level3_idx()];
+            page_dir_entry.as_page_table_mut().entries[page.level2_idx()].clear();
+            assert!(PageTable::free_table(page_dir_entry, phy_alloc));
+            assert!(PageTable::free_table(page_dir_ptr_table_entry, phy_alloc));
+            invlpg(VirtAddr::from(page).0);
+            assert!(!page_table.is_present(inside));
+        }
+    }
+
+    #[test_case]
+    fn huge_page_alignment() {
+        use crate::memory::physical::BasicPhysicalAllocator;
+        // the assert comes before any allocation, and a panic mustn't leave the global allocator locked
+        let mut bitmap = [true; 1];
+        let mut phy_alloc = unsafe { BasicPhysicalAllocator::new(&raw mut bitmap, PhyAddr(0)) };
+        should_panic!();
+        unsafe {
+            PageTable::current_mut().map_huge_page_unchecked(
+                Page::new(0),
+                PhyAddr(PAGE_SIZE),
+                PageTableEntryFlags::kernel_data(),
+                &mut phy_alloc,
+            );
+        }
+    }
+
+    #[test_case]
+    fn translate_addresses() {
+        use crate::memory::virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator};
+        unsafe {
+            let alloc = GLOBAL_PAGE_ALLOCATOR.alloc_pages(2).unwrap();
+            let page_table = PageTable::current();
+            let second = alloc.first_page.next().unwrap();
+            let frame = page_table.page_entry(second).unwrap().addr();
+            let addr = VirtAddr(VirtAddr::from(second).0 + 0x123);
+            assert_eq!(page_table.translate(addr), Some(PhyAddr(frame.0 + 0x123)));
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+            assert_eq!(page_table.translate(addr), None);
+
+            // the direct map is usually made of huge pages
+            let phy_addr = PhyAddr(HUGE_PAGE_SIZE * 3 + 0x4567);
+            assert_eq!(page_table.translate(phy_addr.as_virtual()), Some(phy_addr));
+            let kernel_begin = VirtAddr(crate::kernel_virt_begin());
+            assert_eq!(
+                page_table.translate(kernel_begin),
+                Some(PhyAddr(crate::kernel_phy_begin()))
+            );
+            assert_eq!(page_table.translate(VirtAddr(0x0000_8000_0000_0000)), None);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use core::fmt::Debug;
 
-use crate::{HIGHER_HALF_DIRECT_MAP, memory::virt::VirtAddr};
+use crate::{HIGHER_HALF_DIRECT_MAP, kernel_phy_begin, kernel_virt_begin, memory::virt::VirtAddr};
 
 // Todo: the current align_up/down methods are bad,
 // we align to the power of 2 all the time anyways, we should use something like:
@@ -42,22 +42,24 @@ impl PhyAddr {
 /// based on contigous usable physical memory, and is able to
 /// give and free physical memory.
 pub unsafe trait PhysicalAllocator {
-    /// allocate one singular frame
-    unsafe fn allocate_frame(&mut self) -> PhyAddr;
+    /// allocate one singular frame, None if we're out of memory
+    unsafe fn allocate_frame(&mut self) -> Option<PhyAddr>;
     /// free a frame
     unsafe fn free_frame(&mut self, frame: PhyAddr);
 
     /// allocate a frames contigously at a specific address. Returns None if the address is already allocated.
     /// Address must be aligned to Self::frame_size()
     unsafe fn alloc_phy_addr(&mut self, phy_addr: PhyAddr, frame_count: usize) -> Option<PhyAddr>;
+    /// Whether the frame is within the range this allocator keeps track of, i.e. whether it can be freed by it.
+    fn manages_frame(&self, frame: PhyAddr) -> bool;
     // frame size in bytes
     fn frame_size() -> u64;
 }
 
 const BITMAP_SIZE: usize = 8388608;
 pub struct BasicPhysicalAllocator {
-    // can handle up to 32GiB of ram
-    bitmap: *mut [bool; BITMAP_SIZE],
+    // one entry per frame, the global one can handle up to 32GiB of ram
+    bitmap: *mut [bool],
     offset: PhyAddr,
     limit: u64,
 }
@@ -80,6 +82,28 @@ impl BasicPhysicalAllocator {
         }
     }
 
+    /// Create a BasicPhysicalAllocator which keeps track of the frames in [offset, offset + bitmap.len() frames)
+    /// with its own bitmap, i.e. which is completely independent of the global one.
+    /// ## Safety
+    /// The bitmap must stay valid for as long as the allocator is used, and no one else may use it in the meantime.
+    /// ## Panic
+    /// Panics if the bitmap overlaps the global allocator's bitmap.
+    pub unsafe fn new(bitmap: *mut [bool], offset: PhyAddr) -> Self {
+        let global = (&raw const BITMAP).addr()..(&raw const BITMAP).addr() + BITMAP_SIZE;
+        let start = bitmap.addr();
+        assert!(
+            start + bitmap.len() <= global.start || start >= global.end,
+            "the bitmap aliases the global one"
+        );
+        assert!(offset.0.is_multiple_of(Self::frame_size()));
+        let len = bitmap.len() as u64;
+        BasicPhysicalAllocator {
+            bitmap,
+            offset,
+            limit: len * Self::frame_size(),
+        }
+    }
+
     pub unsafe fn set_offset(&mut self, offset: PhyAddr) {
         assert!(offset.0.is_multiple_of(Self::frame_size()));
         self.offset = offset;
@@ -88,13 +112,51 @@ impl BasicPhysicalAllocator {
     pub unsafe fn limit_mut(&mut self) -> &mut u64 {
         &mut self.limit
     }
+
+    /// Mark every frame which overlaps [start, start + size) as used, so that it's never allocated.
+    /// Frames which are only partially covered are reserved as a whole, and frames we don't manage are ignored.
+    ///
+    /// # Safety
+    /// No one else may use the bitmap meanwhile, it's written through a raw pointer.
+    pub unsafe fn reserve(&mut self, start: PhyAddr, size: u64) {
+        if size == 0 {
+            return;
+        }
+        let frame_size = Self::frame_size();
+        let first = start.align_down(frame_size as usize).0.max(self.offset.0);
+        let end = PhyAddr(start.0 + size).align_up(frame_size as usize).0;
+        let bitmap = unsafe { self.bitmap.as_mut().unwrap() };
+        for frame in (first..end).step_by(frame_size as usize) {
+            if !self.manages_frame(PhyAddr(frame)) {
+                break;
+            }
+            bitmap[((frame - self.offset.0) / frame_size) as usize] = true;
+        }
+    }
+
+    /// The physical memory the bitmap itself lives in.
+    /// Only meaningful for the allocator created by init, whose bitmap is part of the kernel image.
+    pub fn bitmap_region(&self) -> (PhyAddr, u64) {
+        // the bitmap is a static, so it's part of the kernel image
+        let virt_addr = self.bitmap.addr() as u64;
+        (
+            PhyAddr(virt_addr - kernel_virt_begin() + kernel_phy_begin()),
+            self.bitmap.len() as u64,
+        )
+    }
+
+    /// Amount of frames which can still be allocated
+    pub fn free_frames(&self) -> usize {
+        let bitmap = unsafe { self.bitmap.as_ref().unwrap() };
+        bitmap.iter().filter(|&&used| !used).count()
+    }
 }
 
 /// safety: you need unsafe to use the pointer anyways
 unsafe impl Send for BasicPhysicalAllocator {}
 
 unsafe impl PhysicalAllocator for BasicPhysicalAllocator {
-    unsafe fn allocate_frame(&mut self) -> PhyAddr {
+    unsafe fn allocate_frame(&mut self) -> Option<PhyAddr> {
         let bitmap = unsafe { self.bitmap.as_mut().unwrap() };
         if let Some(index) = bitmap
             .iter()
@@ -104,14 +166,14 @@ unsafe impl PhysicalAllocator for BasicPhysicalAllocator {
         {
             bitmap[index] = true;
 
-            PhyAddr((index as u64 * Self::frame_size()) + self.offset.0)
+            Some(PhyAddr((index as u64 * Self::frame_size()) + self.offset.0))
         } else {
-            PhyAddr(0)
+            None
         }
     }
 
     unsafe fn free_frame(&mut self, frame: PhyAddr) {
-        if frame.0 % Self::frame_size() != 0 {
+        if !frame.0.is_multiple_of(Self::frame_size()) {
             panic!("WHAT IS THIS ALIGNMENT?! ptr: {:x}", frame.0);
         }
         let bitmap = unsafe { self.bitmap.as_mut().unwrap() };
@@ -124,7 +186,7 @@ unsafe impl PhysicalAllocator for BasicPhysicalAllocator {
     }
 
     unsafe fn alloc_phy_addr(&mut self, phy_addr: PhyAddr, frame_count: usize) -> Option<PhyAddr> {
-        if phy_addr.0 % Self::frame_size() != 0 {
+        if !phy_addr.0.is_multiple_of(Self::frame_size()) {
             panic!("bad alignment. ptr: {:?}", phy_addr);
         }
 
@@ -141,6 +203,12 @@ unsafe impl PhysicalAllocator for BasicPhysicalAllocator {
         }
         Some(phy_addr)
     }
+
+    fn manages_frame(&self, frame: PhyAddr) -> bool {
+        frame.0 >= self.offset.0
+            && (frame.0 - self.offset.0) / Self::frame_size() < self.bitmap.len() as u64
+    }
+
     fn frame_size() -> u64 {
         4096
     }
@@ -149,6 +217,8 @@ unsafe impl PhysicalAllocator for BasicPhysicalAllocator {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::should_panic_with;
+
     #[test_case]
     fn align_up() {
         let addr = PhyAddr(0);
@@ -168,4 +238,19 @@ mod test {
         let addr = PhyAddr(0xdeadbeef);
         assert_eq!(addr.align_down(0x1000), PhyAddr(0xdeadb000));
     }
+
+    #[test_case]
+    fn bitmap_cant_alias_global() {
+        let mut bitmap = [false; 4];
+        let phy_alloc = unsafe { BasicPhysicalAllocator::new(&raw mut bitmap, PhyAddr(0x1000)) };
+        assert!(phy_alloc.manages_frame(PhyAddr(0x4000)));
+        assert!(!phy_alloc.manages_frame(PhyAddr(0x5000)));
+        assert_eq!(phy_alloc.free_frames(), 4);
+
+        should_panic_with!("the bitmap aliases the global one");
+        let global = unsafe { (&raw mut BITMAP as *mut bool).add(16) };
+        let _ = unsafe {
+            BasicPhysicalAllocator::new(core::ptr::slice_from_raw_parts_mut(global, 4), PhyAddr(0))
+        };
+    }
 }
@@ -0,0 +1,176 @@
+// Keeping the TLBs of all the cpus coherent. Every cpu caches translations on its own, so a page which is
+// unmapped has to be dropped from all of their TLBs before it (or its frame) is reused.
+#[cfg(feature = "smp")]
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(feature = "smp")]
+use alloc::boxed::Box;
+#[cfg(feature = "smp")]
+use spin::{Mutex, Once};
+
+#[cfg(feature = "smp")]
+use crate::{arch_x86_64::cpuid, dev::local_apic::LocalApic, interrupts::register_handler};
+use crate::{
+    arch_x86_64::invlpg,
+    memory::{
+        paging::{PAGE_SIZE, Page},
+        virt::VirtAddr,
+    },
+};
+
+/// The vector of the shootdown ipi
+#[cfg(feature = "smp")]
+pub const SHOOTDOWN_VECTOR: u8 = 0x40;
+
+/// bit n is set if the cpu with local apic id n takes shootdowns, see join
+#[cfg(feature = "smp")]
+static PARTICIPANTS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+/// The address of the first page being shot down
+#[cfg(feature = "smp")]
+static SHOOTDOWN_ADDR: AtomicU64 = AtomicU64::new(0);
+/// The amount of pages being shot down, starting at SHOOTDOWN_ADDR
+#[cfg(feature = "smp")]
+static SHOOTDOWN_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// Amount of cpus which didn't invalidate the pages yet
+#[cfg(feature = "smp")]
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+/// One shootdown at a time, and nobody joins in the middle of one
+#[cfg(feature = "smp")]
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+#[cfg(feature = "smp")]
+static SHOOTDOWN_HANDLER: Once<()> = Once::new();
+
+// the id of the current cpu without touching the local apic, like sync::current_cpu.
+// unmapping happens while the page allocator is locked, and the first access to the local apic maps it,
+// which unmaps the ACPI tables it was found through, which shoots them down, and so on
+#[cfg(feature = "smp")]
+fn current_cpu() -> usize {
+    (cpuid(1, 0).ebx >> 24) as usize
+}
+
+/// Start taking shootdowns on the current cpu. From now on every shootdown waits for it to acknowledge,
+/// so it must keep taking interrupts (i.e. by idling with interrupts enabled) and its local apic must be enabled.
+#[cfg(feature = "smp")]
+pub fn join() {
+    SHOOTDOWN_HANDLER.call_once(|| {
+        register_handler(SHOOTDOWN_VECTOR, Box::new(|_| handle_shootdown()));
+    });
+    let id = current_cpu();
+    let _lock = SHOOTDOWN_LOCK.lock();
+    PARTICIPANTS[id / 64].fetch_or(1 << (id % 64), Ordering::Relaxed);
+}
+
+#[cfg(feature = "smp")]
+fn handle_shootdown() {
+    let addr = SHOOTDOWN_ADDR.load(Ordering::Acquire);
+    invalidate(addr, SHOOTDOWN_PAGES.load(Ordering::Acquire));
+    PENDING_ACKS.fetch_sub(1, Ordering::Release);
+    LocalApic::eoi();
+}
+
+/// invlpg every page of [addr, addr + page_amount pages)
+fn invalidate(addr: u64, page_amount: usize) {
+    for i in 0..page_amount as u64 {
+        unsafe { invlpg(addr + i * PAGE_SIZE) };
+    }
+}
+
+/// Drop page_amount pages from first_page on from the TLB of every cpu, after they were unmapped or their mapping changed.
+/// Waits for every other cpu to acknowledge, so the caller mustn't hold locks which other cpus take
+/// with interrupts disabled, i.e. the page allocator's: collect the pages and shoot them down once it's unlocked.
+#[cfg(feature = "smp")]
+pub fn shootdown(first_page: Page, page_amount: usize) {
+    let addr = VirtAddr::from(first_page).0;
+    invalidate(addr, page_amount);
+    let _lock = SHOOTDOWN_LOCK.lock();
+    let this_cpu = current_cpu();
+    let others = || {
+        (0..PARTICIPANTS.len() * 64).filter(move |&id| {
+            id != this_cpu && PARTICIPANTS[id / 64].load(Ordering::Relaxed) & (1 << (id % 64)) != 0
+        })
+    };
+    let count = others().count();
+    // nobody else to tell, so the local apic (which may not be mapped yet) isn't touched
+    if count == 0 {
+        return;
+    }
+    SHOOTDOWN_ADDR.store(addr, Ordering::Relaxed);
+    SHOOTDOWN_PAGES.store(page_amount, Ordering::Relaxed);
+    // publishes the range as well
+    PENDING_ACKS.store(count, Ordering::Release);
+    for id in others() {
+        LocalApic::send_ipi(id as u8, SHOOTDOWN_VECTOR);
+    }
+    // the acknowledgment barrier: until every cpu invalidated the pages, one of them may still write
+    // through its stale entries, i.e. into a frame which was already given to someone else.
+    // a cpu which spins on a lock we hold with interrupts disabled never acknowledges, hence the rule above
+    while PENDING_ACKS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Drop page_amount pages from first_page on from the TLB, after they were unmapped or their mapping changed.
+/// Without smp there are no other cpus.
+#[cfg(not(feature = "smp"))]
+pub fn shootdown(first_page: Page, page_amount: usize) {
+    invalidate(VirtAddr::from(first_page).0, page_amount);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{
+        paging::PageTable,
+        physical::PhysicalAllocator,
+        virt::{GLOBAL_PAGE_ALLOCATOR, PageAllocator},
+    };
+
+    #[test_case]
+    fn remapped_pages() {
+        let alloc = unsafe { GLOBAL_PAGE_ALLOCATOR.alloc_pages(2).unwrap() };
+        let ptr = alloc.as_virt_addr().0 as *mut u64;
+        let second = unsafe { ptr.byte_add(PAGE_SIZE as usize) };
+        unsafe {
+            ptr.write_volatile(1);
+            second.write_volatile(1);
+        }
+        // point both pages at other frames, behind the TLB's back
+        let old_frames = [alloc.first_page, alloc.first_page.next_by(1).unwrap()].map(|page| {
+            let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+            let new_frame = unsafe { inner.physical_allocator.allocate_frame() }.unwrap();
+            unsafe { (new_frame.as_virtual().0 as *mut u64).write_volatile(2) };
+            let entry = unsafe { PageTable::current_mut() }
+                .page_entry_mut(page)
+                .unwrap();
+            let old_frame = entry.addr();
+            entry.set_addr(new_frame, entry.flags());
+            old_frame
+        });
+        // one shootdown for both
+        shootdown(alloc.first_page, 2);
+        assert_eq!(unsafe { ptr.read_volatile() }, 2);
+        assert_eq!(unsafe { second.read_volatile() }, 2);
+        // the allocation frees the new frames, and we free the old ones
+        unsafe {
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+            let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+            for frame in old_frames {
+                inner.physical_allocator.free_frame(frame);
+            }
+        }
+    }
+
+    #[cfg(feature = "smp")]
+    #[test_case]
+    fn unmap_without_local_apic() {
+        let was_mapped = LocalApic::is_mapped();
+        // freeing pages, and mapping and unmapping the ACPI tables like the local apic's first access does
+        let alloc = unsafe { GLOBAL_PAGE_ALLOCATOR.alloc_pages(2).unwrap() };
+        unsafe { GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc) };
+        crate::acpi::with_tables(|_| ());
+        // shooting down with no other participants never maps it
+        assert_eq!(LocalApic::is_mapped(), was_mapped);
+        // and neither deadlocks when it's mapped for the first time, which unmaps the ACPI tables
+        LocalApic::id();
+    }
+}
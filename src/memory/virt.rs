@@ -1,51 +1,78 @@
 use core::fmt::Debug;
-use limine::memory_map::EntryType;
-use spin::Mutex;
+use limine::memory_map::{Entry, EntryType};
 
 use crate::{
-    LIMINE_MEMORY_MAP,
-    arch_x86_64::invlpg,
+    FRAMEBUFFER_REQUEST, HIGHER_HALF_DIRECT_MAP, LIMINE_MEMORY_MAP,
+    arch_x86_64::{compiler_fence, invlpg},
+    kernel_phy_begin, kernel_size,
     memory::{
+        MemoryError,
+        buddy::BuddyPhysicalAllocator,
         paging::{PAGE_SIZE, Page, PageIter, PageTable, PageTableEntryFlags},
-        physical::{BasicPhysicalAllocator, PhyAddr, PhysicalAllocator},
+        physical::{PhyAddr, PhysicalAllocator},
     },
+    qemu_println,
+    sync::CheckedMutex,
 };
 
 // TODO:
 // Create a better memory allocator, the current one simply searches for a contigous set of pages in the page table,
-// and then save the last page it allocated, and searches from that place the next time.
-// If we fill up the entire memory space, it will fail. NEED TO FIX THIS!
+// and then save the last page it allocated, and searches from that place the next time
+// (wrapping around to the start once it reaches the end of the address space).
+
+/// The lowest page page allocators hand out, page 0 stays unmapped so that null pointers fault
+const FIRST_PAGE: Page = Page::new(1);
 
 /// The kernel's global page allocator.
-pub static GLOBAL_PAGE_ALLOCATOR: BasicPageAllocator<BasicPhysicalAllocator> =
+pub static GLOBAL_PAGE_ALLOCATOR: BasicPageAllocator<BuddyPhysicalAllocator> =
     BasicPageAllocator::new_const();
 
-pub fn init() {
-    let usable_mem = LIMINE_MEMORY_MAP
+pub fn init() -> Result<(), MemoryError> {
+    let memory_map = LIMINE_MEMORY_MAP
         .get_response()
-        .unwrap()
-        .entries()
-        .iter()
-        .filter(|e| e.entry_type == EntryType::USABLE)
-        .max_by_key(|e| e.length)
-        .unwrap();
+        .ok_or(MemoryError::NoMemoryMap)?;
+    let entries = memory_map.entries();
+    if usable_regions(entries).next().is_none() {
+        return Err(MemoryError::NoUsableMemory);
+    }
     crate::console_println!(
-        "base mem: 0x{:x}, size: {} bytes, max_mem: {}",
-        usable_mem.base,
-        usable_mem.length,
-        LIMINE_MEMORY_MAP
-            .get_response()
-            .unwrap()
-            .entries()
-            .iter()
-            .filter(|e| e.entry_type == EntryType::USABLE)
-            .map(|e| e.length)
-            .sum::<u64>()
+        "usable regions: {}, max_mem: {}",
+        usable_regions(entries).count(),
+        usable_regions(entries).map(|(_, size)| size).sum::<u64>()
     );
     unsafe {
-        GLOBAL_PAGE_ALLOCATOR.configure_physical_area(PhyAddr(usable_mem.base), usable_mem.length);
+        GLOBAL_PAGE_ALLOCATOR.configure_physical_area(usable_regions(entries));
+    }
+    // the region is usable according to Limine, but make sure we never hand out frames
+    // which back something we're already using
+    let hhdm_offset = HIGHER_HALF_DIRECT_MAP.get_response().unwrap().offset();
+    unsafe {
+        GLOBAL_PAGE_ALLOCATOR.reserve_physical_area(PhyAddr(kernel_phy_begin()), kernel_size());
+        // headless, there may be no framebuffer at all
+        let framebuffers = FRAMEBUFFER_REQUEST
+            .get_response()
+            .into_iter()
+            .flat_map(|response| response.framebuffers());
+        for framebuffer in framebuffers {
+            GLOBAL_PAGE_ALLOCATOR.reserve_physical_area(
+                PhyAddr(framebuffer.addr() as u64 - hhdm_offset),
+                framebuffer.pitch() * framebuffer.height(),
+            );
+        }
+        let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+        let (bitmap_start, bitmap_size) = inner.physical_allocator.bitmap_region();
+        inner.physical_allocator.reserve(bitmap_start, bitmap_size);
     }
+    Ok(())
 }
+/// The (start, size) of every usable entry of the memory map
+fn usable_regions(entries: &[&Entry]) -> impl Iterator<Item = (PhyAddr, u64)> {
+    entries
+        .iter()
+        .filter(|e| e.entry_type == EntryType::USABLE)
+        .map(|e| (PhyAddr(e.base), e.length))
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct VirtAddr(pub u64);
 
@@ -56,6 +83,12 @@ impl VirtAddr {
         let last_16_bits = self.0 >> 48;
         last_16_bits == 0xffff || last_16_bits == 0
     }
+
+    /// Get the canonical form of the address, i.e. sign-extend bit 47 into the top 16 bits.
+    /// Note: this discards whatever was in the top 16 bits before.
+    pub const fn canonicalize(&self) -> VirtAddr {
+        VirtAddr((((self.0 << 16) as i64) >> 16) as u64)
+    }
 }
 
 impl Debug for VirtAddr {
@@ -91,10 +124,12 @@ pub trait PageAllocator {
     /// Map a physical address to some amount of pages. Allocates at least page_amount * self.page_size()
     /// amount of memory after the address. Returns the allocation along with virtual address which corresponds to the physical one.
     /// Note: the physical address need not be aligned, and the given PageAllocation may be bigger than page_amount.
+    /// Use PageTableEntryFlags::mmio() for device registers and PageTableEntryFlags::kernel_data() for normal memory.
     unsafe fn map_physical(
         &self,
         addr: PhyAddr,
         page_amount: usize,
+        flags: PageTableEntryFlags,
     ) -> Option<(PageAllocation, VirtAddr)>;
 
     fn page_size(&self) -> usize {
@@ -103,32 +138,117 @@ pub trait PageAllocator {
 }
 
 pub struct BasicPageAllocator<T: PhysicalAllocator> {
-    pub inner: Mutex<BasicPageAllocatorInner<T>>,
+    pub inner: CheckedMutex<BasicPageAllocatorInner<T>>,
 }
 pub struct BasicPageAllocatorInner<T: PhysicalAllocator> {
     pub physical_allocator: T,
     last_page_alloc: Page,
 }
 
-impl BasicPageAllocator<BasicPhysicalAllocator> {
-    pub const fn new_const() -> Self {
+impl<T: PhysicalAllocator> BasicPageAllocatorInner<T> {
+    /// Search from the last allocation up to the end of the address space, then from the start up to the last allocation.
+    fn find_free_pages(&self, page_table: &PageTable, page_amount: usize) -> Option<PageIter> {
+        page_table
+            .find_free_pages(self.last_page_alloc, page_amount)
+            .or_else(|| {
+                page_table.find_free_pages_before(FIRST_PAGE, self.last_page_alloc, page_amount)
+            })
+    }
+}
+
+impl<T: PhysicalAllocator> BasicPageAllocator<T> {
+    /// A page allocator which takes its frames from physical_allocator.
+    /// It still maps into the current page table, so its pages never overlap the ones of other page allocators.
+    /// The page tables it creates come from physical_allocator as well, and go back to it once
+    /// dealloc_pages leaves them without any mapped page.
+    pub const fn new(physical_allocator: T) -> Self {
         BasicPageAllocator {
-            inner: Mutex::new(BasicPageAllocatorInner {
-                physical_allocator: unsafe { BasicPhysicalAllocator::init(PhyAddr(0)) },
-                last_page_alloc: Page::new(1),
+            inner: CheckedMutex::new(BasicPageAllocatorInner {
+                physical_allocator,
+                last_page_alloc: FIRST_PAGE,
             }),
         }
     }
+}
+
+impl BasicPageAllocator<BuddyPhysicalAllocator> {
+    pub const fn new_const() -> Self {
+        Self::new(unsafe { BuddyPhysicalAllocator::init(PhyAddr(0)) })
+    }
+
+    /// Manage the frames of all the regions, see BuddyPhysicalAllocator::set_regions
+    unsafe fn configure_physical_area(&self, regions: impl IntoIterator<Item = (PhyAddr, u64)>) {
+        unsafe {
+            self.inner.lock().physical_allocator.set_regions(regions);
+        }
+    }
 
-    unsafe fn configure_physical_area(&self, start: PhyAddr, size: u64) {
+    /// Never allocate the frames which overlap [start, start + size), see BuddyPhysicalAllocator::reserve
+    unsafe fn reserve_physical_area(&self, start: PhyAddr, size: u64) {
         unsafe {
-            let phy_alloc = &mut self.inner.lock().physical_allocator;
-            phy_alloc.set_offset(start);
-            *phy_alloc.limit_mut() = size;
+            self.inner.lock().physical_allocator.reserve(start, size);
         }
     }
 }
 
+/// Frames which were unmapped, but can't be freed before the other cpus dropped them from their TLBs.
+/// Linked through the frames themselves (like the buddy allocator's free lists), so collecting them doesn't allocate.
+struct UnmappedFrames {
+    head: Option<PhyAddr>,
+}
+
+impl UnmappedFrames {
+    /// ## Safety
+    /// Nothing may use the frame anymore, the link to the next one is written into it.
+    unsafe fn push(&mut self, frame: PhyAddr) {
+        let next = self.head.map_or(u64::MAX, |head| head.0);
+        unsafe { (frame.as_virtual().0 as *mut u64).write(next) };
+        self.head = Some(frame);
+    }
+
+    /// ## Safety
+    /// The frames must have been allocated by physical_allocator.
+    unsafe fn free_all(self, physical_allocator: &mut impl PhysicalAllocator) {
+        let mut next = self.head;
+        while let Some(frame) = next {
+            let link = unsafe { (frame.as_virtual().0 as *const u64).read() };
+            next = (link != u64::MAX).then_some(PhyAddr(link));
+            unsafe { physical_allocator.free_frame(frame) };
+        }
+    }
+}
+
+/// Collects the frames unmap_page frees instead of freeing them, see UnmappedFrames
+struct DeferredFree<'a, T: PhysicalAllocator> {
+    physical_allocator: &'a mut T,
+    unmapped: &'a mut UnmappedFrames,
+}
+
+unsafe impl<T: PhysicalAllocator> PhysicalAllocator for DeferredFree<'_, T> {
+    unsafe fn allocate_frame(&mut self) -> Option<PhyAddr> {
+        unsafe { self.physical_allocator.allocate_frame() }
+    }
+
+    unsafe fn free_frame(&mut self, frame: PhyAddr) {
+        unsafe { self.unmapped.push(frame) };
+    }
+
+    unsafe fn alloc_phy_addr(&mut self, phy_addr: PhyAddr, frame_count: usize) -> Option<PhyAddr> {
+        unsafe {
+            self.physical_allocator
+                .alloc_phy_addr(phy_addr, frame_count)
+        }
+    }
+
+    fn manages_frame(&self, frame: PhyAddr) -> bool {
+        self.physical_allocator.manages_frame(frame)
+    }
+
+    fn frame_size() -> u64 {
+        T::frame_size()
+    }
+}
+
 impl<T: PhysicalAllocator> PageAllocator for BasicPageAllocator<T> {
     unsafe fn alloc_pages(&self, page_amount: usize) -> Option<PageAllocation> {
         let mut inner = self.inner.lock();
@@ -136,21 +256,26 @@ impl<T: PhysicalAllocator> PageAllocator for BasicPageAllocator<T> {
         // and this is only (or at least should be only) accessed by the page allocator.
         let page_table = unsafe { PageTable::current_mut() };
 
-        let Some(free_pages) =
-            page_table.find_free_pages(inner.last_page_alloc, page_amount as usize)
-        else {
-            return None;
-        };
+        let free_pages = inner.find_free_pages(page_table, page_amount)?;
 
         let first_page = free_pages.first();
         inner.last_page_alloc = free_pages.last_page();
         for page in free_pages {
             unsafe {
-                let frame = inner.physical_allocator.allocate_frame();
+                let Some(frame) = inner.physical_allocator.allocate_frame() else {
+                    // out of memory, give back what we took so far. nobody saw those pages yet
+                    for mapped in Page::range(first_page, page) {
+                        let frame = page_table
+                            .unmap_page(mapped, &mut inner.physical_allocator)
+                            .unwrap();
+                        inner.physical_allocator.free_frame(frame);
+                    }
+                    return None;
+                };
                 page_table.map_page_unchecked(
                     page,
                     frame,
-                    PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+                    PageTableEntryFlags::kernel_data(),
                     &mut inner.physical_allocator,
                 );
                 invlpg(VirtAddr::from(page).0);
@@ -164,31 +289,55 @@ impl<T: PhysicalAllocator> PageAllocator for BasicPageAllocator<T> {
     }
 
     unsafe fn dealloc_pages(&self, alloc: &PageAllocation) {
-        let pages_to_free = PageIter {
-            start: alloc.first_page,
-            end: alloc
+        let pages_to_free = Page::range_inclusive(
+            alloc.first_page,
+            alloc
                 .first_page
                 .next_by(alloc.page_amount as u64 - 1)
                 .unwrap(),
-        };
+        );
 
+        // the frames (and page tables) stay ours until the other cpus dropped the pages from their TLBs
+        let mut unmapped = UnmappedFrames { head: None };
         let mut inner = self.inner.lock();
         // safety: we have mutual exclusion due to locking ourselves and the page table should only be accessed by us.
         let page_table = unsafe { PageTable::current_mut() };
+        let mut deferred = DeferredFree {
+            physical_allocator: &mut inner.physical_allocator,
+            unmapped: &mut unmapped,
+        };
         for page in pages_to_free {
+            // a bogus allocation (i.e. already freed, or with a wrong page amount) shouldn't take down the kernel
+            let Some(frame) = (unsafe { page_table.unmap_page(page, &mut deferred) }) else {
+                qemu_println!(
+                    "dealloc_pages: page at {:?} is not mapped, skipping it",
+                    VirtAddr::from(page)
+                );
+                continue;
+            };
             unsafe {
-                let page_entry = page_table.page_entry_mut(page).unwrap();
-                inner.physical_allocator.free_frame(page_entry.addr());
-                page_entry.clear();
-                invlpg(VirtAddr::from(page).0);
+                if deferred.manages_frame(frame) {
+                    deferred.free_frame(frame);
+                } else {
+                    qemu_println!(
+                        "dealloc_pages: frame {:?} of page at {:?} is not managed by the physical allocator, not freeing it",
+                        frame,
+                        VirtAddr::from(page)
+                    );
+                }
             }
         }
+        // the shootdown waits for the other cpus, which may be spinning on the lock with interrupts disabled
+        drop(inner);
+        crate::memory::tlb::shootdown(alloc.first_page, alloc.page_amount);
+        unsafe { unmapped.free_all(&mut self.inner.lock().physical_allocator) };
     }
 
     unsafe fn map_physical(
         &self,
         addr: PhyAddr,
         page_amount: usize,
+        flags: PageTableEntryFlags,
     ) -> Option<(PageAllocation, VirtAddr)> {
         let mut inner = self.inner.lock();
         unsafe {
@@ -204,24 +353,26 @@ impl<T: PhysicalAllocator> PageAllocator for BasicPageAllocator<T> {
             };
             // safety: mutual exlcusion via inner, only the page allocator has access to the page table
             let page_table = PageTable::current_mut();
-            let Some(pages) = page_table.find_free_pages(inner.last_page_alloc, page_amount) else {
+            let Some(pages) = inner.find_free_pages(page_table, page_amount) else {
                 let mut addr = phy_addr;
                 for _ in 0..page_amount {
-                    inner.physical_allocator.free_frame(addr);
+                    // alloc_phy_addr doesn't keep track of the frames it doesn't manage
+                    if inner.physical_allocator.manages_frame(addr) {
+                        inner.physical_allocator.free_frame(addr);
+                    }
                     addr.0 += T::frame_size();
                 }
                 return None;
             };
             let first_page = pages.first();
+            // like alloc_pages, so that the next mapping doesn't start searching inside this one
+            inner.last_page_alloc = pages.last_page();
 
             let mut phy_addr = phy_addr;
             for page in pages {
-                page_table.map_page_unchecked(
-                    page,
-                    phy_addr,
-                    PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
-                    &mut inner.physical_allocator,
-                );
+                page_table.map_page_unchecked(page, phy_addr, flags, &mut inner.physical_allocator);
+                // the compiler doesn't know the entry is what makes the page accessible
+                compiler_fence();
                 if phy_addr.0 == 0xfee00000 {
                     for i in 0..self.page_size() {
                         let byte = (VirtAddr::from(page).0 + i as u64) as *mut u8;
@@ -242,3 +393,221 @@ impl<T: PhysicalAllocator> PageAllocator for BasicPageAllocator<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::paging::PAGE_TABLE_ENTRY_NUM;
+    use crate::memory::physical::BasicPhysicalAllocator;
+
+    #[test_case]
+    fn dealloc_partially_unmapped() {
+        unsafe {
+            let alloc = GLOBAL_PAGE_ALLOCATOR.alloc_pages(3).unwrap();
+            let middle = alloc.first_page.next().unwrap();
+            {
+                let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+                let entry = PageTable::current_mut().page_entry_mut(middle).unwrap();
+                inner.physical_allocator.free_frame(entry.addr());
+                entry.clear();
+                invlpg(VirtAddr::from(middle).0);
+            }
+            // shouldn't panic even though the middle page is gone
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+            let page_table = PageTable::current();
+            assert!(!page_table.is_present(alloc.first_page));
+            assert!(!page_table.is_present(middle));
+            // freeing it again is survivable as well
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&alloc);
+        }
+    }
+
+    #[test_case]
+    fn reserved_frames_are_never_allocated() {
+        unsafe {
+            let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+            let phy_alloc = &mut inner.physical_allocator;
+            // the next frame allocate_frame would hand out
+            let next = phy_alloc.allocate_frame().unwrap();
+            phy_alloc.free_frame(next);
+            // a "kernel" which begins in the middle of the (used) frame before it, and ends in the middle of it
+            phy_alloc.reserve(PhyAddr(next.0.saturating_sub(0x10)), 0x20);
+            let frames = [(); 8].map(|_| phy_alloc.allocate_frame().unwrap());
+            for frame in frames {
+                assert_ne!(frame, next);
+                phy_alloc.free_frame(frame);
+            }
+            phy_alloc.free_frame(next);
+        }
+    }
+
+    #[test_case]
+    fn map_physical_distinct_ranges() {
+        unsafe {
+            // frames which are free, so that map_physical can take them
+            let (first_frame, second_frame) = {
+                let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+                let first = inner.physical_allocator.allocate_frame().unwrap();
+                let second = inner.physical_allocator.allocate_frame().unwrap();
+                inner.physical_allocator.free_frame(first);
+                inner.physical_allocator.free_frame(second);
+                (first, second)
+            };
+            let (first, _) = GLOBAL_PAGE_ALLOCATOR
+                .map_physical(first_frame, 1, PageTableEntryFlags::kernel_data())
+                .unwrap();
+            let (second, _) = GLOBAL_PAGE_ALLOCATOR
+                .map_physical(second_frame, 1, PageTableEntryFlags::kernel_data())
+                .unwrap();
+            let first_range = first.as_virt_addr().0
+                ..first.as_virt_addr().0 + (first.page_amount as u64) * PAGE_SIZE;
+            let second_range = second.as_virt_addr().0
+                ..second.as_virt_addr().0 + (second.page_amount as u64) * PAGE_SIZE;
+            assert!(first_range.end <= second_range.start || second_range.end <= first_range.start);
+            assert_eq!(
+                GLOBAL_PAGE_ALLOCATOR.inner.lock().last_page_alloc,
+                second.first_page
+            );
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&first);
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&second);
+        }
+    }
+
+    /// Take frame_count contiguous frames out of the global allocator
+    unsafe fn scratch_region(frame_count: usize) -> PhyAddr {
+        let mut inner = GLOBAL_PAGE_ALLOCATOR.inner.lock();
+        let phy_alloc = &mut inner.physical_allocator;
+        let mut candidate = unsafe { phy_alloc.allocate_frame() }.unwrap();
+        unsafe { phy_alloc.free_frame(candidate) };
+        loop {
+            if let Some(start) = unsafe { phy_alloc.alloc_phy_addr(candidate, frame_count) } {
+                return start;
+            }
+            candidate.0 += PAGE_SIZE;
+        }
+    }
+
+    #[test_case]
+    fn isolated_allocator() {
+        const FRAMES: usize = 32;
+        // never given back: the page tables the isolated allocator creates live in it
+        let region = unsafe { scratch_region(FRAMES) };
+        let region_range = region.0..region.0 + (FRAMES as u64) * PAGE_SIZE;
+        let mut bitmap = [false; FRAMES];
+        let allocator = BasicPageAllocator::new(unsafe {
+            BasicPhysicalAllocator::new(&raw mut bitmap, region)
+        });
+        let global_free = GLOBAL_PAGE_ALLOCATOR
+            .inner
+            .lock()
+            .physical_allocator
+            .free_frames();
+        let heap_allocations = crate::memory::allocator::allocation_count();
+
+        unsafe {
+            let alloc = allocator.alloc_pages(4).unwrap();
+            let free_after_alloc = allocator.inner.lock().physical_allocator.free_frames();
+            assert!(free_after_alloc <= FRAMES - 4);
+            for (i, page) in
+                Page::range(alloc.first_page, alloc.first_page.next_by(4).unwrap()).enumerate()
+            {
+                let frame = PageTable::current().page_entry(page).unwrap().addr();
+                assert!(region_range.contains(&frame.0));
+                let ptr = VirtAddr::from(page).0 as *mut u64;
+                ptr.write_volatile(i as u64);
+                assert_eq!(ptr.read_volatile(), i as u64);
+            }
+            allocator.dealloc_pages(&alloc);
+            assert!(!PageTable::current().is_present(alloc.first_page));
+            // the page tables which were left empty are freed as well
+            assert!(
+                allocator.inner.lock().physical_allocator.free_frames() >= free_after_alloc + 4
+            );
+        }
+
+        assert_eq!(
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .free_frames(),
+            global_free
+        );
+        assert_eq!(
+            crate::memory::allocator::allocation_count(),
+            heap_allocations
+        );
+    }
+
+    #[test_case]
+    fn wraps_around_at_the_end() {
+        unsafe {
+            let low = GLOBAL_PAGE_ALLOCATOR.alloc_pages(4).unwrap();
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&low);
+            // only 3 pages are left after it, so 4 can't fit without wrapping around
+            let near_the_end = Page::new((PAGE_TABLE_ENTRY_NUM as u64).pow(4) - 3);
+            assert!(near_the_end.next_by(3).is_none());
+            GLOBAL_PAGE_ALLOCATOR.inner.lock().last_page_alloc = near_the_end;
+            let again = GLOBAL_PAGE_ALLOCATOR.alloc_pages(4).unwrap();
+            // the lowest free range which fits, which is the freed one at the latest
+            assert!(again.first_page <= low.first_page);
+            assert!(again.first_page >= FIRST_PAGE);
+            GLOBAL_PAGE_ALLOCATOR.dealloc_pages(&again);
+        }
+    }
+
+    #[test_case]
+    fn several_usable_regions() {
+        const FRAMES: usize = 64;
+        let region = unsafe {
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .allocate_block(6)
+        }
+        .unwrap();
+        let frame = |i: u64| region.0 + i * PAGE_SIZE;
+        let entry = |base, length, entry_type| Entry {
+            base,
+            length,
+            entry_type,
+        };
+        let entries = [
+            entry(frame(0), 4 * PAGE_SIZE, EntryType::USABLE),
+            entry(frame(4), 12 * PAGE_SIZE, EntryType::RESERVED),
+            entry(frame(16), 8 * PAGE_SIZE, EntryType::USABLE),
+            entry(frame(24), 40 * PAGE_SIZE, EntryType::ACPI_NVS),
+        ];
+        let mut bitmap = [0u64; BuddyPhysicalAllocator::metadata_words(FRAMES)];
+        let mut phy_alloc = unsafe { BuddyPhysicalAllocator::new(&raw mut bitmap, region, FRAMES) };
+        unsafe { phy_alloc.set_regions(usable_regions(&entries.each_ref())) };
+        assert_eq!(phy_alloc.free_frames(), 12);
+        assert!(phy_alloc.manages_frame(PhyAddr(frame(17))));
+        assert!(!phy_alloc.manages_frame(PhyAddr(frame(5))));
+
+        let mut from_second = 0;
+        for _ in 0..12 {
+            let addr = unsafe { phy_alloc.allocate_frame() }.unwrap().0;
+            assert!((frame(0)..frame(4)).contains(&addr) || (frame(16)..frame(24)).contains(&addr));
+            if addr >= frame(16) {
+                from_second += 1;
+            }
+        }
+        assert_eq!(from_second, 8);
+        assert_eq!(unsafe { phy_alloc.allocate_frame() }, None);
+        // the frames between the regions aren't ours, just like device memory
+        assert_eq!(
+            unsafe { phy_alloc.alloc_phy_addr(PhyAddr(frame(6)), 2) },
+            Some(PhyAddr(frame(6)))
+        );
+
+        unsafe {
+            GLOBAL_PAGE_ALLOCATOR
+                .inner
+                .lock()
+                .physical_allocator
+                .free_block(region, 6)
+        };
+    }
+}
@@ -1,4 +1,12 @@
 pub const APIC_BASE: u32 = 0x1b;
+/// Base of the GS segment. Holds a pointer to the current cpu's PerCpu.
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+/// The value swapped into IA32_GS_BASE by swapgs
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+/// Extended feature enable register
+pub const EFER: u32 = 0xC000_0080;
+/// EFER bit which allows using PageTableEntryFlags::NO_EXECUTE
+pub const EFER_NXE: u64 = 1 << 11;
 pub unsafe fn rdmsr(msr: u32) -> u64 {
     let (high, low): (u32, u32);
 
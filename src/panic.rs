@@ -1,47 +1,161 @@
-use crate::arch_x86_64::hlt;
-use crate::qemu_println;
-use crate::stack_trace::StackTrace;
-use crate::{CONSOLE, screen::Color};
-use core::fmt::Write;
-use core::panic::PanicInfo;
+use crate::arch_x86_64::{cr0, cr2, cr3, cr4, rbp, rflags, rsp};
+use crate::dev::local_apic::LocalApic;
+use crate::interrupts::{PageFaultError, fatal_page_fault};
+use core::fmt::{Display, Formatter};
+use core::time::Duration;
+// only the real panic handler needs these, tests have their own
+#[cfg(not(test))]
+use {
+    crate::{arch_x86_64::hlt, qemu_println, screen::Color, stack_trace::StackTrace},
+    core::{
+        fmt::Write,
+        panic::PanicInfo,
+        sync::atomic::{AtomicBool, Ordering},
+    },
+};
 
 // think of a better system rather than doing this,
 // since it doesn't help against multi-cpu
+#[cfg(not(test))]
 unsafe fn _force_unlock_panic_outputs() {
     unsafe {
-        crate::CONSOLE.force_unlock();
+        if let Some(console) = crate::console() {
+            console.force_unlock();
+        }
         crate::qemu_log::GLOBAL_LOGGER.force_unlock();
     }
 }
 
+/// Set once a panic started, so that a panic inside the panic handler doesn't run it all over again
+#[cfg(not(test))]
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The first line of a panic, saying which cpu panicked and when.
+/// Each part is None if getting it could fault (i.e. the device isn't mapped yet), so that we don't panic inside the panic.
+pub struct PanicHeader {
+    pub cpu: Option<u32>,
+    pub uptime: Option<Duration>,
+}
+
+impl PanicHeader {
+    pub fn current() -> Self {
+        PanicHeader {
+            cpu: LocalApic::is_mapped().then(LocalApic::id),
+            uptime: crate::time::uptime(),
+        }
+    }
+}
+
+impl Display for PanicHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.cpu {
+            Some(cpu) => write!(f, "cpu {} panicked", cpu)?,
+            None => write!(f, "cpu ? panicked")?,
+        }
+        match self.uptime {
+            Some(uptime) => write!(f, " at {}.{:06}s", uptime.as_secs(), uptime.subsec_micros()),
+            None => write!(f, " at an unknown time"),
+        }
+    }
+}
+
+/// The state of the cpu when it panicked, printed before the stack trace
+pub struct RegisterDump {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    /// Set if we're panicking on a page fault, whose address is cr2
+    pub page_fault: Option<PageFaultError>,
+}
+
+impl RegisterDump {
+    // always inlined, so that rsp and rbp are of the panic handler and not of this function
+    #[inline(always)]
+    pub fn current() -> Self {
+        RegisterDump {
+            cr0: cr0(),
+            cr2: cr2(),
+            cr3: cr3(),
+            cr4: cr4(),
+            rflags: unsafe { rflags() },
+            rsp: rsp(),
+            rbp: rbp(),
+            page_fault: fatal_page_fault(),
+        }
+    }
+}
+
+impl Display for RegisterDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "cr0: {:#018x} cr2: {:#018x} cr3: {:#018x} cr4: {:#018x}\nrflags: {:#018x} rsp: {:#018x} rbp: {:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4, self.rflags, self.rsp, self.rbp
+        )?;
+        if let Some(error) = self.page_fault {
+            write!(
+                f,
+                "\npage fault at {:#x}: {}, {}, {} mode",
+                self.cr2,
+                if error.contains(PageFaultError::PRESENT) {
+                    "protection violation"
+                } else {
+                    "not present"
+                },
+                if error.contains(PageFaultError::WRITE) {
+                    "write"
+                } else {
+                    "read"
+                },
+                if error.contains(PageFaultError::USER) {
+                    "user"
+                } else {
+                    "kernel"
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(inf: &PanicInfo) -> ! {
-    // Note: it is fine to to use the SCREEN/CONSOLE here since if the screen is not functional we're doing something else
+    // checked before anything takes a lock, since whatever panicked may have been holding it
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        double_panic(inf);
+    }
+    // Note: the console is only used if screen::init succeeded, otherwise everything goes to the qemu log
     // ensure that the console/logger aren't locked
     // safety: currently the computer only runs on 1 cpu,
     // so panic = nothing else runs
     unsafe { _force_unlock_panic_outputs() }
+    let header = PanicHeader::current();
+    let registers = RegisterDump::current();
+    qemu_println!("{}", header);
     qemu_println!("{}", inf);
+    qemu_println!("{}", registers);
 
-    let mut console = CONSOLE.lock();
-    console.bg_color = Color::blue();
-    console.fg_color = Color::white();
-    console.clear();
-    writeln!(console, "{}", inf).unwrap();
-
-    let mut trace = StackTrace::new();
-
-    writeln!(console, "\nstack trace:").unwrap();
-    let mut func_name = unsafe { StackTrace::lookup_current_function().unwrap() };
-    while let Some(addr) = unsafe { trace.next() } {
-        writeln!(console, "{} <called at {:#x}>", func_name, addr).unwrap();
-        func_name = if let Some(sym) = unsafe { StackTrace::lookup_symbol_from_return_addr(addr) } {
-            sym
-        } else {
-            "unknown_func"
-        };
+    let console = crate::console().map(|console| {
+        let mut console = console.lock();
+        console.bg_color = Color::blue();
+        console.fg_color = Color::white();
+        console.clear();
+        writeln!(console, "{}", header).unwrap();
+        writeln!(console, "{}", inf).unwrap();
+        writeln!(console, "{}", registers).unwrap();
+        write_stack_trace(&mut *console);
+        console
+    });
+    if console.is_none() {
+        // headless, so the stack trace goes to the qemu log instead
+        write_stack_trace(&mut *crate::qemu_log::GLOBAL_LOGGER.lock());
     }
-    writeln!(console, "{}", func_name).unwrap();
+    crate::qemu_log::dump_tail(crate::qemu_log::FAILURE_TAIL_LEN);
 
     loop {
         // we keep the CONSOLE locked so that no other CPU writes to it
@@ -51,3 +165,107 @@ fn panic(inf: &PanicInfo) -> ! {
         }
     }
 }
+
+/// A panic inside the panic handler (or on another cpu at the same time). The console and the stack trace
+/// are what may have panicked, so only say where it happened, on the qemu log.
+#[cfg(not(test))]
+fn double_panic(inf: &PanicInfo) -> ! {
+    // safety: if it's locked, it's most likely by the panic we're inside of, which never resumes
+    unsafe { crate::qemu_log::GLOBAL_LOGGER.force_unlock() };
+    // the message is formatted by whoever panicked, which may panic again, the location can't
+    match inf.location() {
+        Some(location) => qemu_println!("double panic at {}", location),
+        None => qemu_println!("double panic"),
+    }
+    loop {
+        unsafe { hlt() };
+    }
+}
+
+#[cfg(not(test))]
+fn write_stack_trace(out: &mut impl Write) {
+    let mut trace = StackTrace::new();
+
+    writeln!(out, "\nstack trace:").unwrap();
+    match StackTrace::lookup_current_function() {
+        Ok(current) => {
+            let mut func_name = current.unwrap_or("unknown_func");
+            while let Some(addr) = unsafe { trace.next() } {
+                writeln!(out, "{} <called at {:#x}>", func_name, addr).unwrap();
+                func_name = StackTrace::lookup_symbol_from_return_addr(addr)
+                    .ok()
+                    .flatten()
+                    .unwrap_or("unknown_func");
+            }
+            writeln!(out, "{}", func_name).unwrap();
+        }
+        // without names, the addresses are still worth something
+        Err(e) => {
+            writeln!(out, "<{}>", e).unwrap();
+            while let Some(addr) = unsafe { trace.next() } {
+                writeln!(out, "<called at {:#x}>", addr).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn header_without_devices() {
+        let header = PanicHeader {
+            cpu: None,
+            uptime: None,
+        };
+        assert_eq!(format!("{}", header), "cpu ? panicked at an unknown time");
+        let header = PanicHeader {
+            cpu: Some(3),
+            uptime: Some(Duration::from_micros(2_000_015)),
+        };
+        assert_eq!(format!("{}", header), "cpu 3 panicked at 2.000015s");
+    }
+
+    #[test_case]
+    fn current_header() {
+        // make sure both are available
+        LocalApic::id();
+        crate::dev::hpet::Hpet::enable();
+        let header = PanicHeader::current();
+        // xapic ids are 8 bits
+        assert!(header.cpu.is_some_and(|cpu| cpu <= 0xff));
+        assert!(header.uptime.is_some());
+    }
+
+    #[test_case]
+    fn register_dump() {
+        let mut dump = RegisterDump {
+            cr0: 0x8001_0033,
+            cr2: 0xdead_b000,
+            cr3: 0x1000,
+            cr4: 0x6a0,
+            rflags: 0x2,
+            rsp: 0xffff_8000_0000_1000,
+            rbp: 0xffff_8000_0000_1010,
+            page_fault: None,
+        };
+        assert_eq!(
+            format!("{}", dump),
+            "cr0: 0x0000000080010033 cr2: 0x00000000deadb000 cr3: 0x0000000000001000 cr4: 0x00000000000006a0\n\
+             rflags: 0x0000000000000002 rsp: 0xffff800000001000 rbp: 0xffff800000001010"
+        );
+        dump.page_fault = Some(PageFaultError::WRITE);
+        assert!(
+            format!("{}", dump)
+                .ends_with("\npage fault at 0xdeadb000: not present, write, kernel mode")
+        );
+        dump.page_fault = Some(PageFaultError::PRESENT | PageFaultError::USER);
+        assert!(format!("{}", dump).ends_with(": protection violation, read, user mode"));
+
+        let current = RegisterDump::current();
+        assert_eq!(current.cr3, cr3());
+        assert!(current.rbp >= current.rsp);
+    }
+}
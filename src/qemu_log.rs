@@ -3,6 +3,8 @@ use core::fmt::Write;
 
 use spin::mutex::SpinMutex;
 
+use crate::io::Port;
+
 #[macro_export]
 macro_rules! qemu_print {
     ($($arg:tt)*) => ($crate::qemu_log::_print(format_args!($($arg)*)));
@@ -14,16 +16,59 @@ macro_rules! qemu_println {
     ($($arg:tt)*) => ($crate::qemu_print!("{}\n", format_args!($($arg)*)));
 }
 
-const QEMU_PORT: u16 = 0xe9;
-pub struct QemuLogger;
+const QEMU_PORT: Port<u8> = Port::new(0xe9);
+/// Amount of the latest logged bytes which are kept in memory, see dump_tail
+pub const LOG_RING_SIZE: usize = 4096;
+/// How much of the log dump_tail repeats when something failed, enough for the last few messages
+pub const FAILURE_TAIL_LEN: usize = 1024;
+/// Printed after the tail in dump_tail, so that CI can tell the output wasn't cut off
+pub const TAIL_END_MARKER: &str = "--- end of log tail ---";
+
+pub struct QemuLogger {
+    ring: [u8; LOG_RING_SIZE],
+    /// total amount of bytes ever written, the next byte goes to ring[written % LOG_RING_SIZE]
+    written: usize,
+}
+
+impl QemuLogger {
+    pub const fn new() -> Self {
+        QemuLogger {
+            ring: [0; LOG_RING_SIZE],
+            written: 0,
+        }
+    }
+
+    /// Keep a logged byte in the ring
+    fn record(&mut self, byte: u8) {
+        self.ring[self.written % LOG_RING_SIZE] = byte;
+        self.written = self.written.wrapping_add(1);
+    }
+
+    /// The last (up to) n logged bytes, as the two parts of the ring in order
+    fn tail(&self, n: usize) -> (&[u8], &[u8]) {
+        let n = n.min(self.written).min(LOG_RING_SIZE);
+        let end = self.written % LOG_RING_SIZE;
+        if n <= end {
+            (&self.ring[end - n..end], &[])
+        } else {
+            (&self.ring[LOG_RING_SIZE - (n - end)..], &self.ring[..end])
+        }
+    }
+}
+
+impl Default for QemuLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-pub static GLOBAL_LOGGER: SpinMutex<QemuLogger> = SpinMutex::new(QemuLogger {});
+pub static GLOBAL_LOGGER: SpinMutex<QemuLogger> = SpinMutex::new(QemuLogger::new());
 
 /// Safety: should only be ran when we're in qemu and with a lock if
 /// it's in a multi-cpu environment
 unsafe fn qemu_write(c: u8) {
     unsafe {
-        crate::io::write_u8(QEMU_PORT, c);
+        QEMU_PORT.write(c);
     }
 }
 
@@ -32,6 +77,7 @@ impl fmt::Write for QemuLogger {
         for char in s.chars() {
             if let Some(ascii) = char.as_ascii() {
                 unsafe { qemu_write(ascii.to_u8()) };
+                self.record(ascii.to_u8());
             }
         }
         Ok(())
@@ -41,3 +87,54 @@ impl fmt::Write for QemuLogger {
 pub fn _print(args: fmt::Arguments) {
     GLOBAL_LOGGER.lock().write_fmt(args).unwrap();
 }
+
+/// Write s to the port without taking GLOBAL_LOGGER's lock, for interrupt handlers which might have
+/// interrupted a logger (i.e. along with util::StackString). It isn't kept in the ring, and may be interleaved
+/// with other output.
+pub fn write_unlocked(s: &str) {
+    for byte in s.bytes().filter(u8::is_ascii) {
+        unsafe { qemu_write(byte) };
+    }
+}
+
+/// Write the last n logged bytes (at most LOG_RING_SIZE) to the port again, followed by TAIL_END_MARKER.
+/// Meant for right before the machine stops (panic, exiting qemu), so that the latest output survives
+/// even if earlier output was lost. With n = 0 only the marker is written.
+pub fn dump_tail(n: usize) {
+    let logger = GLOBAL_LOGGER.lock();
+    let (first, second) = logger.tail(n);
+    unsafe {
+        if n > 0 {
+            for byte in b"\n--- log tail ---\n".iter().chain(first).chain(second) {
+                qemu_write(*byte);
+            }
+        }
+        for byte in b"\n".iter().chain(TAIL_END_MARKER.as_bytes()).chain(b"\n") {
+            qemu_write(*byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ring_tail() {
+        let mut logger = QemuLogger::new();
+        assert_eq!(logger.tail(10), (&[][..], &[][..]));
+        // record directly, so that the test doesn't spam the log
+        b"hello".iter().for_each(|b| logger.record(*b));
+        assert_eq!(logger.tail(3), (&b"llo"[..], &[][..]));
+        assert_eq!(logger.tail(100), (&b"hello"[..], &[][..]));
+        // wrap around
+        for _ in 0..LOG_RING_SIZE {
+            logger.record(b'a');
+        }
+        b"bye".iter().for_each(|b| logger.record(*b));
+        let (first, second) = logger.tail(5);
+        assert_eq!([first, second].concat(), b"aabye");
+        let (first, second) = logger.tail(usize::MAX);
+        assert_eq!(first.len() + second.len(), LOG_RING_SIZE);
+    }
+}
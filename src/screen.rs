@@ -1,18 +1,85 @@
+use core::ptr::NonNull;
+
 use limine::framebuffer::Framebuffer;
+use spin::Once;
+
+use crate::{
+    CONSOLE, FRAMEBUFFER_REQUEST,
+    console::{Console, ThreadSafeConsole},
+    util::volatile::VolatileSlice,
+};
+
+/// The screen of the first framebuffer, set by init
+static SCREEN: Once<Screen> = Once::new();
 
 #[derive(Clone)]
 pub struct Screen {
-    framebuffer_addr: *mut u8,
+    /// bytes_per_row * height bytes
+    framebuffer: VolatileSlice,
     pub width: usize,
     pub height: usize,
     bytes_per_pixel: usize,
     bytes_per_row: usize,
+    /// where the channels go in a pixel, Color is converted to it when drawing
+    pub pixel_format: PixelFormat,
 }
 
-/// safety: the pointer in framebuffer_addr needs to be unsafely derefrenced anyways
-/// to be used, and the API always requires &mut to write to the pointer,
+/// safety: the framebuffer is only accessed through VolatileSlice,
+/// and the API always requires &mut to write to it,
 /// implying single ownership at the time of writing.
 unsafe impl Send for Screen {}
+/// safety: same as Send, a shared Screen can't write to the pointer
+unsafe impl Sync for Screen {}
+
+/// Limine didn't give us any framebuffer, i.e. we're running headless
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct NoFramebuffer;
+
+/// Set up the screen and the console on the first framebuffer.
+/// Until this succeeds, console output only goes to the qemu log, so it's fine to keep running if it fails.
+/// Calling it again returns the result of the first successful call, or tries again.
+pub fn init() -> Result<(), NoFramebuffer> {
+    let screen = SCREEN.try_call_once(|| {
+        let framebuffer = FRAMEBUFFER_REQUEST
+            .get_response()
+            .and_then(|response| response.framebuffers().next())
+            .ok_or(NoFramebuffer)?;
+        // safety: limine protocol should give us accurate data, and the framebuffer lives forever
+        Ok(unsafe { Screen::new(framebuffer) })
+    })?;
+    CONSOLE.call_once(|| {
+        ThreadSafeConsole::new(Console::new(screen.clone(), Color::black(), Color::blue()))
+    });
+    Ok(())
+}
+
+/// The screen, if init succeeded
+pub fn screen() -> Option<Screen> {
+    SCREEN.get().cloned()
+}
+
+/// Where the red, green and blue channels are in a pixel of the framebuffer, i.e. RGB or BGR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub red_shift: u8,
+    pub red_size: u8,
+    pub green_shift: u8,
+    pub green_size: u8,
+    pub blue_shift: u8,
+    pub blue_size: u8,
+}
+
+impl PixelFormat {
+    /// 0x00RRGGBB, the layout of Color itself
+    pub const RGB: PixelFormat = PixelFormat {
+        red_shift: 16,
+        red_size: 8,
+        green_shift: 8,
+        green_size: 8,
+        blue_shift: 0,
+        blue_size: 8,
+    };
+}
 
 /// RGB color
 #[derive(Clone, Copy)]
@@ -38,20 +105,101 @@ impl Color {
     pub fn black() -> Self {
         Self(0)
     }
+
+    /// Color key for Image pixels which shouldn't be drawn (magenta)
+    pub const fn transparent() -> Self {
+        Self(0xFF00FF)
+    }
+
+    pub const fn from_rgb(red: u8, green: u8, blue: u8) -> Self {
+        Self(((red as u32) << 16) | ((green as u32) << 8) | blue as u32)
+    }
+
+    pub const fn as_u32(&self) -> u32 {
+        self.0
+    }
+
+    /// The pixel value of the color in the framebuffer of the screen, according to its pixel format
+    pub fn to_framebuffer_u32(&self, screen: &Screen) -> u32 {
+        let format = screen.pixel_format;
+        // scale the 8 bits of the channel to the size the framebuffer has for it
+        let channel = |value: u32, shift: u8, size: u8| {
+            let value = if size <= 8 {
+                value >> (8 - size)
+            } else {
+                value << (size - 8)
+            };
+            value << shift
+        };
+        channel((self.0 >> 16) & 0xff, format.red_shift, format.red_size)
+            | channel((self.0 >> 8) & 0xff, format.green_shift, format.green_size)
+            | channel(self.0 & 0xff, format.blue_shift, format.blue_size)
+    }
+}
+
+/// An image made of raw RGB pixels, row by row.
+/// Pixels which equal Color::transparent() are skipped when drawing.
+pub struct Image<'a> {
+    pub width: usize,
+    pub height: usize,
+    /// must be of length width * height
+    pub pixels: &'a [u32],
+}
+
+/// The position is outside of the screen, see Screen::try_draw_pixel
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct OutOfBounds {
+    pub x: usize,
+    pub y: usize,
 }
 
 impl Screen {
     /// Create a new screen from a framebuffer.
-    /// ## Saftey
+    /// # Safety
     /// the provided framebuffer must have valid information,
     /// and must live as long as the Screen lives.
     pub unsafe fn new(framebuffer: Framebuffer) -> Self {
+        let len = (framebuffer.pitch() * framebuffer.height()) as usize;
         Self {
-            framebuffer_addr: framebuffer.addr() as *mut _,
+            framebuffer: unsafe {
+                VolatileSlice::new(NonNull::new(framebuffer.addr()).unwrap(), len)
+            },
             bytes_per_pixel: (framebuffer.bpp() / 8) as usize,
             bytes_per_row: framebuffer.pitch() as usize,
             height: framebuffer.height() as usize,
             width: framebuffer.width() as usize,
+            pixel_format: PixelFormat {
+                red_shift: framebuffer.red_mask_shift(),
+                red_size: framebuffer.red_mask_size(),
+                green_shift: framebuffer.green_mask_shift(),
+                green_size: framebuffer.green_mask_size(),
+                blue_shift: framebuffer.blue_mask_shift(),
+                blue_size: framebuffer.blue_mask_size(),
+            },
+        }
+    }
+
+    /// Create a screen from a raw buffer, i.e. some memory which isn't an actual framebuffer.
+    /// Its pixel format is PixelFormat::RGB.
+    /// # Safety
+    /// addr must be valid for writes of bytes_per_row * height bytes,
+    /// and must live as long as the Screen lives.
+    pub unsafe fn from_raw(
+        addr: *mut u8,
+        width: usize,
+        height: usize,
+        bytes_per_pixel: usize,
+        bytes_per_row: usize,
+    ) -> Self {
+        Self {
+            framebuffer: unsafe {
+                VolatileSlice::new(NonNull::new(addr).unwrap(), bytes_per_row * height)
+            },
+            width,
+            height,
+            bytes_per_pixel,
+            bytes_per_row,
+            pixel_format: PixelFormat::RGB,
         }
     }
 
@@ -61,33 +209,293 @@ impl Screen {
     pub fn draw_pixel(&mut self, x: usize, y: usize, color: Color) {
         assert!(x < self.width && y < self.height);
         let pixel_offset = x * self.bytes_per_pixel + y * self.bytes_per_row;
-        unsafe {
-            self.write_pixel(pixel_offset, color);
+        self.write_pixel(pixel_offset, color);
+    }
+
+    /// draw a single pixel on the screen, or return OutOfBounds (and draw nothing) if the position goes out of the screen.
+    pub fn try_draw_pixel(&mut self, x: usize, y: usize, color: Color) -> Result<(), OutOfBounds> {
+        if x >= self.width || y >= self.height {
+            return Err(OutOfBounds { x, y });
         }
+        let pixel_offset = x * self.bytes_per_pixel + y * self.bytes_per_row;
+        self.write_pixel(pixel_offset, color);
+        Ok(())
     }
 
-    /// write a single pixel to the framebuffer
-    /// ## Saftey
-    /// Ensure that the offset is valid. This does not check it.
+    /// write a single pixel to the framebuffer, in its pixel format
+    /// Note: panics if the offset is outside of the framebuffer, which means the offset was calculated wrong.
     /// Takes &mut self to ensure ownership of the Screen.
     #[inline]
-    unsafe fn write_pixel(&mut self, offset: usize, color: Color) {
-        unsafe {
-            self.framebuffer_addr
-                .add(offset)
-                .cast::<u32>()
-                .write(color.0);
+    fn write_pixel(&mut self, offset: usize, color: Color) {
+        let pixel = color.to_framebuffer_u32(self);
+        self.write_raw_pixel(offset, pixel);
+    }
+
+    /// write a pixel value which is already in the pixel format of the framebuffer, see write_pixel.
+    /// Only bytes_per_pixel bytes are written, so a 3 byte pixel doesn't overwrite the next one.
+    #[inline]
+    fn write_raw_pixel(&mut self, offset: usize, pixel: u32) {
+        let written = if self.bytes_per_pixel == size_of::<u32>() {
+            self.framebuffer.write_u32_at(offset, pixel)
+        } else {
+            let bytes = pixel.to_le_bytes();
+            self.framebuffer
+                .write_bytes_at(offset, &bytes[..self.bytes_per_pixel])
+        };
+        written.expect("pixel offset outside of the framebuffer");
+    }
+
+    /// read a pixel value in the pixel format of the framebuffer, the counterpart of write_raw_pixel
+    #[inline]
+    fn read_raw_pixel(&self, offset: usize) -> u32 {
+        let read = if self.bytes_per_pixel == size_of::<u32>() {
+            self.framebuffer.read_u32_at(offset)
+        } else {
+            let mut bytes = [0; 4];
+            self.framebuffer
+                .read_bytes_at(offset, &mut bytes[..self.bytes_per_pixel])
+                .map(|()| u32::from_le_bytes(bytes))
+        };
+        read.expect("pixel offset outside of the framebuffer")
+    }
+
+    /// Draw an image with its top left corner at (x, y).
+    /// Parts of the image which go outside the screen are clipped, and transparent pixels are skipped.
+    pub fn draw_image(&mut self, x: usize, y: usize, img: &Image) {
+        assert_eq!(img.pixels.len(), img.width * img.height);
+        // nothing to draw, and the rows can't be split into chunks of width 0
+        if img.width == 0 || img.height == 0 || x >= self.width || y >= self.height {
+            return;
+        }
+        let visible_width = img.width.min(self.width - x);
+        let visible_height = img.height.min(self.height - y);
+        for (row_num, row) in img
+            .pixels
+            .chunks(img.width)
+            .take(visible_height)
+            .enumerate()
+        {
+            let mut offset = x * self.bytes_per_pixel + (y + row_num) * self.bytes_per_row;
+            for pixel in &row[..visible_width] {
+                if *pixel != Color::transparent().0 {
+                    self.write_pixel(offset, Color(*pixel));
+                }
+                offset += self.bytes_per_pixel;
+            }
+        }
+    }
+
+    /// Invert the colors of a rectangle with its top left corner at (x, y), clipped to the screen.
+    /// Inverting twice restores the original pixels, so it can be used to draw something over the screen and erase it.
+    pub fn invert_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        // every channel bit set, whatever the pixel format is
+        let white = Color::white().to_framebuffer_u32(self);
+        for y in y..y_end {
+            for x in x..x_end {
+                let offset = x * self.bytes_per_pixel + y * self.bytes_per_row;
+                let pixel = self.read_raw_pixel(offset);
+                self.write_raw_pixel(offset, pixel ^ white);
+            }
         }
     }
 
     /// Paint all the pixels at once
     pub fn draw_all(&mut self, color: Color) {
-        for y in 0..self.height {
-            let mut offset = y * self.bytes_per_row;
-            for _ in 0..self.width {
-                unsafe { self.write_pixel(offset, color) };
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Paint a rectangle with its top left corner at (x, y), clipped to the screen
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        let pixel = color.to_framebuffer_u32(self);
+        for y in y..y_end {
+            let mut offset = x * self.bytes_per_pixel + y * self.bytes_per_row;
+            for _ in x..x_end {
+                self.write_raw_pixel(offset, pixel);
                 offset += self.bytes_per_pixel;
             }
         }
     }
+
+    /// Copy the rectangle with its top left corner at (x, y) so that its top left corner is at (to_x, to_y),
+    /// i.e. to scroll. The rectangles may overlap, and the parts of them which go outside the screen are clipped.
+    pub fn copy_rect(
+        &mut self,
+        (x, y): (usize, usize),
+        (to_x, to_y): (usize, usize),
+        width: usize,
+        height: usize,
+    ) {
+        let width = width
+            .min(self.width.saturating_sub(x))
+            .min(self.width.saturating_sub(to_x));
+        let height = height
+            .min(self.height.saturating_sub(y))
+            .min(self.height.saturating_sub(to_y));
+        let (bytes_per_pixel, bytes_per_row) = (self.bytes_per_pixel, self.bytes_per_row);
+        let mut copy_row = |i: usize| {
+            let src = x * bytes_per_pixel + (y + i) * bytes_per_row;
+            let dest = to_x * bytes_per_pixel + (to_y + i) * bytes_per_row;
+            self.framebuffer
+                .copy_within(src..src + width * bytes_per_pixel, dest)
+                .expect("rectangle outside of the framebuffer");
+        };
+        // copy away from the overlap, so that no row is overwritten before it's copied
+        if to_y <= y {
+            (0..height).for_each(&mut copy_row);
+        } else {
+            (0..height).rev().for_each(&mut copy_row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn draw_image_clipped() {
+        let (width, height) = (4, 4);
+        let mut buf = vec![0u32; width * height];
+        let mut screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        let img = Image {
+            width: 2,
+            height: 2,
+            pixels: &[1, 2, 3, Color::transparent().as_u32()],
+        };
+        screen.draw_image(1, 2, &img);
+        assert_eq!(buf[2 * width + 1], 1);
+        assert_eq!(buf[2 * width + 2], 2);
+        assert_eq!(buf[3 * width + 1], 3);
+        // transparent
+        assert_eq!(buf[3 * width + 2], 0);
+        assert_eq!(buf.iter().filter(|p| **p != 0).count(), 3);
+
+        buf.fill(0);
+        let mut screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        // only the top left pixel of the image is on screen
+        screen.draw_image(3, 3, &img);
+        assert_eq!(buf[3 * width + 3], 1);
+        assert_eq!(buf.iter().filter(|p| **p != 0).count(), 1);
+        // completely off screen
+        screen.draw_image(4, 0, &img);
+        assert_eq!(buf.iter().filter(|p| **p != 0).count(), 1);
+        // empty images
+        for (width, height) in [(0, 3), (3, 0), (0, 0)] {
+            let empty = Image {
+                width,
+                height,
+                pixels: &[],
+            };
+            screen.draw_image(0, 0, &empty);
+        }
+        assert_eq!(buf.iter().filter(|p| **p != 0).count(), 1);
+    }
+
+    #[test_case]
+    fn three_bytes_per_pixel() {
+        // 2x1 pixels, and 2 bytes past the end of the framebuffer which must stay untouched
+        let mut buf = [0u8; 8];
+        let mut screen = unsafe { Screen::from_raw(buf.as_mut_ptr(), 2, 1, 3, 6) };
+        screen.draw_pixel(1, 0, Color::from_rgb(0x12, 0x34, 0x56));
+        assert_eq!(buf, [0, 0, 0, 0x56, 0x34, 0x12, 0, 0]);
+        screen.draw_pixel(0, 0, Color::white());
+        assert_eq!(buf, [0xff, 0xff, 0xff, 0x56, 0x34, 0x12, 0, 0]);
+        screen.invert_rect(0, 0, 2, 1);
+        assert_eq!(buf, [0, 0, 0, 0xa9, 0xcb, 0xed, 0, 0]);
+    }
+
+    #[test_case]
+    fn copy_and_fill_rects() {
+        let (width, height) = (4, 4);
+        let mut buf = (0..(width * height) as u32).collect::<alloc::vec::Vec<_>>();
+        let mut screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        // scroll the bottom 3 rows up by one
+        screen.copy_rect((0, 1), (0, 0), width, 3);
+        screen.fill_rect(0, 3, width, 1, Color(0xff));
+        assert_eq!(buf[..12], (4..16).collect::<alloc::vec::Vec<_>>()[..]);
+        assert_eq!(buf[12..], [0xff; 4]);
+
+        // down and to the right, clipped
+        let mut screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        screen.copy_rect((0, 0), (2, 1), 4, 4);
+        assert_eq!(buf[..4], [4, 5, 6, 7]);
+        assert_eq!(buf[4..8], [8, 9, 4, 5]);
+        assert_eq!(buf[8..12], [12, 13, 8, 9]);
+        assert_eq!(buf[12..], [0xff, 0xff, 12, 13]);
+    }
+
+    #[test_case]
+    fn pixel_formats() {
+        let mut buf = [0u32; 1];
+        let mut screen = unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), 1, 1, 4, 4) };
+        let color = Color::from_rgb(0x12, 0x34, 0x56);
+        assert_eq!(color.to_framebuffer_u32(&screen), 0x123456);
+        screen.pixel_format = PixelFormat {
+            red_shift: 0,
+            green_shift: 8,
+            blue_shift: 16,
+            ..PixelFormat::RGB
+        };
+        assert_eq!(color.to_framebuffer_u32(&screen), 0x563412);
+        assert_ne!(
+            Color::red().to_framebuffer_u32(&screen),
+            Color::red().as_u32()
+        );
+        screen.draw_pixel(0, 0, Color::red());
+        assert_eq!(buf[0], 0xff);
+        screen.invert_rect(0, 0, 1, 1);
+        assert_eq!(buf[0], 0xffff00);
+
+        // 5-6-5, the channels are scaled down
+        screen.pixel_format = PixelFormat {
+            red_shift: 11,
+            red_size: 5,
+            green_shift: 5,
+            green_size: 6,
+            blue_shift: 0,
+            blue_size: 5,
+        };
+        assert_eq!(Color::white().to_framebuffer_u32(&screen), 0xffff);
+        assert_eq!(Color::red().to_framebuffer_u32(&screen), 0xf800);
+    }
+
+    #[test_case]
+    fn try_draw_pixel_off_screen() {
+        let (width, height) = (4, 4);
+        let mut buf = vec![0u32; width * height];
+        let mut screen =
+            unsafe { Screen::from_raw(buf.as_mut_ptr().cast(), width, height, 4, width * 4) };
+        assert_eq!(screen.try_draw_pixel(3, 3, Color::white()), Ok(()));
+        assert_eq!(
+            screen.try_draw_pixel(4, 0, Color::white()),
+            Err(OutOfBounds { x: 4, y: 0 })
+        );
+        assert_eq!(
+            screen.try_draw_pixel(0, usize::MAX, Color::white()),
+            Err(OutOfBounds {
+                x: 0,
+                y: usize::MAX
+            })
+        );
+        assert_eq!(buf.iter().filter(|p| **p != 0).count(), 1);
+    }
+
+    #[test_case]
+    fn init_is_idempotent() {
+        let first = init();
+        assert_eq!(init(), first);
+        // the console exists exactly when the screen does
+        assert_eq!(screen().is_some(), crate::console().is_some());
+        // printing works either way, headless output goes to the qemu log
+        crate::console_println!("console available: {}", crate::console().is_some());
+    }
 }
@@ -0,0 +1,131 @@
+// Deferred work for interrupt handlers ("bottom halves").
+// Interrupt handlers shouldn't lock or allocate, so instead of doing heavy work they raise a softirq,
+// which is later run by softirq::run_pending outside of the interrupt context.
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Maximum amount of pending softirqs, raising more than that drops them.
+pub const SOFTIRQ_QUEUE_SIZE: usize = 64;
+
+struct Slot {
+    // fn(usize) as usize, 0 if the slot wasn't written yet
+    func: AtomicUsize,
+    data: AtomicUsize,
+}
+
+static QUEUE: [Slot; SOFTIRQ_QUEUE_SIZE] = [const {
+    Slot {
+        func: AtomicUsize::new(0),
+        data: AtomicUsize::new(0),
+    }
+}; SOFTIRQ_QUEUE_SIZE];
+// both only go up, the slot of an index is index % SOFTIRQ_QUEUE_SIZE
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+static TAIL: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// only one consumer at a time
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Queue func(data) to be run later by run_pending. Doesn't lock or allocate, so it's fine to call in an interrupt handler.
+/// Returns false if the queue is full, in which case the work is dropped and counted in dropped().
+pub fn raise(func: fn(usize), data: usize) -> bool {
+    // reserve a slot
+    let mut tail = TAIL.load(Ordering::Relaxed);
+    loop {
+        // a stale head is smaller than the real one, so this can only be overly cautious
+        if tail.wrapping_sub(HEAD.load(Ordering::Acquire)) >= SOFTIRQ_QUEUE_SIZE {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        match TAIL.compare_exchange_weak(
+            tail,
+            tail.wrapping_add(1),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(current) => tail = current,
+        }
+    }
+    let slot = &QUEUE[tail % SOFTIRQ_QUEUE_SIZE];
+    slot.data.store(data, Ordering::Relaxed);
+    // publishes data as well
+    slot.func.store(func as usize, Ordering::Release);
+    true
+}
+
+/// Run the pending softirqs, should be called with interrupts enabled (i.e. from the main loop after a hlt).
+/// Returns the amount of softirqs that were run. If another cpu is already running them, returns 0.
+pub fn run_pending() -> usize {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let mut ran = 0;
+    loop {
+        let head = HEAD.load(Ordering::Relaxed);
+        if head == TAIL.load(Ordering::Acquire) {
+            break;
+        }
+        let slot = &QUEUE[head % SOFTIRQ_QUEUE_SIZE];
+        let func = slot.func.swap(0, Ordering::Acquire);
+        if func == 0 {
+            // reserved but not written yet, we'll get it next time
+            break;
+        }
+        let data = slot.data.load(Ordering::Relaxed);
+        // free the slot before running, so that the work itself may raise softirqs
+        HEAD.store(head.wrapping_add(1), Ordering::Release);
+        // safety: only raise writes to func, and it writes a fn(usize)
+        let func: fn(usize) = unsafe { core::mem::transmute(func) };
+        func(data);
+        ran += 1;
+    }
+    RUNNING.store(false, Ordering::Release);
+    ran
+}
+
+/// Whether there are softirqs waiting for run_pending
+pub fn is_pending() -> bool {
+    HEAD.load(Ordering::Relaxed) != TAIL.load(Ordering::Acquire)
+}
+
+/// Amount of softirqs dropped since boot because the queue was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(amount: usize) {
+        RAN.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn run_pending_once() {
+        RAN.store(0, Ordering::Relaxed);
+        // simulate a handler
+        assert!(raise(count, 3));
+        assert!(raise(count, 4));
+        assert!(is_pending());
+        assert_eq!(RAN.load(Ordering::Relaxed), 0);
+        assert_eq!(run_pending(), 2);
+        assert!(!is_pending());
+        assert_eq!(RAN.load(Ordering::Relaxed), 7);
+        assert_eq!(run_pending(), 0);
+        assert_eq!(RAN.load(Ordering::Relaxed), 7);
+    }
+
+    #[test_case]
+    fn overflow_drops() {
+        let dropped_before = dropped();
+        for _ in 0..SOFTIRQ_QUEUE_SIZE {
+            assert!(raise(count, 0));
+        }
+        assert!(!raise(count, 0));
+        assert_eq!(dropped(), dropped_before + 1);
+        assert_eq!(run_pending(), SOFTIRQ_QUEUE_SIZE);
+    }
+}
@@ -1,4 +1,44 @@
-use crate::{KERNEL_SYMBOL_MODULE, MODULE_REQUEST, arch_x86_64, kernel_virt_begin};
+use core::fmt;
+
+use alloc::vec::Vec;
+use limine::file::File;
+use spin::Once;
+
+use crate::{
+    KERNEL_SYMBOL_MODULE, MODULE_REQUEST, arch_x86_64, kernel_virt_begin,
+    util::{crc32, parse_hex_u64},
+};
+
+/// The last line of the symbol module is a trailer added at build time (see the Makefile):
+/// `crc32 <crc32 of everything before this line, in hex>`
+const CRC_TRAILER_PREFIX: &[u8] = b"crc32 ";
+
+/// The symbols without the trailer, or why there are none.
+/// Verified once since computing the checksum on every lookup would be too slow.
+static VERIFIED_SYMBOLS: Once<Result<&'static [u8], SymbolError>> = Once::new();
+/// The verified symbols parsed and sorted by address, see load_symbols
+static SYMBOLS: Once<&'static [Symbol<'static>]> = Once::new();
+
+/// The address of a symbol and its name
+pub type Symbol<'a> = (u64, &'a str);
+
+/// Why symbols can't be looked up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolError {
+    /// The bootloader didn't load the symbol module
+    Unavailable,
+    /// The symbol table's checksum doesn't match, so its names would be garbage
+    Corrupt,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Unavailable => write!(f, "symbols unavailable"),
+            SymbolError::Corrupt => write!(f, "symbol table corrupt"),
+        }
+    }
+}
 
 pub struct StackTrace {
     rbp: Option<u64>,
@@ -33,64 +73,170 @@ impl StackTrace {
         Some(addr)
     }
 
-    /// Look up the symbol of a function from a certain return address
-    /// ## Safety:
-    /// Must ensure that the KERNEL_SYMBOL_MODULE is loaded
-    // todo: instead of making it unsafe, just make sure that it is intialized and return an error if it i not.
-    pub unsafe fn lookup_symbol_from_return_addr(ret_addr: u64) -> Option<&'static str> {
-        for addr in (kernel_virt_begin()..ret_addr).rev() {
-            // safety: we require our called to ensure that the kerenl symbol module is loaded
-            if let Some(sym) = unsafe { lookup_symbol(addr) } {
-                return Some(sym);
-            }
-        }
-
-        None
+    /// Look up the symbol of a function from a certain return address, i.e. the symbol with the greatest
+    /// address below it. The return address itself may already be the next function, after a call which doesn't return.
+    /// Ok(None) if no kernel symbol is below it.
+    pub fn lookup_symbol_from_return_addr(
+        ret_addr: u64,
+    ) -> Result<Option<&'static str>, SymbolError> {
+        let bytes = verified_symbols()?;
+        let symbol = match SYMBOLS.get() {
+            Some(symbols) => symbol_before(symbols, ret_addr).copied(),
+            // before load_symbols, which needs the heap, go over the whole table
+            None => symbol_lines(bytes)
+                .filter(|(addr, _)| *addr < ret_addr)
+                .max_by_key(|(addr, _)| *addr),
+        };
+        Ok(symbol
+            .filter(|(addr, _)| *addr >= kernel_virt_begin())
+            .map(|(_, name)| name))
     }
 
     // inline always since otherwise we'll look the name of this function
     #[inline(always)]
-    pub unsafe fn lookup_current_function() -> Option<&'static str> {
-        unsafe { Self::lookup_symbol_from_return_addr(arch_x86_64::rip()) }
+    pub fn lookup_current_function() -> Result<Option<&'static str>, SymbolError> {
+        Self::lookup_symbol_from_return_addr(arch_x86_64::rip())
     }
 }
 
-/// Lookup a name of a symbol from an address.
-/// ## Safety:
-/// must ensure that the KERNEL_SYMBOL_MODULE is loaded
-// todo: make less ugly
-// todo: binary search?
-pub unsafe fn lookup_symbol(addr: u64) -> Option<&'static str> {
-    //qemu_println!("looking up addr: {:#x}", addr);
-    let modules = MODULE_REQUEST.get_response().unwrap();
+/// Split the checksum trailer from the symbol table and verify it.
+/// Returns the symbols without the trailer if the checksum matches, None otherwise.
+pub fn verify_symbol_table(bytes: &[u8]) -> Option<&[u8]> {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let trailer_start = bytes.iter().rposition(|c| *c == b'\n').map_or(0, |i| i + 1);
+    let (symbols, trailer) = bytes.split_at(trailer_start);
+    let expected = trailer.strip_prefix(CRC_TRAILER_PREFIX)?;
+    let expected = u32::try_from(parse_hex_u64(expected)?).ok()?;
+    if crc32(symbols) == expected {
+        Some(symbols)
+    } else {
+        None
+    }
+}
+
+/// Find the symbol module among the modules the bootloader loaded, and verify it
+fn find_symbol_table(modules: &[&File]) -> Result<&'static [u8], SymbolError> {
     let symbols_module = modules
-        .modules()
         .iter()
         .find(|f| f.path().to_bytes().ends_with(KERNEL_SYMBOL_MODULE.path()))
-        .unwrap();
-    // the symbol module is just a file in the following format:
-    // addr | SYMBOL_TYPE | symbol_name
-    // so we just parse that basically
+        .ok_or(SymbolError::Unavailable)?;
+    // safety: the bootloader keeps the modules it loaded mapped for good
     let bytes = unsafe {
         core::slice::from_raw_parts(symbols_module.addr(), symbols_module.size() as usize)
     };
-    let mut lines = bytes.split(|s| *s == b'\n');
-    while let Some(line) = lines.next() {
-        // skip the type of the symbol
-        if line.is_empty() {
-            continue;
-        }
-        let mut split = line.splitn(3, |c| c.is_ascii_whitespace());
-        let sym_addr = split.next().unwrap();
-        let _type = split.next();
-        let name = split.next();
-        let addr_as_num = u64::from_str_radix(str::from_utf8(sym_addr).unwrap(), 16).unwrap();
-        if addr_as_num > addr {
-            break;
-        }
-        if addr_as_num == addr && name.is_some() {
-            return Some(str::from_utf8(name.unwrap()).unwrap());
-        }
+    verify_symbol_table(bytes).ok_or(SymbolError::Corrupt)
+}
+
+/// Get the symbol table (without the checksum trailer), or why there is none.
+/// Never panics, since it's used by the panic handler.
+fn verified_symbols() -> Result<&'static [u8], SymbolError> {
+    *VERIFIED_SYMBOLS.call_once(|| {
+        let modules = MODULE_REQUEST
+            .get_response()
+            .ok_or(SymbolError::Unavailable)?;
+        find_symbol_table(modules.modules())
+    })
+}
+
+/// Parse the symbol table once, so that lookups are a binary search instead of going over the whole table.
+/// Needs the heap, so it's called once memory is initialized. Does nothing if there's no intact table.
+pub fn load_symbols() {
+    if let Ok(bytes) = verified_symbols() {
+        SYMBOLS.call_once(|| parse_symbol_table(bytes).leak());
+    }
+}
+
+/// Parse a line of the symbol module, which is in the following format:
+/// addr | SYMBOL_TYPE | symbol_name
+/// None for empty or malformed lines, which shouldn't take down whoever is printing a stack trace
+fn parse_symbol_line(line: &[u8]) -> Option<Symbol<'_>> {
+    let mut split = line.splitn(3, |c| c.is_ascii_whitespace());
+    let addr = parse_hex_u64(split.next()?)?;
+    let _type = split.next()?;
+    let name = str::from_utf8(split.next()?).ok()?;
+    Some((addr, name))
+}
+
+fn symbol_lines(bytes: &[u8]) -> impl Iterator<Item = Symbol<'_>> {
+    bytes.split(|c| *c == b'\n').filter_map(parse_symbol_line)
+}
+
+/// Parse the symbols (without the checksum trailer), sorted by address
+pub fn parse_symbol_table(bytes: &[u8]) -> Vec<Symbol<'_>> {
+    let mut symbols: Vec<_> = symbol_lines(bytes).collect();
+    // nm already sorts them by address, but the binary searches depend on it
+    symbols.sort_by_key(|(addr, _)| *addr);
+    symbols
+}
+
+/// The symbol with the greatest address below addr, in symbols sorted by address
+fn symbol_before<'a, 'b>(symbols: &'b [Symbol<'a>], addr: u64) -> Option<&'b Symbol<'a>> {
+    let after = symbols.partition_point(|(sym_addr, _)| *sym_addr < addr);
+    symbols[..after].last()
+}
+
+/// Lookup a name of a symbol from an address. Ok(None) if no symbol is at exactly that address.
+pub fn lookup_symbol(addr: u64) -> Result<Option<&'static str>, SymbolError> {
+    let bytes = verified_symbols()?;
+    Ok(match SYMBOLS.get() {
+        Some(symbols) => symbols
+            .binary_search_by_key(&addr, |(sym_addr, _)| *sym_addr)
+            .ok()
+            .map(|i| symbols[i].1),
+        None => symbol_lines(bytes)
+            .find(|(sym_addr, _)| *sym_addr == addr)
+            .map(|(_, name)| name),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn symbol_table_checksum() {
+        let symbols = b"ffffffff80000000 T kmain\nffffffff80000010 T kmain_rs\n";
+        let mut table = alloc::vec::Vec::from(&symbols[..]);
+        table.extend_from_slice(alloc::format!("crc32 {:08x}\n", crc32(symbols)).as_bytes());
+        assert_eq!(verify_symbol_table(&table), Some(&symbols[..]));
+        // flip a byte in one of the names
+        table[20] ^= 1;
+        assert_eq!(verify_symbol_table(&table), None);
+        // no trailer at all
+        assert_eq!(verify_symbol_table(symbols), None);
+        assert_eq!(verify_symbol_table(b""), None);
+    }
+
+    #[test_case]
+    fn symbol_binary_search() {
+        let symbols = parse_symbol_table(
+            b"ffffffff80000020 T c\nffffffff80000000 T a\n\nnot a symbol\nffffffff80000010 t b\n",
+        );
+        assert_eq!(
+            symbols,
+            [
+                (0xffffffff80000000, "a"),
+                (0xffffffff80000010, "b"),
+                (0xffffffff80000020, "c")
+            ]
+        );
+        assert_eq!(symbol_before(&symbols, 0xffffffff80000000), None);
+        assert_eq!(symbol_before(&symbols, 0xffffffff80000001).unwrap().1, "a");
+        // a return address right at the start of the next function is still in the previous one
+        assert_eq!(symbol_before(&symbols, 0xffffffff80000010).unwrap().1, "a");
+        assert_eq!(symbol_before(&symbols, 0xffffffff80000011).unwrap().1, "b");
+        assert_eq!(symbol_before(&symbols, u64::MAX).unwrap().1, "c");
+        assert_eq!(symbol_before(&[], u64::MAX), None);
+    }
+
+    #[test_case]
+    fn missing_symbol_module() {
+        assert_eq!(find_symbol_table(&[]), Err(SymbolError::Unavailable));
+        // the Makefile builds the symbol module into every image, and the module is required,
+        // so the one the tests run with is there and intact
+        assert_eq!(verified_symbols().err(), None);
+        // no symbol is below address 0, or at the very end of the address space
+        assert_eq!(StackTrace::lookup_symbol_from_return_addr(0), Ok(None));
+        assert_eq!(lookup_symbol(u64::MAX), Ok(None));
     }
-    None
 }
@@ -0,0 +1,157 @@
+use core::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+};
+
+use spin::mutex::{SpinMutex, SpinMutexGuard};
+
+use crate::{arch_x86_64::cpuid, cpu::percpu};
+
+/// A mutex which catches recursive locking in debug builds, and is a plain SpinMutex in release builds.
+#[cfg(debug_assertions)]
+pub type CheckedMutex<T> = DebugMutex<T>;
+/// A mutex which catches recursive locking in debug builds, and is a plain SpinMutex in release builds.
+#[cfg(not(debug_assertions))]
+pub type CheckedMutex<T> = SpinMutex<T>;
+
+/// A spin lock which remembers which cpu holds it and where it was locked.
+/// If the holding cpu tries to lock it again (i.e. logging inside a handler which interrupted the logger),
+/// it panics with both lock sites instead of spinning forever. Different cpus still just spin.
+pub struct DebugMutex<T> {
+    inner: SpinMutex<T>,
+    /// initial apic id + 1 of the holder, 0 if it isn't locked
+    holder: AtomicU32,
+    locked_at: AtomicPtr<Location<'static>>,
+}
+
+// the id of the current cpu without touching the local apic, unlike LocalApic::id.
+// the page allocator is locked with a DebugMutex, so mapping the local apic here would recurse.
+// cpuid always exits to the hypervisor, so it's only the fallback for locks taken before init_percpu
+fn current_cpu() -> u32 {
+    match percpu() {
+        Some(percpu) => percpu.lapic_id,
+        None => cpuid(1, 0).ebx >> 24,
+    }
+}
+
+impl<T> DebugMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: SpinMutex::new(value),
+            holder: AtomicU32::new(0),
+            locked_at: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Lock the mutex.
+    /// ## Panic
+    /// panics with "recursive lock" if the current cpu already holds it.
+    #[track_caller]
+    pub fn lock(&self) -> DebugMutexGuard<'_, T> {
+        let cpu = current_cpu() + 1;
+        if self.holder.load(Ordering::Relaxed) == cpu {
+            let locked_at = self.locked_at.load(Ordering::Relaxed);
+            // safety: locked_at only ever holds 'static locations
+            match unsafe { locked_at.as_ref() } {
+                Some(locked_at) => panic!(
+                    "recursive lock: locked at {}, locked again at {}",
+                    locked_at,
+                    Location::caller()
+                ),
+                None => panic!("recursive lock: locked again at {}", Location::caller()),
+            }
+        }
+        let guard = self.inner.lock();
+        self.holder.store(cpu, Ordering::Relaxed);
+        self.locked_at.store(
+            Location::caller() as *const Location<'static> as *mut _,
+            Ordering::Relaxed,
+        );
+        DebugMutexGuard { mutex: self, guard }
+    }
+
+    /// Where the mutex was last locked, if it's locked
+    pub fn locked_at(&self) -> Option<&'static Location<'static>> {
+        if self.holder.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        // safety: locked_at only ever holds 'static locations
+        unsafe { self.locked_at.load(Ordering::Relaxed).as_ref() }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Force unlock the mutex, see SpinMutex::force_unlock.
+    /// ## Safety
+    /// Whoever holds the lock must never touch the data or unlock it again, i.e. it panicked or was aborted.
+    pub unsafe fn force_unlock(&self) {
+        self.holder.store(0, Ordering::Relaxed);
+        self.locked_at
+            .store(core::ptr::null_mut(), Ordering::Relaxed);
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+impl<T: Debug> Debug for DebugMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DebugMutex")
+            .field("inner", &self.inner)
+            .field("locked_at", &self.locked_at())
+            .finish()
+    }
+}
+
+pub struct DebugMutexGuard<'a, T> {
+    mutex: &'a DebugMutex<T>,
+    guard: SpinMutexGuard<'a, T>,
+}
+
+impl<T> Drop for DebugMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the inner guard is dropped (unlocked) after this, so nobody else can lock in between
+        self.mutex.holder.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<T> Deref for DebugMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for DebugMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::should_panic_at;
+
+    #[test_case]
+    fn lock_unlock() {
+        let mutex = DebugMutex::new(1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.locked_at().is_some());
+        }
+        assert!(mutex.locked_at().is_none());
+        assert_eq!(*mutex.lock(), 2);
+    }
+
+    #[test_case]
+    fn recursive_lock_panics() {
+        let mutex = DebugMutex::new(());
+        let _guard = mutex.lock();
+        should_panic_at!(line!() + 1);
+        let _guard2 = mutex.lock();
+    }
+}
@@ -1,5 +1,14 @@
-use crate::{qemu_print, qemu_println};
-use core::{panic::PanicInfo, pin::pin};
+use crate::{io::Port, qemu_print, qemu_println};
+use core::{
+    fmt,
+    ops::RangeInclusive,
+    panic::{Location, PanicInfo},
+    pin::pin,
+    time::Duration,
+};
+
+/// How long a test may run before the watchdog fails it, see set_test_timeout
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Todo: add colors
 // Todo: ensure that tests don't interfere with each other by making sure memory is the same for each test
@@ -13,6 +22,10 @@ use core::{panic::PanicInfo, pin::pin};
 // which seems much more complicated than this.
 pub struct Tests {
     pub should_current_test_panic: bool,
+    /// If set, the panic of the current test only counts if it happened at this location
+    pub expected_panic_location: Option<ExpectedPanicLocation>,
+    /// If set, the panic of the current test only counts if its message contains this
+    pub expected_panic_message: Option<&'static str>,
     current_test: usize,
     tests: &'static [&'static dyn Testable],
     failed_tests_num: usize,
@@ -34,9 +47,15 @@ impl Tests {
                         TESTS.failed_tests_num
                     );
                 }
-                exit_qemu(QemuExitCode::Success);
+                if TESTS.failed_tests_num == 0 {
+                    exit_qemu(QemuExitCode::Success);
+                } else {
+                    exit_qemu(QemuExitCode::Failed);
+                }
             } else {
                 TESTS.should_current_test_panic = false;
+                TESTS.expected_panic_location = None;
+                TESTS.expected_panic_message = None;
                 TESTS.tests[TESTS.current_test].run_test();
             }
         }
@@ -60,12 +79,16 @@ unsafe impl Sync for Tests {}
 
 pub trait Testable {
     fn run_test(&self);
+    fn name(&self) -> &'static str;
 }
 
 impl<T: Fn()> Testable for T {
     fn run_test(&self) {
-        qemu_print!("{}... ", core::any::type_name::<T>());
+        qemu_print!("{}... ", self.name());
+        // a hanging test panics with watchdog::TIMEOUT_MESSAGE instead of blocking the rest
+        crate::watchdog::arm(TEST_TIMEOUT);
         self();
+        crate::watchdog::disarm();
         if unsafe { TESTS.should_current_test_panic } {
             qemu_println!("[failed] (did not panic)");
             Tests::failed();
@@ -77,19 +100,45 @@ impl<T: Fn()> Testable for T {
             Tests::next_test();
         }
     }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// Give the current test a different timeout than TEST_TIMEOUT, starting from now.
+/// Use it in tests which are slow, but not stuck.
+pub fn set_test_timeout(timeout: Duration) {
+    crate::watchdog::arm(timeout);
 }
 
 const DUMMY: &'static [&'static dyn Testable] = &[];
 pub static mut TESTS: Tests = Tests {
     should_current_test_panic: false,
+    expected_panic_location: None,
+    expected_panic_message: None,
     current_test: 0,
     tests: DUMMY,
     success_tests_num: 0,
     failed_tests_num: 0,
 };
+/// The stack pointer of test_runner. A test which panicked never returns, so the tests after it start over from here
+/// instead of on top of the stack it panicked on, which may be a small IST stack (see cpu::gdt).
+static mut RUNNER_RSP: u64 = 0;
+
+/// Run the tests after the one which panicked, see RUNNER_RSP
+extern "C" fn continue_after_panic() -> ! {
+    unsafe { Tests::next_test() };
+    // only if there's no isa-debug-exit device to stop qemu
+    loop {
+        unsafe { crate::arch_x86_64::hlt() };
+    }
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     qemu_println!("\n\nrunning {} lib tests\n", tests.len());
     unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) RUNNER_RSP, options(nomem, nostack, preserves_flags));
         TESTS.current_test = 0;
         TESTS.should_current_test_panic = false;
         // wildly unsafe
@@ -100,21 +149,116 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     }
 }
 
+/// Where a test expects to panic, see should_panic_at!
+pub struct ExpectedPanicLocation {
+    pub file: &'static str,
+    pub lines: RangeInclusive<u32>,
+}
+
+impl ExpectedPanicLocation {
+    pub const fn new(file: &'static str, lines: RangeInclusive<u32>) -> Self {
+        Self { file, lines }
+    }
+
+    /// Whether a panic at location is the expected one. A panic without a location never matches.
+    pub fn matches(&self, location: Option<&Location>) -> bool {
+        location.is_some_and(|l| l.file() == self.file && self.lines.contains(&l.line()))
+    }
+}
+
+impl fmt::Display for ExpectedPanicLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}",
+            self.file,
+            self.lines.start(),
+            self.lines.end()
+        )
+    }
+}
+
+/// Max length of a panic message which can be matched by should_panic_with!, the rest of the message is ignored.
+const MAX_MATCHED_MESSAGE_LEN: usize = 256;
+
+/// Whether the panic message contains expected, without allocating
+fn message_contains(inf: &PanicInfo, expected: &str) -> bool {
+    struct Buf {
+        bytes: [u8; MAX_MATCHED_MESSAGE_LEN],
+        len: usize,
+    }
+    impl fmt::Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let amount = s.len().min(self.bytes.len() - self.len);
+            self.bytes[self.len..self.len + amount].copy_from_slice(&s.as_bytes()[..amount]);
+            self.len += amount;
+            Ok(())
+        }
+    }
+    let mut buf = Buf {
+        bytes: [0; MAX_MATCHED_MESSAGE_LEN],
+        len: 0,
+    };
+    let _ = fmt::write(&mut buf, format_args!("{}", inf.message()));
+    buf.bytes[..buf.len]
+        .windows(expected.len().max(1))
+        .any(|window| window == expected.as_bytes())
+}
+
 #[panic_handler]
 fn panic(inf: &PanicInfo) -> ! {
+    crate::watchdog::disarm();
+    let timed_out = message_contains(inf, crate::watchdog::TIMEOUT_MESSAGE);
+    if timed_out {
+        // we came from the NMI handler and never return to it, so NMIs (and the watchdog) would stay blocked
+        unsafe { crate::arch_x86_64::unblock_nmi() };
+    }
     unsafe {
-        if TESTS.should_current_test_panic {
+        #[allow(static_mut_refs)]
+        let wrong_location = TESTS
+            .expected_panic_location
+            .as_ref()
+            .filter(|expected| !expected.matches(inf.location()));
+        let wrong_message = TESTS
+            .expected_panic_message
+            .filter(|expected| !message_contains(inf, expected));
+        if TESTS.should_current_test_panic
+            && let Some(expected) = wrong_location
+        {
+            qemu_println!("[failed] (panicked, but not at {})", expected);
+            qemu_println!("{}\n", inf);
+            Tests::failed();
+        } else if TESTS.should_current_test_panic
+            && let Some(expected) = wrong_message
+        {
+            qemu_println!("[failed] (panicked, but not with \"{}\")", expected);
+            qemu_println!("{}\n", inf);
+            Tests::failed();
+        } else if TESTS.should_current_test_panic {
             qemu_println!("[success] (panicked)");
             Tests::success();
         } else {
-            qemu_println!("[failed]");
+            if timed_out {
+                #[allow(static_mut_refs)]
+                let name = TESTS.tests[TESTS.current_test].name();
+                qemu_println!("[TIMEOUT] {}", name);
+            } else {
+                qemu_println!("[failed]");
+            }
+            qemu_println!("{}", crate::panic::PanicHeader::current());
             qemu_println!("{}\n", inf);
             Tests::failed();
         }
-        Tests::next_test()
+        // nothing below the runner's frame is used anymore
+        core::arch::asm!(
+            "mov rsp, {rsp}",
+            "and rsp, -16",
+            "call {next}",
+            rsp = in(reg) RUNNER_RSP,
+            next = sym continue_after_panic,
+            options(noreturn),
+        );
     }
-
-    loop {}
 }
 
 #[unsafe(naked)]
@@ -134,11 +278,21 @@ unsafe extern "C" fn kmain() -> ! {
 unsafe extern "C" fn kmain_rs() -> ! {
     use crate::{create_init_idt, memory};
     use core::mem::MaybeUninit;
+    crate::arch_x86_64::enable_cpu_features();
+    crate::cpu::fpu_init();
+    // tests don't need a screen, the console falls back to the qemu log
+    let _ = crate::screen::init();
+    crate::cpu::gdt::init();
     // create initial idt
     let uninit_idt = pin!(MaybeUninit::uninit());
     let init = create_init_idt(uninit_idt);
     unsafe { init.as_ref().load() };
-    memory::init();
+    if let Err(e) = memory::init() {
+        panic!("{}", e);
+    }
+    // like cpu_start, so that tests run with per cpu data
+    crate::cpu::alloc_percpus();
+    crate::cpu::init_percpu(crate::cpu::bsp_lapic_id());
     crate::lib_test();
     loop {}
 }
@@ -147,12 +301,20 @@ unsafe extern "C" fn kmain_rs() -> ! {
 #[repr(u32)]
 pub enum QemuExitCode {
     Success = 0x10,
-    #[allow(dead_code)]
     Failed = 0x11,
 }
+/// The isa-debug-exit device, qemu exits with (value << 1) | 1 once it's written
+const QEMU_EXIT_PORT: Port<u32> = Port::new(0xf4);
+
 fn exit_qemu(exit_code: QemuExitCode) {
+    // must come before the exit, since it stops qemu right away. a successful run only needs the marker
+    let tail_len = match exit_code {
+        QemuExitCode::Success => 0,
+        QemuExitCode::Failed => crate::qemu_log::FAILURE_TAIL_LEN,
+    };
+    crate::qemu_log::dump_tail(tail_len);
     unsafe {
-        crate::io::write_u32(0xf4, exit_code as u32);
+        QEMU_EXIT_PORT.write(exit_code as u32);
     }
 }
 
@@ -183,8 +345,88 @@ macro_rules! should_panic {
     };
 }
 
+/// Like should_panic!, but the panic only counts if it happens in the current file
+/// at the given line (or between the given lines, inclusive).
+/// Use it to make sure a specific assertion fires, rather than some unrelated earlier panic.
+/// # Example
+/// ```rust
+/// #[test_case]
+/// fn test() {
+///     should_panic_at!(line!() + 1);
+///     assert_eq!(1, 2);
+/// }
+/// ```
+#[macro_export]
+macro_rules! should_panic_at {
+    ($line: expr) => {
+        $crate::should_panic_at!($line, $line)
+    };
+    ($first: expr, $last: expr) => {
+        $crate::should_panic!();
+        let expected = $crate::test::ExpectedPanicLocation::new(file!(), $first..=$last);
+        #[allow(unused_unsafe)]
+        unsafe {
+            $crate::test::TESTS.expected_panic_location = Some(expected);
+        }
+    };
+}
+
+/// Like should_panic!, but the panic only counts if its message contains the given string.
+/// # Example
+/// ```rust
+/// #[test_case]
+/// fn test() {
+///     should_panic_with!("invalid opcode");
+///     unsafe { core::arch::asm!("ud2") };
+/// }
+/// ```
+#[macro_export]
+macro_rules! should_panic_with {
+    ($message: expr) => {
+        $crate::should_panic!();
+        let message: &'static str = $message;
+        #[allow(unused_unsafe)]
+        unsafe {
+            $crate::test::TESTS.expected_panic_message = Some(message);
+        }
+    };
+}
+
+#[test_case]
+fn expected_location_matching() {
+    let here = Location::caller();
+    let expected = ExpectedPanicLocation::new(file!(), here.line()..=here.line());
+    assert!(expected.matches(Some(here)));
+    let elsewhere = ExpectedPanicLocation::new(file!(), here.line() + 1..=here.line() + 5);
+    assert!(!elsewhere.matches(Some(here)));
+    let other_file = ExpectedPanicLocation::new("src/lib.rs", here.line()..=here.line());
+    assert!(!other_file.matches(Some(here)));
+    assert!(!expected.matches(None));
+}
+
+#[test_case]
+fn should_panic_at_test() {
+    should_panic_at!(line!() + 1);
+    assert_eq!(1, 2);
+}
+
 #[test_case]
 fn should_panic_test() {
     should_panic!();
     panic!()
 }
+
+#[test_case]
+fn should_panic_with_test() {
+    should_panic_with!("expected message");
+    panic!("some expected message {}", 1);
+}
+
+#[test_case]
+fn hanging_test_times_out() {
+    set_test_timeout(Duration::from_millis(10));
+    should_panic_with!(crate::watchdog::TIMEOUT_MESSAGE);
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -1,8 +1,50 @@
-use core::time::Duration;
+use core::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
-use crate::dev::hpet::Hpet;
+use alloc::boxed::Box;
+use spin::{Mutex, Once};
 
-/// Time elapsed in femto seconds
+use crate::{
+    arch_x86_64::{CpuFeatures, lfence, rdtsc},
+    cpu::percpu,
+    dev::{
+        hpet::{Hpet, Timer},
+        ioapic::{
+            DeliveryMode, DestinationMode, InterruptPolarity, IoApic, IoApicRedirectEntry,
+            TriggerMode,
+        },
+        local_apic::LocalApic,
+    },
+    interrupts::{irq_disable, irq_enable, irq_is_enabled, register_handler},
+};
+
+/// Amount of buckets in the timer latency histogram
+pub const LATENCY_BUCKETS: usize = 16;
+
+/// Bucket i holds latencies in [2^(i-1), 2^i) microseconds, bucket 0 holds latencies under 1 microsecond
+/// and the last bucket holds everything bigger.
+static LATENCY_HISTOGRAM: [AtomicU64; LATENCY_BUCKETS] =
+    [const { AtomicU64::new(0) }; LATENCY_BUCKETS];
+
+/// The main counter value Hpet::timer(0) was last armed with, or 0 if it isn't armed
+static ARMED_COMPARATOR: AtomicU64 = AtomicU64::new(0);
+
+/// How long tsc_calibrate measures the TSC for
+const TSC_CALIBRATION_MS: u64 = 10;
+
+/// Period of the local apic timer's tick, see start_ticks
+pub const TICK_MS: u32 = 1;
+/// Amount of ticks since start_ticks
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Amount of times poll_sleep saw the time go backwards
+static COUNTER_RESETS: AtomicU64 = AtomicU64::new(0);
+
+/// Time elapsed in femto seconds.
+/// It only ever grows, unless someone writes the main counter (see Hpet::set_main_counter_raw),
+/// so the difference of two samples should be taken with saturating_sub.
 pub fn elapsed_fs() -> u128 {
     // will take 2^64 * Hpet::fs_per_tick femto seconds to to overflow.
     // assuming Hpet's tick resolution is in nanoseconds or bigger, it will take at least more than
@@ -10,6 +52,72 @@ pub fn elapsed_fs() -> u128 {
     Hpet::read_main_counter() as u128 * Hpet::fs_per_tick() as u128
 }
 
+/// Time since the Hpet's main counter started, or None if the Hpet isn't usable yet.
+/// Unlike elapsed_fs, it never maps the Hpet, so it's safe to call from the panic handler.
+pub fn uptime() -> Option<Duration> {
+    if !Hpet::is_mapped() || Hpet::is_disabled() {
+        return None;
+    }
+    Some(Duration::from_nanos((elapsed_fs() / 1_000_000) as u64))
+}
+
+/// Measure the rate of the current cpu's TSC against the Hpet, in ticks per ms.
+/// Returns None if the TSC isn't invariant, since then its rate changes with the cpu's power state
+/// and it can't be used as a clock. Uses poll_sleep, so it works before interrupts are set up.
+pub fn tsc_calibrate() -> Option<u64> {
+    if !CpuFeatures::detect().has_invariant_tsc {
+        return None;
+    }
+    lfence();
+    let start = rdtsc();
+    poll_sleep(Duration::from_millis(TSC_CALIBRATION_MS));
+    lfence();
+    let end = rdtsc();
+    Some((end - start) / TSC_CALIBRATION_MS)
+}
+
+/// Nanoseconds since an arbitrary point, from the TSC calibrated in this cpu's PerCpu,
+/// or from the Hpet if the TSC can't be used as a clock (or this cpu didn't calibrate it yet).
+/// Only monotonic on a single cpu: the TSCs of different cpus may not be in sync.
+pub fn monotonic_ns() -> u64 {
+    monotonic_ns_with(percpu().and_then(|percpu| percpu.tsc_ticks_per_ms.get().copied().flatten()))
+}
+
+/// monotonic_ns with the given TSC rate, the Hpet if it's None
+fn monotonic_ns_with(tsc_ticks_per_ms: Option<u64>) -> u64 {
+    match tsc_ticks_per_ms {
+        Some(ticks_per_ms) => (rdtsc() as u128 * 1_000_000 / ticks_per_ms as u128) as u64,
+        None => (elapsed_fs() / 1_000_000) as u64,
+    }
+}
+
+/// Tick every TICK_MS on the current cpu's local apic timer, which must have been calibrated into its PerCpu.
+/// Every cpu's ticks go to the same counter, so only one cpu should call it.
+/// ## Panic
+/// Panics if the current cpu didn't calibrate its local apic timer yet.
+pub fn start_ticks() {
+    let ticks_per_ms = percpu()
+        .and_then(|percpu| percpu.lapic_ticks_per_ms.get())
+        .expect("start_ticks before the local apic timer was calibrated");
+    LocalApic::set_timer_periodic(ticks_per_ms * TICK_MS);
+}
+
+/// Count a tick. Called by the local apic timer's handler, so it doesn't lock or allocate.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Amount of ticks since start_ticks
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since start_ticks, by the amount of ticks.
+/// It falls behind while the ticking cpu doesn't take interrupts.
+pub fn tick_uptime() -> Duration {
+    Duration::from_millis(ticks() * TICK_MS as u64)
+}
+
 /// Duration which is small enough (namely, its nanoseconds are smaller than SmallDuration::MAX_NANOS) \
 /// Note: smaller than 1e+13 nanoseconds/10000 seconds sufficies
 pub struct SmallDuration {
@@ -31,6 +139,20 @@ impl SmallDuration {
         }
     }
 
+    /// Like SmallDuration::new, but clamps durations which are too large to SmallDuration::MAX_NANOS
+    pub fn saturating_from(duration: Duration) -> SmallDuration {
+        Self::try_from(duration)
+            .unwrap_or_else(|_| Self::new(Duration::from_nanos(Self::MAX_NANOS)).unwrap())
+    }
+
+    pub fn from_millis(millis: u64) -> Result<SmallDuration, DurationTooLarge> {
+        Self::try_from(Duration::from_millis(millis))
+    }
+
+    pub fn from_micros(micros: u64) -> Result<SmallDuration, DurationTooLarge> {
+        Self::try_from(Duration::from_micros(micros))
+    }
+
     pub fn as_femto_secs(&self) -> u64 {
         self.femto_seconds
     }
@@ -39,6 +161,27 @@ impl SmallDuration {
     }
 }
 
+/// The duration's nanoseconds are bigger than SmallDuration::MAX_NANOS
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DurationTooLarge {
+    pub nanos: u128,
+}
+
+impl TryFrom<Duration> for SmallDuration {
+    type Error = DurationTooLarge;
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        Self::new(duration).ok_or(DurationTooLarge {
+            nanos: duration.as_nanos(),
+        })
+    }
+}
+
+impl From<SmallDuration> for Duration {
+    fn from(value: SmallDuration) -> Self {
+        value.inner
+    }
+}
+
 /// Start Hpet::timer(0) to throw an interrupt after duration.
 /// This can be prone to a race condition if duration so small that setting the timer will already make the Hpet's
 /// main counter pass it.
@@ -47,19 +190,431 @@ pub fn start_timer(duration: SmallDuration) {
     unsafe {
         let timer = Hpet::timer(0);
         let ticks = duration.as_femto_secs() / Hpet::fs_per_tick();
-        timer.set_counter_raw(Hpet::read_main_counter() + ticks);
+        let fire_at = Hpet::read_main_counter() + ticks;
+        ARMED_COMPARATOR.store(fire_at, Ordering::Relaxed);
+        timer.set_counter_raw(fire_at);
+    }
+}
+
+/// Record the latency between the time Hpet::timer(0) was programmed to fire and now.
+/// Meant to be called at the start of the timer's interrupt handler; it doesn't lock or allocate.
+pub fn record_timer_latency() {
+    let now = Hpet::read_main_counter();
+    let fire_at = ARMED_COMPARATOR.swap(0, Ordering::Relaxed);
+    if fire_at == 0 {
+        // the timer wasn't armed via start_timer, nothing to measure against
+        return;
     }
+    let latency_fs = now.saturating_sub(fire_at) as u128 * Hpet::fs_per_tick() as u128;
+    let latency_us = (latency_fs / 1_000_000_000) as u64;
+    let bucket = ((u64::BITS - latency_us.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+    LATENCY_HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
 }
 
-/// Sleep by polling on time::elapsed_fs
+/// Get a snapshot of the timer latency histogram. See LATENCY_HISTOGRAM for the bucket ranges.
+pub fn latency_histogram() -> [u64; LATENCY_BUCKETS] {
+    core::array::from_fn(|i| LATENCY_HISTOGRAM[i].load(Ordering::Relaxed))
+}
+
+/// Sleep by polling on time::elapsed_fs. This keeps the cpu busy, so it's only for when interrupts
+/// can't be taken yet (i.e. while calibrating the local apic timer), otherwise use sleep.
+/// If the main counter is reset in the meantime, the time until the reset still counts and the sleep
+/// continues from the new value, so it neither returns early nor waits for the counter to catch up.
 pub fn poll_sleep(duration: Duration) {
-    let now = elapsed_fs();
+    poll_sleep_on(duration, elapsed_fs);
+}
+
+/// Amount of times a sleep saw the main counter go backwards, i.e. because it was reset
+pub fn counter_resets() -> u64 {
+    COUNTER_RESETS.load(Ordering::Relaxed)
+}
+
+// the clock is a parameter so that tests can reset it
+fn poll_sleep_on(duration: Duration, mut clock: impl FnMut() -> u128) {
     let nanos = duration.as_nanos();
+    let mut last = clock();
+    let mut slept_fs: u128 = 0;
     loop {
-        let elapsed_time_fs = elapsed_fs() - now;
-        let elapsed_time_ns = elapsed_time_fs / 1_000_000;
-        if elapsed_time_ns > nanos {
+        let now = clock();
+        if now < last {
+            // we can't know how long the counter was stopped for, so only count the time until the last sample
+            COUNTER_RESETS.fetch_add(1, Ordering::Relaxed);
+        }
+        slept_fs += now.saturating_sub(last);
+        last = now;
+        if slept_fs / 1_000_000 > nanos {
             break;
         }
     }
 }
+
+/// Amount of main counter ticks to wait for, so that at least ns nanoseconds pass.
+/// We can start anywhere inside a tick, so the first one we see advance may be almost over and doesn't count.
+fn delay_ticks(ns: u64, fs_per_tick: u64) -> u64 {
+    let ticks = (ns as u128 * 1_000_000).div_ceil(fs_per_tick as u128);
+    u64::try_from(ticks).unwrap_or(u64::MAX).saturating_add(1)
+}
+
+/// Busy wait for at least ns nanoseconds by spinning on the Hpet's main counter.
+/// Meant for delays which are too short to sleep on an interrupt, i.e. drivers waiting between register writes.
+/// Delays shorter than a tick (including 0) wait for the counter to advance at least once.
+pub fn spin_delay_ns(ns: u64) {
+    debug_assert!(!Hpet::is_disabled(), "spinning on a stopped hpet");
+    let ticks = delay_ticks(ns, Hpet::fs_per_tick());
+    let start = Hpet::read_main_counter();
+    // wrapping_sub keeps working when the counter wraps around
+    while Hpet::read_main_counter().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// The vector all the oneshot timers interrupt on, see oneshot
+pub const ONESHOT_VECTOR: u8 = 0x30;
+/// The HPET has at most 32 timers
+const MAX_TIMERS: usize = 32;
+
+struct Oneshot {
+    /// fn() as usize, 0 once it fired (or if there's no oneshot on this timer)
+    callback: AtomicUsize,
+    /// main counter value it fires at
+    deadline: AtomicU64,
+}
+
+/// The oneshot of every HPET timer, by timer number
+static ONESHOTS: [Oneshot; MAX_TIMERS] = [const {
+    Oneshot {
+        callback: AtomicUsize::new(0),
+        deadline: AtomicU64::new(0),
+    }
+}; MAX_TIMERS];
+static ONESHOT_HANDLER: Once<()> = Once::new();
+/// Held while looking for a free irq and taking it, so that two oneshots don't take the same one
+static IRQ_SELECTION: Mutex<()> = Mutex::new(());
+
+/// Why a oneshot couldn't be armed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OneshotError {
+    /// All the HPET timers are taken
+    NoFreeTimer,
+    /// The free timer can't be routed to any irq which isn't taken
+    NoFreeIrq,
+}
+
+/// Run the callback of the timer, unless it already ran or was canceled
+fn fire(num: u64) {
+    let callback = ONESHOTS[num as usize].callback.swap(0, Ordering::AcqRel);
+    if callback != 0 {
+        // safety: only oneshot writes callbacks, and it writes a fn()
+        let callback: fn() = unsafe { core::mem::transmute(callback) };
+        callback();
+    }
+}
+
+fn handle_oneshot_interrupt() {
+    let now = Hpet::read_main_counter();
+    // the timers share a vector, so fire whichever are due
+    for (num, oneshot) in ONESHOTS.iter().enumerate() {
+        if oneshot.callback.load(Ordering::Acquire) != 0
+            && now >= oneshot.deadline.load(Ordering::Relaxed)
+        {
+            fire(num as u64);
+        }
+    }
+    LocalApic::eoi();
+}
+
+/// Call callback once duration passes, on a free HPET timer. Dropping the returned handle cancels it.
+/// The callback runs in an interrupt handler, so it shouldn't lock or allocate, see softirq::raise.
+/// If the duration is so short that it passed while arming the timer, the callback runs right away instead.
+pub fn oneshot(duration: Duration, callback: fn()) -> Result<TimerHandle, OneshotError> {
+    ONESHOT_HANDLER.call_once(|| {
+        register_handler(ONESHOT_VECTOR, Box::new(|_| handle_oneshot_interrupt()));
+        LocalApic::enable();
+    });
+    let timer = Hpet::claim_timer().ok_or(OneshotError::NoFreeTimer)?;
+    let irq = {
+        let _selection = IRQ_SELECTION.lock();
+        // the legacy irqs belong to the legacy devices, even if they're masked for now
+        let Some(irq) = (16..24)
+            .rev()
+            .find(|irq| timer.can_route_irq_to(*irq) && IoApic::is_masked(*irq as u8))
+        else {
+            // safety: we claimed it and don't use it anymore
+            unsafe { Hpet::release_timer(&timer) };
+            return Err(OneshotError::NoFreeIrq);
+        };
+        timer.disable();
+        timer.route_irq_to(irq);
+        IoApic::redirect_irq(
+            irq as u8,
+            IoApicRedirectEntry {
+                dest: LocalApic::id() as u8,
+                mask: false,
+                trigger_mode: TriggerMode::EdgeSensetive,
+                interrupt_polarity: InterruptPolarity::HighActive,
+                destination_mode: DestinationMode::Physical,
+                delivery_mode: DeliveryMode::Fixed,
+                redirected_irq_num: ONESHOT_VECTOR,
+            },
+        );
+        irq as u8
+    };
+    if Hpet::is_disabled() {
+        Hpet::enable();
+    }
+
+    let ns = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    let ticks = delay_ticks(ns, Hpet::fs_per_tick());
+    let deadline = Hpet::read_main_counter().saturating_add(ticks);
+    let oneshot = &ONESHOTS[timer.num() as usize];
+    oneshot.deadline.store(deadline, Ordering::Relaxed);
+    oneshot.callback.store(callback as usize, Ordering::Release);
+    // whoever had the timer before may have left it periodic
+    timer.set_one_shot();
+    timer.set_counter_raw(deadline);
+    timer.enable();
+    // if the counter passed the deadline before the comparator was set, the timer won't fire until the counter wraps around
+    if Hpet::read_main_counter() >= deadline {
+        fire(timer.num());
+    }
+    Ok(TimerHandle { timer, irq })
+}
+
+/// A oneshot armed by time::oneshot, which owns its HPET timer and irq.
+/// Dropping it cancels the oneshot if it didn't fire yet, and frees them.
+pub struct TimerHandle {
+    timer: Timer,
+    irq: u8,
+}
+
+impl TimerHandle {
+    pub fn has_fired(&self) -> bool {
+        ONESHOTS[self.timer.num() as usize]
+            .callback
+            .load(Ordering::Acquire)
+            == 0
+    }
+
+    /// Cancel the oneshot, same as dropping the handle. Does nothing if it already fired.
+    pub fn cancel(self) {}
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        self.timer.disable();
+        ONESHOTS[self.timer.num() as usize]
+            .callback
+            .store(0, Ordering::Release);
+        IoApic::mask_irq(self.irq);
+        // safety: oneshot claimed it, and the handle is the only one using it
+        unsafe { Hpet::release_timer(&self.timer) };
+    }
+}
+
+/// Sleep for duration by halting until a oneshot wakes us, so the cpu is free for other work meanwhile.
+/// Interrupts are taken while halting, but are left the way they were once it returns.
+/// Falls back to poll_sleep if no HPET timer is free.
+pub fn sleep(duration: Duration) {
+    // only its handle is needed: it counts as fired once the callback ran
+    fn wake() {}
+    if duration.is_zero() {
+        return;
+    }
+    let were_enabled = irq_is_enabled();
+    unsafe { irq_disable() };
+    match oneshot(duration, wake) {
+        // if the deadline passed while arming it, oneshot already fired it and we don't halt at all
+        Ok(handle) => {
+            while !handle.has_fired() {
+                // sti only takes effect after the next instruction, so the interrupt can't come in
+                // between the check and the hlt and leave us sleeping
+                unsafe { core::arch::asm!("sti; hlt; cli", options(nomem, nostack)) };
+            }
+        }
+        Err(_) => poll_sleep(duration),
+    }
+    if were_enabled {
+        unsafe { irq_enable() };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interrupts::SHARED_IDT;
+
+    #[test_case]
+    fn monotonic_clock() {
+        Hpet::enable();
+        // the Hpet fallback, and the TSC if this cpu's is invariant
+        let rates = [None, tsc_calibrate()];
+        for tsc_ticks_per_ms in rates {
+            let start = monotonic_ns_with(tsc_ticks_per_ms);
+            poll_sleep(Duration::from_millis(2));
+            let end = monotonic_ns_with(tsc_ticks_per_ms);
+            // calibration is only as good as poll_sleep, which oversleeps a bit
+            assert!(
+                end - start >= 1_000_000,
+                "{tsc_ticks_per_ms:?}: {start} -> {end}"
+            );
+            assert!(monotonic_ns_with(tsc_ticks_per_ms) >= end);
+        }
+        // whichever of them this cpu's PerCpu picks
+        let start = monotonic_ns();
+        poll_sleep(Duration::from_millis(2));
+        assert!(monotonic_ns() - start >= 1_000_000);
+    }
+
+    #[test_case]
+    fn spin_delay() {
+        Hpet::enable();
+        let fs_per_tick = Hpet::fs_per_tick();
+        let start = Hpet::read_main_counter();
+        spin_delay_ns(1000);
+        let waited = Hpet::read_main_counter() - start;
+        assert!(waited as u128 * fs_per_tick as u128 >= 1000 * 1_000_000);
+
+        // sub tick delays still wait for the counter
+        let start = Hpet::read_main_counter();
+        spin_delay_ns(0);
+        assert!(Hpet::read_main_counter() > start);
+
+        assert_eq!(delay_ticks(0, 100), 1);
+        assert_eq!(delay_ticks(1, 100_000_000), 2);
+        assert_eq!(delay_ticks(100, 100_000_000), 2);
+        assert_eq!(delay_ticks(101, 100_000_000), 3);
+        assert_eq!(delay_ticks(u64::MAX, 1), u64::MAX);
+    }
+
+    #[test_case]
+    fn poll_sleep_survives_counter_reset() {
+        const MS_FS: u128 = 1_000_000_000_000;
+        let resets = counter_resets();
+        let mut calls = 0;
+        // advances by 1ms per sample, and is reset back to 0 on the 6th one
+        poll_sleep_on(Duration::from_millis(10), || {
+            let sample = if calls < 5 { calls } else { calls - 5 };
+            calls += 1;
+            sample * MS_FS
+        });
+        // 4ms before the reset, none across it and 7ms after it
+        assert_eq!(calls, 13);
+        assert_eq!(counter_resets(), resets + 1);
+
+        // the real clock is monotonic
+        poll_sleep(Duration::from_micros(10));
+        assert_eq!(counter_resets(), resets + 1);
+    }
+
+    #[test_case]
+    fn latency_histogram_records() {
+        Hpet::enable();
+        let before: u64 = latency_histogram().iter().sum();
+        for micros in [10, 50, 100] {
+            start_timer(SmallDuration::new(Duration::from_micros(micros)).unwrap());
+            poll_sleep(Duration::from_micros(micros * 2));
+            // simulate the interrupt handler
+            record_timer_latency();
+        }
+        // not armed, shouldn't be recorded
+        record_timer_latency();
+        let after: u64 = latency_histogram().iter().sum();
+        assert_eq!(after - before, 3);
+    }
+
+    #[test_case]
+    fn small_duration_conversions() {
+        let max = Duration::from_nanos(SmallDuration::MAX_NANOS);
+        assert_eq!(
+            SmallDuration::try_from(max).unwrap().as_femto_secs(),
+            SmallDuration::MAX_NANOS * 1_000_000
+        );
+        let too_large = max + Duration::from_nanos(1);
+        assert_eq!(
+            SmallDuration::try_from(too_large).err(),
+            Some(DurationTooLarge {
+                nanos: SmallDuration::MAX_NANOS as u128 + 1
+            })
+        );
+        assert_eq!(
+            *SmallDuration::saturating_from(Duration::from_secs(1_000_000)).as_duration(),
+            max
+        );
+        assert_eq!(
+            SmallDuration::from_millis(3).unwrap().as_femto_secs(),
+            3_000_000_000_000
+        );
+        assert_eq!(
+            SmallDuration::from_micros(3).unwrap().as_femto_secs(),
+            3_000_000_000
+        );
+        assert!(SmallDuration::from_millis(u64::MAX).is_err());
+    }
+
+    static ONESHOT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_oneshot_call() {
+        ONESHOT_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn oneshot_fires_once() {
+        SHARED_IDT.guard(|idt| unsafe { idt.lock().as_ref().load() });
+        let before = ONESHOT_CALLS.load(Ordering::Relaxed);
+        let handle = oneshot(Duration::from_millis(1), count_oneshot_call).unwrap();
+        let num = handle.timer.num();
+        assert!(Hpet::is_claimed(num));
+        unsafe { crate::interrupts::irq_enable() };
+        poll_sleep(Duration::from_millis(20));
+        unsafe { crate::interrupts::irq_disable() };
+        assert!(handle.has_fired());
+        assert_eq!(ONESHOT_CALLS.load(Ordering::Relaxed), before + 1);
+
+        // canceling a fired oneshot does nothing but free the timer
+        handle.cancel();
+        assert_eq!(ONESHOT_CALLS.load(Ordering::Relaxed), before + 1);
+        assert!(!Hpet::is_claimed(num));
+
+        // a canceled oneshot never fires
+        let handle = oneshot(Duration::from_millis(1), count_oneshot_call).unwrap();
+        drop(handle);
+        unsafe { crate::interrupts::irq_enable() };
+        poll_sleep(Duration::from_millis(5));
+        unsafe { crate::interrupts::irq_disable() };
+        assert_eq!(ONESHOT_CALLS.load(Ordering::Relaxed), before + 1);
+
+        // an already passed duration runs right away
+        let handle = oneshot(Duration::ZERO, count_oneshot_call).unwrap();
+        assert!(handle.has_fired());
+        assert_eq!(ONESHOT_CALLS.load(Ordering::Relaxed), before + 2);
+        drop(handle);
+
+        // all the timers taken
+        let mut claimed = alloc::vec::Vec::new();
+        while let Some(timer) = Hpet::claim_timer() {
+            claimed.push(timer);
+        }
+        assert_eq!(
+            oneshot(Duration::from_millis(1), count_oneshot_call).err(),
+            Some(OneshotError::NoFreeTimer)
+        );
+        for timer in claimed {
+            unsafe { Hpet::release_timer(&timer) };
+        }
+    }
+
+    #[test_case]
+    fn sleep_halts_until_deadline() {
+        SHARED_IDT.guard(|idt| unsafe { idt.lock().as_ref().load() });
+        Hpet::enable();
+        let start = elapsed_fs();
+        sleep(Duration::from_millis(2));
+        assert!(elapsed_fs() - start >= 2_000_000_000_000);
+        // the interrupts are left disabled, like they were
+        assert!(!irq_is_enabled());
+
+        // durations which may pass before the timer is armed don't leave us halting forever
+        sleep(Duration::from_nanos(1));
+        sleep(Duration::ZERO);
+    }
+}
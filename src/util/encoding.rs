@@ -0,0 +1,178 @@
+/// Why some input couldn't be decoded
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecodeError {
+    /// the byte at this index isn't a valid digit
+    InvalidDigit(usize),
+    /// the input can't be split into whole bytes (i.e. an odd amount of hex digits)
+    InvalidLength,
+    /// the output buffer is too small, this many bytes are needed
+    BufferTooSmall(usize),
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The value of a single hex digit, either case
+pub fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a hex number, with or without a leading 0x.
+/// Returns None if it's empty, has anything but hex digits, or doesn't fit in a u64.
+pub fn parse_hex_u64(bytes: &[u8]) -> Option<u64> {
+    let digits = bytes
+        .strip_prefix(b"0x")
+        .or_else(|| bytes.strip_prefix(b"0X"))
+        .unwrap_or(bytes);
+    if digits.is_empty() {
+        return None;
+    }
+    let mut value = 0u64;
+    for c in digits {
+        // the top digit is taken, shifting would overflow
+        if value >> 60 != 0 {
+            return None;
+        }
+        value = (value << 4) | hex_digit(*c)? as u64;
+    }
+    Some(value)
+}
+
+/// Decode hex digits (2 per byte, no 0x) into out.
+/// Returns the amount of bytes written.
+pub fn hex_decode(input: &[u8], out: &mut [u8]) -> Result<usize, DecodeError> {
+    if !input.len().is_multiple_of(2) {
+        return Err(DecodeError::InvalidLength);
+    }
+    let len = input.len() / 2;
+    if out.len() < len {
+        return Err(DecodeError::BufferTooSmall(len));
+    }
+    for (i, pair) in input.chunks_exact(2).enumerate() {
+        let high = hex_digit(pair[0]).ok_or(DecodeError::InvalidDigit(2 * i))?;
+        let low = hex_digit(pair[1]).ok_or(DecodeError::InvalidDigit(2 * i + 1))?;
+        out[i] = (high << 4) | low;
+    }
+    Ok(len)
+}
+
+fn base64_digit(c: u8) -> Option<u8> {
+    BASE64_ALPHABET
+        .iter()
+        .position(|d| *d == c)
+        .map(|i| i as u8)
+}
+
+/// Decode standard (padded) base64 into out.
+/// Returns the amount of bytes written.
+pub fn base64_decode(input: &[u8], out: &mut [u8]) -> Result<usize, DecodeError> {
+    if !input.len().is_multiple_of(4) {
+        return Err(DecodeError::InvalidLength);
+    }
+    // padding is only allowed at the very end
+    let padding = input.iter().rev().take_while(|c| **c == b'=').count();
+    if padding > 2 {
+        return Err(DecodeError::InvalidDigit(input.len() - padding));
+    }
+    let len = input.len() / 4 * 3 - padding;
+    if out.len() < len {
+        return Err(DecodeError::BufferTooSmall(len));
+    }
+    let digits = &input[..input.len() - padding];
+    let mut written = 0;
+    for (chunk_idx, chunk) in digits.chunks(4).enumerate() {
+        let mut group = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let digit = base64_digit(*c).ok_or(DecodeError::InvalidDigit(chunk_idx * 4 + i))?;
+            group |= (digit as u32) << (18 - 6 * i);
+        }
+        // 4 digits make 3 bytes, the padded last chunk makes less
+        let bytes = chunk.len() * 6 / 8;
+        for i in 0..bytes {
+            out[written] = (group >> (16 - 8 * i)) as u8;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_hex() {
+        assert_eq!(parse_hex_u64(b"ffffffff80000000"), Some(0xffffffff80000000));
+        assert_eq!(parse_hex_u64(b"0x1A"), Some(0x1a));
+        assert_eq!(parse_hex_u64(b"0X0"), Some(0));
+        assert_eq!(parse_hex_u64(b"ffffffffffffffff"), Some(u64::MAX));
+        assert_eq!(parse_hex_u64(b"0000000000000000001"), Some(1));
+        // overflow
+        assert_eq!(parse_hex_u64(b"10000000000000000"), None);
+        assert_eq!(parse_hex_u64(b""), None);
+        assert_eq!(parse_hex_u64(b"0x"), None);
+        assert_eq!(parse_hex_u64(b"12g4"), None);
+        assert_eq!(parse_hex_u64(b" 12"), None);
+    }
+
+    #[test_case]
+    fn decode_hex() {
+        let mut buf = [0; 4];
+        assert_eq!(hex_decode(b"deADbeef", &mut buf), Ok(4));
+        assert_eq!(buf, [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(hex_decode(b"", &mut buf), Ok(0));
+        assert_eq!(
+            hex_decode(b"abc", &mut buf),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            hex_decode(b"a0zz", &mut buf),
+            Err(DecodeError::InvalidDigit(2))
+        );
+        assert_eq!(
+            hex_decode(b"0011223344", &mut buf),
+            Err(DecodeError::BufferTooSmall(5))
+        );
+    }
+
+    #[test_case]
+    fn decode_base64() {
+        let mut buf = [0; 16];
+        assert_eq!(base64_decode(b"", &mut buf), Ok(0));
+        assert_eq!(base64_decode(b"Zg==", &mut buf), Ok(1));
+        assert_eq!(&buf[..1], b"f");
+        assert_eq!(base64_decode(b"Zm8=", &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"fo");
+        assert_eq!(base64_decode(b"Zm9vYmFy", &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"foobar");
+        assert_eq!(base64_decode(b"a+/b", &mut buf), Ok(3));
+        assert_eq!(&buf[..3], [0x6b, 0xef, 0xdb]);
+
+        assert_eq!(
+            base64_decode(b"Zm9", &mut buf),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            base64_decode(b"Zm!v", &mut buf),
+            Err(DecodeError::InvalidDigit(2))
+        );
+        // padding in the middle
+        assert_eq!(
+            base64_decode(b"Zg==Zg==", &mut buf),
+            Err(DecodeError::InvalidDigit(2))
+        );
+        assert_eq!(
+            base64_decode(b"Z===", &mut buf),
+            Err(DecodeError::InvalidDigit(1))
+        );
+        assert_eq!(
+            base64_decode(b"Zm9vYmFy", &mut buf[..5]),
+            Err(DecodeError::BufferTooSmall(6))
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use core::fmt;
+
+/// A byte count which is displayed in the biggest binary unit it reaches, i.e. "1.5 MiB" or "512 B".
+/// The fraction is truncated to one digit, so the displayed size is never bigger than the real one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanBytes(pub u64);
+
+const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0;
+        let exponent = if bytes == 0 {
+            0
+        } else {
+            (bytes.ilog2() / 10) as usize
+        };
+        if exponent == 0 {
+            return write!(f, "{} B", bytes);
+        }
+        let unit = 1u64 << (exponent * 10);
+        let whole = bytes / unit;
+        // bytes % unit < 2^60, so this can't overflow
+        let tenths = bytes % unit * 10 / unit;
+        if tenths == 0 {
+            write!(f, "{} {}", whole, UNITS[exponent])
+        } else {
+            write!(f, "{}.{} {}", whole, tenths, UNITS[exponent])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn units() {
+        assert_eq!(format!("{}", HumanBytes(0)), "0 B");
+        assert_eq!(format!("{}", HumanBytes(1023)), "1023 B");
+        assert_eq!(format!("{}", HumanBytes(1024)), "1 KiB");
+        assert_eq!(format!("{}", HumanBytes(1536)), "1.5 KiB");
+        // truncated, not rounded up to 2 KiB
+        assert_eq!(format!("{}", HumanBytes(2047)), "1.9 KiB");
+        assert_eq!(format!("{}", HumanBytes(3 << 30)), "3 GiB");
+        assert_eq!(format!("{}", HumanBytes(u64::MAX)), "15.9 EiB");
+    }
+}
@@ -0,0 +1,12 @@
+pub mod checksum;
+pub mod encoding;
+pub mod human_bytes;
+pub mod ring_buffer;
+pub mod stack_string;
+pub mod volatile;
+
+pub use checksum::crc32;
+pub use encoding::parse_hex_u64;
+pub use human_bytes::HumanBytes;
+pub use ring_buffer::RingBuffer;
+pub use stack_string::StackString;
@@ -0,0 +1,109 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+/// A fixed size queue which an interrupt handler can push into without locking or allocating,
+/// like the softirq queue. There may only be one producer at a time (i.e. the handler of one irq),
+/// consumers take turns: if another one is popping, pop returns None.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    // both only go up, the slot of an index is index % N
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+    popping: AtomicBool,
+}
+
+// safety: a slot is only written by the producer before it's published by tail,
+// and only read by the consumer before it's given back by head
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    /// Add value at the end. Returns false if the buffer is full, in which case it's dropped and counted in dropped().
+    /// ## Safety
+    /// There may not be another push running at the same time.
+    pub unsafe fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        // publishes the slot as well
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Take the value at the start, None if the buffer is empty or someone else is popping
+    pub fn pop(&self) -> Option<T> {
+        if self.popping.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let value = (head != self.tail.load(Ordering::Acquire)).then(|| {
+            // safety: push wrote it before publishing it, and won't touch it until we give it back
+            let value = unsafe { (*self.slots[head % N].get()).assume_init() };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            value
+        });
+        self.popping.store(false, Ordering::Release);
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Amount of values pushed while the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn push_and_pop() {
+        let buffer = RingBuffer::<u8, 4>::new();
+        assert_eq!(buffer.pop(), None);
+        // wraps around a few times
+        for round in 0..3u8 {
+            for i in 0..4 {
+                assert!(unsafe { buffer.push(round * 4 + i) });
+            }
+            assert!(!unsafe { buffer.push(0xff) });
+            assert_eq!(buffer.len(), 4);
+            for i in 0..4 {
+                assert_eq!(buffer.pop(), Some(round * 4 + i));
+            }
+            assert!(buffer.is_empty());
+        }
+        assert_eq!(buffer.dropped(), 3);
+    }
+}
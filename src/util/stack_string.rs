@@ -0,0 +1,109 @@
+use core::fmt;
+
+/// A string in a fixed size buffer, for formatting where we can't allocate or take the logger's lock,
+/// i.e. in interrupt handlers. Format into it with write!, then output it with qemu_log::write_unlocked.
+/// Writes which don't fit are truncated (on a char boundary), and the write fails so that write! stops early.
+pub struct StackString<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> StackString<N> {
+    pub const fn new() -> Self {
+        StackString {
+            buf: [0; N],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // safety: only whole chars of &strs are ever copied into buf
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether something didn't fit and was cut off
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Default for StackString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for StackString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = N - self.len;
+        let mut fits = s.len().min(room);
+        // don't cut a char in half
+        while !s.is_char_boundary(fits) {
+            fits -= 1;
+        }
+        self.buf[self.len..self.len + fits].copy_from_slice(&s.as_bytes()[..fits]);
+        self.len += fits;
+        if fits < s.len() {
+            self.truncated = true;
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Display for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn truncates_on_overflow() {
+        let mut s = StackString::<8>::new();
+        write!(s, "irq {}", 32).unwrap();
+        assert_eq!(s.as_str(), "irq 32");
+        assert!(!s.is_truncated());
+
+        assert!(write!(s, "; vector {}", 0x20).is_err());
+        assert_eq!(s.as_str(), "irq 32; ");
+        assert_eq!(s.len(), 8);
+        assert!(s.is_truncated());
+
+        // a char which doesn't fit entirely is dropped
+        s.clear();
+        assert!(write!(s, "1234567é").is_err());
+        assert_eq!(s.as_str(), "1234567");
+        assert!(s.is_truncated());
+
+        let mut empty = StackString::<0>::new();
+        assert!(write!(empty, "").is_ok());
+        assert!(write!(empty, "a").is_err());
+        assert!(empty.is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use core::{ops::Range, ptr::NonNull};
+
+/// The access goes past the end of a VolatileSlice
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct OutOfRange {
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Memory which is only ever accessed with bounds checked, volatile reads and writes,
+/// i.e. memory mapped IO such as the framebuffer.
+#[derive(Clone)]
+pub struct VolatileSlice {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl VolatileSlice {
+    /// ## Safety
+    /// ptr must be valid for reads and writes of len bytes for as long as the VolatileSlice (or a clone of it) lives.
+    pub unsafe fn new(ptr: NonNull<u8>, len: usize) -> Self {
+        Self { ptr, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn check(&self, offset: usize, size: usize) -> Result<(), OutOfRange> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(OutOfRange {
+                offset,
+                len: self.len,
+            }),
+        }
+    }
+
+    pub fn write_u32_at(&mut self, offset: usize, val: u32) -> Result<(), OutOfRange> {
+        self.check(offset, size_of::<u32>())?;
+        // safety: checked that it's in range above, and new requires the whole range to be valid
+        unsafe {
+            let ptr = self.ptr.add(offset);
+            if !ptr.cast::<u32>().is_aligned() {
+                return self.write_bytes_at(offset, &val.to_le_bytes());
+            }
+            ptr.cast::<u32>().write_volatile(val);
+        }
+        Ok(())
+    }
+
+    /// Write bytes starting at offset, one at a time, i.e. a pixel which is 3 bytes
+    pub fn write_bytes_at(&mut self, offset: usize, bytes: &[u8]) -> Result<(), OutOfRange> {
+        self.check(offset, bytes.len())?;
+        // safety: same as write_u32_at
+        unsafe {
+            let ptr = self.ptr.add(offset);
+            for (i, byte) in bytes.iter().enumerate() {
+                ptr.add(i).write_volatile(*byte);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill bytes with the ones starting at offset, one at a time
+    pub fn read_bytes_at(&self, offset: usize, bytes: &mut [u8]) -> Result<(), OutOfRange> {
+        self.check(offset, bytes.len())?;
+        // safety: same as write_u32_at
+        unsafe {
+            let ptr = self.ptr.add(offset);
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = ptr.add(i).read_volatile();
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the bytes in src to dest, like slice::copy_within. The ranges may overlap.
+    pub fn copy_within(&mut self, src: Range<usize>, dest: usize) -> Result<(), OutOfRange> {
+        let count = src.end.saturating_sub(src.start);
+        self.check(src.start, count)?;
+        self.check(dest, count)?;
+        // safety: same as write_u32_at
+        unsafe {
+            let from = self.ptr.add(src.start);
+            let to = self.ptr.add(dest);
+            // word by word when possible, the framebuffer is slow
+            let word = size_of::<u64>();
+            let (unit, units) = if from.cast::<u64>().is_aligned()
+                && to.cast::<u64>().is_aligned()
+                && count.is_multiple_of(word)
+            {
+                (word, count / word)
+            } else {
+                (1, count)
+            };
+            let copy = |i: usize| {
+                if unit == word {
+                    let value = from.cast::<u64>().add(i).read_volatile();
+                    to.cast::<u64>().add(i).write_volatile(value);
+                } else {
+                    to.add(i).write_volatile(from.add(i).read_volatile());
+                }
+            };
+            // copy away from the overlap, so that nothing is overwritten before it's copied
+            if dest <= src.start {
+                (0..units).for_each(copy);
+            } else {
+                (0..units).rev().for_each(copy);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_u32_at(&self, offset: usize) -> Result<u32, OutOfRange> {
+        self.check(offset, size_of::<u32>())?;
+        // safety: same as write_u32_at
+        unsafe {
+            let ptr = self.ptr.add(offset);
+            if ptr.cast::<u32>().is_aligned() {
+                Ok(ptr.cast::<u32>().read_volatile())
+            } else {
+                let mut bytes = [0; 4];
+                self.read_bytes_at(offset, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn bounds_checked_access() {
+        let mut buf = [0u32; 4];
+        let mut slice = unsafe { VolatileSlice::new(NonNull::from(&mut buf).cast(), 16) };
+        assert_eq!(slice.write_u32_at(0, 1), Ok(()));
+        assert_eq!(slice.write_u32_at(12, 2), Ok(()));
+        // straddles the end
+        assert_eq!(
+            slice.write_u32_at(13, 3),
+            Err(OutOfRange {
+                offset: 13,
+                len: 16
+            })
+        );
+        assert!(slice.write_u32_at(16, 3).is_err());
+        assert!(slice.write_u32_at(usize::MAX, 3).is_err());
+        // unaligned
+        assert_eq!(slice.write_u32_at(5, 0xaabbccdd), Ok(()));
+        assert_eq!(slice.read_u32_at(5), Ok(0xaabbccdd));
+        assert_eq!(slice.read_u32_at(0), Ok(1));
+        assert!(slice.read_u32_at(14).is_err());
+        assert_eq!(buf[3], 2);
+        // a 3 byte pixel at the very end
+        assert_eq!(slice.write_bytes_at(13, &[4, 5, 6]), Ok(()));
+        let mut bytes = [0; 3];
+        assert_eq!(slice.read_bytes_at(13, &mut bytes), Ok(()));
+        assert_eq!(bytes, [4, 5, 6]);
+        assert!(slice.write_bytes_at(14, &[4, 5, 6]).is_err());
+        assert!(slice.read_bytes_at(14, &mut bytes).is_err());
+    }
+
+    #[test_case]
+    fn overlapping_copies() {
+        let mut buf = [0u8; 32];
+        buf.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let mut slice = unsafe { VolatileSlice::new(NonNull::from(&mut buf).cast(), 32) };
+        // backwards and forwards, word by word and byte by byte
+        slice.copy_within(8..24, 0).unwrap();
+        slice.copy_within(1..4, 2).unwrap();
+        assert!(slice.copy_within(20..33, 0).is_err());
+        assert!(slice.copy_within(0..8, 30).is_err());
+        let mut expected = [0u8; 32];
+        expected
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8);
+        expected.copy_within(8..24, 0);
+        expected.copy_within(1..4, 2);
+        assert_eq!(buf, expected);
+    }
+}
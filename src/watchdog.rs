@@ -0,0 +1,107 @@
+// A one shot timer which fires an NMI unless it's disarmed in time.
+// Being an NMI, it fires even if whatever got stuck disabled interrupts (i.e. spinning on a lock inside interrupt_guard).
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use crate::dev::{
+    hpet::{Hpet, Timer},
+    ioapic::{
+        DeliveryMode, DestinationMode, InterruptPolarity, IoApic, IoApicRedirectEntry, TriggerMode,
+    },
+    local_apic::LocalApic,
+};
+
+/// The HPET timer the watchdog uses. Timer 0 is the system timer, see cpu::init
+const WATCHDOG_TIMER: u64 = 1;
+/// What the NMI handler panics with when the watchdog fires
+pub const TIMEOUT_MESSAGE: &str = "watchdog timeout";
+
+/// main counter value at which the watchdog fires, 0 if it's disarmed
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+static ROUTED: Once<()> = Once::new();
+
+fn timer() -> Timer {
+    // safety: only the watchdog uses this timer
+    unsafe { Hpet::timer(WATCHDOG_TIMER) }
+}
+
+/// Deliver the timer's interrupt as an NMI to the current cpu
+fn route() {
+    ROUTED.call_once(|| {
+        let timer = timer();
+        // prefer the non legacy irqs
+        let irq = (0..24)
+            .rev()
+            .find(|irq| timer.can_route_irq_to(*irq))
+            .expect("the watchdog timer can't be routed to any irq");
+        timer.route_irq_to(irq);
+        IoApic::redirect_irq(
+            irq as u8,
+            IoApicRedirectEntry {
+                dest: LocalApic::id() as u8,
+                mask: false,
+                trigger_mode: TriggerMode::EdgeSensetive,
+                interrupt_polarity: InterruptPolarity::HighActive,
+                destination_mode: DestinationMode::Physical,
+                delivery_mode: DeliveryMode::Nmi,
+                // ignored for NMIs
+                redirected_irq_num: 2,
+            },
+        );
+    });
+}
+
+/// Arm the watchdog, or re-arm it with a new timeout if it's already armed.
+pub fn arm(timeout: Duration) {
+    route();
+    if Hpet::is_disabled() {
+        Hpet::enable();
+    }
+    // 10^6 femto seconds per nano second
+    let ticks = (timeout.as_nanos() * 1_000_000 / Hpet::fs_per_tick() as u128) as u64;
+    let deadline = Hpet::read_main_counter().saturating_add(ticks.max(1));
+    let timer = timer();
+    timer.disable();
+    DEADLINE.store(deadline, Ordering::Relaxed);
+    timer.set_counter_raw(deadline);
+    timer.enable();
+}
+
+pub fn disarm() {
+    DEADLINE.store(0, Ordering::Relaxed);
+    if Hpet::is_mapped() {
+        timer().disable();
+    }
+}
+
+/// Whether the watchdog is armed and its deadline passed, i.e. whether an NMI came from it
+pub fn expired() -> bool {
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    // armed implies the Hpet is mapped, so this doesn't map anything from the NMI handler
+    deadline != 0 && Hpet::read_main_counter() >= deadline
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn arm_and_disarm() {
+        // the test runner armed it for us
+        assert!(DEADLINE.load(Ordering::Relaxed) != 0);
+        arm(Duration::from_secs(60));
+        assert!(!expired());
+        let deadline = DEADLINE.load(Ordering::Relaxed);
+        crate::time::poll_sleep(Duration::from_millis(1));
+        arm(Duration::from_secs(60));
+        assert!(DEADLINE.load(Ordering::Relaxed) > deadline);
+        disarm();
+        assert!(!expired());
+        // so that the rest of the test is still watched
+        crate::test::set_test_timeout(crate::test::TEST_TIMEOUT);
+    }
+}